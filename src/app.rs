@@ -4,3 +4,4 @@ pub mod config;
 pub mod backends;
 pub mod keyvalue;
 pub mod filesystem;
+pub mod hex;
@@ -1,3 +1,9 @@
 //! Module containing the different filesystem backends
 
+pub mod directory;
+pub mod log;
+pub mod memory;
+pub mod postgres;
+pub mod redis;
+pub mod s3;
 pub mod sqlite;
@@ -0,0 +1,503 @@
+//! Directory storage backend
+//!
+//! Every key is stored as a file under a root directory, named after the key in
+//! hex and holding the value as is. This makes the store easy to inspect and
+//! lets it live on any filesystem, including network shares.
+//!
+//! Files are spread over a fixed number of levels of subdirectories named after
+//! the leading bytes of their keys, so keys sharing a prefix stay together.
+//! Keys too short to fill every level use `_` for the missing bytes. File names
+//! longer than `MAX_NAME_LEN` are split into directories whose names end with
+//! `-`, which can't be confused with hex.
+
+use crate::app::hex;
+use crate::app::keyvalue::{BatchOp, KeyValueResult, KeyValueStore};
+use crate::{try_to, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Directory backend configuration
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoryConfig {
+    /// The directory to store the files in
+    pub root: String,
+    /// The number of levels of subdirectories to spread files over. Can't be
+    /// changed once the store has been written to.
+    #[serde(default = "default_fanout")]
+    pub fanout: usize,
+    /// Whether to wait for each write to reach the disk before returning
+    #[serde(default = "default_sync")]
+    pub sync: bool,
+}
+
+fn default_fanout() -> usize {
+    2
+}
+
+fn default_sync() -> bool {
+    true
+}
+
+impl DirectoryConfig {
+    /// Create a config for the directory at `root` with the default settings
+    pub fn new(root: String) -> DirectoryConfig {
+        DirectoryConfig {
+            root,
+            fanout: default_fanout(),
+            sync: default_sync(),
+        }
+    }
+}
+
+/// The directory that files are written to before being moved into place
+const TMP_DIR: &str = ".tmp";
+/// The file that writes lock to keep out of each other's way
+const LOCK_FILE: &str = ".lock";
+/// The longest file name that is written. Most filesystems allow 255 bytes.
+const MAX_NAME_LEN: usize = 240;
+/// The name of fan-out directories for keys that are too short to fill them
+const SHORT_KEY_DIR: &str = "_";
+
+/// How old a temporary file must be before it is assumed to be left over from
+/// a crash. Another process, possibly on another host, may still be writing
+/// newer ones.
+const STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A `KeyValueStore` that keeps each key in its own file
+///
+/// Writes are made to a temporary file that is renamed over the key's file, so
+/// readers never see a partially written value. Batches are not atomic, but
+/// every value in a batch is written out before any of them are moved into
+/// place.
+///
+/// Writes hold a shared `flock` on a lock file in the root, which
+/// compare-and-swap holds exclusively, so that it is atomic with respect to
+/// every process writing to the directory. `flock` isn't supported by every
+/// network filesystem.
+#[derive(Debug)]
+pub struct DirectoryKvStore {
+    config: DirectoryConfig,
+    root: PathBuf,
+    /// Makes the names of temporary files unique across processes and hosts
+    tmp_prefix: String,
+    next_tmp: AtomicU64,
+}
+
+impl DirectoryKvStore {
+    /// Open the store in the configured directory, creating it if it doesn't
+    /// exist
+    pub fn new(config: DirectoryConfig) -> PolyfsResult<DirectoryKvStore> {
+        let root = PathBuf::from(&config.root);
+        let tmp_dir = root.join(TMP_DIR);
+
+        try_to!(fs::create_dir_all(&tmp_dir), "Could not create store directory");
+
+        // Temporary files left by a crash were never moved into place
+        for entry in try_to!(fs::read_dir(&tmp_dir), "Could not read store directory") {
+            let entry = try_to!(entry, "Could not read store directory");
+            let modified = try_to!(
+                entry.metadata().and_then(|metadata| metadata.modified()),
+                "Could not read temporary file"
+            );
+            let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+            if age < STALE_TMP_AGE {
+                continue;
+            }
+
+            match fs::remove_file(entry.path()) {
+                // Another process may have cleaned it up first
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => try_to!(result, "Could not remove temporary file"),
+            }
+        }
+
+        Ok(DirectoryKvStore {
+            config,
+            root,
+            tmp_prefix: format!("{}-{:016x}", std::process::id(), rand::random::<u64>()),
+            next_tmp: AtomicU64::new(0),
+        })
+    }
+
+    /// Lock the lock file until the returned file is dropped, shared to block
+    /// compare-and-swap while writing, or exclusive to block all other writes
+    /// during a compare-and-swap
+    ///
+    /// The lock file is opened for every write, since `flock` locks belong to
+    /// an open file and would be shared by every thread using the same one.
+    fn lock_writes(&self, exclusive: bool) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root.join(LOCK_FILE))?;
+
+        let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(file)
+    }
+
+    /// Get the fan-out directory for the leading bytes of a key
+    fn fanout_dir(&self, key: &[u8]) -> PathBuf {
+        let mut path = self.root.clone();
+        for level in 0..self.config.fanout {
+            match key.get(level) {
+                Some(byte) => path.push(hex::encode(&[*byte])),
+                None => path.push(SHORT_KEY_DIR),
+            }
+        }
+        path
+    }
+
+    /// Get the path of the file for a key
+    fn key_path(&self, key: &[u8]) -> PathBuf {
+        let mut path = self.fanout_dir(key);
+        let hex = hex::encode(key);
+
+        let mut rest = hex.as_str();
+        while rest.len() > MAX_NAME_LEN {
+            path.push(format!("{}-", &rest[..MAX_NAME_LEN]));
+            rest = &rest[MAX_NAME_LEN..];
+        }
+        path.push(rest);
+
+        path
+    }
+
+    /// Write a value to a new temporary file
+    fn write_tmp(&self, value: &[u8]) -> io::Result<PathBuf> {
+        let path = self.root.join(TMP_DIR).join(format!(
+            "{}-{}",
+            self.tmp_prefix,
+            self.next_tmp.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // Never reuse a file another writer may still be moving into place
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let result = file.write_all(value).and_then(|()| match self.config.sync {
+            true => file.sync_all(),
+            false => Ok(()),
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+
+        Ok(path)
+    }
+
+    /// Move a temporary file into place as the file for a key, removing it if
+    /// it can't be moved
+    fn rename_into_place(&self, tmp: &Path, key: &[u8]) -> io::Result<()> {
+        let path = self.key_path(key);
+        let dir = path.parent().expect("Key path has no parent");
+
+        if let Err(e) = fs::create_dir_all(dir).and_then(|()| fs::rename(tmp, &path)) {
+            let _ = fs::remove_file(tmp);
+            return Err(e);
+        }
+        if self.config.sync {
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> io::Result<()> {
+        match fs::remove_file(self.key_path(key)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Collect the keys of every file under `dir`, which is `depth` levels of
+    /// fan-out below the root
+    fn walk(&self, dir: &Path, depth: usize, hex: &str, keys: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if depth == 0 && (name == TMP_DIR || name == LOCK_FILE) {
+                continue;
+            }
+
+            if depth < self.config.fanout {
+                self.walk(&entry.path(), depth + 1, hex, keys)?;
+            } else if let Some(part) = name.strip_suffix('-') {
+                let hex = format!("{}{}", hex, part);
+                self.walk(&entry.path(), depth, &hex, keys)?;
+            } else if let Some(key) = hex::decode(&format!("{}{}", hex, name)) {
+                keys.push(key);
+            } else {
+                log::warn!("Ignoring unexpected file in store: {}", entry.path().display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get every key that starts with `prefix`, sorted
+    fn keys_with_prefix(&self, prefix: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        // Only walk the fan-out directories that can hold the prefix
+        let depth = prefix.len().min(self.config.fanout);
+        let mut dir = self.root.clone();
+        for byte in &prefix[..depth] {
+            dir.push(hex::encode(&[*byte]));
+        }
+
+        let mut keys = vec![];
+        self.walk(&dir, depth, "", &mut keys)?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+
+        Ok(keys)
+    }
+}
+
+/// Remove the temporary files of a batch that won't be moved into place
+fn remove_tmp_files(staged: impl IntoIterator<Item = (Vec<u8>, Option<PathBuf>)>) {
+    for (_, tmp) in staged {
+        if let Some(tmp) = tmp {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+impl KeyValueStore for DirectoryKvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        match fs::read(self.key_path(&key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        let tmp = self.write_tmp(&value)?;
+        let _writing = match self.lock_writes(false) {
+            Ok(lock) => lock,
+            Err(e) => {
+                let _ = fs::remove_file(tmp);
+                return Err(e.into());
+            }
+        };
+        Ok(self.rename_into_place(&tmp, &key)?)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        let _writing = self.lock_writes(false)?;
+        Ok(self.remove(&key)?)
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        Ok(self.keys_with_prefix(&[])?)
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = vec![];
+        for key in self.keys_with_prefix(&prefix)? {
+            // Skip keys deleted since they were listed
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        // Write every value first so that a failure there leaves the store
+        // unchanged
+        let mut staged = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => match self.write_tmp(&value) {
+                    Ok(tmp) => staged.push((key, Some(tmp))),
+                    Err(e) => {
+                        remove_tmp_files(staged);
+                        return Err(e.into());
+                    }
+                },
+                BatchOp::Delete(key) => staged.push((key, None)),
+            }
+        }
+
+        let _writing = match self.lock_writes(false) {
+            Ok(lock) => lock,
+            Err(e) => {
+                remove_tmp_files(staged);
+                return Err(e.into());
+            }
+        };
+        let mut staged = staged.into_iter();
+        while let Some((key, tmp)) = staged.next() {
+            let result = match tmp {
+                Some(tmp) => self.rename_into_place(&tmp, &key),
+                None => self.remove(&key),
+            };
+            // Don't leave the files that haven't been moved yet behind
+            if let Err(e) = result {
+                remove_tmp_files(staged);
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let _writing = self.lock_writes(true)?;
+
+        if self.get(key.clone())? != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => {
+                let tmp = self.write_tmp(&value)?;
+                self.rename_into_place(&tmp, &key)?;
+            }
+            None => self.remove(&key)?,
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn keys_as_files() -> TestResult {
+        let root = std::env::temp_dir().join(format!("polyfs-directory-{}", std::process::id()));
+
+        let result = (|| -> TestResult {
+            let kv_store = DirectoryKvStore::new(DirectoryConfig {
+                sync: false,
+                ..DirectoryConfig::new(root.to_string_lossy().into_owned())
+            })?;
+            let long_key = vec![7; 300];
+
+            kv_store.batch(vec![
+                BatchOp::Set(vec![1], b"short".to_vec()),
+                BatchOp::Set(vec![1, 2, 3], b"a".to_vec()),
+                BatchOp::Set(long_key.clone(), b"long".to_vec()),
+                BatchOp::Set(vec![2], b"deleted".to_vec()),
+                BatchOp::Delete(vec![2]),
+            ])?;
+            kv_store.set(vec![1, 2, 3], b"b".to_vec())?;
+
+            assert!(root.join("01").join("02").join("010203").is_file());
+            assert!(root.join("01").join("_").join("01").is_file());
+            assert_eq!(kv_store.get(vec![1, 2, 3])?.unwrap(), b"b");
+            assert_eq!(kv_store.get(long_key.clone())?.unwrap(), b"long");
+            assert_eq!(kv_store.get(vec![2])?, None);
+
+            assert_eq!(
+                kv_store.scan_prefix(vec![1])?,
+                vec![(vec![1], b"short".to_vec()), (vec![1, 2, 3], b"b".to_vec())]
+            );
+            assert_eq!(kv_store.list()?, vec![vec![1], vec![1, 2, 3], long_key]);
+
+            assert!(kv_store.compare_and_swap(vec![2], None, Some(b"new".to_vec()))?);
+            assert!(!kv_store.compare_and_swap(vec![2], None, Some(b"other".to_vec()))?);
+            assert!(kv_store.compare_and_swap(vec![2], Some(b"new".to_vec()), None)?);
+            assert_eq!(kv_store.get(vec![2])?, None);
+
+            Ok(())
+        })();
+
+        let _ = fs::remove_dir_all(&root);
+
+        result
+    }
+
+    #[test]
+    fn remove_stale_tmp_files() -> TestResult {
+        let root = std::env::temp_dir().join(format!("polyfs-directory-tmp-{}", std::process::id()));
+        let config = || DirectoryConfig::new(root.to_string_lossy().into_owned());
+
+        let result = (|| -> TestResult {
+            DirectoryKvStore::new(config())?;
+            let tmp_dir = root.join(TMP_DIR);
+
+            // Another process may still be about to move a recent file into place
+            File::create(tmp_dir.join("1-0"))?;
+            File::create(tmp_dir.join("1-1"))?
+                .set_modified(SystemTime::now() - STALE_TMP_AGE - Duration::from_secs(1))?;
+
+            DirectoryKvStore::new(config())?;
+            assert!(tmp_dir.join("1-0").exists());
+            assert!(!tmp_dir.join("1-1").exists());
+
+            Ok(())
+        })();
+
+        let _ = fs::remove_dir_all(&root);
+
+        result
+    }
+
+    #[test]
+    fn failed_writes_remove_tmp_files() -> TestResult {
+        let root = std::env::temp_dir().join(format!("polyfs-directory-fail-{}", std::process::id()));
+
+        let result = (|| -> TestResult {
+            let kv_store = DirectoryKvStore::new(DirectoryConfig {
+                sync: false,
+                ..DirectoryConfig::new(root.to_string_lossy().into_owned())
+            })?;
+
+            // Keys starting with 3 can't be written while a file is in the
+            // way of their directory
+            File::create(root.join("03"))?;
+
+            assert!(kv_store.set(vec![3], b"a".to_vec()).is_err());
+            assert!(kv_store
+                .batch(vec![
+                    BatchOp::Set(vec![3], b"a".to_vec()),
+                    BatchOp::Set(vec![4], b"b".to_vec()),
+                    BatchOp::Set(vec![5], b"c".to_vec()),
+                ])
+                .is_err());
+            assert!(kv_store.compare_and_swap(vec![3], None, Some(b"a".to_vec())).is_err());
+
+            assert_eq!(fs::read_dir(root.join(TMP_DIR))?.count(), 0);
+            assert_eq!(kv_store.get(vec![4])?, None);
+
+            Ok(())
+        })();
+
+        let _ = fs::remove_dir_all(&root);
+
+        result
+    }
+}
@@ -0,0 +1,806 @@
+//! Log-structured storage backend
+//!
+//! Every write is appended to a single log file as a checksummed record, and an
+//! in-memory index maps each key to the position of its latest value in the
+//! log. Writes never modify earlier parts of the log, so a crash can only leave
+//! a partially written record at the end of it, which is discarded when the log
+//! is opened again.
+//!
+//! Overwritten and deleted values stay in the log until it is compacted by
+//! copying the live values into a new log that replaces the old one.
+
+use crate::app::keyvalue::{prefix_successor, BatchOp, KeyValueResult, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Log backend configuration
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// The directory to keep the log in
+    pub dir: String,
+    /// Whether to wait for each write to reach the disk before returning
+    #[serde(default = "default_sync")]
+    pub sync: bool,
+    /// The number of bytes of overwritten and deleted values to allow in the
+    /// log before compacting it. The log is also only compacted when at least
+    /// half of it is garbage.
+    #[serde(default = "default_compaction_threshold")]
+    pub compaction_threshold: u64,
+}
+
+fn default_sync() -> bool {
+    true
+}
+
+/// The default `compaction_threshold`
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+fn default_compaction_threshold() -> u64 {
+    DEFAULT_COMPACTION_THRESHOLD
+}
+
+/// The name of the log file in the log directory
+const LOG_FILE: &str = "polyfs.log";
+/// The name of the log being written during compaction
+const COMPACT_FILE: &str = "polyfs.log.compact";
+/// The name of the file that is locked while a store has the log open
+const LOCK_FILE: &str = "polyfs.lock";
+
+// A record is `[crc32 of the rest][payload length][payload]` with big-endian
+// `u32`s. The payload is a sequence of operations, each `[tag][key length][key]`
+// followed by `[value length][value]` for sets. A batch is a single record so
+// that it is either recovered entirely or not at all.
+const HEADER_LEN: usize = 8;
+const OP_SET: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+/// The location of a value in the log
+#[derive(Debug, Clone, Copy)]
+struct ValuePos {
+    offset: u64,
+    len: u32,
+}
+
+/// An operation decoded from a record payload
+enum LogOp<'a> {
+    Set {
+        key: &'a [u8],
+        value_offset: usize,
+        value_len: u32,
+    },
+    Delete {
+        key: &'a [u8],
+    },
+}
+
+/// Decode the operations in a record payload, or `None` if it is malformed
+fn decode_ops(payload: &[u8]) -> Option<Vec<LogOp<'_>>> {
+    fn read_u32(payload: &[u8], pos: &mut usize) -> Option<u32> {
+        let bytes = payload.get(*pos..*pos + 4)?;
+        *pos += 4;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    let mut ops = vec![];
+    let mut pos = 0;
+
+    while pos < payload.len() {
+        let tag = payload[pos];
+        pos += 1;
+
+        let key_len = read_u32(payload, &mut pos)? as usize;
+        let key = payload.get(pos..pos + key_len)?;
+        pos += key_len;
+
+        ops.push(match tag {
+            OP_SET => {
+                let value_len = read_u32(payload, &mut pos)?;
+                let value_offset = pos;
+                payload.get(pos..pos + value_len as usize)?;
+                pos += value_len as usize;
+
+                LogOp::Set {
+                    key,
+                    value_offset,
+                    value_len,
+                }
+            }
+            OP_DELETE => LogOp::Delete { key },
+            _ => return None,
+        });
+    }
+
+    Some(ops)
+}
+
+/// Encode a batch of operations as a record
+fn encode_record(ops: &[BatchOp]) -> io::Result<Vec<u8>> {
+    fn push_len(record: &mut Vec<u8>, len: usize) -> io::Result<()> {
+        let len: u32 = len.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Key or value is too large")
+        })?;
+        record.extend_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+
+    let mut record = vec![0; HEADER_LEN];
+    for op in ops {
+        match op {
+            BatchOp::Set(key, value) => {
+                record.push(OP_SET);
+                push_len(&mut record, key.len())?;
+                record.extend_from_slice(key);
+                push_len(&mut record, value.len())?;
+                record.extend_from_slice(value);
+            }
+            BatchOp::Delete(key) => {
+                record.push(OP_DELETE);
+                push_len(&mut record, key.len())?;
+                record.extend_from_slice(key);
+            }
+        }
+    }
+
+    let payload_len = record.len() - HEADER_LEN;
+    let mut length = vec![];
+    push_len(&mut length, payload_len)?;
+    record[4..HEADER_LEN].copy_from_slice(&length);
+    let crc = crc32fast::hash(&record[4..]);
+    record[..4].copy_from_slice(&crc.to_be_bytes());
+
+    Ok(record)
+}
+
+/// The open log and its index
+struct LogState {
+    file: File,
+    index: BTreeMap<Vec<u8>, ValuePos>,
+    /// The length of the valid part of the log
+    end: u64,
+    /// The number of bytes in the log taken up by overwritten or deleted values
+    garbage: u64,
+}
+
+/// The number of bytes a set operation takes up in a record
+fn set_len(key_len: usize, value_len: u32) -> u64 {
+    9 + key_len as u64 + u64::from(value_len)
+}
+
+impl LogState {
+    /// Update the index with the operations of a record whose payload starts
+    /// at `payload_offset` in the log
+    fn apply(&mut self, payload_offset: u64, payload: &[u8]) -> Option<()> {
+        for op in decode_ops(payload)? {
+            match op {
+                LogOp::Set {
+                    key,
+                    value_offset,
+                    value_len,
+                } => {
+                    let pos = ValuePos {
+                        offset: payload_offset + value_offset as u64,
+                        len: value_len,
+                    };
+                    if let Some(old) = self.index.insert(key.to_vec(), pos) {
+                        self.garbage += set_len(key.len(), old.len);
+                    }
+                }
+                LogOp::Delete { key } => {
+                    if let Some(old) = self.index.remove(key) {
+                        self.garbage += set_len(key.len(), old.len);
+                    }
+                    // The delete itself is garbage as soon as it is written
+                    self.garbage += 5 + key.len() as u64;
+                }
+            }
+        }
+
+        Some(())
+    }
+
+    /// Read the value at a position in the log
+    fn read_value(&self, pos: ValuePos) -> io::Result<Vec<u8>> {
+        let mut value = vec![0; pos.len as usize];
+        self.file.read_exact_at(&mut value, pos.offset)?;
+        Ok(value)
+    }
+
+    /// Append a record to the log and add its operations to the index
+    fn append(&mut self, record: &[u8], sync: bool) -> io::Result<()> {
+        if let Err(e) = self.file.write_all_at(record, self.end) {
+            // Don't leave part of the record behind for the next write to
+            // follow
+            let _ = self.file.set_len(self.end);
+            return Err(e);
+        }
+        if sync {
+            self.file.sync_data()?;
+        }
+
+        let payload_offset = self.end + HEADER_LEN as u64;
+        self.end += record.len() as u64;
+        self.apply(payload_offset, &record[HEADER_LEN..])
+            .expect("Encoded record could not be decoded");
+
+        Ok(())
+    }
+}
+
+/// Read the header of the record at `offset` in a log of `len` bytes, giving
+/// its checksum and payload length, or `None` if the record doesn't fit in
+/// the log
+fn read_header(file: &File, offset: u64, len: u64) -> io::Result<Option<(u32, u64)>> {
+    if len - offset < HEADER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_LEN];
+    file.read_exact_at(&mut header, offset)?;
+    let crc = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let payload_len = u32::from_be_bytes(header[4..].try_into().unwrap());
+
+    if u64::from(payload_len) > len - offset - HEADER_LEN as u64 {
+        return Ok(None);
+    }
+    Ok(Some((crc, u64::from(payload_len))))
+}
+
+/// The checksum of a record with the given payload
+fn record_crc(payload_len: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(payload_len as u32).to_be_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Read the payload of the record at `offset`, or `None` if there is no
+/// complete record with a valid checksum there
+fn read_record(file: &File, offset: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+    let (crc, payload_len) = match read_header(file, offset, len)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let mut payload = vec![0; payload_len as usize];
+    file.read_exact_at(&mut payload, offset + HEADER_LEN as u64)?;
+
+    if record_crc(payload_len, &payload) == crc {
+        Ok(Some(payload))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Find the first offset after `offset` at which a complete record with a
+/// valid checksum starts
+///
+/// Payloads are checksummed in pieces so that a corrupt length can't make this
+/// read a large part of the log into memory.
+fn find_record_after(file: &File, offset: u64, len: u64) -> io::Result<Option<u64>> {
+    let mut piece = vec![0; 64 * 1024];
+
+    for candidate in offset + 1..len {
+        let (crc, payload_len) = match read_header(file, candidate, len)? {
+            Some(header) => header,
+            None => continue,
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&(payload_len as u32).to_be_bytes());
+        let mut read = 0;
+        while read < payload_len {
+            let n = (payload_len - read).min(piece.len() as u64) as usize;
+            file.read_exact_at(&mut piece[..n], candidate + HEADER_LEN as u64 + read)?;
+            hasher.update(&piece[..n]);
+            read += n as u64;
+        }
+
+        if hasher.finalize() == crc {
+            return Ok(Some(candidate));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read the records in a log and build its index
+///
+/// A record that is incomplete or fails its checksum, and isn't followed by
+/// any valid record, was being written when the store stopped and is cut off.
+/// If a valid record follows it, the log is corrupt, and nothing is cut off so
+/// that the records after it aren't lost.
+fn recover(file: File) -> io::Result<LogState> {
+    let len = file.metadata()?.len();
+    let mut state = LogState {
+        file,
+        index: BTreeMap::new(),
+        end: 0,
+        garbage: 0,
+    };
+
+    while state.end < len {
+        let payload = match read_record(&state.file, state.end, len)? {
+            Some(payload) => payload,
+            None => {
+                if let Some(next) = find_record_after(&state.file, state.end, len)? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Log record at offset {} is corrupt, and is followed by a valid \
+                             record at offset {}",
+                            state.end, next
+                        ),
+                    ));
+                }
+
+                log::warn!(
+                    "Discarding incomplete record at the end of the log ({} bytes)",
+                    len - state.end
+                );
+                state.file.set_len(state.end)?;
+                state.file.sync_all()?;
+                break;
+            }
+        };
+
+        let payload_offset = state.end + HEADER_LEN as u64;
+        state.apply(payload_offset, &payload).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Log record at offset {} is malformed", state.end),
+            )
+        })?;
+        state.end = payload_offset + payload.len() as u64;
+    }
+
+    Ok(state)
+}
+
+/// A `KeyValueStore` backed by an append-only log
+///
+/// Reads may run concurrently with each other, while writes are serialized.
+/// Batches are written as a single record, so they are applied atomically.
+pub struct LogKvStore {
+    config: LogConfig,
+    dir: PathBuf,
+    state: RwLock<LogState>,
+    // Held for as long as the store is open so that no other process writes
+    // to the same log
+    _lock: File,
+}
+
+impl std::fmt::Debug for LogKvStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LogKvStore {{ config: {:#?} }}", self.config)
+    }
+}
+
+impl LogKvStore {
+    /// Open the log in the configured directory, creating it if it doesn't
+    /// exist
+    pub fn open(config: LogConfig) -> PolyfsResult<LogKvStore> {
+        let dir = PathBuf::from(&config.dir);
+        try_to!(std::fs::create_dir_all(&dir), "Could not create log directory");
+
+        let lock = try_to!(
+            File::create(dir.join(LOCK_FILE)),
+            "Could not create log lock file"
+        );
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(PolyfsError {
+                message: format!("Log in '{}' is already in use", config.dir),
+                cause: Some(Box::new(io::Error::last_os_error())),
+            });
+        }
+
+        // A compaction that didn't finish never replaced the log
+        let compact_path = dir.join(COMPACT_FILE);
+        if compact_path.exists() {
+            log::warn!("Removing log from an unfinished compaction");
+            try_to!(
+                std::fs::remove_file(&compact_path),
+                "Could not remove unfinished compaction"
+            );
+        }
+
+        let file = try_to!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(LOG_FILE)),
+            "Could not open log file"
+        );
+        let state = try_to!(recover(file), "Could not read log");
+        log::debug!(
+            "Opened log with {} keys, {} of {} bytes are garbage",
+            state.index.len(),
+            state.garbage,
+            state.end
+        );
+
+        Ok(LogKvStore {
+            config,
+            dir,
+            state: RwLock::new(state),
+            _lock: lock,
+        })
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, LogState> {
+        // The index is only updated after a record has been written, so a
+        // panic can't leave it out of sync with the log
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, LogState> {
+        self.state.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Write a batch of operations to the log, compacting it afterwards if it
+    /// has collected enough garbage
+    fn write_ops(&self, ops: &[BatchOp]) -> KeyValueResult<()> {
+        self.write_ops_locked(&mut self.write(), ops)
+    }
+
+    /// Write a batch of operations to the log while already holding the
+    /// writer lock
+    fn write_ops_locked(&self, state: &mut LogState, ops: &[BatchOp]) -> KeyValueResult<()> {
+        let record = encode_record(ops)?;
+
+        state.append(&record, self.config.sync)?;
+
+        if state.garbage >= self.config.compaction_threshold && state.garbage * 2 >= state.end {
+            // The write itself succeeded, so a failed compaction is only
+            // logged and retried on the next write
+            if let Err(e) = compact(&self.dir, state) {
+                log::error!("Could not compact log: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the log with only the current value of each key
+    pub fn compact(&self) -> KeyValueResult<()> {
+        Ok(compact(&self.dir, &mut self.write())?)
+    }
+}
+
+/// Copy the live values of a log into a new log and replace the old one with it
+fn compact(dir: &Path, state: &mut LogState) -> io::Result<()> {
+    log::info!(
+        "Compacting log: {} of {} bytes are garbage",
+        state.garbage,
+        state.end
+    );
+
+    let compact_path = dir.join(COMPACT_FILE);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&compact_path)?;
+
+    let mut index = BTreeMap::new();
+    let mut end = 0;
+    {
+        let mut writer = BufWriter::new(&file);
+        for (key, pos) in &state.index {
+            let value = state.read_value(*pos)?;
+            let record = encode_record(&[BatchOp::Set(key.clone(), value)])?;
+            writer.write_all(&record)?;
+
+            // The value is the last thing in a single set record
+            index.insert(
+                key.clone(),
+                ValuePos {
+                    offset: end + record.len() as u64 - u64::from(pos.len),
+                    len: pos.len,
+                },
+            );
+            end += record.len() as u64;
+        }
+        writer.flush()?;
+    }
+    file.sync_all()?;
+
+    std::fs::rename(&compact_path, dir.join(LOG_FILE))?;
+
+    // The new log is in place from here on, so switch to it before anything
+    // else can fail
+    *state = LogState {
+        file,
+        index,
+        end,
+        garbage: 0,
+    };
+
+    // Make sure the rename itself is durable
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+impl KeyValueStore for LogKvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        let state = self.read();
+
+        match state.index.get(&key) {
+            Some(pos) => Ok(Some(state.read_value(*pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        self.write_ops(&[BatchOp::Set(key, value)])
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        // Don't fill the log with deletes of keys that don't exist
+        if !self.read().index.contains_key(&key) {
+            return Ok(());
+        }
+
+        self.write_ops(&[BatchOp::Delete(key)])
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        Ok(self.read().index.keys().cloned().collect())
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = match prefix_successor(&prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        let state = self.read();
+
+        let mut pairs = vec![];
+        for (key, pos) in state.index.range((Bound::Included(prefix), end)) {
+            pairs.push((key.clone(), state.read_value(*pos)?));
+        }
+
+        Ok(pairs)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        self.write_ops(&ops)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        // Hold the writer lock across the check and the write so no other
+        // write can come in between
+        let mut state = self.write();
+
+        let current = match state.index.get(&key) {
+            Some(pos) => Some(state.read_value(*pos)?),
+            None => None,
+        };
+        if current != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => self.write_ops_locked(&mut state, &[BatchOp::Set(key, value)])?,
+            None if current.is_some() => self.write_ops_locked(&mut state, &[BatchOp::Delete(key)])?,
+            None => {}
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// Run a test with a log in a fresh directory that is removed afterwards
+    fn with_log_dir(name: &str, test: impl FnOnce(&str) -> TestResult) -> TestResult {
+        let dir = std::env::temp_dir().join(format!("polyfs-log-{}-{}", name, std::process::id()));
+        let dir_str = dir.to_str().unwrap().to_owned();
+
+        let result = test(&dir_str);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        result
+    }
+
+    fn log_config(dir: &str) -> LogConfig {
+        LogConfig {
+            dir: dir.to_owned(),
+            sync: false,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+        }
+    }
+
+    #[test]
+    fn reopen_log() -> TestResult {
+        with_log_dir("reopen", |dir| {
+            {
+                let kv_store = LogKvStore::open(log_config(dir))?;
+                kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+                kv_store.set(b"goodbye".to_vec(), b"later".to_vec())?;
+                kv_store.batch(vec![
+                    BatchOp::Set(b"hello".to_vec(), b"mister".to_vec()),
+                    BatchOp::Delete(b"goodbye".to_vec()),
+                ])?;
+
+                // The log can't be opened twice
+                assert!(LogKvStore::open(log_config(dir)).is_err());
+            }
+
+            let kv_store = LogKvStore::open(log_config(dir))?;
+            assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"mister");
+            assert_eq!(kv_store.get(b"goodbye".to_vec())?, None);
+            assert_eq!(kv_store.scan_prefix(vec![])?.len(), 1);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn incomplete_record_is_discarded() -> TestResult {
+        with_log_dir("incomplete", |dir| {
+            let log_path = Path::new(dir).join(LOG_FILE);
+            let len = {
+                let kv_store = LogKvStore::open(log_config(dir))?;
+                kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+                let len = std::fs::metadata(&log_path)?.len();
+                kv_store.batch(vec![
+                    BatchOp::Set(b"goodbye".to_vec(), b"later".to_vec()),
+                    BatchOp::Delete(b"hello".to_vec()),
+                ])?;
+                len
+            };
+
+            // Cut the batch off half way through as if it was being written
+            // during a crash
+            let file = OpenOptions::new().write(true).open(&log_path)?;
+            file.set_len(std::fs::metadata(&log_path)?.len() - 4)?;
+
+            let kv_store = LogKvStore::open(log_config(dir))?;
+            assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"world");
+            assert_eq!(kv_store.get(b"goodbye".to_vec())?, None);
+            assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn corrupt_record_is_an_error() -> TestResult {
+        with_log_dir("corrupt", |dir| {
+            let log_path = Path::new(dir).join(LOG_FILE);
+            {
+                let kv_store = LogKvStore::open(log_config(dir))?;
+                kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+                kv_store.set(b"goodbye".to_vec(), b"later".to_vec())?;
+            }
+
+            // Flip a bit in the first value
+            let file = OpenOptions::new().read(true).write(true).open(&log_path)?;
+            let mut byte = [0];
+            file.read_exact_at(&mut byte, 20)?;
+            file.write_all_at(&[byte[0] ^ 1], 20)?;
+
+            assert!(LogKvStore::open(log_config(dir)).is_err());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn corrupt_length_keeps_later_records() -> TestResult {
+        with_log_dir("corrupt-length", |dir| {
+            let log_path = Path::new(dir).join(LOG_FILE);
+            {
+                let kv_store = LogKvStore::open(log_config(dir))?;
+                kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+                kv_store.set(b"goodbye".to_vec(), b"later".to_vec())?;
+            }
+            let len = std::fs::metadata(&log_path)?.len();
+
+            // Make the first record claim to run past the end of the log
+            let file = OpenOptions::new().write(true).open(&log_path)?;
+            file.write_all_at(&u32::MAX.to_be_bytes(), 4)?;
+
+            assert!(LogKvStore::open(log_config(dir)).is_err());
+            assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn zeroed_tail_is_discarded() -> TestResult {
+        with_log_dir("zeroed", |dir| {
+            let log_path = Path::new(dir).join(LOG_FILE);
+            {
+                let kv_store = LogKvStore::open(log_config(dir))?;
+                kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+            }
+            let len = std::fs::metadata(&log_path)?.len();
+
+            // A crash can leave the space of a record that was being written
+            // filled with zeros
+            let file = OpenOptions::new().write(true).open(&log_path)?;
+            file.set_len(len + 100)?;
+
+            let kv_store = LogKvStore::open(log_config(dir))?;
+            assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"world");
+            assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn compaction() -> TestResult {
+        with_log_dir("compaction", |dir| {
+            let log_path = Path::new(dir).join(LOG_FILE);
+            let kv_store = LogKvStore::open(LogConfig {
+                compaction_threshold: 1024,
+                ..log_config(dir)
+            })?;
+
+            kv_store.set(b"keep".to_vec(), b"value".to_vec())?;
+            for i in 0..100u32 {
+                kv_store.set(b"overwritten".to_vec(), i.to_be_bytes().repeat(16))?;
+            }
+            assert!(std::fs::metadata(&log_path)?.len() < 1024 * 2);
+
+            kv_store.delete(b"overwritten".to_vec())?;
+            kv_store.compact()?;
+            assert_eq!(kv_store.list()?, vec![b"keep".to_vec()]);
+            drop(kv_store);
+
+            let kv_store = LogKvStore::open(log_config(dir))?;
+            assert_eq!(kv_store.get(b"keep".to_vec())?.unwrap(), b"value");
+            assert_eq!(kv_store.get(b"overwritten".to_vec())?, None);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        with_log_dir("cas", |dir| {
+            let kv_store = LogKvStore::open(log_config(dir))?;
+
+            assert!(kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"world".to_vec()))?);
+            assert!(!kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"mister".to_vec()))?);
+            assert!(kv_store.compare_and_swap(b"hello".to_vec(), Some(b"world".to_vec()), None)?);
+            drop(kv_store);
+
+            let kv_store = LogKvStore::open(log_config(dir))?;
+            assert_eq!(kv_store.get(b"hello".to_vec())?, None);
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,180 @@
+//! In-memory storage backend
+
+use crate::app::keyvalue::{prefix_successor, BatchOp, KeyValueResult, KeyValueStore};
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A `KeyValueStore` that keeps its keys in an ordered map in memory
+///
+/// Nothing is persisted, so this is mostly useful for testing. Reads can run
+/// concurrently with each other and batches are applied atomically.
+#[derive(Debug, Default)]
+pub struct MemoryKvStore {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryKvStore {
+    /// Create an empty store
+    pub fn new() -> MemoryKvStore {
+        MemoryKvStore::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        // Every write to the map is a single call that can't be interrupted
+        // half way, so ignore poisoning
+        self.map.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.map.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl KeyValueStore for MemoryKvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        Ok(self.read().get(&key).cloned())
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        self.write().insert(key, value);
+
+        Ok(())
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        self.write().remove(&key);
+
+        Ok(())
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        Ok(self.read().keys().cloned().collect())
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = match prefix_successor(&prefix) {
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+
+        Ok(self
+            .read()
+            .range((Bound::Included(prefix), end))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        let mut map = self.write();
+
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    map.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    map.remove(&key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let mut map = self.write();
+
+        if map.get(&key) != expected.as_ref() {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn set_get_and_delete() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+        kv_store.set(b"goodbye".to_vec(), b"later".to_vec())?;
+        assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"world");
+        assert_eq!(kv_store.list()?, vec![b"goodbye".to_vec(), b"hello".to_vec()]);
+
+        kv_store.delete(b"hello".to_vec())?;
+        assert_eq!(kv_store.get(b"hello".to_vec())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        kv_store.set(vec![1, 0xFF], b"after".to_vec())?;
+        kv_store.set(vec![1, 2, 0xFF], b"b".to_vec())?;
+        kv_store.set(vec![1, 2, 0], b"a".to_vec())?;
+        kv_store.set(vec![1, 2], b"prefix".to_vec())?;
+        kv_store.set(vec![1, 1, 0xFF], b"before".to_vec())?;
+
+        assert_eq!(
+            kv_store.scan_prefix(vec![1, 2])?,
+            vec![
+                (vec![1, 2], b"prefix".to_vec()),
+                (vec![1, 2, 0], b"a".to_vec()),
+                (vec![1, 2, 0xFF], b"b".to_vec()),
+            ]
+        );
+        assert_eq!(kv_store.scan_prefix(vec![1, 0xFF])?.len(), 1);
+        assert_eq!(kv_store.scan_prefix(vec![])?.len(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        kv_store.set(b"goodbye".to_vec(), b"world".to_vec())?;
+        kv_store.batch(vec![
+            BatchOp::Set(b"hello".to_vec(), b"world".to_vec()),
+            BatchOp::Delete(b"goodbye".to_vec()),
+            BatchOp::Set(b"hello".to_vec(), b"mister".to_vec()),
+        ])?;
+
+        assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"mister");
+        assert_eq!(kv_store.get(b"goodbye".to_vec())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"world".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"mister".to_vec()))?);
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), Some(b"world".to_vec()), None)?);
+        assert_eq!(kv_store.get(b"hello".to_vec())?, None);
+
+        Ok(())
+    }
+}
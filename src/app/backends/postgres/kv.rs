@@ -0,0 +1,461 @@
+//! PostgreSQL key-value store implementation
+
+use super::PostgresConfig;
+use crate::app::keyvalue::{
+    prefix_successor, BatchOp, KeyValueResult, KeyValueStore,
+};
+use crate::{PolyfsError, PolyfsResult, try_to};
+
+use diesel::dsl::sql;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::result::Error as DieselError;
+use diesel::sql_types::Bool;
+use diesel_migrations::{embed_migrations, MigrationConnection};
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+mod kv_schema;
+use self::kv_schema::kv_store;
+
+embed_migrations!("src/app/backends/postgres/kv/kv-migrations");
+
+/// The versions of the embedded migrations. This must be kept in sync with the
+/// `kv-migrations` directory.
+const MIGRATION_VERSIONS: &[&str] = &[BASELINE_MIGRATION];
+
+/// The version of the migration that creates the KV table. Databases created
+/// before migrations were introduced already have the table, but no migration
+/// history.
+const BASELINE_MIGRATION: &str = "20261019000000";
+
+/// A Queryable and Insertable KeyValue pair
+#[derive(Queryable, Insertable)]
+#[table_name = "kv_store"]
+struct KvPair {
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// Check whether a table exists in a database
+fn table_exists(conn: &PgConnection, table: &str) -> QueryResult<bool> {
+    sql::<Bool>(&format!("SELECT to_regclass('{}') IS NOT NULL", table)).get_result::<bool>(conn)
+}
+
+/// Get the versions of the embedded migrations that haven't been run on a
+/// database
+///
+/// A database that has neither the KV table nor any migration history is new
+/// and has no pending migrations. A database that has the KV table but no
+/// history predates migrations, so only the migrations after the baseline are
+/// pending.
+fn pending_migrations(conn: &PgConnection) -> PolyfsResult<Vec<&'static str>> {
+    let has_history = try_to!(
+        table_exists(conn, "__diesel_schema_migrations"),
+        "Could not read database schema"
+    );
+    if !has_history {
+        if try_to!(table_exists(conn, "kv_store"), "Could not read database schema") {
+            return Ok(MIGRATION_VERSIONS
+                .iter()
+                .filter(|version| **version != BASELINE_MIGRATION)
+                .cloned()
+                .collect());
+        } else {
+            return Ok(vec![]);
+        }
+    }
+
+    let run_versions = try_to!(
+        conn.previously_run_migration_versions(),
+        "Could not read database migration history"
+    );
+
+    Ok(MIGRATION_VERSIONS
+        .iter()
+        .filter(|version| !run_versions.contains(**version))
+        .cloned()
+        .collect())
+}
+
+/// Record the baseline migration as run on a database that has the KV table
+/// but no migration history, so that it isn't run a second time
+fn mark_baseline_migration(conn: &PgConnection) -> QueryResult<()> {
+    if table_exists(conn, "__diesel_schema_migrations")? || !table_exists(conn, "kv_store")? {
+        return Ok(());
+    }
+
+    diesel_migrations::setup_database(conn)?;
+    conn.insert_new_migration(BASELINE_MIGRATION)
+}
+
+/// The advisory lock held on a database while it is opened exclusively
+const EXCLUSIVE_LOCK: &str = "hashtext('polyfs-exclusive')";
+
+/// A PostgreSQL backed implementation of `KeyValueStore`
+///
+/// Every operation takes a connection from a pool, so operations run
+/// concurrently up to the size of the pool.
+///
+/// A database can't be shared by several hosts mounting the filesystem, since
+/// a mounted filesystem keeps some of its state, like quota usage, in memory.
+/// A store opened with `lock_exclusive` holds a session-level advisory lock on
+/// a connection of its own until it is dropped, so a second mount fails
+/// instead of corrupting the filesystem.
+pub struct PostgresKvStore {
+    config: PostgresConfig,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// The connection holding the exclusive lock, if it is held. It is never
+    /// used by more than one thread, the mutex only makes the store `Sync`.
+    exclusive: Option<Mutex<PooledConnection<ConnectionManager<PgConnection>>>>,
+}
+
+use std::fmt;
+
+impl fmt::Debug for PostgresKvStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Don't print the URL, it may contain a password
+        write!(f, "PostgresKvStore {{ pool_size: {} }}", self.config.pool_size)
+    }
+}
+
+impl PostgresKvStore {
+    /// Instantiate a PostgreSQL KV store
+    ///
+    /// Fails if an existing database needs to be migrated. Use `open` to allow
+    /// migrations.
+    pub fn new(config: PostgresConfig) -> PolyfsResult<PostgresKvStore> {
+        PostgresKvStore::open(config, false)
+    }
+
+    /// Get the versions of the database migrations that will be run when the
+    /// store is opened
+    pub fn pending_migrations(config: &PostgresConfig) -> PolyfsResult<Vec<&'static str>> {
+        pending_migrations(&try_to!(
+            PgConnection::establish(&config.url),
+            "Could not connect to database for KV store"
+        ))
+    }
+
+    /// Instantiate a PostgreSQL KV store, migrating an existing database if
+    /// `allow_migrations` is true
+    ///
+    /// Migrations are run in a single transaction so a failed migration
+    /// leaves the database unchanged.
+    pub fn open(config: PostgresConfig, allow_migrations: bool) -> PolyfsResult<PostgresKvStore> {
+        let pool = try_to!(
+            Pool::builder()
+                .max_size(config.pool_size)
+                .connection_timeout(Duration::from_secs(config.connect_timeout))
+                .build(ConnectionManager::<PgConnection>::new(config.url.as_str())),
+            "Could not connect to database for KV store"
+        );
+        let conn = try_to!(pool.get(), "Could not connect to database for KV store");
+
+        let pending = pending_migrations(&conn)?;
+        if !pending.is_empty() && !allow_migrations {
+            return Err(PolyfsError {
+                message: format!(
+                    "Database needs to be migrated ({}). Migrations must be allowed explicitly.",
+                    pending.join(", ")
+                ),
+                cause: None,
+            });
+        }
+
+        // Other processes may be opening the same database, so make sure only
+        // one of them runs the migrations
+        let migrated = conn.transaction(|| {
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext('polyfs-migrations'))")
+                .execute(&conn)?;
+            mark_baseline_migration(&conn)?;
+            embedded_migrations::run(&conn)
+        });
+        try_to!(migrated, "Could not run database migrations");
+        drop(conn);
+
+        Ok(PostgresKvStore {
+            config,
+            pool,
+            exclusive: None,
+        })
+    }
+
+    /// Take the exclusive lock of the database, which is held until the store
+    /// is dropped
+    ///
+    /// Fails straight away if another process holds it. The lock belongs to
+    /// the database session, so it is released by the server if this process
+    /// dies.
+    pub fn lock_exclusive(&mut self) -> PolyfsResult<()> {
+        if self.exclusive.is_some() {
+            return Ok(());
+        }
+
+        let conn = try_to!(self.pool.get(), "Could not connect to database for KV store");
+        let locked = try_to!(
+            sql::<Bool>(&format!("SELECT pg_try_advisory_lock({})", EXCLUSIVE_LOCK))
+                .get_result::<bool>(&conn),
+            "Could not lock database"
+        );
+        if !locked {
+            return Err(PolyfsError {
+                message: String::from(
+                    "The database is in use by another mount or command. \
+                     A database can't be shared by several hosts.",
+                ),
+                cause: None,
+            });
+        }
+
+        self.exclusive = Some(Mutex::new(conn));
+        Ok(())
+    }
+
+    fn conn(&self) -> KeyValueResult<PooledConnection<ConnectionManager<PgConnection>>> {
+        Ok(self.pool.get()?)
+    }
+}
+
+impl Drop for PostgresKvStore {
+    fn drop(&mut self) {
+        // The connection goes back to the pool, so the lock must be released
+        // explicitly
+        if let Some(conn) = self.exclusive.take() {
+            let conn = conn.into_inner().unwrap_or_else(|e| e.into_inner());
+            let unlocked = sql::<Bool>(&format!("SELECT pg_advisory_unlock({})", EXCLUSIVE_LOCK))
+                .get_result::<bool>(&conn);
+            if let Err(e) = unlocked {
+                log::error!("Could not unlock database: {}", e);
+            }
+        }
+    }
+}
+
+impl KeyValueStore for PostgresKvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        Ok(kv_store::table
+            .select(kv_store::value)
+            .filter(kv_store::key.eq(key))
+            .get_result::<Vec<u8>>(&self.conn()?)
+            .optional()?)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        diesel::insert_into(kv_store::table)
+            .values(KvPair { key, value })
+            .on_conflict(kv_store::key)
+            .do_update()
+            .set(kv_store::value.eq(excluded(kv_store::value)))
+            .execute(&self.conn()?)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        diesel::delete(kv_store::table.filter(kv_store::key.eq(key))).execute(&self.conn()?)?;
+
+        Ok(())
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        Ok(kv_store::table
+            .select(kv_store::key)
+            .load::<Vec<u8>>(&self.conn()?)?)
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        // PostgreSQL compares `bytea` values byte by byte, so the prefix is a
+        // range of keys
+        let query = kv_store::table
+            .filter(kv_store::key.ge(prefix.clone()))
+            .order(kv_store::key)
+            .into_boxed();
+        let query = match prefix_successor(&prefix) {
+            Some(end) => query.filter(kv_store::key.lt(end)),
+            None => query,
+        };
+
+        Ok(query
+            .load::<KvPair>(&self.conn()?)?
+            .into_iter()
+            .map(|pair| (pair.key, pair.value))
+            .collect())
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        let conn = self.conn()?;
+
+        conn.transaction::<_, DieselError, _>(|| {
+            for op in ops {
+                match op {
+                    BatchOp::Set(key, value) => {
+                        diesel::insert_into(kv_store::table)
+                            .values(KvPair { key, value })
+                            .on_conflict(kv_store::key)
+                            .do_update()
+                            .set(kv_store::value.eq(excluded(kv_store::value)))
+                            .execute(&conn)?;
+                    }
+                    BatchOp::Delete(key) => {
+                        diesel::delete(kv_store::table.filter(kv_store::key.eq(key)))
+                            .execute(&conn)?;
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let conn = self.conn()?;
+
+        // Each case is a single conditional statement, so the check and the
+        // write are atomic
+        let changed = match (expected, new) {
+            (None, Some(value)) => diesel::insert_into(kv_store::table)
+                .values(KvPair { key, value })
+                .on_conflict_do_nothing()
+                .execute(&conn)?,
+            (Some(expected), Some(value)) => diesel::update(
+                kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .filter(kv_store::value.eq(expected)),
+            )
+            .set(kv_store::value.eq(value))
+            .execute(&conn)?,
+            (Some(expected), None) => diesel::delete(
+                kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .filter(kv_store::value.eq(expected)),
+            )
+            .execute(&conn)?,
+            (None, None) => {
+                let exists = kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .count()
+                    .get_result::<i64>(&conn)?;
+                return Ok(exists == 0);
+            }
+        };
+
+        Ok(changed == 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// Get a store for the database in `POLYFS_TEST_POSTGRES_URL`, if it is set
+    ///
+    /// The tests share the database, so each uses its own keys.
+    fn test_store() -> PolyfsResult<Option<PostgresKvStore>> {
+        match std::env::var("POLYFS_TEST_POSTGRES_URL") {
+            Ok(url) => Ok(Some(PostgresKvStore::open(PostgresConfig::new(url), true)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    #[test]
+    fn migration_versions_match_directory() -> TestResult {
+        let mut versions = std::fs::read_dir("src/app/backends/postgres/kv/kv-migrations")?
+            .map(|entry| {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                Ok(name
+                    .split('_')
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>())
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        versions.sort();
+
+        assert_eq!(versions, MIGRATION_VERSIONS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_get_and_scan() -> TestResult {
+        let kv_store = match test_store()? {
+            Some(kv_store) => kv_store,
+            None => return Ok(()),
+        };
+
+        kv_store.batch(vec![
+            BatchOp::Set(vec![0xAA, 1], b"a".to_vec()),
+            BatchOp::Set(vec![0xAA, 2], b"b".to_vec()),
+            BatchOp::Set(vec![0xAB], b"after".to_vec()),
+        ])?;
+        kv_store.set(vec![0xAA, 1], b"updated".to_vec())?;
+        assert_eq!(kv_store.get(vec![0xAA, 1])?.unwrap(), b"updated");
+
+        assert_eq!(
+            kv_store.scan_prefix(vec![0xAA])?,
+            vec![
+                (vec![0xAA, 1], b"updated".to_vec()),
+                (vec![0xAA, 2], b"b".to_vec()),
+            ]
+        );
+
+        kv_store.batch(vec![
+            BatchOp::Delete(vec![0xAA, 1]),
+            BatchOp::Delete(vec![0xAA, 2]),
+        ])?;
+        kv_store.delete(vec![0xAB])?;
+        assert_eq!(kv_store.get(vec![0xAA, 1])?, None);
+        assert!(kv_store.scan_prefix(vec![0xAA])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        let kv_store = match test_store()? {
+            Some(kv_store) => kv_store,
+            None => return Ok(()),
+        };
+
+        assert!(kv_store.compare_and_swap(vec![0xAC], None, Some(b"world".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(vec![0xAC], None, Some(b"mister".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(vec![0xAC], Some(b"mister".to_vec()), None)?);
+        assert!(kv_store.compare_and_swap(vec![0xAC], Some(b"world".to_vec()), Some(b"there".to_vec()))?);
+        assert!(kv_store.compare_and_swap(vec![0xAC], Some(b"there".to_vec()), None)?);
+        assert_eq!(kv_store.get(vec![0xAC])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn exclusive_lock() -> TestResult {
+        let mut kv_store = match test_store()? {
+            Some(kv_store) => kv_store,
+            None => return Ok(()),
+        };
+        let mut other = test_store()?.expect("Test database went away");
+
+        kv_store.lock_exclusive()?;
+        assert!(other.lock_exclusive().is_err());
+
+        drop(kv_store);
+        other.lock_exclusive()?;
+
+        Ok(())
+    }
+}
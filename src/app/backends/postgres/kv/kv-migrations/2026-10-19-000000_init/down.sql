@@ -0,0 +1 @@
+drop table kv_store;
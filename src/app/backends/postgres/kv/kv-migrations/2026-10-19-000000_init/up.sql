@@ -0,0 +1,4 @@
+create table kv_store (
+    key bytea primary key not null,
+    value bytea not null
+);
@@ -0,0 +1,6 @@
+table! {
+    kv_store (key) {
+        key -> Binary,
+        value -> Binary,
+    }
+}
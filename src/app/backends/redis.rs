@@ -0,0 +1,346 @@
+//! Redis storage backend
+//!
+//! Keys are stored as Redis strings under a configurable prefix so that a
+//! Redis server can be shared with other services.
+
+use crate::app::keyvalue::{BatchOp, KeyValueResult, KeyValueStore};
+use crate::{try_to, PolyfsResult};
+
+use redis::{Client, Connection, ConnectionAddr, ConnectionInfo};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// Redis backend configuration
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    /// The host name of the server
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// The port of the server
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// The database number to use
+    #[serde(default)]
+    pub db: i64,
+    /// The password to authenticate with
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The prefix added to every key
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    /// The maximum number of idle connections to keep open
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+}
+
+fn default_host() -> String {
+    String::from("127.0.0.1")
+}
+
+fn default_port() -> u16 {
+    6379
+}
+
+fn default_key_prefix() -> String {
+    String::from("polyfs:")
+}
+
+fn default_connections() -> usize {
+    4
+}
+
+impl Default for RedisConfig {
+    fn default() -> RedisConfig {
+        RedisConfig {
+            host: default_host(),
+            port: default_port(),
+            db: 0,
+            password: None,
+            key_prefix: default_key_prefix(),
+            connections: default_connections(),
+        }
+    }
+}
+
+/// The number of keys to ask for in each `SCAN` and `MGET`
+const SCAN_COUNT: usize = 1000;
+
+/// Escape the characters that have a special meaning in `SCAN` patterns
+fn escape_pattern(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        if let b'*' | b'?' | b'[' | b']' | b'\\' = byte {
+            out.push(b'\\');
+        }
+        out.push(byte);
+    }
+}
+
+/// A Redis backed implementation of `KeyValueStore`
+///
+/// Batches are sent in a single pipelined `MULTI`/`EXEC` transaction, and
+/// `compare_and_swap` uses `WATCH` to detect writes made by other clients.
+pub struct RedisKvStore {
+    config: RedisConfig,
+    client: Client,
+    idle: Mutex<Vec<Connection>>,
+}
+
+use std::fmt;
+
+impl fmt::Debug for RedisKvStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Don't print the password
+        write!(
+            f,
+            "RedisKvStore {{ host: {}, port: {}, db: {}, key_prefix: {:?} }}",
+            self.config.host, self.config.port, self.config.db, self.config.key_prefix
+        )
+    }
+}
+
+impl RedisKvStore {
+    /// Connect to a Redis server
+    pub fn new(config: RedisConfig) -> PolyfsResult<RedisKvStore> {
+        let client = try_to!(
+            Client::open(ConnectionInfo {
+                addr: Box::new(ConnectionAddr::Tcp(config.host.clone(), config.port)),
+                db: config.db,
+                passwd: config.password.clone(),
+            }),
+            "Invalid Redis connection settings"
+        );
+
+        // Connect once up front so that bad settings are reported right away
+        let conn = try_to!(client.get_connection(), "Could not connect to Redis server");
+
+        Ok(RedisKvStore {
+            config,
+            client,
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    /// Run an operation on an idle connection, or a new one if there are none
+    ///
+    /// The connection is only reused if the operation succeeded, since a
+    /// failed one may have left it in the middle of a transaction.
+    fn with_conn<T, F>(&self, operation: F) -> KeyValueResult<T>
+    where
+        F: FnOnce(&mut Connection) -> redis::RedisResult<T>,
+    {
+        let conn = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let mut conn = match conn {
+            Some(conn) => conn,
+            None => self.client.get_connection()?,
+        };
+
+        let result = operation(&mut conn)?;
+
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.config.connections {
+            idle.push(conn);
+        }
+
+        Ok(result)
+    }
+
+    fn redis_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut redis_key = self.config.key_prefix.as_bytes().to_vec();
+        redis_key.extend_from_slice(key);
+        redis_key
+    }
+
+    /// Get every key, without the key prefix, that starts with `prefix`
+    fn scan_keys(&self, prefix: &[u8]) -> KeyValueResult<Vec<Vec<u8>>> {
+        let mut pattern = vec![];
+        escape_pattern(self.config.key_prefix.as_bytes(), &mut pattern);
+        escape_pattern(prefix, &mut pattern);
+        pattern.push(b'*');
+
+        let prefix_len = self.config.key_prefix.len();
+        self.with_conn(|conn| {
+            let mut scan = redis::cmd("SCAN");
+            scan.cursor_arg(0)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT);
+            let keys: Vec<Vec<u8>> = scan.iter(conn)?.collect();
+
+            Ok(keys
+                .into_iter()
+                .map(|key| key[prefix_len..].to_vec())
+                .collect())
+        })
+    }
+}
+
+impl KeyValueStore for RedisKvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        let key = self.redis_key(&key);
+        self.with_conn(|conn| redis::cmd("GET").arg(key).query(conn))
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        let key = self.redis_key(&key);
+        self.with_conn(|conn| redis::cmd("SET").arg(key).arg(value).query(conn))
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        let key = self.redis_key(&key);
+        self.with_conn(|conn| redis::cmd("DEL").arg(key).query(conn))
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        self.scan_keys(&[])
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Redis doesn't keep keys in order, so find and sort them first
+        let mut keys = self.scan_keys(&prefix)?;
+        keys.sort();
+        keys.dedup();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(SCAN_COUNT) {
+            let redis_keys: Vec<Vec<u8>> = chunk.iter().map(|key| self.redis_key(key)).collect();
+            let values: Vec<Option<Vec<u8>>> =
+                self.with_conn(|conn| redis::cmd("MGET").arg(redis_keys).query(conn))?;
+
+            // Keys deleted since they were scanned have no value
+            pairs.extend(
+                chunk
+                    .iter()
+                    .cloned()
+                    .zip(values)
+                    .filter_map(|(key, value)| value.map(|value| (key, value))),
+            );
+        }
+
+        Ok(pairs)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => pipe.cmd("SET").arg(self.redis_key(&key)).arg(value),
+                BatchOp::Delete(key) => pipe.cmd("DEL").arg(self.redis_key(&key)),
+            }
+            .ignore();
+        }
+
+        self.with_conn(|conn| pipe.query(conn))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let key = self.redis_key(&key);
+
+        self.with_conn(|conn| {
+            redis::transaction(conn, &[&key[..]], |conn, pipe| {
+                let current: Option<Vec<u8>> = redis::cmd("GET").arg(&key[..]).query(conn)?;
+                if current != expected {
+                    return Ok(Some(false));
+                }
+
+                match &new {
+                    Some(value) => pipe.cmd("SET").arg(&key[..]).arg(&value[..]),
+                    None => pipe.cmd("DEL").arg(&key[..]),
+                }
+                .ignore();
+
+                // The transaction is aborted and retried if the key changed
+                // after it was watched
+                Ok(pipe.query::<Option<()>>(conn)?.map(|_| true))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// Get a store for the server at `POLYFS_TEST_REDIS_HOST`, if it is set
+    ///
+    /// Each test uses its own key prefix.
+    fn test_store(key_prefix: &str) -> PolyfsResult<Option<RedisKvStore>> {
+        match std::env::var("POLYFS_TEST_REDIS_HOST") {
+            Ok(host) => Ok(Some(RedisKvStore::new(RedisConfig {
+                host,
+                key_prefix: format!("polyfs-test-{}:{}:", std::process::id(), key_prefix),
+                ..Default::default()
+            })?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    #[test]
+    fn escape_scan_pattern() {
+        let mut pattern = vec![];
+        escape_pattern(b"a*b?[c]\\", &mut pattern);
+        assert_eq!(pattern, b"a\\*b\\?\\[c\\]\\\\");
+    }
+
+    #[test]
+    fn set_get_and_scan() -> TestResult {
+        let kv_store = match test_store("scan")? {
+            Some(kv_store) => kv_store,
+            None => return Ok(()),
+        };
+
+        kv_store.batch(vec![
+            BatchOp::Set(vec![1, b'*'], b"a".to_vec()),
+            BatchOp::Set(vec![1, 2], b"b".to_vec()),
+            BatchOp::Set(vec![2], b"after".to_vec()),
+        ])?;
+        kv_store.set(vec![1, 2], b"updated".to_vec())?;
+        assert_eq!(kv_store.get(vec![1, 2])?.unwrap(), b"updated");
+
+        assert_eq!(
+            kv_store.scan_prefix(vec![1])?,
+            vec![
+                (vec![1, 2], b"updated".to_vec()),
+                (vec![1, b'*'], b"a".to_vec()),
+            ]
+        );
+        assert_eq!(kv_store.list()?.len(), 3);
+
+        kv_store.batch(vec![
+            BatchOp::Delete(vec![1, b'*']),
+            BatchOp::Delete(vec![1, 2]),
+            BatchOp::Delete(vec![2]),
+        ])?;
+        assert!(kv_store.list()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        let kv_store = match test_store("cas")? {
+            Some(kv_store) => kv_store,
+            None => return Ok(()),
+        };
+
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"world".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"mister".to_vec()))?);
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), Some(b"world".to_vec()), None)?);
+        assert_eq!(kv_store.get(b"hello".to_vec())?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,727 @@
+//! S3-compatible object storage backend
+//!
+//! Every key is stored as an object named after the key in hex under a
+//! configurable prefix. Hex keeps the objects in the same order as the keys, so
+//! listing objects lists keys in order and a key prefix maps to an object name
+//! prefix. Requests are signed with AWS Signature Version 4, which is accepted
+//! by AWS as well as by stand-ins like MinIO.
+
+pub mod signing;
+
+use crate::app::hex;
+use crate::app::keyvalue::{KeyValueError, KeyValueResult, KeyValueStore};
+use crate::{PolyfsError, PolyfsResult};
+use signing::{sha256_hex, uri_encode, Signer};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::time::Duration;
+
+/// The smallest part size S3 accepts for every part but the last
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// S3 backend configuration
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// The bucket to store objects in
+    pub bucket: String,
+    /// The prefix added to every object name
+    #[serde(default)]
+    pub prefix: String,
+    /// The URL of the service. Defaults to the AWS endpoint for the region.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// The region of the bucket
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// The access key ID. Read from `AWS_ACCESS_KEY_ID` if not set.
+    #[serde(default)]
+    pub access_key: Option<String>,
+    /// The secret access key. Read from `AWS_SECRET_ACCESS_KEY` if not set.
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Whether to put the bucket in the path of requests instead of the host
+    /// name. Most stand-ins for S3 need this.
+    #[serde(default)]
+    pub path_style: bool,
+    /// The size in bytes above which values are uploaded in parts
+    #[serde(default = "default_multipart_threshold")]
+    pub multipart_threshold: usize,
+    /// The size in bytes of each part of a multipart upload
+    #[serde(default = "default_part_size")]
+    pub part_size: usize,
+    /// The number of seconds to wait for a request before giving up
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_region() -> String {
+    String::from("us-east-1")
+}
+
+fn default_multipart_threshold() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_part_size() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+impl S3Config {
+    /// Create a config for `bucket` with the default settings
+    pub fn new(bucket: String) -> S3Config {
+        S3Config {
+            bucket,
+            prefix: String::new(),
+            endpoint: None,
+            region: default_region(),
+            access_key: None,
+            secret_key: None,
+            path_style: false,
+            multipart_threshold: default_multipart_threshold(),
+            part_size: default_part_size(),
+            timeout: default_timeout(),
+        }
+    }
+}
+
+fn config_error(message: &str) -> PolyfsError {
+    PolyfsError {
+        message: message.to_owned(),
+        cause: None,
+    }
+}
+
+/// The credentials requests are signed with
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key", &self.access_key)
+            .field("secret_key", &"<hidden>")
+            .finish()
+    }
+}
+
+/// A `KeyValueStore` that keeps each key in an object in an S3 bucket
+///
+/// Values larger than the multipart threshold are uploaded in parts. S3 has no
+/// transactions, so batches are not atomic. Compare-and-swap writes with a
+/// conditional request on the ETag of the object it read, so it is atomic on
+/// services that support conditional writes and deletes, like AWS. Services
+/// that ignore the conditions make it a plain get and write.
+#[derive(Debug)]
+pub struct S3KvStore {
+    config: S3Config,
+    credentials: Credentials,
+    /// The scheme of the endpoint, like `https`
+    scheme: String,
+    /// The host name requests are sent to, including the bucket unless the
+    /// config uses path-style requests
+    host: String,
+    agent: ureq::Agent,
+}
+
+impl S3KvStore {
+    /// Create a store for the configured bucket
+    ///
+    /// Nothing is sent to the service until the store is used.
+    pub fn new(config: S3Config) -> PolyfsResult<S3KvStore> {
+        let credentials = Credentials {
+            access_key: config
+                .access_key
+                .clone()
+                .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())
+                .ok_or_else(|| config_error("No S3 access key configured or in AWS_ACCESS_KEY_ID"))?,
+            secret_key: config
+                .secret_key
+                .clone()
+                .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+                .ok_or_else(|| {
+                    config_error("No S3 secret key configured or in AWS_SECRET_ACCESS_KEY")
+                })?,
+        };
+
+        if config.part_size < MIN_PART_SIZE {
+            return Err(config_error(&format!(
+                "S3 part size must be at least {} bytes",
+                MIN_PART_SIZE
+            )));
+        }
+
+        let endpoint = match &config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!("https://s3.{}.amazonaws.com", config.region),
+        };
+        let (scheme, host) = match endpoint.find("://") {
+            Some(index) => (&endpoint[..index], endpoint[index + 3..].trim_end_matches('/')),
+            None => return Err(config_error(&format!("Invalid S3 endpoint '{}'", endpoint))),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(config_error(&format!(
+                "S3 endpoint '{}' must not have a path",
+                endpoint
+            )));
+        }
+        let host = if config.path_style {
+            host.to_owned()
+        } else {
+            format!("{}.{}", config.bucket, host)
+        };
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(config.timeout))
+            .build();
+
+        Ok(S3KvStore {
+            scheme: scheme.to_owned(),
+            host,
+            credentials,
+            config,
+            agent,
+        })
+    }
+
+    /// Get the name of the object for a key
+    fn object_name(&self, key: &[u8]) -> String {
+        format!("{}{}", self.config.prefix, hex::encode(key))
+    }
+
+    /// Get the encoded request path for an object, or for the bucket itself if
+    /// `object` is `None`
+    fn path(&self, object: Option<&str>) -> String {
+        let mut path = String::new();
+        if self.config.path_style {
+            path.push('/');
+            path.push_str(&uri_encode(&self.config.bucket));
+        }
+        if let Some(object) = object {
+            for segment in object.split('/') {
+                path.push('/');
+                path.push_str(&uri_encode(segment));
+            }
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        path
+    }
+
+    /// Send a signed request
+    fn send(
+        &self,
+        method: &str,
+        object: Option<&str>,
+        query: &[(String, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        self.send_if(method, object, query, body, &Precondition::None)
+    }
+
+    /// Send a signed request that only succeeds if `precondition` holds
+    fn send_if(
+        &self,
+        method: &str,
+        object: Option<&str>,
+        query: &[(String, String)],
+        body: &[u8],
+        precondition: &Precondition,
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let path = self.path(object);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = sha256_hex(body);
+
+        let mut headers = vec![
+            (String::from("host"), self.host.clone()),
+            (String::from("x-amz-content-sha256"), payload_hash.clone()),
+            (String::from("x-amz-date"), amz_date.clone()),
+        ];
+        match precondition {
+            Precondition::None => (),
+            Precondition::Missing => {
+                headers.push((String::from("if-none-match"), String::from("*")))
+            }
+            Precondition::Matches(etag) => headers.push((String::from("if-match"), etag.clone())),
+        }
+        let signer = Signer {
+            access_key: &self.credentials.access_key,
+            secret_key: &self.credentials.secret_key,
+            region: &self.config.region,
+        };
+        let authorization =
+            signer.authorization(method, &path, query, &headers, &amz_date, &payload_hash);
+
+        let mut url = format!("{}://{}{}", self.scheme, self.host, path);
+        for (index, (name, value)) in query.iter().enumerate() {
+            url.push(if index == 0 { '?' } else { '&' });
+            url.push_str(&uri_encode(name));
+            url.push('=');
+            url.push_str(&uri_encode(value));
+        }
+
+        let mut request = self.agent.request(method, &url);
+        for (name, value) in &headers[1..] {
+            request = request.set(name, value);
+        }
+        request
+            .set("authorization", &authorization)
+            .send_bytes(body)
+            .map_err(Box::new)
+    }
+
+    /// Send a signed request and return the response body
+    fn send_for_body(
+        &self,
+        method: &str,
+        object: Option<&str>,
+        query: &[(String, String)],
+        body: &[u8],
+    ) -> KeyValueResult<Vec<u8>> {
+        let response = self.send(method, object, query, body).map_err(request_error)?;
+        read_body(response)
+    }
+
+    /// Get the value of an object and its ETag
+    fn get_with_etag(&self, object: &str) -> KeyValueResult<Option<(Vec<u8>, String)>> {
+        let response = match self.send("GET", Some(object), &[], &[]) {
+            Ok(response) => response,
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(request_error(e)),
+        };
+        let etag = response
+            .header("etag")
+            .ok_or_else(|| KeyValueError::S3Error(String::from("No ETag for object")))?
+            .to_owned();
+
+        Ok(Some((read_body(response)?, etag)))
+    }
+
+    /// Write a value if `precondition` holds, uploading it in parts if it is
+    /// larger than the multipart threshold
+    ///
+    /// Returns false if the precondition doesn't hold.
+    fn put_if(
+        &self,
+        object: &str,
+        value: &[u8],
+        precondition: &Precondition,
+    ) -> KeyValueResult<bool> {
+        let result = if value.len() > self.config.multipart_threshold {
+            self.put_multipart(object, value, precondition)
+        } else {
+            self.send_if("PUT", Some(object), &[], value, precondition)
+                .map(drop)
+                .map_err(request_error)
+        };
+
+        match result {
+            Err(KeyValueError::S3Error(_)) if self.precondition_failed(object, precondition)? => {
+                Ok(false)
+            }
+            result => result.map(|()| true),
+        }
+    }
+
+    /// Delete an object if `precondition` holds
+    ///
+    /// Returns false if the precondition doesn't hold.
+    fn delete_if(&self, object: &str, precondition: &Precondition) -> KeyValueResult<bool> {
+        // There is nothing to delete if the object must be missing
+        if let Precondition::Missing = precondition {
+            return Ok(self.get_with_etag(object)?.is_none());
+        }
+
+        match self.send_if("DELETE", Some(object), &[], &[], precondition) {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(*e, ureq::Error::Status(404 | 409 | 412, _)) => Ok(false),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    /// Check whether a write failed because its precondition doesn't hold
+    /// anymore
+    ///
+    /// S3 reports a failed precondition with status 412, or 409 if another
+    /// conditional write was in progress, and multipart uploads may report it
+    /// in the body of a successful response. Checking the current ETag covers
+    /// all of them.
+    fn precondition_failed(
+        &self,
+        object: &str,
+        precondition: &Precondition,
+    ) -> KeyValueResult<bool> {
+        if let Precondition::None = precondition {
+            return Ok(false);
+        }
+
+        let current = self.get_with_etag(object)?.map(|(_, etag)| etag);
+        Ok(match precondition {
+            Precondition::None => false,
+            Precondition::Missing => current.is_some(),
+            Precondition::Matches(etag) => current.as_ref() != Some(etag),
+        })
+    }
+
+    /// Upload a value in parts
+    fn put_multipart(
+        &self,
+        object: &str,
+        value: &[u8],
+        precondition: &Precondition,
+    ) -> KeyValueResult<()> {
+        let body = self.send_for_body("POST", Some(object), &[(String::from("uploads"), String::new())], &[])?;
+        let upload_id = parse_xml(&body, |document| {
+            document
+                .descendants()
+                .find(|node| node.has_tag_name("UploadId"))
+                .and_then(|node| node.text())
+                .map(str::to_owned)
+        })?
+        .ok_or_else(|| KeyValueError::S3Error(String::from("No upload ID in response")))?;
+
+        let result = self.upload_parts(object, &upload_id, value, precondition);
+        if result.is_err() {
+            // Abort the upload so that its parts aren't kept around and billed
+            let query = [(String::from("uploadId"), upload_id)];
+            if let Err(e) = self.send("DELETE", Some(object), &query, &[]) {
+                log::warn!("Could not abort upload of {}: {}", object, e);
+            }
+        }
+
+        result
+    }
+
+    fn upload_parts(
+        &self,
+        object: &str,
+        upload_id: &str,
+        value: &[u8],
+        precondition: &Precondition,
+    ) -> KeyValueResult<()> {
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        for (index, part) in value.chunks(self.config.part_size).enumerate() {
+            let part_number = index + 1;
+            let query = [
+                (String::from("partNumber"), part_number.to_string()),
+                (String::from("uploadId"), upload_id.to_owned()),
+            ];
+            let response = self
+                .send("PUT", Some(object), &query, part)
+                .map_err(request_error)?;
+            let etag = response
+                .header("etag")
+                .ok_or_else(|| KeyValueError::S3Error(String::from("No ETag for uploaded part")))?;
+
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part_number,
+                escape_xml(etag)
+            ));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        // The precondition applies when the upload is completed
+        let query = [(String::from("uploadId"), upload_id.to_owned())];
+        let response = self
+            .send_if("POST", Some(object), &query, complete.as_bytes(), precondition)
+            .map_err(request_error)?;
+        let body = read_body(response)?;
+
+        // Completing an upload can fail after the response has started, in
+        // which case the error is in the body of a successful response
+        if let Some(error) = parse_xml(&body, error_from_document)? {
+            return Err(KeyValueError::S3Error(error));
+        }
+
+        Ok(())
+    }
+
+    /// Get every key that starts with `prefix`, sorted
+    fn keys_with_prefix(&self, prefix: &[u8]) -> KeyValueResult<Vec<Vec<u8>>> {
+        let object_prefix = self.object_name(prefix);
+        let mut keys = vec![];
+        let mut continuation = None;
+
+        loop {
+            let mut query = vec![
+                (String::from("list-type"), String::from("2")),
+                (String::from("prefix"), object_prefix.clone()),
+            ];
+            if let Some(token) = continuation.take() {
+                query.push((String::from("continuation-token"), token));
+            }
+
+            let body = self.send_for_body("GET", None, &query, &[])?;
+            let (names, next) = parse_xml(&body, |document| {
+                let names: Vec<String> = document
+                    .descendants()
+                    .filter(|node| node.has_tag_name("Contents"))
+                    .filter_map(|node| node.children().find(|child| child.has_tag_name("Key")))
+                    .filter_map(|node| node.text().map(str::to_owned))
+                    .collect();
+                let truncated = document
+                    .descendants()
+                    .find(|node| node.has_tag_name("IsTruncated"))
+                    .and_then(|node| node.text())
+                    == Some("true");
+                let next = document
+                    .descendants()
+                    .find(|node| node.has_tag_name("NextContinuationToken"))
+                    .and_then(|node| node.text())
+                    .filter(|_| truncated)
+                    .map(str::to_owned);
+                (names, next)
+            })?;
+
+            for name in names {
+                match name
+                    .strip_prefix(&self.config.prefix)
+                    .and_then(hex::decode)
+                {
+                    Some(key) => keys.push(key),
+                    None => log::warn!("Ignoring unexpected object in bucket: {}", name),
+                }
+            }
+
+            match next {
+                Some(token) => continuation = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// A condition on the current version of an object that a request only
+/// succeeds under
+enum Precondition {
+    /// No condition
+    None,
+    /// The object doesn't exist
+    Missing,
+    /// The object has the given ETag
+    Matches(String),
+}
+
+/// Read the body of a response
+fn read_body(response: ureq::Response) -> KeyValueResult<Vec<u8>> {
+    let mut body = vec![];
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// Parse an XML response body and extract something from it
+fn parse_xml<T>(
+    body: &[u8],
+    extract: impl FnOnce(&roxmltree::Document) -> T,
+) -> KeyValueResult<T> {
+    let text = std::str::from_utf8(body)
+        .map_err(|e| KeyValueError::S3Error(format!("Invalid response: {}", e)))?;
+    let document = roxmltree::Document::parse(text)
+        .map_err(|e| KeyValueError::S3Error(format!("Invalid response: {}", e)))?;
+    Ok(extract(&document))
+}
+
+/// Get the code and message of an S3 `Error` document
+fn error_from_document(document: &roxmltree::Document) -> Option<String> {
+    let root = document.root_element();
+    if !root.has_tag_name("Error") {
+        return None;
+    }
+
+    let field = |name: &str| {
+        root.children()
+            .find(|node| node.has_tag_name(name))
+            .and_then(|node| node.text())
+            .unwrap_or("")
+            .to_owned()
+    };
+    Some(format!("{}: {}", field("Code"), field("Message")))
+}
+
+/// Turn a failed request into a `KeyValueError`, with the error reported by the
+/// service if there is one
+fn request_error(error: Box<ureq::Error>) -> KeyValueError {
+    match *error {
+        ureq::Error::Status(status, response) => {
+            let reported = read_body(response)
+                .ok()
+                .and_then(|body| parse_xml(&body, error_from_document).ok())
+                .flatten();
+            match reported {
+                Some(reported) => KeyValueError::S3Error(format!("{} ({})", reported, status)),
+                None => KeyValueError::S3Error(format!("Request failed with status {}", status)),
+            }
+        }
+        ureq::Error::Transport(transport) => KeyValueError::S3Error(transport.to_string()),
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl KeyValueStore for S3KvStore {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        match self.send("GET", Some(&self.object_name(&key)), &[], &[]) {
+            Ok(response) => Ok(Some(read_body(response)?)),
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(request_error(e)),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        self.put_if(&self.object_name(&key), &value, &Precondition::None)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        // Deleting a missing object succeeds
+        self.send_for_body("DELETE", Some(&self.object_name(&key)), &[], &[])?;
+        Ok(())
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        self.keys_with_prefix(&[])
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = vec![];
+        for key in self.keys_with_prefix(&prefix)? {
+            // Skip keys deleted since they were listed
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let object = self.object_name(&key);
+        let (current, etag) = match self.get_with_etag(&object)? {
+            Some((value, etag)) => (Some(value), Some(etag)),
+            None => (None, None),
+        };
+        if current != expected {
+            return Ok(false);
+        }
+
+        // The write only succeeds if the object hasn't changed since it was
+        // read
+        let precondition = match etag {
+            Some(etag) => Precondition::Matches(etag),
+            None => Precondition::Missing,
+        };
+        match new {
+            Some(value) => self.put_if(&object, &value, &precondition),
+            None => self.delete_if(&object, &precondition),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::keyvalue::BatchOp;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// Get a config for the bucket in `POLYFS_TEST_S3_BUCKET` at the endpoint
+    /// in `POLYFS_TEST_S3_ENDPOINT`, with credentials from the usual AWS
+    /// environment variables
+    fn test_config() -> Option<S3Config> {
+        let endpoint = std::env::var("POLYFS_TEST_S3_ENDPOINT").ok()?;
+        let bucket = std::env::var("POLYFS_TEST_S3_BUCKET").unwrap_or_else(|_| String::from("polyfs"));
+
+        Some(S3Config {
+            endpoint: Some(endpoint),
+            prefix: format!("test-{}-{}/", std::process::id(), rand::random::<u32>()),
+            path_style: true,
+            multipart_threshold: MIN_PART_SIZE,
+            part_size: MIN_PART_SIZE,
+            ..S3Config::new(bucket)
+        })
+    }
+
+    #[test]
+    fn objects_in_bucket() -> TestResult {
+        let config = match test_config() {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let kv_store = S3KvStore::new(config)?;
+        let large = (0..MIN_PART_SIZE * 2 + 10).map(|i| i as u8).collect::<Vec<u8>>();
+
+        kv_store.batch(vec![
+            BatchOp::Set(vec![1], b"short".to_vec()),
+            BatchOp::Set(vec![1, 2, 3], b"a".to_vec()),
+            BatchOp::Set(vec![2], large.clone()),
+            BatchOp::Set(vec![3], b"deleted".to_vec()),
+            BatchOp::Delete(vec![3]),
+        ])?;
+
+        assert_eq!(kv_store.get(vec![1, 2, 3])?.unwrap(), b"a");
+        assert_eq!(kv_store.get(vec![2])?.unwrap(), large);
+        assert_eq!(kv_store.get(vec![3])?, None);
+        assert_eq!(
+            kv_store.scan_prefix(vec![1])?,
+            vec![(vec![1], b"short".to_vec()), (vec![1, 2, 3], b"a".to_vec())]
+        );
+        assert_eq!(kv_store.list()?, vec![vec![1], vec![1, 2, 3], vec![2]]);
+
+        for key in kv_store.list()? {
+            kv_store.delete(key)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        let config = match test_config() {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let kv_store = S3KvStore::new(config)?;
+
+        assert!(kv_store.compare_and_swap(vec![4], None, Some(b"world".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(vec![4], None, Some(b"mister".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(vec![4], Some(b"mister".to_vec()), None)?);
+        assert!(kv_store.compare_and_swap(vec![4], Some(b"world".to_vec()), Some(b"there".to_vec()))?);
+        assert_eq!(kv_store.get(vec![4])?.unwrap(), b"there");
+        assert!(kv_store.compare_and_swap(vec![4], Some(b"there".to_vec()), None)?);
+        assert_eq!(kv_store.get(vec![4])?, None);
+
+        // Writes that lost a race with another client
+        let object = kv_store.object_name(&[5]);
+        let stale = Precondition::Matches(String::from("\"stale\""));
+        kv_store.set(vec![5], b"other".to_vec())?;
+        assert!(!kv_store.put_if(&object, b"mine", &Precondition::Missing)?);
+        assert!(!kv_store.put_if(&object, b"mine", &stale)?);
+        assert!(!kv_store.delete_if(&object, &stale)?);
+        assert_eq!(kv_store.get(vec![5])?.unwrap(), b"other");
+        kv_store.delete(vec![5])?;
+
+        Ok(())
+    }
+}
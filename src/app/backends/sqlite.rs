@@ -3,11 +3,122 @@
 use serde::{Serialize, Deserialize};
 
 /// Sqlite database configuation structure
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct SqliteConfig {
     /// The Sqlite database configuration
-    pub db: SqliteDb
+    pub db: SqliteDb,
+    /// The journal mode of the database
+    #[serde(default)]
+    pub journal_mode: SqliteJournalMode,
+    /// How often Sqlite waits for data to be written to disk
+    #[serde(default)]
+    pub synchronous: SqliteSynchronous,
+    /// How long to wait for a locked database in milliseconds
+    #[serde(default = "default_busy_timeout")]
+    pub busy_timeout: u32,
+    /// The page cache size for each connection. Positive values are a number
+    /// of pages and negative values a number of KiB, as for
+    /// `PRAGMA cache_size`. Uses the Sqlite default if not set.
+    #[serde(default)]
+    pub cache_size: Option<i64>,
+    /// The maximum number of bytes of the database to access with memory
+    /// mapped I/O. Uses the Sqlite default if not set.
+    #[serde(default)]
+    pub mmap_size: Option<u64>,
+    /// The number of read-only connections kept open alongside the single
+    /// write connection. Databases that aren't files always use a single
+    /// connection.
+    #[serde(default = "default_readers")]
+    pub readers: u32,
+    /// The directory to create temporary databases in. Uses the system
+    /// temporary directory if not set.
+    #[serde(default)]
+    pub temp_dir: Option<String>,
+}
+
+fn default_busy_timeout() -> u32 {
+    5000
+}
+
+fn default_readers() -> u32 {
+    4
+}
+
+impl Default for SqliteConfig {
+    fn default() -> SqliteConfig {
+        SqliteConfig {
+            db: SqliteDb::default(),
+            journal_mode: SqliteJournalMode::default(),
+            synchronous: SqliteSynchronous::default(),
+            busy_timeout: default_busy_timeout(),
+            cache_size: None,
+            mmap_size: None,
+            readers: default_readers(),
+            temp_dir: None,
+        }
+    }
+}
+
+/// Sqlite journal mode, see `PRAGMA journal_mode`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteJournalMode {
+    /// Delete the rollback journal at the end of each transaction
+    Delete,
+    /// Truncate the rollback journal at the end of each transaction
+    Truncate,
+    /// Zero the header of the rollback journal at the end of each transaction
+    Persist,
+    /// Keep the rollback journal in memory
+    Memory,
+    /// Use a write-ahead log, allowing readers to run concurrently with the
+    /// writer
+    #[default]
+    Wal,
+    /// Disable the rollback journal
+    Off,
+}
+
+impl SqliteJournalMode {
+    /// The name of the mode in Sqlite
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SqliteJournalMode::Delete => "DELETE",
+            SqliteJournalMode::Truncate => "TRUNCATE",
+            SqliteJournalMode::Persist => "PERSIST",
+            SqliteJournalMode::Memory => "MEMORY",
+            SqliteJournalMode::Wal => "WAL",
+            SqliteJournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// Sqlite synchronous setting, see `PRAGMA synchronous`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronous {
+    /// Don't wait for writes to reach the disk
+    Off,
+    /// Wait for writes at the most critical moments
+    Normal,
+    /// Wait for writes to reach the disk before continuing
+    #[default]
+    Full,
+    /// Like `Full`, but also sync the directory of a deleted rollback journal
+    Extra,
+}
+
+impl SqliteSynchronous {
+    /// The name of the setting in Sqlite
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SqliteSynchronous::Off => "OFF",
+            SqliteSynchronous::Normal => "NORMAL",
+            SqliteSynchronous::Full => "FULL",
+            SqliteSynchronous::Extra => "EXTRA",
+        }
+    }
 }
 
 /// Sqlite database type
@@ -16,19 +127,23 @@ pub struct SqliteConfig {
 pub enum SqliteDb {
     /// An in-memory Sqlite database for testing
     InMemory,
-    /// A temporary database that will be cleaned up on exit
+    /// A database file in the temporary directory that is deleted when the
+    /// store is closed
     Temporary,
     /// An Sqlite database file
     #[serde(rename = "file")]
     File(String),
 }
 
+/// `polyfs.db` in the working directory. `config default` writes it as an
+/// absolute path next to the config file.
 impl Default for SqliteDb {
     fn default() -> SqliteDb {
-        SqliteDb::Temporary
+        SqliteDb::File(String::from("polyfs.db"))
     }
 }
 
+pub mod backup;
 pub mod kv;
 pub use self::kv::SqliteKvStore;
 
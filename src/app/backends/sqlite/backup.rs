@@ -0,0 +1,105 @@
+//! Sqlite database backups using Sqlite's online backup API
+
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use libsqlite3_sys as ffi;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::ptr;
+use std::time::Duration;
+
+/// An open raw Sqlite connection that is closed when dropped
+#[derive(Debug)]
+struct RawConnection(*mut ffi::sqlite3);
+
+impl RawConnection {
+    fn open(path: &str, flags: c_int) -> PolyfsResult<RawConnection> {
+        let c_path = try_to!(CString::new(path), "Invalid database path");
+        let mut handle = ptr::null_mut();
+
+        let result = unsafe { ffi::sqlite3_open_v2(c_path.as_ptr(), &mut handle, flags, ptr::null()) };
+        // Sqlite allocates a handle even when opening fails so that the error
+        // message can be read from it
+        let connection = RawConnection(handle);
+
+        if result != ffi::SQLITE_OK {
+            return Err(PolyfsError {
+                message: format!("Could not open database '{}': {}", path, connection.error_message()),
+                cause: None,
+            });
+        }
+
+        Ok(connection)
+    }
+
+    fn error_message(&self) -> String {
+        if self.0.is_null() {
+            return String::from("out of memory");
+        }
+
+        unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// Copy the database at `source` to a new database at `destination`
+///
+/// The backup is consistent even if the source database is being written to
+/// by other connections while it is copied.
+pub fn backup_database(source: &str, destination: &str) -> PolyfsResult<()> {
+    let source_conn = RawConnection::open(source, ffi::SQLITE_OPEN_READONLY)?;
+    let destination_conn = RawConnection::open(
+        destination,
+        ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+    )?;
+
+    let main = CString::new("main").expect("Static string has no nul bytes");
+    let backup = unsafe {
+        ffi::sqlite3_backup_init(destination_conn.0, main.as_ptr(), source_conn.0, main.as_ptr())
+    };
+    if backup.is_null() {
+        return Err(PolyfsError {
+            message: format!(
+                "Could not start database backup: {}",
+                destination_conn.error_message()
+            ),
+            cause: None,
+        });
+    }
+
+    // Copy every page at once, retrying while the source is locked by a writer
+    let mut result;
+    loop {
+        result = unsafe { ffi::sqlite3_backup_step(backup, -1) };
+        match result {
+            ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => std::thread::sleep(Duration::from_millis(100)),
+            _ => break,
+        }
+    }
+
+    // Finishing the backup releases it even if it failed
+    let finish_result = unsafe { ffi::sqlite3_backup_finish(backup) };
+
+    if result != ffi::SQLITE_DONE || finish_result != ffi::SQLITE_OK {
+        return Err(PolyfsError {
+            message: format!(
+                "Could not back up database '{}' to '{}': {}",
+                source,
+                destination,
+                destination_conn.error_message()
+            ),
+            cause: None,
+        });
+    }
+
+    Ok(())
+}
@@ -1,19 +1,71 @@
 //! Sqlite key-value store implementation
 
+use super::backup::backup_database;
 use super::{SqliteConfig, SqliteDb};
-use crate::app::keyvalue::{KeyValueError, KeyValueResult, KeyValueStore};
-use crate::{PolyfsResult, try_to};
+use crate::app::keyvalue::{
+    prefix_successor, BatchOp, KeyValueResult, KeyValueStore,
+};
+use crate::{PolyfsError, PolyfsResult, try_to};
 
+use diesel::connection::SimpleConnection;
+use diesel::dsl::sql;
 use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
 use diesel::result::Error as DieselError;
+use diesel::sql_types::BigInt;
 use diesel::sqlite::SqliteConnection;
-use diesel_migrations::embed_migrations;
+use diesel_migrations::{embed_migrations, MigrationConnection};
+
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
 
 mod kv_schema;
 use self::kv_schema::kv_store;
 
 embed_migrations!("src/app/backends/sqlite/kv/kv-migrations");
 
+/// The versions of the embedded migrations. This must be kept in sync with the
+/// `kv-migrations` directory.
+const MIGRATION_VERSIONS: &[&str] = &["20190507222156"];
+
+/// Get the versions of the embedded migrations that haven't been run on a
+/// database
+///
+/// Databases without any tables are new and have no pending migrations since
+/// there is nothing in them to lose.
+fn pending_migrations(conn: &SqliteConnection) -> PolyfsResult<Vec<&'static str>> {
+    let count_tables = |filter: &str| {
+        sql::<BigInt>(&format!(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table'{}",
+            filter
+        ))
+        .get_result::<i64>(conn)
+    };
+
+    if try_to!(count_tables(""), "Could not read database schema") == 0 {
+        return Ok(vec![]);
+    }
+
+    let run_versions = if try_to!(
+        count_tables(" AND name = '__diesel_schema_migrations'"),
+        "Could not read database schema"
+    ) == 0
+    {
+        Default::default()
+    } else {
+        try_to!(
+            conn.previously_run_migration_versions(),
+            "Could not read database migration history"
+        )
+    };
+
+    Ok(MIGRATION_VERSIONS
+        .iter()
+        .filter(|version| !run_versions.contains(**version))
+        .cloned()
+        .collect())
+}
+
 /// A Queryable and Insertable KeyValue pair
 #[derive(Queryable, Insertable)]
 #[table_name = "kv_store"]
@@ -25,25 +77,142 @@ pub struct KvPair {
 }
 
 /// A Sqlite backed implementation of `KeyValueStore`
+///
+/// All writes go through a single write connection. Reads use a pool of
+/// read-only connections when the database is a file, which lets them run
+/// concurrently with each other and, in WAL mode, with the writer.
 pub struct SqliteKvStore {
     config: SqliteConfig,
-    conn: SqliteConnection,
+    conn: Mutex<SqliteConnection>,
+    readers: Option<Pool<ConnectionManager<SqliteConnection>>>,
+    // Must come after the connections so that they are closed before the
+    // file is removed
+    temp_file: Option<TempFile>,
+}
+
+/// A temporary database file that is removed when dropped, along with any
+/// journal files Sqlite left beside it
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Pick a unique database file name in `dir`
+    fn new(dir: Option<&str>) -> TempFile {
+        let dir = dir.map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+
+        TempFile {
+            path: dir.join(format!(
+                "polyfs-{}-{:08x}.db",
+                std::process::id(),
+                rand::random::<u32>()
+            )),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        log::debug!("Removing temporary database '{}'", self.path.display());
+
+        for suffix in &["", "-journal", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            // Journal files only exist in some journal modes
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Applies the configured pragmas to pooled reader connections
+#[derive(Debug)]
+struct ReaderSettings {
+    pragmas: String,
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ReaderSettings {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&self.pragmas)
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Get the pragmas that must be set on every connection
+fn connection_pragmas(config: &SqliteConfig) -> String {
+    let mut pragmas = format!(
+        "PRAGMA busy_timeout = {}; PRAGMA synchronous = {};",
+        config.busy_timeout,
+        config.synchronous.as_str()
+    );
+
+    if let Some(cache_size) = config.cache_size {
+        pragmas.push_str(&format!(" PRAGMA cache_size = {};", cache_size));
+    }
+    if let Some(mmap_size) = config.mmap_size {
+        pragmas.push_str(&format!(" PRAGMA mmap_size = {};", mmap_size));
+    }
+
+    pragmas
 }
 
 use std::fmt;
 
 impl fmt::Debug for SqliteKvStore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SqliteKvStore {{ config: {:#?} }}", self.config)
+        write!(
+            f,
+            "SqliteKvStore {{ config: {:#?}, temp_file: {:?} }}",
+            self.config, self.temp_file
+        )
     }
 }
 
 impl SqliteKvStore {
     /// Instantiate a Sqlite KV store
+    ///
+    /// Fails if an existing database needs to be migrated. Use `open` to allow
+    /// migrations.
     pub fn new(config: SqliteConfig) -> PolyfsResult<SqliteKvStore> {
+        SqliteKvStore::open(config, false)
+    }
+
+    /// Get the versions of the database migrations that will be run when the
+    /// store is opened
+    pub fn pending_migrations(config: &SqliteConfig) -> PolyfsResult<Vec<&'static str>> {
+        match &config.db {
+            // These databases always start out empty
+            SqliteDb::InMemory | SqliteDb::Temporary => Ok(vec![]),
+            SqliteDb::File(file) => pending_migrations(&try_to!(
+                SqliteConnection::establish(file),
+                "Could not connect to database for KV store"
+            )),
+        }
+    }
+
+    /// Instantiate a Sqlite KV store, migrating an existing database if
+    /// `allow_migrations` is true
+    ///
+    /// The database is backed up to a timestamped file next to it before it is
+    /// migrated. Migrations are run in a single transaction so a failed
+    /// migration leaves the database unchanged.
+    pub fn open(config: SqliteConfig, allow_migrations: bool) -> PolyfsResult<SqliteKvStore> {
+        let mut temp_file = None;
         let db_path = match &config.db {
-            SqliteDb::InMemory => ":memory:".into(),
-            SqliteDb::Temporary => "".into(),
+            SqliteDb::InMemory => {
+                log::warn!("Using an in-memory database. All data will be lost on exit!");
+                ":memory:".into()
+            }
+            SqliteDb::Temporary => {
+                let file = TempFile::new(config.temp_dir.as_deref());
+                let path = file.path.to_string_lossy().into_owned();
+                log::warn!(
+                    "Using temporary database '{}'. All data will be lost on exit!",
+                    path
+                );
+                temp_file = Some(file);
+                path
+            }
             SqliteDb::File(file) => file.clone(),
         };
 
@@ -52,27 +221,111 @@ impl SqliteKvStore {
             "Could not connect to database for KV store"
         );
 
-        // TODO: Migrations should not be run without warning the user to backup
-        // their database first
+        let pending = pending_migrations(&conn)?;
+        let mut backup_path = None;
+        if !pending.is_empty() {
+            if !allow_migrations {
+                return Err(PolyfsError {
+                    message: format!(
+                        "Database '{}' needs to be migrated ({}). Migrations must be allowed \
+                         explicitly.",
+                        db_path,
+                        pending.join(", ")
+                    ),
+                    cause: None,
+                });
+            }
+
+            if let SqliteDb::File(file) = &config.db {
+                let path = format!(
+                    "{}.{}.backup",
+                    file,
+                    chrono::Local::now().format("%Y%m%d%H%M%S")
+                );
+                backup_database(file, &path)?;
+                log::warn!("Backed up database '{}' to '{}' before migrating it", file, path);
+                backup_path = Some(path);
+            }
+        }
+
+        // The journal mode is a property of the database, so it only needs to
+        // be set by the writer
+        let pragmas = connection_pragmas(&config);
         try_to!(
-            embedded_migrations::run(&conn),
-            "Could not run database migrations"
+            conn.batch_execute(&format!(
+                "{} PRAGMA journal_mode = {};",
+                pragmas,
+                config.journal_mode.as_str()
+            )),
+            "Could not configure database connection"
         );
 
-        Ok(SqliteKvStore { config, conn })
+        if let Err(e) = conn.transaction(|| embedded_migrations::run(&conn)) {
+            return Err(PolyfsError {
+                message: match backup_path {
+                    Some(path) => format!(
+                        "Could not run database migrations. The database has been left \
+                         unchanged and a backup is at '{}'.",
+                        path
+                    ),
+                    None => String::from("Could not run database migrations"),
+                },
+                cause: Some(Box::new(e)),
+            });
+        }
+
+        // Every connection to an in-memory database opens a separate database,
+        // so it can only use the write connection
+        let readers = match &config.db {
+            SqliteDb::File(_) | SqliteDb::Temporary if config.readers > 0 => Some(try_to!(
+                Pool::builder()
+                    .max_size(config.readers)
+                    .connection_customizer(Box::new(ReaderSettings {
+                        pragmas: format!("{} PRAGMA query_only = 1;", pragmas),
+                    }))
+                    .build(ConnectionManager::<SqliteConnection>::new(db_path.as_str())),
+                "Could not open reader connections to database"
+            )),
+            _ => None,
+        };
+
+        Ok(SqliteKvStore {
+            config,
+            conn: Mutex::new(conn),
+            readers,
+            temp_file,
+        })
+    }
+
+    /// Get exclusive access to the connection
+    fn conn(&self) -> MutexGuard<'_, SqliteConnection> {
+        // A panic while holding the lock can't leave the connection in an
+        // inconsistent state, so ignore poisoning
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run a read-only query on a reader connection if there are any, or the
+    /// write connection if there aren't
+    fn read<T, F>(&self, query: F) -> KeyValueResult<T>
+    where
+        F: FnOnce(&SqliteConnection) -> QueryResult<T>,
+    {
+        match &self.readers {
+            Some(readers) => Ok(query(&*readers.get()?)?),
+            None => Ok(query(&self.conn())?),
+        }
     }
 }
 
 impl KeyValueStore for SqliteKvStore {
     fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
-        match kv_store::table
-            .filter(kv_store::key.eq(key))
-            .get_result::<KvPair>(&self.conn)
-        {
-            Ok(kv_pair) => Ok(Some(kv_pair.value)),
-            Err(DieselError::NotFound) => Ok(None),
-            Err(other_error) => Err(KeyValueError::DatabaseError(other_error)),
-        }
+        self.read(|conn| {
+            kv_store::table
+                .filter(kv_store::key.eq(key))
+                .get_result::<KvPair>(conn)
+                .optional()
+        })
+        .map(|kv_pair| kv_pair.map(|kv_pair| kv_pair.value))
     }
 
     fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
@@ -81,38 +334,126 @@ impl KeyValueStore for SqliteKvStore {
                 key,
                 value,
             })
-            .execute(&self.conn)?;
+            .execute(&*self.conn())?;
 
         Ok(())
     }
 
     fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
-        diesel::delete(kv_store::table.filter(kv_store::key.eq(key))).execute(&self.conn)?;
+        diesel::delete(kv_store::table.filter(kv_store::key.eq(key))).execute(&*self.conn())?;
 
         Ok(())
     }
 
     fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
-        Ok(kv_store::table
-            .select(kv_store::key)
-            .load::<Vec<u8>>(&self.conn)?)
+        self.read(|conn| {
+            kv_store::table
+                .select(kv_store::key)
+                .load::<Vec<u8>>(conn)
+        })
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Sqlite compares blobs with `memcmp` so the prefix is a range of keys
+        let query = kv_store::table
+            .filter(kv_store::key.ge(prefix.clone()))
+            .order(kv_store::key)
+            .into_boxed();
+
+        let query = match prefix_successor(&prefix) {
+            Some(end) => query.filter(kv_store::key.lt(end)),
+            None => query,
+        };
+
+        Ok(self
+            .read(|conn| query.load::<KvPair>(conn))?
+            .into_iter()
+            .map(|pair| (pair.key, pair.value))
+            .collect())
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        let conn = self.conn();
+
+        conn.transaction::<_, DieselError, _>(|| {
+            for op in ops {
+                match op {
+                    BatchOp::Set(key, value) => {
+                        diesel::replace_into(kv_store::table)
+                            .values(KvPair { key, value })
+                            .execute(&*conn)?;
+                    }
+                    BatchOp::Delete(key) => {
+                        diesel::delete(kv_store::table.filter(kv_store::key.eq(key)))
+                            .execute(&*conn)?;
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let conn = self.conn();
+
+        // Each case is a single conditional statement, so the check and the
+        // write are atomic
+        let changed = match (expected, new) {
+            (None, Some(value)) => diesel::insert_or_ignore_into(kv_store::table)
+                .values(KvPair { key, value })
+                .execute(&*conn)?,
+            (Some(expected), Some(value)) => diesel::update(
+                kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .filter(kv_store::value.eq(expected)),
+            )
+            .set(kv_store::value.eq(value))
+            .execute(&*conn)?,
+            (Some(expected), None) => diesel::delete(
+                kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .filter(kv_store::value.eq(expected)),
+            )
+            .execute(&*conn)?,
+            (None, None) => {
+                let exists = kv_store::table
+                    .filter(kv_store::key.eq(key))
+                    .count()
+                    .get_result::<i64>(&*conn)?;
+                return Ok(exists == 0);
+            }
+        };
+
+        Ok(changed == 1)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::app::backends::sqlite::SqliteJournalMode;
     use crate::app::keyvalue::KeyValueStore;
 
     type TestResult = Result<(), Box<dyn std::error::Error>>;
 
-    const DB_CONFIG: SqliteConfig = SqliteConfig {
-        db: SqliteDb::InMemory,
-    };
+    fn db_config() -> SqliteConfig {
+        SqliteConfig {
+            db: SqliteDb::InMemory,
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn set_and_get() -> TestResult {
-        let kv_store = SqliteKvStore::new(DB_CONFIG)?;
+        let kv_store = SqliteKvStore::new(db_config())?;
 
         // Set a couple values then get them
         kv_store.set(b"hello".to_vec().to_vec(), "world".as_bytes().to_vec())?;
@@ -125,7 +466,7 @@ mod test {
 
     #[test]
     fn set_and_update_key() -> TestResult {
-        let kv_store = SqliteKvStore::new(DB_CONFIG)?;
+        let kv_store = SqliteKvStore::new(db_config())?;
 
         kv_store.set(b"hello".to_vec(), "world".as_bytes().to_vec())?;
         assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), "world".as_bytes());
@@ -137,7 +478,7 @@ mod test {
 
     #[test]
     fn get_nothing() -> TestResult {
-        let kv_store = SqliteKvStore::new(DB_CONFIG)?;
+        let kv_store = SqliteKvStore::new(db_config())?;
 
         // Get a non-existant value
         assert_eq!(kv_store.get(b"none".to_vec())?, None);
@@ -147,7 +488,7 @@ mod test {
 
     #[test]
     fn delete_key() -> TestResult {
-        let kv_store = SqliteKvStore::new(DB_CONFIG)?;
+        let kv_store = SqliteKvStore::new(db_config())?;
 
         // Set a value and make sure it is set
         kv_store.set(b"hello".to_vec(), "world".as_bytes().to_vec())?;
@@ -162,7 +503,7 @@ mod test {
 
     #[test]
     fn list_keys() -> TestResult {
-        let kv_store = SqliteKvStore::new(DB_CONFIG)?;
+        let kv_store = SqliteKvStore::new(db_config())?;
 
         kv_store.set(b"hello".to_vec(), "world".as_bytes().to_vec())?;
         kv_store.set(b"goodbye".to_vec(), "world".as_bytes().to_vec())?;
@@ -171,4 +512,177 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn scan_prefix() -> TestResult {
+        let kv_store = SqliteKvStore::new(db_config())?;
+
+        kv_store.set(vec![1, 0xFF], b"after".to_vec())?;
+        kv_store.set(vec![1, 2, 0xFF], b"b".to_vec())?;
+        kv_store.set(vec![1, 2, 0], b"a".to_vec())?;
+        kv_store.set(vec![1, 2], b"prefix".to_vec())?;
+        kv_store.set(vec![1, 1, 0xFF], b"before".to_vec())?;
+
+        assert_eq!(
+            kv_store.scan_prefix(vec![1, 2])?,
+            vec![
+                (vec![1, 2], b"prefix".to_vec()),
+                (vec![1, 2, 0], b"a".to_vec()),
+                (vec![1, 2, 0xFF], b"b".to_vec()),
+            ]
+        );
+        assert_eq!(kv_store.scan_prefix(vec![1, 0xFF])?.len(), 1);
+        assert_eq!(kv_store.scan_prefix(vec![])?.len(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_database_with_readers() -> TestResult {
+        let path = std::env::temp_dir().join(format!("polyfs-test-{}.db", std::process::id()));
+        let db_file = path.to_str().unwrap().to_owned();
+
+        let result = (|| -> TestResult {
+            let kv_store = SqliteKvStore::new(SqliteConfig {
+                db: SqliteDb::File(db_file.clone()),
+                readers: 2,
+                ..Default::default()
+            })?;
+            assert!(kv_store.readers.is_some());
+
+            kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+            assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"world");
+            assert_eq!(kv_store.list()?, vec![b"hello".to_vec()]);
+
+            Ok(())
+        })();
+
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", db_file, suffix));
+        }
+
+        result
+    }
+
+    #[test]
+    fn temporary_database_is_removed() -> TestResult {
+        let kv_store = SqliteKvStore::new(SqliteConfig {
+            db: SqliteDb::Temporary,
+            temp_dir: Some(std::env::temp_dir().to_string_lossy().into_owned()),
+            ..Default::default()
+        })?;
+        let path = kv_store.temp_file.as_ref().unwrap().path.clone();
+
+        kv_store.set(b"hello".to_vec(), b"world".to_vec())?;
+        assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"world");
+        assert!(path.exists());
+
+        drop(kv_store);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn migration_versions_match_directory() -> TestResult {
+        let mut versions = std::fs::read_dir("src/app/backends/sqlite/kv/kv-migrations")?
+            .map(|entry| {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                Ok(name
+                    .split('_')
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .filter(char::is_ascii_digit)
+                    .collect::<String>())
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        versions.sort();
+
+        assert_eq!(versions, MIGRATION_VERSIONS);
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_existing_database() -> TestResult {
+        let path = std::env::temp_dir().join(format!("polyfs-migrate-{}.db", std::process::id()));
+        let db_file = path.to_str().unwrap().to_owned();
+        let config = || SqliteConfig {
+            db: SqliteDb::File(db_file.clone()),
+            journal_mode: SqliteJournalMode::Delete,
+            readers: 0,
+            ..Default::default()
+        };
+
+        let backups = || -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+            let mut backups = vec![];
+            for entry in std::fs::read_dir(std::env::temp_dir())? {
+                let path = entry?.path();
+                if path.to_string_lossy().starts_with(&format!("{}.", db_file)) {
+                    backups.push(path);
+                }
+            }
+            Ok(backups)
+        };
+
+        let result = (|| -> TestResult {
+            // A database from before migrations were tracked
+            SqliteConnection::establish(&db_file)?
+                .batch_execute("CREATE TABLE kv_store (key BLOB PRIMARY KEY NOT NULL, value BLOB NOT NULL);")?;
+
+            assert_eq!(SqliteKvStore::pending_migrations(&config())?, MIGRATION_VERSIONS);
+            assert!(SqliteKvStore::new(config()).is_err());
+
+            // The failing migration must be rolled back
+            assert!(SqliteKvStore::open(config(), true).is_err());
+            assert_eq!(SqliteKvStore::pending_migrations(&config())?, MIGRATION_VERSIONS);
+            assert_eq!(backups()?.len(), 1);
+
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_file(&db_file);
+        for backup in backups()? {
+            let _ = std::fs::remove_file(backup);
+        }
+
+        result
+    }
+
+    #[test]
+    fn batch() -> TestResult {
+        let kv_store = SqliteKvStore::new(db_config())?;
+
+        kv_store.set(b"goodbye".to_vec(), b"world".to_vec())?;
+        kv_store.batch(vec![
+            BatchOp::Set(b"hello".to_vec(), b"world".to_vec()),
+            BatchOp::Delete(b"goodbye".to_vec()),
+            BatchOp::Set(b"hello".to_vec(), b"mister".to_vec()),
+        ])?;
+
+        assert_eq!(kv_store.get(b"hello".to_vec())?.unwrap(), b"mister");
+        assert_eq!(kv_store.get(b"goodbye".to_vec())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_and_swap() -> TestResult {
+        let kv_store = SqliteKvStore::new(db_config())?;
+
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"world".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(b"hello".to_vec(), None, Some(b"mister".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(b"hello".to_vec(), Some(b"mister".to_vec()), None)?);
+        assert!(kv_store.compare_and_swap(
+            b"hello".to_vec(),
+            Some(b"world".to_vec()),
+            Some(b"there".to_vec())
+        )?);
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), Some(b"there".to_vec()), None)?);
+        assert!(kv_store.compare_and_swap(b"hello".to_vec(), None, None)?);
+        assert_eq!(kv_store.get(b"hello".to_vec())?, None);
+
+        Ok(())
+    }
 }
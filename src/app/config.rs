@@ -1,14 +1,31 @@
 //! Module containing aspects of the global application configuration
 
 use serde::{Serialize, Deserialize};
+use crate::app::backends::directory::DirectoryConfig;
+use crate::app::backends::log::LogConfig;
+use crate::app::backends::postgres::PostgresConfig;
+use crate::app::backends::redis::RedisConfig;
+use crate::app::backends::s3::S3Config;
 use crate::app::backends::sqlite::SqliteConfig;
+use crate::app::filesystem::FilesystemConfig;
+use crate::app::keyvalue::encrypted::EncryptionConfig;
 
 /// Application config
 #[derive(Serialize, Deserialize, Default, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct AppConfig {
-    /// Storage backend configuration
-    pub backend: Backend
+    /// Storage backend configuration for metadata, and for file contents if
+    /// `data_backend` isn't set
+    pub backend: Backend,
+    /// Storage backend configuration for file contents
+    #[serde(default)]
+    pub data_backend: Option<Backend>,
+    /// Encryption of the stored keys and values, in every backend
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    /// Filesystem behavior configuration
+    #[serde(default)]
+    pub filesystem: FilesystemConfig,
 }
 
 /// A supported storage backend with its config
@@ -16,7 +33,19 @@ pub struct AppConfig {
 #[serde(deny_unknown_fields, rename_all="snake_case")]
 pub enum Backend {
     /// Sqlite backend config
-    Sqlite(SqliteConfig)
+    Sqlite(SqliteConfig),
+    /// In-memory backend that doesn't persist anything
+    Memory,
+    /// Log-structured backend config
+    Log(LogConfig),
+    /// PostgreSQL backend config
+    Postgres(PostgresConfig),
+    /// Redis backend config
+    Redis(RedisConfig),
+    /// Directory of files backend config
+    Directory(DirectoryConfig),
+    /// S3-compatible object storage backend config
+    S3(S3Config),
 }
 
 impl Default for Backend {
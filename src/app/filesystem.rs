@@ -3,271 +3,261 @@
 
 use crate::app::keyvalue::KeyValueStore;
 
-use bincode::{deserialize, serialize};
 use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyEntry, Request,
+    FileAttr, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request,
 };
-use libc::ENOENT;
 use log::{debug, trace};
-use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use threadpool::ThreadPool;
 use time::Timespec;
 
-mod types;
-use self::types::*;
+pub mod chunks;
+pub mod compression;
+pub mod fsck;
+pub mod inode;
+mod locks;
+pub mod migration;
+pub mod operations;
+pub mod quota;
+pub mod snapshots;
+pub mod trash;
+pub mod types;
+pub mod usage;
+pub mod versions;
+use self::compression::CompressionConfig;
+use self::inode::{FileKind, Inode, Timestamp};
+use self::operations::{AttrChanges, FilesystemCore};
+use self::trash::TrashConfig;
+
+/// Filesystem behavior configuration
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FilesystemConfig {
+    /// When to update the access time of files
+    #[serde(default)]
+    pub atime: AtimePolicy,
+    /// The number of worker threads handling filesystem requests. Defaults to
+    /// the number of CPUs.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// How file contents are compressed
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Whether removed files are moved to the trash, and for how long
+    #[serde(default)]
+    pub trash: TrashConfig,
+}
+
+/// Policy for updating the access time of files when they are read
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AtimePolicy {
+    /// Update the access time on every access
+    StrictAtime,
+    /// Only update the access time if it is older than the modification or
+    /// change time, or if it is more than a day old
+    #[default]
+    RelAtime,
+    /// Never update the access time
+    NoAtime,
+}
+
+impl AtimePolicy {
+    /// Whether or not the access time of `inode` should be updated when it is
+    /// accessed at `now`
+    pub fn should_update(self, inode: &Inode, now: Timestamp) -> bool {
+        match self {
+            AtimePolicy::StrictAtime => true,
+            AtimePolicy::RelAtime => {
+                inode.atime <= inode.mtime
+                    || inode.atime <= inode.ctime
+                    || now.seconds_since(inode.atime) >= 24 * 60 * 60
+            }
+            AtimePolicy::NoAtime => false,
+        }
+    }
+}
 
 /// The PolyFS filesystem implementation
-pub struct PolyfsFilesystem<KvStore: KeyValueStore> {
-    kv_store: KvStore,
+///
+/// FUSE requests are received on the thread that mounted the filesystem and
+/// handled by a pool of worker threads, so that a slow request doesn't hold up
+/// every other request.
+pub struct PolyfsFilesystem<KvStore: KeyValueStore + 'static> {
+    core: Arc<FilesystemCore<KvStore>>,
+    pool: ThreadPool,
+    purge_trash: bool,
 }
 
-impl<KvStore: KeyValueStore> PolyfsFilesystem<KvStore> {
+impl<KvStore: KeyValueStore + 'static> PolyfsFilesystem<KvStore> {
     /// Create a filesystem instance backed by the provided `KeyValueStore`
-    pub fn new(kv_store: KvStore) -> PolyfsFilesystem<KvStore> {
-        PolyfsFilesystem { kv_store }
+    ///
+    /// File contents are kept in `data_store` if it is provided and with the
+    /// metadata in `kv_store` otherwise. A `read_only` filesystem never writes
+    /// to the stores.
+    pub fn new(
+        kv_store: KvStore,
+        data_store: Option<KvStore>,
+        config: FilesystemConfig,
+        read_only: bool,
+    ) -> PolyfsFilesystem<KvStore> {
+        let threads = config.threads.unwrap_or_else(num_cpus::get).max(1);
+        debug!("Starting {} filesystem worker threads", threads);
+        let purge_trash = config.trash.retention_days.is_some() && !read_only;
+
+        PolyfsFilesystem {
+            core: Arc::new(FilesystemCore::new(kv_store, data_store, config, read_only)),
+            pool: ThreadPool::with_name(String::from("polyfs-worker"), threads),
+            purge_trash,
+        }
     }
 
-    /// Get an inode id that isn't used by any existing node
-    ///
-    /// The implementation involves generating a random ino and checking to see
-    /// whether or not it exists.
-    ///
-    /// TODO: This will be very unperformant as soon as the number of inodes
-    /// approaches the maxiumum number of inodes, but I'm not sure if that will
-    /// ever happen. Either way we should do this differently later.
-    fn get_available_ino(&self) -> u64 {
-        let is_used = |ino| {
-            let key = KvQuery::FileAttributes(ino).get_key();
-            match self.kv_store.get(key).unwrap() {
-                Some(_) => true,
-                None => false,
+    /// Purge expired files from the trash now and every
+    /// `TRASH_PURGE_INTERVAL` until the filesystem is dropped
+    fn start_trash_purge(core: Weak<FilesystemCore<KvStore>>) {
+        let purge = move || {
+            while let Some(core) = core.upgrade() {
+                match core.purge_expired_trash() {
+                    Ok(0) => (),
+                    Ok(purged) => log::info!("Purged {} expired files from the trash", purged),
+                    Err(errno) => log::error!("Could not purge the trash: errno {}", errno),
+                }
+                drop(core);
+
+                std::thread::sleep(TRASH_PURGE_INTERVAL);
             }
         };
 
-        let mut ino;
-        loop {
-            ino = rand::random::<u64>();
+        if let Err(error) = std::thread::Builder::new()
+            .name(String::from("polyfs-trash"))
+            .spawn(purge)
+        {
+            log::error!("Could not start purging the trash: {}", error);
+        }
+    }
+
+    /// Run a request on the worker pool
+    fn dispatch<F>(&self, job: F)
+    where
+        F: FnOnce(&FilesystemCore<KvStore>) + Send + 'static,
+    {
+        let core = self.core.clone();
+        self.pool.execute(move || job(&core));
+    }
+
+    fn reply_entry(result: Result<Inode, i32>, reply: ReplyEntry) {
+        match result {
+            Ok(inode) => {
+                let attributes = FileAttr::from(&inode);
+                trace!("    Attr: {:#?}", attributes);
+                reply.entry(&TTL, &attributes, 0);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-            if !is_used(ino) {
-                return ino;
+    fn reply_attr(result: Result<Inode, i32>, reply: ReplyAttr) {
+        match result {
+            Ok(inode) => {
+                let attributes = FileAttr::from(&inode);
+                trace!("    Attr: {:#?}", attributes);
+                reply.attr(&TTL, &attributes);
             }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn reply_empty(result: Result<(), i32>, reply: ReplyEmpty) {
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn reply_xattr(result: Result<Vec<u8>, i32>, size: u32, reply: ReplyXattr) {
+        match result {
+            Ok(data) if size == 0 => reply.size(data.len() as u32),
+            Ok(data) if data.len() > size as usize => reply.error(libc::ERANGE),
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn create_file(
         &self,
-        file_type: FileType,
+        file_type: FileKind,
         req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _rdev: Option<u32>,
         reply: ReplyEntry,
     ) {
-        let ino = self.get_available_ino();
-
-        let created_time = time::get_time();
-
-        let attributes = FileAttr {
-            ino,
-            size: 0,
-            blocks: 0,
-            atime: created_time.clone(),
-            mtime: created_time.clone(),
-            ctime: created_time.clone(),
-            crtime: created_time.clone(),
-            kind: file_type,
-            perm: mode as u16,
-            nlink: 1,
-            uid: req.uid(),
-            gid: req.gid(),
-            rdev: 0,
-            flags: 0,
-        };
-
-        // Insert file attributes
-        let key = KvQuery::FileAttributes(ino).get_key();
-        self.kv_store
-            .set(key, serialize(&SerdeFileAttr(attributes)).unwrap())
-            .unwrap();
-
-        // Insert file record
-        let key = KvQuery::Files(parent, name).get_key();
-        self.kv_store.set(key, ino.to_le_bytes().to_vec()).unwrap();
-
-        // Update inode children record
-        let key = KvQuery::InodeChildren(parent).get_key();
-        let data: Vec<u8>;
-        let mut child_list = match self.kv_store.get(key.clone()).unwrap() {
-            Some(bytes) => {
-                data = bytes;
-                deserialize::<Vec<(u64, SerdeFileType, &str)>>(&data).unwrap()
-            }
-            None => vec![],
-        };
-
-        child_list.push((ino, SerdeFileType(attributes.kind), name.to_str().unwrap()));
-
-        self.kv_store
-            .set(key, serialize(&child_list).unwrap())
-            .unwrap();
-
-        reply.entry(&TTL, &attributes, 0);
+        let (uid, gid) = (req.uid(), req.gid());
+        let name = name.to_os_string();
+
+        self.dispatch(move |core| {
+            Self::reply_entry(
+                core.create_file(file_type, uid, gid, parent, &name, mode),
+                reply,
+            )
+        });
     }
 
-    fn remove_file(&self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let key = KvQuery::Files(parent, name).get_key();
-        let ino = match self.kv_store.get(key.clone()).unwrap() {
-            Some(data) => u64::from_le_bytes(data.as_slice().try_into().unwrap()),
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        // Remove file record
-        self.kv_store.delete(key).unwrap();
-
-        // Remove entry from `inode_children`
-        let key = KvQuery::InodeChildren(parent).get_key();
-        let mut data: Vec<u8> = Vec::new();
-        let mut dir_children = self
-            .kv_store
-            .get(key.clone())
-            .unwrap()
-            .map_or(vec![], |bytes| {
-                data = bytes;
-                deserialize::<Vec<(u64, SerdeFileType, &str)>>(&data).unwrap()
-            });
-
-        for (index, (item_ino, _, _)) in dir_children.iter().enumerate() {
-            if *item_ino == ino {
-                dir_children.remove(index);
-                break;
-            }
-        }
-
-        self.kv_store
-            .set(key, serialize(&dir_children).unwrap())
-            .unwrap();
+    fn remove_file(&self, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_os_string();
 
-        // Delete file attributes
-        self.kv_store
-            .delete(KvQuery::FileAttributes(ino).get_key())
-            .unwrap();
-
-        reply.ok();
+        self.dispatch(move |core| Self::reply_empty(core.remove_file(parent, &name), reply));
     }
 }
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
-const DEFAULT_TIME: Timespec = Timespec { sec: 0, nsec: 0 };
+
+/// The block size reported by `statfs`
+const BLOCK_SIZE: u64 = 4096;
+
+/// The free blocks and inodes reported by `statfs`. Backends don't have a fixed
+/// capacity, so this is just large enough to never run out.
+const FREE_BLOCKS: u64 = 1 << 40;
+
+/// How often files that have been in the trash for longer than the retention
+/// period are purged
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 impl<KvStore> Filesystem for PolyfsFilesystem<KvStore>
 where
-    KvStore: KeyValueStore,
+    KvStore: KeyValueStore + 'static,
 {
     fn init(&mut self, _req: &Request) -> Result<(), i32> {
         println!("Starting up FUSE filesystem");
 
-        // Insert the attributes for the mountpoint directory
-        let key = KvQuery::FileAttributes(1).get_key();
-
-        if let None = self.kv_store.get(key.clone()).unwrap() {
-            self.kv_store
-                .set(
-                    key,
-                    serialize(&SerdeFileAttr(FileAttr {
-                        ino: 2,
-                        size: 13,
-                        blocks: 1,
-                        atime: DEFAULT_TIME,
-                        mtime: DEFAULT_TIME,
-                        ctime: DEFAULT_TIME,
-                        crtime: DEFAULT_TIME,
-                        kind: FileType::Directory,
-                        perm: 0o777,
-                        nlink: 1,
-                        uid: 1001,
-                        gid: 1001,
-                        rdev: 0,
-                        flags: 0,
-                    }))
-                    .unwrap(),
-                )
-                .unwrap();
+        self.core.init()?;
+        if self.purge_trash {
+            Self::start_trash_purge(Arc::downgrade(&self.core));
         }
 
         Ok(())
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        debug!(
-            "Lookup: parent({}), name({})",
-            parent,
-            name.to_str().unwrap()
-        );
-        // Get inode of requested file
-        let key = KvQuery::Files(parent, name).get_key();
-        let ino = match self.kv_store.get(key).unwrap() {
-            Some(data) => {
-                let ino = u64::from_le_bytes(
-                    data.as_slice()
-                        .try_into()
-                        .expect("Could not decode data from database"),
-                );
-
-                debug!("    Found: ino({})", ino);
-
-                ino
-            }
-            None => {
-                debug!("    Not found: ENOENT");
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        debug!("    Found: ino({})", ino);
+    fn destroy(&mut self, _req: &Request) {
+        // Finish any requests that are still in progress
+        self.pool.join();
+    }
 
-        // Get file attributes using inode
-        let key = KvQuery::FileAttributes(ino).get_key();
-        match self.kv_store.get(key).unwrap() {
-            Some(data) => {
-                let attributes: FileAttr = deserialize::<SerdeFileAttr>(data.as_slice()).unwrap().0;
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("Lookup: parent({}), name({:?})", parent, name);
+        let name = name.to_os_string();
 
-                trace!("    Attr: {:#?}", attributes);
-                reply.entry(&TTL, &attributes, 0);
-                return;
-            }
-            None => {
-                debug!("    Attributes not found for ino!");
-                reply.error(ENOENT);
-                return;
-            }
-        }
+        self.dispatch(move |core| Self::reply_entry(core.lookup(parent, &name), reply));
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("Get attr: ino({})", ino);
-        let key = KvQuery::FileAttributes(ino).get_key();
-        match self.kv_store.get(key).unwrap() {
-            Some(data) => {
-                let attributes: FileAttr = deserialize::<SerdeFileAttr>(data.as_slice()).unwrap().0;
 
-                debug!("    Found attr");
-                trace!("        {:#?}", attributes);
-
-                reply.attr(&TTL, &attributes);
-                return;
-            }
-            None => {
-                debug!("    Not found: ENOENT");
-
-                reply.error(ENOENT);
-                return;
-            }
-        }
+        self.dispatch(move |core| Self::reply_attr(core.getattr(ino), reply));
     }
 
     fn setattr(
@@ -287,62 +277,28 @@ where
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let key = KvQuery::FileAttributes(ino).get_key();
-
-        // Get attributes
-        let mut attributes = match self.kv_store.get(key.clone()).unwrap() {
-            Some(data) => deserialize::<SerdeFileAttr>(data.as_slice()).unwrap().0,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+        // TODO: Handle fh and bkuptime
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime: atime.map(Timestamp::from),
+            mtime: mtime.map(Timestamp::from),
+            crtime: crtime.map(Timestamp::from),
+            chgtime: chgtime.map(Timestamp::from),
+            flags,
         };
 
-        // Patch attributes
-        if let Some(value) = mode {
-            attributes.perm = value as u16;
-        }
-        if let Some(value) = uid {
-            attributes.uid = value;
-        }
-        if let Some(value) = gid {
-            attributes.gid = value;
-        }
-        if let Some(value) = size {
-            attributes.size = value;
-        }
-        if let Some(value) = atime {
-            attributes.atime = value;
-        }
-        if let Some(value) = mtime {
-            attributes.mtime = value;
-        }
-        // TODO: Handle fh
-        if let Some(value) = crtime {
-            attributes.crtime = value;
-        }
-        if let Some(value) = chgtime {
-            attributes.mtime = value;
-        }
-        // TODO: Handle bkuptime
-        if let Some(value) = flags {
-            attributes.flags = value;
-        }
-
-        // Set attributes
-        self.kv_store
-            .set(key, serialize(&SerdeFileAttr(attributes)).unwrap())
-            .unwrap();
-
-        reply.attr(&TTL, &attributes)
+        self.dispatch(move |core| Self::reply_attr(core.setattr(ino, changes), reply));
     }
 
-    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        self.remove_file(req, parent, name, reply);
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove_file(parent, name, reply);
     }
 
-    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        self.remove_file(req, parent, name, reply);
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.remove_file(parent, name, reply);
     }
 
     fn mknod(
@@ -351,57 +307,185 @@ where
         parent: u64,
         name: &OsStr,
         mode: u32,
-        rdev: u32,
+        _rdev: u32,
         reply: ReplyEntry,
     ) {
-        self.create_file(
-            FileType::RegularFile,
-            req,
-            parent,
-            name,
-            mode,
-            Some(rdev),
-            reply,
-        );
+        self.create_file(FileKind::RegularFile, req, parent, name, mode, reply);
     }
 
     fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
-        self.create_file(FileType::Directory, req, parent, name, mode, None, reply);
+        self.create_file(FileKind::Directory, req, parent, name, mode, reply);
     }
 
-    fn readdir(
+    fn read(
         &mut self,
         _req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        size: u32,
+        reply: ReplyData,
     ) {
-        debug!("Read dir: ino({}), offset({})", ino, offset);
-        let key = KvQuery::InodeChildren(ino).get_key();
-        let mut data: Vec<u8> = Vec::new();
-        let mut children = self.kv_store.get(key).unwrap().map_or(vec![], |bytes| {
-            data = bytes;
-            deserialize::<Vec<(u64, SerdeFileType, &str)>>(&data).unwrap()
+        debug!("Read: ino({}), offset({}), size({})", ino, offset, size);
+
+        self.dispatch(move |core| match core.read(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
         });
+    }
 
-        // TODO: Inserting these records may cause vector to realocate which
-        // could be inefficient
-        children.insert(0, (ino, SerdeFileType(FileType::Directory), ".."));
-        children.insert(0, (ino, SerdeFileType(FileType::Directory), "."));
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        debug!("Write: ino({}), offset({}), size({})", ino, offset, data.len());
+        let data = data.to_vec();
 
-        for (index, (ino, SerdeFileType(file_type), filename)) in
-            &mut children.split_off(offset as usize).iter().enumerate()
-        {
-            trace!(
-                "    {:?}",
-                (*ino, index as i64 + offset + 1, *file_type, filename)
+        self.dispatch(move |core| match core.write(ino, offset, &data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    /// Close a file, so that the next write to it starts a new session and
+    /// keeps the content from before it as a version
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        debug!("Release: ino({})", ino);
+
+        self.dispatch(move |core| {
+            core.release(ino);
+            reply.ok();
+        });
+    }
+
+    /// Report the space used by the filesystem
+    ///
+    /// Used blocks are the physical bytes stored after compression. The
+    /// logical size of the files is reported by `polyfs usage`, because
+    /// `statfs` has nowhere to put it.
+    ///
+    /// Inside a project directory with quota limits, the limits are reported
+    /// as the size of the filesystem and the project's logical usage as the
+    /// space used, so that `df` shows how much of the quota is left.
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        debug!("Statfs: ino({})", ino);
+
+        self.dispatch(move |core| {
+            let usage = core.usage();
+            debug!("    Usage: {:?}", usage);
+            let (mut blocks, mut free_blocks) =
+                (usage.physical_bytes.div_ceil(BLOCK_SIZE) + FREE_BLOCKS, FREE_BLOCKS);
+            let (mut files, mut free_files) = (usage.files, FREE_BLOCKS);
+
+            match core.project_quota(ino) {
+                Ok(Some((quota, limits))) => {
+                    debug!("    Project quota: {:?} of {:?}", quota, limits);
+                    if let Some(limit) = limits.bytes {
+                        blocks = limit / BLOCK_SIZE;
+                        free_blocks = limit.saturating_sub(quota.bytes) / BLOCK_SIZE;
+                    }
+                    if let Some(limit) = limits.inodes {
+                        files = limit;
+                        free_files = limit.saturating_sub(quota.inodes);
+                    }
+                }
+                Ok(None) => (),
+                Err(errno) => return reply.error(errno),
+            }
+
+            reply.statfs(
+                blocks,
+                free_blocks,
+                free_blocks,
+                files,
+                free_files,
+                BLOCK_SIZE as u32,
+                255,
+                BLOCK_SIZE as u32,
             );
-            if reply.add(*ino, index as i64 + offset + 1, *file_type, filename) {
-                break;
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("Set xattr: ino({}), name({:?})", ino, name);
+
+        let (name, value) = (name.to_os_string(), value.to_vec());
+        self.dispatch(move |core| Self::reply_empty(core.setxattr(ino, &name, &value, flags), reply));
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("Get xattr: ino({}), name({:?}), size({})", ino, name, size);
+
+        let name = name.to_os_string();
+        self.dispatch(move |core| Self::reply_xattr(core.getxattr(ino, &name), size, reply));
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("List xattr: ino({}), size({})", ino, size);
+
+        self.dispatch(move |core| Self::reply_xattr(core.listxattr(ino), size, reply));
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("Remove xattr: ino({}), name({:?})", ino, name);
+
+        let name = name.to_os_string();
+        self.dispatch(move |core| Self::reply_empty(core.removexattr(ino, &name), reply));
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!("Read dir: ino({}), offset({})", ino, offset);
+
+        self.dispatch(move |core| {
+            let children = match core.readdir(ino) {
+                Ok(children) => children,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            };
+
+            for (index, (ino, file_type, filename)) in
+                children.into_iter().enumerate().skip(offset as usize)
+            {
+                trace!("    {:?}", (ino, index as i64 + 1, file_type, &filename));
+                if reply.add(ino, index as i64 + 1, file_type.into(), filename) {
+                    break;
+                }
             }
-        }
-        debug!("    Done");
-        reply.ok();
+            debug!("    Done");
+            reply.ok();
+        });
     }
 }
@@ -0,0 +1,326 @@
+//! Content-addressed storage of file chunks
+//!
+//! Chunks are stored once under the SHA-256 hash of their uncompressed content,
+//! no matter how many files contain them. Everything about chunks is kept in
+//! the data store:
+//!
+//! | Key                        | Value                                  |
+//! | -------------------------- | -------------------------------------- |
+//! | `FileChunk(ino, index)`    | hash of the chunk at `index` in `ino`  |
+//! | `Chunk(hash)`              | the chunk, encoded by `encode_chunk`   |
+//! | `ChunkRefs(hash)`          | `ChunkRefs` record                     |
+//!
+//! Snapshots and file versions also refer to chunks, and hold references to
+//! them like files do.
+//!
+//! A chunk's reference count is updated in the same batch as the `FileChunk`
+//! keys that refer to it. When the count drops to zero the `ChunkRefs` record
+//! is deleted but the chunk itself is left in place, to be deleted by
+//! `collect_garbage`. This way a chunk never disappears from under a write
+//! that is about to refer to it again.
+
+use super::inode::Inode;
+use super::snapshots::{captured_refs, list_snapshots, pair_snapshot_id};
+use super::types::KvQuery;
+use super::usage::Usage;
+use super::versions::Version;
+use crate::app::hex;
+use crate::app::keyvalue::{BatchOp, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+
+/// The SHA-256 hash of the uncompressed content of a chunk
+pub type ChunkHash = [u8; 32];
+
+/// Hash the uncompressed content of a chunk
+pub fn hash_chunk(data: &[u8]) -> ChunkHash {
+    Sha256::digest(data).into()
+}
+
+/// Decode a chunk hash stored as the value of a `FileChunk` key
+pub fn decode_hash(value: &[u8]) -> PolyfsResult<ChunkHash> {
+    Ok(try_to!(value.try_into(), "Could not decode chunk hash"))
+}
+
+/// The references to a stored chunk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRefs {
+    /// The number of `FileChunk` keys referring to the chunk
+    pub count: u64,
+    /// The size of the chunk as it is stored, after compression
+    pub size: u64,
+}
+
+impl ChunkRefs {
+    /// Encode the record to be stored under `KvQuery::ChunkRefs`
+    pub fn encode(&self) -> PolyfsResult<Vec<u8>> {
+        Ok(try_to!(bincode::serialize(self), "Could not serialize chunk references"))
+    }
+
+    /// Decode a record stored under `KvQuery::ChunkRefs`
+    pub fn decode(value: &[u8]) -> PolyfsResult<ChunkRefs> {
+        Ok(try_to!(bincode::deserialize(value), "Could not deserialize chunk references"))
+    }
+}
+
+/// Get the writes that change the reference counts of chunks by the given
+/// amounts, and the resulting change in stored bytes
+///
+/// Counts that drop to zero are deleted, leaving their chunks for
+/// `collect_garbage`.
+pub fn adjust_refs<S: KeyValueStore>(
+    data_store: &S,
+    changes: &BTreeMap<ChunkHash, i64>,
+) -> PolyfsResult<(Vec<BatchOp>, i64)> {
+    let mut ops = vec![];
+    let mut physical = 0;
+
+    for (hash, change) in changes {
+        let key = KvQuery::ChunkRefs(hash).get_key();
+        let stored = match try_to!(data_store.get(key.clone()), "Could not read chunk references") {
+            Some(record) => Some(ChunkRefs::decode(&record)?),
+            None => None,
+        };
+
+        let (count, size) = match stored {
+            Some(refs) => (refs.count as i64, refs.size),
+            None => (0, chunk_size(data_store, hash)?),
+        };
+        let new_count = count + change;
+
+        if count <= 0 && new_count > 0 {
+            physical += size as i64;
+        } else if count > 0 && new_count <= 0 {
+            physical -= size as i64;
+        }
+        ops.push(if new_count > 0 {
+            BatchOp::Set(key, ChunkRefs { count: new_count as u64, size }.encode()?)
+        } else {
+            BatchOp::Delete(key)
+        });
+    }
+
+    Ok((ops, physical))
+}
+
+/// The outcome of a garbage collection
+#[derive(Debug, Default)]
+pub struct GcReport {
+    /// The number of `FileChunk` keys and versions deleted because their inode
+    /// is gone
+    pub orphaned_refs: usize,
+    /// The number of pairs deleted because the snapshot they were captured
+    /// for doesn't exist
+    pub orphaned_snapshot_pairs: usize,
+    /// The number of reference counts that were wrong and have been corrected
+    pub refcounts_fixed: usize,
+    /// The number of unreferenced chunks deleted
+    pub chunks_deleted: usize,
+    /// The stored size of the unreferenced chunks deleted
+    pub bytes_freed: u64,
+    /// The number of referenced chunks that are missing from the data store
+    pub chunks_missing: usize,
+    /// Whether or not this was a dry run that didn't modify the stores
+    pub dry_run: bool,
+}
+
+/// Delete the chunks that no file refers to
+///
+/// Every `FileChunk` key and every version, including those captured by
+/// snapshots, is read to recount the references to each chunk, so that counts
+/// left wrong by an interrupted operation are corrected and keys left behind
+/// by a file whose removal was interrupted are deleted. The caller must hold
+/// the lease of the store ( see `keyvalue::lease` ), so that a mount can't
+/// write chunks and their references while they are counted.
+pub fn collect_garbage<S: KeyValueStore>(
+    kv_store: &S,
+    data_store: &S,
+    dry_run: bool,
+) -> PolyfsResult<GcReport> {
+    let mut report = GcReport {
+        dry_run,
+        ..GcReport::default()
+    };
+    let mut ops = vec![];
+
+    // Count the references held by inodes that still exist
+    let mut inodes: HashMap<u64, bool> = HashMap::new();
+    let mut counts: BTreeMap<ChunkHash, u64> = BTreeMap::new();
+    let file_chunks = try_to!(
+        data_store.scan_prefix(KvQuery::FileChunk(0, 0).get_type_prefix()),
+        "Could not read file chunk keys"
+    );
+    for (key, value) in file_chunks {
+        if inode_exists(kv_store, &mut inodes, &key)? {
+            *counts.entry(decode_hash(&value)?).or_insert(0) += 1;
+        } else {
+            ops.push(BatchOp::Delete(key));
+            report.orphaned_refs += 1;
+        }
+    }
+
+    // Count the references held by versions of files that still exist
+    let versions = try_to!(
+        data_store.scan_prefix(KvQuery::Version(0, 0).get_type_prefix()),
+        "Could not read versions"
+    );
+    for (key, value) in versions {
+        if inode_exists(kv_store, &mut inodes, &key)? {
+            for (_, hash) in Version::decode(&value)?.chunks {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+        } else {
+            ops.push(BatchOp::Delete(key));
+            report.orphaned_refs += 1;
+        }
+    }
+
+    // Count the references held by snapshots, deleting the pairs left behind
+    // by snapshots that were interrupted while being created or deleted
+    let snapshots: HashSet<u64> = list_snapshots(kv_store)?.iter().map(|s| s.id).collect();
+    let pair_prefix = KvQuery::SnapshotPair(0, &[]).get_type_prefix();
+    let mut metadata_ops = vec![];
+    for (key, value) in try_to!(data_store.scan_prefix(pair_prefix.clone()), "Could not read snapshots") {
+        if !snapshots.contains(&pair_snapshot_id(&key)?) {
+            ops.push(BatchOp::Delete(key));
+            report.orphaned_snapshot_pairs += 1;
+        } else {
+            for hash in captured_refs(&key[9..], &value)? {
+                *counts.entry(hash).or_insert(0) += 1;
+            }
+        }
+    }
+    if !std::ptr::eq(kv_store, data_store) {
+        for (key, _) in try_to!(kv_store.scan_prefix(pair_prefix), "Could not read snapshots") {
+            if !snapshots.contains(&pair_snapshot_id(&key)?) {
+                metadata_ops.push(BatchOp::Delete(key));
+                report.orphaned_snapshot_pairs += 1;
+            }
+        }
+    }
+
+    let mut refs: BTreeMap<ChunkHash, ChunkRefs> = BTreeMap::new();
+    let stored_refs = try_to!(
+        data_store.scan_prefix(KvQuery::ChunkRefs(&[]).get_type_prefix()),
+        "Could not read chunk reference counts"
+    );
+    for (key, value) in stored_refs {
+        refs.insert(decode_hash(&key[1..])?, ChunkRefs::decode(&value)?);
+    }
+
+    // Compare the counts with every stored chunk
+    let chunk_prefix = KvQuery::Chunk(&[]).get_type_prefix();
+    let mut physical = 0;
+    for (key, value) in try_to!(data_store.scan_prefix(chunk_prefix), "Could not read chunks") {
+        let hash = decode_hash(&key[1..])?;
+        let stored = refs.remove(&hash);
+
+        match counts.remove(&hash) {
+            Some(count) => {
+                let size = match stored {
+                    Some(stored) => stored.size,
+                    None => value.len() as u64,
+                };
+                let expected = ChunkRefs { count, size };
+                if stored != Some(expected) {
+                    ops.push(BatchOp::Set(
+                        KvQuery::ChunkRefs(&hash).get_key(),
+                        expected.encode()?,
+                    ));
+                    report.refcounts_fixed += 1;
+                }
+                physical += size;
+            }
+            None => {
+                report.bytes_freed += value.len() as u64;
+                report.chunks_deleted += 1;
+                ops.push(BatchOp::Delete(key));
+                if stored.is_some() {
+                    ops.push(BatchOp::Delete(KvQuery::ChunkRefs(&hash).get_key()));
+                    report.refcounts_fixed += 1;
+                }
+            }
+        }
+    }
+
+    // Anything left refers to chunks that aren't stored
+    for hash in counts.keys() {
+        log::error!("Chunk {} is referred to but missing", hex::encode(hash));
+        report.chunks_missing += 1;
+    }
+    for hash in refs.keys() {
+        if !counts.contains_key(hash) {
+            ops.push(BatchOp::Delete(KvQuery::ChunkRefs(hash).get_key()));
+            report.refcounts_fixed += 1;
+        }
+    }
+
+    if !dry_run {
+        try_to!(data_store.batch(ops), "Could not write garbage collection changes");
+        try_to!(kv_store.batch(metadata_ops), "Could not delete snapshot pairs");
+
+        if let Some(mut usage) = Usage::load(kv_store)? {
+            if usage.physical_bytes != physical {
+                usage.physical_bytes = physical;
+                try_to!(
+                    kv_store.set(KvQuery::Usage.get_key(), usage.encode()?),
+                    "Could not write usage"
+                );
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Check whether the inode whose ino follows the prefix of `key` exists,
+/// remembering the answer in `inodes`
+fn inode_exists<S: KeyValueStore>(
+    kv_store: &S,
+    inodes: &mut HashMap<u64, bool>,
+    key: &[u8],
+) -> PolyfsResult<bool> {
+    let ino = u64::from_be_bytes(try_to!(
+        key.get(1..9).unwrap_or_default().try_into(),
+        "Could not decode ino"
+    ));
+
+    if let Some(exists) = inodes.get(&ino) {
+        return Ok(*exists);
+    }
+
+    let record = try_to!(
+        kv_store.get(KvQuery::FileAttributes(ino).get_key()),
+        "Could not read inode"
+    );
+    // A record that can't be decoded may still belong to a file that fsck can
+    // repair, so its chunks are kept
+    let exists = match record {
+        Some(record) => {
+            if let Err(e) = Inode::decode(&record) {
+                log::warn!("Keeping the chunks of inode {}, which could not be decoded: {}", ino, e);
+            }
+            true
+        }
+        None => false,
+    };
+    inodes.insert(ino, exists);
+
+    Ok(exists)
+}
+
+/// Get the stored size of a chunk
+fn chunk_size<S: KeyValueStore>(data_store: &S, hash: &ChunkHash) -> PolyfsResult<u64> {
+    match try_to!(data_store.get(KvQuery::Chunk(hash).get_key()), "Could not read chunk") {
+        Some(value) => Ok(value.len() as u64),
+        None => Err(PolyfsError {
+            message: format!("Chunk {} is missing", hex::encode(hash)),
+            cause: None,
+        }),
+    }
+}
+
@@ -0,0 +1,142 @@
+//! Compression of file chunks
+//!
+//! Every stored chunk starts with a tag for the codec that compressed the rest
+//! of it, so chunks can be read no matter which codec was configured when they
+//! were written. Chunks that don't get any smaller when compressed are stored
+//! uncompressed.
+
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+
+/// A compression algorithm for file chunks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Store chunks uncompressed
+    #[default]
+    None,
+    /// LZ4: very fast, with a modest compression ratio
+    Lz4,
+    /// Zstandard: slower, with a better compression ratio
+    Zstd,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Codec> {
+        match tag {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// File chunk compression configuration
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// The codec to compress newly written chunks with
+    #[serde(default)]
+    pub codec: Codec,
+    /// The compression level for zstd. `0` uses zstd's default level.
+    #[serde(default)]
+    pub level: i32,
+}
+
+/// Encode a chunk for storage, compressing it with the configured codec
+pub fn encode_chunk(config: &CompressionConfig, data: &[u8]) -> Vec<u8> {
+    let compressed = match config.codec {
+        Codec::None => None,
+        Codec::Lz4 => Some(lz4_flex::compress_prepend_size(data)),
+        Codec::Zstd => match zstd::bulk::compress(data, config.level) {
+            Ok(compressed) => Some(compressed),
+            Err(e) => {
+                log::warn!("Could not compress chunk, storing it uncompressed: {}", e);
+                None
+            }
+        },
+    };
+
+    match compressed {
+        Some(compressed) if compressed.len() < data.len() => {
+            let mut value = Vec::with_capacity(compressed.len() + 1);
+            value.push(config.codec.tag());
+            value.extend(compressed);
+            value
+        }
+        _ => {
+            let mut value = Vec::with_capacity(data.len() + 1);
+            value.push(Codec::None.tag());
+            value.extend_from_slice(data);
+            value
+        }
+    }
+}
+
+/// Decode a stored chunk
+pub fn decode_chunk(value: &[u8]) -> PolyfsResult<Vec<u8>> {
+    let (tag, payload) = match value.split_first() {
+        Some(split) => split,
+        None => {
+            return Err(PolyfsError {
+                message: String::from("Stored chunk is empty"),
+                cause: None,
+            })
+        }
+    };
+
+    match Codec::from_tag(*tag) {
+        Some(Codec::None) => Ok(payload.to_vec()),
+        Some(Codec::Lz4) => Ok(try_to!(
+            lz4_flex::decompress_size_prepended(payload),
+            "Could not decompress lz4 chunk"
+        )),
+        Some(Codec::Zstd) => Ok(try_to!(
+            zstd::decode_all(payload),
+            "Could not decompress zstd chunk"
+        )),
+        None => Err(PolyfsError {
+            message: format!("Unknown chunk codec {}", tag),
+            cause: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn compress_and_fall_back() -> TestResult {
+        let text = b"{\"level\":\"info\",\"message\":\"hello\"}\n".repeat(100);
+        let noise: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+
+        for codec in &[Codec::None, Codec::Lz4, Codec::Zstd] {
+            let config = CompressionConfig { codec: *codec, level: 0 };
+
+            let value = encode_chunk(&config, &text);
+            assert_eq!(value[0], codec.tag());
+            assert_eq!(decode_chunk(&value)?, text);
+
+            let value = encode_chunk(&config, &noise);
+            assert_eq!(value[0], Codec::None.tag());
+            assert_eq!(decode_chunk(&value)?, noise);
+        }
+
+        assert!(decode_chunk(&[9, 1, 2]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+//! Checking the metadata of the filesystem for consistency and repairing it
+//!
+//! Every file is recorded in three places that are expected to agree: its
+//! `FileAttributes`, the `Files` entry naming it in its directory and the
+//! `InodeChildren` list of that directory. A crash between writes to different
+//! stores, or a directory removed while it still had files in it, can leave
+//! them disagreeing. `check_filesystem` finds where they do:
+//!
+//! * `Files` entries in a directory that doesn't exist, or naming an inode
+//!   that doesn't exist, are deleted
+//! * `InodeChildren` lists are rebuilt from the `Files` entries
+//! * trash entries for inodes that don't exist are deleted
+//! * the `nlink` of every inode is set to the number of entries naming it
+//! * inodes that can't be reached from the root directory or the trash are
+//!   linked into `/lost+found` as `#<ino>`
+//!
+//! File contents and chunk reference counts are checked by `collect_garbage`
+//! instead.
+
+use super::inode::{FileKind, Inode, Timestamp};
+use super::trash::TrashEntry;
+use super::types::{unescape_bytes, KvQuery};
+use crate::app::keyvalue::{BatchOp, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fmt;
+
+/// The directory that unreachable files are linked into
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// An inconsistency found by `check_filesystem`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The root directory doesn't exist, so unreachable files can't be linked
+    /// into `/lost+found`
+    MissingRoot,
+    /// An inode record can't be decoded. Corrupt inodes are left alone.
+    CorruptInode(u64),
+    /// A `Files` entry is in a directory that doesn't exist or isn't a
+    /// directory
+    EntryWithoutDirectory {
+        /// The ino the entry is under
+        parent: u64,
+        /// The name of the entry
+        name: String,
+        /// The ino the entry names
+        ino: u64,
+    },
+    /// A `Files` entry names an inode that doesn't exist
+    DanglingEntry {
+        /// The directory the entry is in
+        parent: u64,
+        /// The name of the entry
+        name: String,
+        /// The ino the entry names
+        ino: u64,
+    },
+    /// The `InodeChildren` list of a directory doesn't match its `Files`
+    /// entries
+    ChildrenMismatch(u64),
+    /// An `InodeChildren` list belongs to an inode that doesn't exist or isn't
+    /// a directory
+    StaleChildren(u64),
+    /// A trash entry is for an inode that doesn't exist
+    DanglingTrashEntry(u64),
+    /// An inode can't be reached from the root directory or the trash
+    Unreachable(u64),
+    /// The `nlink` of an inode doesn't match the number of entries naming it
+    WrongLinkCount {
+        /// The inode
+        ino: u64,
+        /// The stored link count
+        nlink: u32,
+        /// The number of entries naming the inode
+        links: u32,
+    },
+}
+
+impl Problem {
+    /// Whether `check_filesystem` repairs the problem
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Problem::MissingRoot | Problem::CorruptInode(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingRoot => write!(f, "The root directory is missing"),
+            Problem::CorruptInode(ino) => write!(f, "ino({}) can't be decoded", ino),
+            Problem::EntryWithoutDirectory { parent, name, ino } => write!(
+                f,
+                "Entry {:?} for ino({}) is in ino({}), which isn't a directory",
+                name, ino, parent
+            ),
+            Problem::DanglingEntry { parent, name, ino } => write!(
+                f,
+                "Entry {:?} in directory ino({}) is for ino({}), which doesn't exist",
+                name, parent, ino
+            ),
+            Problem::ChildrenMismatch(ino) => {
+                write!(f, "The children of directory ino({}) don't match its entries", ino)
+            }
+            Problem::StaleChildren(ino) => {
+                write!(f, "ino({}) has children but isn't a directory", ino)
+            }
+            Problem::DanglingTrashEntry(ino) => {
+                write!(f, "The trash has an entry for ino({}), which doesn't exist", ino)
+            }
+            Problem::Unreachable(ino) => write!(f, "ino({}) can't be reached from the root", ino),
+            Problem::WrongLinkCount { ino, nlink, links } => write!(
+                f,
+                "ino({}) has a link count of {} but {} entries",
+                ino, nlink, links
+            ),
+        }
+    }
+}
+
+/// The outcome of a check
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// The problems found, in the order they were found
+    pub problems: Vec<Problem>,
+    /// The number of unreachable directory trees linked into `/lost+found`
+    pub relinked: usize,
+    /// Whether the problems were repaired
+    pub repaired: bool,
+}
+
+/// A `Files` entry
+struct Entry {
+    name: Vec<u8>,
+    ino: u64,
+}
+
+/// Check the metadata of the filesystem, repairing the problems found if
+/// `repair` is set
+///
+/// The report lists the same problems whether or not they are repaired. When
+/// `/lost+found` has to be created, the usage totals are deleted to be counted
+/// again the next time the filesystem is mounted. The caller must hold the
+/// lease of the store ( see `keyvalue::lease` ), so that no mount changes the
+/// metadata while it is checked.
+pub fn check_filesystem<S: KeyValueStore>(kv_store: &S, repair: bool) -> PolyfsResult<FsckReport> {
+    let mut report = FsckReport::default();
+    let mut ops = vec![];
+
+    let mut inodes: BTreeMap<u64, Inode> = BTreeMap::new();
+    let mut corrupt: HashSet<u64> = HashSet::new();
+    let records = try_to!(
+        kv_store.scan_prefix(KvQuery::FileAttributes(0).get_type_prefix()),
+        "Could not read inodes"
+    );
+    for (key, record) in records {
+        let ino = key_ino(&key)?;
+        match Inode::decode(&record) {
+            Ok(inode) => {
+                inodes.insert(ino, inode);
+            }
+            Err(error) => {
+                log::warn!("{}", error);
+                report.problems.push(Problem::CorruptInode(ino));
+                corrupt.insert(ino);
+            }
+        }
+    }
+    let is_directory = |ino: u64| inodes.get(&ino).map(|inode| inode.kind) == Some(FileKind::Directory);
+
+    if !inodes.is_empty() && !inodes.contains_key(&1) && !corrupt.contains(&1) {
+        report.problems.push(Problem::MissingRoot);
+    }
+
+    // Keep the entries that are in a directory and name an inode
+    let mut entries: BTreeMap<u64, Vec<Entry>> = BTreeMap::new();
+    let files = try_to!(
+        kv_store.scan_prefix(KvQuery::Files(0, OsStr::new("")).get_type_prefix()),
+        "Could not read directory entries"
+    );
+    for (key, value) in files {
+        let parent = key_ino(&key)?;
+        let name = match unescape_bytes(&key[9..]) {
+            Some((name, _)) => name,
+            None => {
+                return Err(PolyfsError {
+                    message: format!("Could not decode the name of an entry in ino({})", parent),
+                    cause: None,
+                })
+            }
+        };
+        let ino = u64::from_be_bytes(try_to!(value.as_slice().try_into(), "Could not decode entry ino"));
+        let display_name = String::from_utf8_lossy(&name).into_owned();
+
+        if corrupt.contains(&parent) {
+            continue;
+        } else if !is_directory(parent) {
+            report.problems.push(Problem::EntryWithoutDirectory { parent, name: display_name, ino });
+            ops.push(BatchOp::Delete(key));
+        } else if !inodes.contains_key(&ino) && !corrupt.contains(&ino) {
+            report.problems.push(Problem::DanglingEntry { parent, name: display_name, ino });
+            ops.push(BatchOp::Delete(key));
+        } else {
+            entries.entry(parent).or_default().push(Entry { name, ino });
+        }
+    }
+
+    // Compare the children of every directory with its entries
+    let mut children: BTreeMap<u64, Vec<(u64, FileKind, String)>> = BTreeMap::new();
+    let stored_children = try_to!(
+        kv_store.scan_prefix(KvQuery::InodeChildren(0).get_type_prefix()),
+        "Could not read directories"
+    );
+    for (key, value) in stored_children {
+        let ino = key_ino(&key)?;
+        if corrupt.contains(&ino) {
+            continue;
+        } else if !is_directory(ino) {
+            report.problems.push(Problem::StaleChildren(ino));
+            ops.push(BatchOp::Delete(key));
+        } else {
+            let list: Option<Vec<(u64, FileKind, String)>> = bincode::deserialize(&value).ok();
+            children.insert(ino, list.unwrap_or_default());
+        }
+    }
+    let mut changed_children: HashSet<u64> = HashSet::new();
+    for (ino, inode) in &inodes {
+        if inode.kind != FileKind::Directory {
+            continue;
+        }
+        let stored = children.remove(ino).unwrap_or_default();
+        let expected = expected_children(entries.get(ino), &inodes, &stored);
+        if !same_children(&stored, &expected) {
+            report.problems.push(Problem::ChildrenMismatch(*ino));
+            changed_children.insert(*ino);
+        }
+        children.insert(*ino, expected);
+    }
+
+    // Trashed files aren't in any directory, but are held by the trash
+    let mut trashed = vec![];
+    let trash = try_to!(
+        kv_store.scan_prefix(KvQuery::Trash(0).get_type_prefix()),
+        "Could not read trash"
+    );
+    for (key, value) in trash {
+        let entry = TrashEntry::decode(&value)?;
+        if inodes.contains_key(&entry.ino) || corrupt.contains(&entry.ino) {
+            trashed.push(entry.ino);
+        } else {
+            report.problems.push(Problem::DanglingTrashEntry(entry.ino));
+            ops.push(BatchOp::Delete(key));
+        }
+    }
+
+    // Find the inodes that can't be reached from the root or the trash
+    let mut reachable: HashSet<u64> = HashSet::new();
+    let mut roots = trashed.clone();
+    roots.push(1);
+    mark_reachable(&entries, roots, &mut reachable);
+    let unreachable: Vec<u64> = inodes.keys().copied().filter(|ino| !reachable.contains(ino)).collect();
+    for ino in &unreachable {
+        report.problems.push(Problem::Unreachable(*ino));
+    }
+
+    // Link the top of every unreachable tree into `/lost+found`, then whatever
+    // is left, which can only be directories in a cycle
+    if !unreachable.is_empty() && inodes.contains_key(&1) {
+        let named: HashSet<u64> = unreachable
+            .iter()
+            .flat_map(|parent| entries.get(parent).into_iter().flatten())
+            .map(|entry| entry.ino)
+            .collect();
+        let (tops, rest): (Vec<u64>, Vec<u64>) =
+            unreachable.iter().partition(|ino| !named.contains(ino));
+
+        let lost_and_found = lost_and_found(&mut inodes, &mut entries, &mut ops)?;
+        if let Some((ino, created)) = lost_and_found {
+            if created {
+                reachable.insert(ino);
+                children.entry(1).or_default().push((ino, FileKind::Directory, LOST_AND_FOUND.to_owned()));
+                children.insert(ino, vec![]);
+                changed_children.insert(1);
+                changed_children.insert(ino);
+            }
+            for orphan in tops.into_iter().chain(rest) {
+                if reachable.contains(&orphan) {
+                    continue;
+                }
+
+                let base = format!("#{}", orphan);
+                let mut name = base.clone();
+                let mut suffix = 0;
+                while entries.get(&ino).into_iter().flatten().any(|entry| entry.name == name.as_bytes()) {
+                    suffix += 1;
+                    name = format!("{}.{}", base, suffix);
+                }
+
+                ops.push(BatchOp::Set(
+                    KvQuery::Files(ino, OsStr::new(&name)).get_key(),
+                    orphan.to_be_bytes().to_vec(),
+                ));
+                let kind = inodes[&orphan].kind;
+                children.entry(ino).or_default().push((orphan, kind, name.clone()));
+                changed_children.insert(ino);
+                entries.entry(ino).or_default().push(Entry { name: name.into_bytes(), ino: orphan });
+                mark_reachable(&entries, vec![orphan], &mut reachable);
+                report.relinked += 1;
+            }
+        }
+    }
+
+    for ino in changed_children {
+        ops.push(BatchOp::Set(
+            KvQuery::InodeChildren(ino).get_key(),
+            try_to!(bincode::serialize(&children[&ino]), "Could not serialize directory"),
+        ));
+    }
+
+    // Count the entries naming every inode, now that orphans are linked
+    let mut links: HashMap<u64, u32> = HashMap::new();
+    for entry in entries.values().flatten() {
+        *links.entry(entry.ino).or_insert(0) += 1;
+    }
+    for ino in trashed.into_iter().chain(std::iter::once(1)) {
+        *links.entry(ino).or_insert(0) += 1;
+    }
+    for inode in inodes.values_mut() {
+        let count = links.get(&inode.ino).copied().unwrap_or(0);
+        if inode.nlink != count {
+            report.problems.push(Problem::WrongLinkCount {
+                ino: inode.ino,
+                nlink: inode.nlink,
+                links: count,
+            });
+            inode.nlink = count;
+            ops.push(BatchOp::Set(KvQuery::FileAttributes(inode.ino).get_key(), inode.encode()?));
+        }
+    }
+
+    if repair && !ops.is_empty() {
+        try_to!(kv_store.batch(ops), "Could not write repairs");
+        report.repaired = true;
+    }
+
+    Ok(report)
+}
+
+/// Decode the ino that follows the prefix of a key
+fn key_ino(key: &[u8]) -> PolyfsResult<u64> {
+    Ok(u64::from_be_bytes(try_to!(
+        key.get(1..9).unwrap_or_default().try_into(),
+        "Could not decode ino"
+    )))
+}
+
+/// Get the children a directory should have for its entries, keeping the
+/// order of the children it has
+fn expected_children(
+    entries: Option<&Vec<Entry>>,
+    inodes: &BTreeMap<u64, Inode>,
+    stored: &[(u64, FileKind, String)],
+) -> Vec<(u64, FileKind, String)> {
+    let mut expected: Vec<(u64, FileKind, String)> = entries
+        .into_iter()
+        .flatten()
+        .map(|entry| {
+            let name = String::from_utf8_lossy(&entry.name).into_owned();
+            let kind = match inodes.get(&entry.ino) {
+                Some(inode) => inode.kind,
+                // Corrupt inodes keep the kind they were listed with
+                None => stored
+                    .iter()
+                    .find(|(ino, _, child)| *ino == entry.ino && *child == name)
+                    .map_or(FileKind::RegularFile, |(_, kind, _)| *kind),
+            };
+            (entry.ino, kind, name)
+        })
+        .collect();
+
+    let position = |child: &(u64, FileKind, String)| stored.iter().position(|stored| stored == child);
+    expected.sort_by_key(|child| position(child).unwrap_or(usize::MAX));
+    expected
+}
+
+/// Whether two lists of children have the same children, in any order
+fn same_children(a: &[(u64, FileKind, String)], b: &[(u64, FileKind, String)]) -> bool {
+    let mut a: Vec<_> = a.iter().collect();
+    let mut b: Vec<_> = b.iter().collect();
+    a.sort_by(|x, y| (x.0, &x.2).cmp(&(y.0, &y.2)));
+    b.sort_by(|x, y| (x.0, &x.2).cmp(&(y.0, &y.2)));
+    a == b
+}
+
+/// Add every inode that can be reached from `roots` through `entries` to
+/// `reachable`
+fn mark_reachable(entries: &BTreeMap<u64, Vec<Entry>>, roots: Vec<u64>, reachable: &mut HashSet<u64>) {
+    let mut pending: VecDeque<u64> = roots.into_iter().filter(|ino| reachable.insert(*ino)).collect();
+    while let Some(ino) = pending.pop_front() {
+        for entry in entries.get(&ino).into_iter().flatten() {
+            if reachable.insert(entry.ino) {
+                pending.push_back(entry.ino);
+            }
+        }
+    }
+}
+
+/// Find `/lost+found`, or create it, returning its ino and whether it was
+/// created
+///
+/// Returns `None` if the name is taken by a file that isn't a directory.
+fn lost_and_found(
+    inodes: &mut BTreeMap<u64, Inode>,
+    entries: &mut BTreeMap<u64, Vec<Entry>>,
+    ops: &mut Vec<BatchOp>,
+) -> PolyfsResult<Option<(u64, bool)>> {
+    let existing = entries
+        .get(&1)
+        .into_iter()
+        .flatten()
+        .find(|entry| entry.name == LOST_AND_FOUND.as_bytes())
+        .map(|entry| entry.ino);
+    if let Some(ino) = existing {
+        if inodes.get(&ino).map(|inode| inode.kind) == Some(FileKind::Directory) {
+            return Ok(Some((ino, false)));
+        }
+        log::error!("/{} is not a directory", LOST_AND_FOUND);
+        return Ok(None);
+    }
+
+    let ino = loop {
+        let ino = rand::random::<u64>();
+        if ino > 1 && !inodes.contains_key(&ino) {
+            break ino;
+        }
+    };
+    let now = Timestamp::now();
+    let inode = Inode::new(ino, FileKind::Directory, 0o700, 0, 0, now);
+
+    ops.push(BatchOp::Set(KvQuery::FileAttributes(ino).get_key(), inode.encode()?));
+    ops.push(BatchOp::Set(
+        KvQuery::Files(1, OsStr::new(LOST_AND_FOUND)).get_key(),
+        ino.to_be_bytes().to_vec(),
+    ));
+    ops.push(BatchOp::Delete(KvQuery::Usage.get_key()));
+    if let Some(root) = inodes.get_mut(&1) {
+        root.mtime = now;
+        root.ctime = now;
+        ops.push(BatchOp::Set(KvQuery::FileAttributes(1).get_key(), root.encode()?));
+    }
+
+    inodes.insert(ino, inode);
+    entries.entry(1).or_default().push(Entry { name: LOST_AND_FOUND.as_bytes().to_vec(), ino });
+
+    Ok(Some((ino, true)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::app::filesystem::FilesystemConfig;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn relink_orphans_and_fix_entries() -> PolyfsResult<()> {
+        let store = Arc::new(MemoryKvStore::new());
+        let mut config = FilesystemConfig::default();
+        config.trash.enabled = true;
+        let core = FilesystemCore::new(store.clone(), None, config, false);
+        let error = |errno| PolyfsError {
+            message: format!("Filesystem operation failed: {}", errno),
+            cause: None,
+        };
+        core.init().map_err(error)?;
+        let dir = core
+            .create_file(FileKind::Directory, 0, 0, 1, OsStr::new("dir"), 0o755)
+            .map_err(error)?;
+        let file = core
+            .create_file(FileKind::RegularFile, 0, 0, dir.ino, OsStr::new("file"), 0o644)
+            .map_err(error)?;
+        core.create_file(FileKind::RegularFile, 0, 0, 1, OsStr::new("trashed"), 0o644)
+            .map_err(error)?;
+        core.remove_file(1, OsStr::new("trashed")).map_err(error)?;
+        assert_eq!(check_filesystem(&store, false)?.problems, vec![]);
+
+        // Orphan the directory and add an entry for an inode that doesn't exist
+        try_to!(store.delete(KvQuery::Files(1, OsStr::new("dir")).get_key()), "Could not delete");
+        let ghost = KvQuery::Files(dir.ino, OsStr::new("ghost")).get_key();
+        try_to!(store.set(ghost, 42u64.to_be_bytes().to_vec()), "Could not write");
+
+        let report = check_filesystem(&store, false)?;
+        assert!(!report.repaired);
+        assert_eq!(report.relinked, 1);
+        assert_eq!(report.problems.len(), 4);
+        assert!(report.problems.contains(&Problem::ChildrenMismatch(1)));
+        assert!(report.problems.contains(&Problem::Unreachable(file.ino)));
+        assert_eq!(check_filesystem(&store, false)?.problems, report.problems);
+
+        assert!(check_filesystem(&store, true)?.repaired);
+        assert_eq!(check_filesystem(&store, false)?.problems, vec![]);
+
+        let core = FilesystemCore::new(store.clone(), None, FilesystemConfig::default(), false);
+        core.init().map_err(error)?;
+        let path = format!("/{}/#{}/file", LOST_AND_FOUND, dir.ino);
+        assert_eq!(core.resolve(Path::new(&path)).map_err(error)?.ino, file.ino);
+        assert_eq!(core.usage().files, 5);
+
+        Ok(())
+    }
+}
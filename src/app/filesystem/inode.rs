@@ -0,0 +1,300 @@
+//! The inode record stored for every file in the KV store
+//!
+//! Inode records are independent of the `fuse` crate's types so that the
+//! stored format only changes when we change it. Every encoded record starts
+//! with a version tag and ends with a checksum of the rest of the record:
+//!
+//! | Bytes       | Content                                   |
+//! | ----------- | ----------------------------------------- |
+//! | `0`         | record version                            |
+//! | `1..len-4`  | bincode serialized record of that version |
+//! | `len-4..`   | big-endian CRC-32 of the preceding bytes  |
+//!
+//! When fields are added to `Inode`, the current record struct should be kept
+//! around as the previous version so that older records can still be decoded.
+
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The record version written by `Inode::encode`
+pub const INODE_RECORD_VERSION: u8 = 2;
+
+/// The type of a file
+///
+/// The variants are in the same order as `fuse::FileType` so that both
+/// serialize identically.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    /// Named pipe ( `S_IFIFO` )
+    NamedPipe,
+    /// Character device ( `S_IFCHR` )
+    CharDevice,
+    /// Block device ( `S_IFBLK` )
+    BlockDevice,
+    /// Directory ( `S_IFDIR` )
+    Directory,
+    /// Regular file ( `S_IFREG` )
+    RegularFile,
+    /// Symbolic link ( `S_IFLNK` )
+    Symlink,
+    /// Unix domain socket ( `S_IFSOCK` )
+    Socket,
+}
+
+/// A point in time relative to the Unix epoch with nanosecond precision
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Timestamp {
+    /// Seconds since the epoch
+    pub sec: i64,
+    /// Nanoseconds since the last full second
+    pub nsec: u32,
+}
+
+impl Timestamp {
+    /// Get the current time
+    ///
+    /// Clocks set before the Unix epoch are treated as being at the epoch.
+    pub fn now() -> Timestamp {
+        let duration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Timestamp {
+            sec: duration.as_secs() as i64,
+            nsec: duration.subsec_nanos(),
+        }
+    }
+
+    /// Get the number of whole seconds from `earlier` to `self`
+    pub fn seconds_since(&self, earlier: Timestamp) -> i64 {
+        let mut seconds = self.sec - earlier.sec;
+        if self.nsec < earlier.nsec {
+            seconds -= 1;
+        }
+        seconds
+    }
+}
+
+/// The attributes and metadata of a file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Inode {
+    /// Inode number
+    pub ino: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// Size in blocks
+    pub blocks: u64,
+    /// Time of last access
+    pub atime: Timestamp,
+    /// Time of last modification
+    pub mtime: Timestamp,
+    /// Time of last change
+    pub ctime: Timestamp,
+    /// Time of creation
+    pub crtime: Timestamp,
+    /// Kind of file
+    pub kind: FileKind,
+    /// Permissions
+    pub perm: u16,
+    /// Number of hard links
+    pub nlink: u32,
+    /// User id
+    pub uid: u32,
+    /// Group id
+    pub gid: u32,
+    /// Device id for device files
+    pub rdev: u32,
+    /// Flags
+    pub flags: u32,
+    /// Generation number, incremented when an ino is reused
+    pub generation: u64,
+    /// Extended attributes that are small enough to be stored inline
+    pub xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Hash of the file's content, if known
+    pub content_hash: Option<Vec<u8>>,
+    /// The project whose quota the file counts towards, or `0` for none
+    pub project: u32,
+}
+
+/// Inode record version 1, from before files belonged to projects
+#[derive(Deserialize)]
+struct InodeV1 {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: Timestamp,
+    mtime: Timestamp,
+    ctime: Timestamp,
+    crtime: Timestamp,
+    kind: FileKind,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    flags: u32,
+    generation: u64,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+    content_hash: Option<Vec<u8>>,
+}
+
+impl From<InodeV1> for Inode {
+    fn from(v1: InodeV1) -> Inode {
+        Inode {
+            ino: v1.ino,
+            size: v1.size,
+            blocks: v1.blocks,
+            atime: v1.atime,
+            mtime: v1.mtime,
+            ctime: v1.ctime,
+            crtime: v1.crtime,
+            kind: v1.kind,
+            perm: v1.perm,
+            nlink: v1.nlink,
+            uid: v1.uid,
+            gid: v1.gid,
+            rdev: v1.rdev,
+            flags: v1.flags,
+            generation: v1.generation,
+            xattrs: v1.xattrs,
+            content_hash: v1.content_hash,
+            project: 0,
+        }
+    }
+}
+
+impl Inode {
+    /// Create a new inode with empty content and all timestamps set to `time`
+    pub fn new(ino: u64, kind: FileKind, perm: u16, uid: u32, gid: u32, time: Timestamp) -> Inode {
+        Inode {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            generation: 0,
+            xattrs: BTreeMap::new(),
+            content_hash: None,
+            project: 0,
+        }
+    }
+
+    /// Encode the inode as the latest record version
+    pub fn encode(&self) -> PolyfsResult<Vec<u8>> {
+        let mut record = vec![INODE_RECORD_VERSION];
+        record.extend(try_to!(
+            bincode::serialize(self),
+            "Could not serialize inode record"
+        ));
+
+        let checksum = crc32fast::hash(&record);
+        record.extend_from_slice(&checksum.to_be_bytes());
+
+        Ok(record)
+    }
+
+    /// Decode an inode record of any supported version
+    pub fn decode(record: &[u8]) -> PolyfsResult<Inode> {
+        if record.len() < 5 {
+            return Err(PolyfsError {
+                message: format!("Inode record is too short ( {} bytes )", record.len()),
+                cause: None,
+            });
+        }
+
+        let (data, checksum) = record.split_at(record.len() - 4);
+        let checksum = u32::from_be_bytes(checksum.try_into().expect("Checksum is 4 bytes"));
+        if crc32fast::hash(data) != checksum {
+            return Err(PolyfsError {
+                message: String::from("Inode record checksum does not match"),
+                cause: None,
+            });
+        }
+
+        match data[0] {
+            1 => Ok(Inode::from(try_to!(
+                bincode::deserialize::<InodeV1>(&data[1..]),
+                "Could not deserialize inode record"
+            ))),
+            2 => Ok(try_to!(
+                bincode::deserialize::<Inode>(&data[1..]),
+                "Could not deserialize inode record"
+            )),
+            version => Err(PolyfsError {
+                message: format!("Unsupported inode record version {}", version),
+                cause: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn inode() -> Inode {
+        let mut inode = Inode::new(
+            42,
+            FileKind::RegularFile,
+            0o644,
+            1000,
+            1000,
+            Timestamp { sec: 1, nsec: 2 },
+        );
+        inode.xattrs.insert(b"user.test".to_vec(), b"value".to_vec());
+        inode
+    }
+
+    #[test]
+    fn encode_and_decode() -> TestResult {
+        let inode = inode();
+        let record = inode.encode()?;
+
+        assert_eq!(record[0], INODE_RECORD_VERSION);
+        assert_eq!(Inode::decode(&record)?, inode);
+
+        Ok(())
+    }
+
+    #[test]
+    fn decode_version_1() -> TestResult {
+        let inode = inode();
+
+        // Version 1 records are version 2 records without the project
+        let mut record = vec![1];
+        record.extend(bincode::serialize(&inode)?);
+        record.truncate(record.len() - 4);
+        let checksum = crc32fast::hash(&record);
+        record.extend_from_slice(&checksum.to_be_bytes());
+
+        assert_eq!(Inode::decode(&record)?, inode);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_record() -> TestResult {
+        let mut record = inode().encode()?;
+        record[3] ^= 0x01;
+
+        assert!(Inode::decode(&record).is_err());
+        assert!(Inode::decode(&record[..4]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+//! Per-inode locking for concurrent filesystem operations
+
+use std::sync::{Mutex, MutexGuard};
+
+/// The number of locks shared by all inodes
+const STRIPES: usize = 256;
+
+/// A fixed set of locks that inodes are mapped onto
+///
+/// Any number of inodes may share the same lock, so locks for several inodes
+/// must always be taken together with `lock` to avoid deadlocks.
+pub struct InodeLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl Default for InodeLocks {
+    fn default() -> Self {
+        InodeLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl InodeLocks {
+    /// Lock all of the given inodes, blocking until every lock is held
+    ///
+    /// The locks are released when the returned guards are dropped.
+    pub fn lock(&self, inos: &[u64]) -> Vec<MutexGuard<'_, ()>> {
+        // Always take locks in the same order
+        let mut indices: Vec<usize> = inos
+            .iter()
+            .map(|ino| (ino % self.stripes.len() as u64) as usize)
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+
+        indices
+            .into_iter()
+            .map(|index| {
+                self.stripes[index]
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+            })
+            .collect()
+    }
+}
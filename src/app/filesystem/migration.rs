@@ -0,0 +1,1075 @@
+//! Versioning and migration of the filesystem's on-disk layout
+//!
+//! Every store written by PolyFS records the version of the layout that was
+//! used to encode its keys and values under `KvQuery::LayoutVersion`. When the
+//! encoding changes, `LAYOUT_VERSION` is incremented and a `Migration` that
+//! rewrites the previous layout into the new one is appended to `MIGRATIONS`.
+//!
+//! Stores that were created before the layout was versioned have no version
+//! key and are treated as layout version `0`.
+//!
+//! Migrations must encode keys and values themselves instead of using
+//! `KvQuery`, because `KvQuery` always produces the latest layout.
+
+use super::chunks::{hash_chunk, ChunkRefs};
+use super::compression::decode_chunk;
+use super::inode::{FileKind, Inode, Timestamp};
+use super::types::{escape_bytes, KvQuery};
+use crate::app::keyvalue::lease::LEASE_KEY;
+use crate::app::keyvalue::{BatchOp, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+/// The version of the on-disk layout written by this build of PolyFS
+pub const LAYOUT_VERSION: u32 = 8;
+
+/// A key-value pair written by a migration
+pub type Pair = (Vec<u8>, Vec<u8>);
+
+/// Get more pairs to write that depend on every pair a migration rewrote,
+/// given the rewritten pairs
+///
+/// The pairs are read from the store one at a time. If the step was
+/// interrupted they may include pairs returned by an earlier run, which must
+/// be ignored.
+pub type Finish = fn(&mut dyn Iterator<Item = PolyfsResult<Pair>>) -> PolyfsResult<Vec<Pair>>;
+
+/// Prefix of the pairs rewritten by a step that hasn't finished yet, followed
+/// by their new key. `KvQuery` must never use it.
+const STAGED_PREFIX: u8 = 254;
+
+/// The key under which an unfinished step records how far it got. `KvQuery`
+/// must never use it.
+const CURSOR_KEY: &[u8] = &[253];
+
+/// The most operations a migration writes in one batch
+const BATCH_OPS: usize = 1000;
+
+/// The most bytes of values a migration writes in one batch, since chunks can
+/// be large
+const BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// What to do with a key-value pair when migrating it to the next layout
+#[derive(Debug)]
+pub enum Rewrite {
+    /// Leave the pair untouched
+    Keep,
+    /// Replace the pair with a new key and value
+    Replace(Vec<u8>, Vec<u8>),
+    /// Replace the pair with several new pairs
+    Expand(Vec<Pair>),
+    /// Delete the pair
+    Delete,
+}
+
+/// A migration from one layout version to the next
+pub struct Migration {
+    /// The layout version this migration upgrades from. It upgrades to
+    /// `from + 1`.
+    pub from: u32,
+    /// A short description of what the migration changes
+    pub description: &'static str,
+    /// Determine how a key-value pair in the old layout is to be rewritten
+    pub rewrite: fn(&[u8], &[u8]) -> PolyfsResult<Rewrite>,
+    /// Get more pairs to write that depend on every pair the step rewrote.
+    /// They are staged after every pair has been rewritten. This isn't run for
+    /// dry runs.
+    pub finish: Option<Finish>,
+}
+
+/// All layout migrations in the order that they must be applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Record the layout version in the store",
+        rewrite: |_, _| Ok(Rewrite::Keep),
+        finish: None,
+    },
+    Migration {
+        from: 1,
+        description: "Encode inos big-endian and escape filenames in keys",
+        rewrite: big_endian_keys,
+        finish: None,
+    },
+    Migration {
+        from: 2,
+        description: "Store file attributes as versioned inode records",
+        rewrite: inode_records,
+        finish: None,
+    },
+    Migration {
+        from: 3,
+        description: "Tag file chunks with their compression codec",
+        rewrite: tagged_chunks,
+        finish: None,
+    },
+    Migration {
+        from: 4,
+        description: "Store file chunks once under the hash of their content",
+        rewrite: content_addressed_chunks,
+        finish: Some(count_chunk_refs),
+    },
+    Migration {
+        from: 5,
+        description: "Allow snapshots, whose chunks older versions would garbage collect",
+        rewrite: |_, _| Ok(Rewrite::Keep),
+        finish: None,
+    },
+    Migration {
+        from: 6,
+        description: "Keep the space used by each user, group and project for quotas",
+        rewrite: recount_usage,
+        finish: None,
+    },
+    Migration {
+        from: 7,
+        description: "Allow file versions, whose chunks older versions would garbage collect",
+        rewrite: |_, _| Ok(Rewrite::Keep),
+        finish: None,
+    },
+];
+
+fn decode_le_ino(bytes: &[u8]) -> PolyfsResult<u64> {
+    Ok(u64::from_le_bytes(try_to!(
+        bytes.try_into(),
+        "Could not decode little-endian ino"
+    )))
+}
+
+/// Layout 1 -> 2: little-endian inos and raw UTF-8 filenames to big-endian inos
+/// and escaped filenames
+fn big_endian_keys(key: &[u8], value: &[u8]) -> PolyfsResult<Rewrite> {
+    match key {
+        // File attributes and inode children: `prefix, ino`
+        [prefix @ 0, ino @ ..] | [prefix @ 2, ino @ ..] if ino.len() == 8 => {
+            let mut new_key = vec![*prefix];
+            new_key.extend_from_slice(&decode_le_ino(ino)?.to_be_bytes());
+
+            Ok(Rewrite::Replace(new_key, value.to_vec()))
+        }
+        // Files: `prefix, parent ino, filename` => `ino`
+        [prefix @ 1, rest @ ..] if rest.len() >= 8 => {
+            let (parent, filename) = rest.split_at(8);
+
+            let mut new_key = vec![*prefix];
+            new_key.extend_from_slice(&decode_le_ino(parent)?.to_be_bytes());
+            escape_bytes(filename, &mut new_key);
+
+            let new_value = decode_le_ino(value)?.to_be_bytes().to_vec();
+
+            Ok(Rewrite::Replace(new_key, new_value))
+        }
+        _ => Ok(Rewrite::Keep),
+    }
+}
+
+/// File attributes as they were stored before layout 3: bincode serialized
+/// `fuse::FileAttr`
+#[derive(Deserialize)]
+struct LegacyFileAttr {
+    ino: u64,
+    size: u64,
+    blocks: u64,
+    atime: (i64, i32),
+    mtime: (i64, i32),
+    ctime: (i64, i32),
+    crtime: (i64, i32),
+    kind: FileKind,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    flags: u32,
+}
+
+/// Layout 2 -> 3: bincode serialized `fuse::FileAttr` to `Inode` records
+///
+/// `Inode::encode` may be used here because inode records carry their own
+/// version.
+fn inode_records(key: &[u8], value: &[u8]) -> PolyfsResult<Rewrite> {
+    let timestamp = |(sec, nsec): (i64, i32)| Timestamp {
+        sec,
+        nsec: nsec as u32,
+    };
+
+    match key {
+        [0, ino @ ..] if ino.len() == 8 => {
+            let attr: LegacyFileAttr = try_to!(
+                bincode::deserialize(value),
+                "Could not deserialize legacy file attributes"
+            );
+
+            let inode = Inode {
+                ino: attr.ino,
+                size: attr.size,
+                blocks: attr.blocks,
+                atime: timestamp(attr.atime),
+                mtime: timestamp(attr.mtime),
+                ctime: timestamp(attr.ctime),
+                crtime: timestamp(attr.crtime),
+                kind: attr.kind,
+                perm: attr.perm,
+                nlink: attr.nlink,
+                uid: attr.uid,
+                gid: attr.gid,
+                rdev: attr.rdev,
+                flags: attr.flags,
+                generation: 0,
+                xattrs: BTreeMap::new(),
+                content_hash: None,
+                project: 0,
+            };
+
+            Ok(Rewrite::Replace(key.to_vec(), inode.encode()?))
+        }
+        _ => Ok(Rewrite::Keep),
+    }
+}
+
+/// Layout 3 -> 4: raw file chunks to chunks tagged with their codec
+fn tagged_chunks(key: &[u8], value: &[u8]) -> PolyfsResult<Rewrite> {
+    match key {
+        // File chunks: `3, ino, index`
+        [3, rest @ ..] if rest.len() == 16 => {
+            // Tag `0` marks an uncompressed chunk
+            let mut new_value = Vec::with_capacity(value.len() + 1);
+            new_value.push(0);
+            new_value.extend_from_slice(value);
+
+            Ok(Rewrite::Replace(key.to_vec(), new_value))
+        }
+        _ => Ok(Rewrite::Keep),
+    }
+}
+
+/// Layout 4 -> 5: file chunks stored under `3, ino, index` to chunks stored
+/// under `5, hash` and referred to by their hash from `3, ino, index`
+///
+/// The usage totals are deleted so that they are counted again, because
+/// identical chunks are now only stored once.
+fn content_addressed_chunks(key: &[u8], value: &[u8]) -> PolyfsResult<Rewrite> {
+    match key {
+        [3, rest @ ..] if rest.len() == 16 => {
+            let hash = hash_chunk(&decode_chunk(value)?);
+
+            let mut chunk_key = vec![5];
+            chunk_key.extend_from_slice(&hash);
+
+            Ok(Rewrite::Expand(vec![
+                (key.to_vec(), hash.to_vec()),
+                (chunk_key, value.to_vec()),
+            ]))
+        }
+        [4] => Ok(Rewrite::Delete),
+        _ => Ok(Rewrite::Keep),
+    }
+}
+
+/// Layout 6 -> 7: delete the usage totals so that they are counted again,
+/// together with the usage of each user, group and project, when the
+/// filesystem is next mounted
+fn recount_usage(key: &[u8], _value: &[u8]) -> PolyfsResult<Rewrite> {
+    match key {
+        [4] => Ok(Rewrite::Delete),
+        _ => Ok(Rewrite::Keep),
+    }
+}
+
+/// Get the reference count of every chunk, to be stored under `6, hash`
+///
+/// Layout 4 -> 5 rewrites every `3, ino, index` key and writes every `5, hash`
+/// key, so the rewritten pairs hold everything there is to count.
+fn count_chunk_refs(pairs: &mut dyn Iterator<Item = PolyfsResult<Pair>>) -> PolyfsResult<Vec<Pair>> {
+    let mut counts: HashMap<Vec<u8>, u64> = HashMap::new();
+    let mut sizes: HashMap<Vec<u8>, u64> = HashMap::new();
+    for pair in pairs {
+        let (key, value) = pair?;
+        match key.as_slice() {
+            [3, rest @ ..] if rest.len() == 16 => *counts.entry(value).or_insert(0) += 1,
+            [5, hash @ ..] => {
+                sizes.insert(hash.to_vec(), value.len() as u64);
+            }
+            _ => (),
+        }
+    }
+
+    let mut refs = vec![];
+    for (hash, count) in counts {
+        let size = match sizes.get(&hash) {
+            Some(size) => *size,
+            None => continue,
+        };
+
+        let mut refs_key = vec![6];
+        refs_key.extend_from_slice(&hash);
+        refs.push((refs_key, ChunkRefs { count, size }.encode()?));
+    }
+
+    Ok(refs)
+}
+
+/// Progress of a running migration, passed to the progress callback
+#[derive(Debug, Clone)]
+pub struct MigrationProgress {
+    /// The layout version the current step migrates from
+    pub from: u32,
+    /// The number of keys that have been examined in the current step
+    pub keys_processed: usize,
+    /// The total number of keys that will be examined in the current step
+    pub keys_total: usize,
+    /// The number of keys that have been rewritten or deleted in the current
+    /// step
+    pub keys_changed: usize,
+}
+
+/// The outcome of a migration
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// The layout version of the store before migrating
+    pub from: u32,
+    /// The layout version of the store after migrating, or the version it
+    /// would have been migrated to for a dry run
+    pub to: u32,
+    /// The number of key-value pairs that were rewritten
+    pub keys_rewritten: usize,
+    /// The number of key-value pairs that were deleted
+    pub keys_deleted: usize,
+    /// Whether or not this was a dry run that didn't modify the store
+    pub dry_run: bool,
+}
+
+/// Get the layout version of a store
+///
+/// Returns `None` if the store is empty and has not been initialized yet.
+pub fn layout_version<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Option<u32>> {
+    let key = KvQuery::LayoutVersion.get_key();
+
+    match try_to!(kv_store.get(key), "Could not read layout version") {
+        Some(data) => {
+            let bytes = try_to!(
+                data.as_slice().try_into(),
+                "Could not decode layout version"
+            );
+
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => {
+            let keys = try_to!(kv_store.list(), "Could not list keys");
+            if keys.iter().all(|key| key == LEASE_KEY) {
+                Ok(None)
+            } else {
+                Ok(Some(0))
+            }
+        }
+    }
+}
+
+fn set_layout_version<S: KeyValueStore>(kv_store: &S, version: u32) -> PolyfsResult<()> {
+    try_to!(
+        kv_store.set(
+            KvQuery::LayoutVersion.get_key(),
+            version.to_be_bytes().to_vec()
+        ),
+        "Could not write layout version"
+    );
+
+    Ok(())
+}
+
+/// Make sure that a store can be used by this version of PolyFS
+///
+/// An empty store is initialized with the current layout version. A store with
+/// any other layout version than the current one results in an error.
+pub fn check_layout<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<()> {
+    match layout_version(kv_store)? {
+        None => set_layout_version(kv_store, LAYOUT_VERSION),
+        Some(LAYOUT_VERSION) => Ok(()),
+        Some(version) if version < LAYOUT_VERSION => Err(PolyfsError {
+            message: format!(
+                "Store uses layout version {} but version {} is required. Back up the \
+                 store and run `polyfs migrate` to upgrade it",
+                version, LAYOUT_VERSION
+            ),
+            cause: None,
+        }),
+        Some(version) => Err(PolyfsError {
+            message: format!(
+                "Store uses layout version {} which is newer than the latest version \
+                 supported by this version of PolyFS ( {} )",
+                version, LAYOUT_VERSION
+            ),
+            cause: None,
+        }),
+    }
+}
+
+/// Make sure that the metadata store and the separate data store, if one is
+/// configured, can be used together by this version of PolyFS
+///
+/// Both stores are checked with `check_layout`. The filesystem's random id is
+/// written to both stores when they are first used, and stores with different
+/// ids are refused. So is a new, empty data store configured for a filesystem
+/// that already has file contents, or an empty metadata store configured with
+/// a data store in use, since files would read as missing chunks and `gc` would
+/// delete the contents. Stores written before filesystem ids existed are given
+/// one.
+pub fn check_stores<S: KeyValueStore>(kv_store: &S, data_store: Option<&S>) -> PolyfsResult<()> {
+    let data_store = match data_store {
+        Some(data_store) => data_store,
+        None => {
+            check_layout(kv_store)?;
+            if filesystem_id(kv_store)?.is_none() {
+                set_filesystem_id(kv_store, &rand::random::<[u8; 16]>())?;
+            }
+            return Ok(());
+        }
+    };
+
+    let metadata_empty = layout_version(kv_store)?.is_none();
+    let data_empty = layout_version(data_store)?.is_none();
+    if metadata_empty && !data_empty {
+        return Err(PolyfsError {
+            message: String::from(
+                "The metadata store is empty, but the data store holds the contents of \
+                 another filesystem",
+            ),
+            cause: None,
+        });
+    }
+    if data_empty && has_contents(kv_store)? {
+        return Err(PolyfsError {
+            message: String::from(
+                "The data store is empty, but the filesystem already has file contents. \
+                 Configure the data backend the filesystem was created with, or none if it \
+                 was created without one",
+            ),
+            cause: None,
+        });
+    }
+
+    check_layout(kv_store)?;
+    check_layout(data_store)?;
+
+    match (filesystem_id(kv_store)?, filesystem_id(data_store)?) {
+        (Some(id), Some(data_id)) if id != data_id => Err(PolyfsError {
+            message: String::from(
+                "The metadata store and the data store belong to different filesystems",
+            ),
+            cause: None,
+        }),
+        (Some(_), Some(_)) => Ok(()),
+        (Some(id), None) => set_filesystem_id(data_store, &id),
+        (None, Some(id)) => set_filesystem_id(kv_store, &id),
+        (None, None) => {
+            let id = rand::random::<[u8; 16]>();
+            set_filesystem_id(data_store, &id)?;
+            set_filesystem_id(kv_store, &id)
+        }
+    }
+}
+
+fn filesystem_id<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Option<Vec<u8>>> {
+    Ok(try_to!(
+        kv_store.get(KvQuery::FilesystemId.get_key()),
+        "Could not read filesystem id"
+    ))
+}
+
+fn set_filesystem_id<S: KeyValueStore>(kv_store: &S, id: &[u8]) -> PolyfsResult<()> {
+    try_to!(
+        kv_store.set(KvQuery::FilesystemId.get_key(), id.to_vec()),
+        "Could not write filesystem id"
+    );
+
+    Ok(())
+}
+
+/// Whether any file in the metadata store has contents
+fn has_contents<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<bool> {
+    let chunks = try_to!(
+        kv_store.scan_prefix(KvQuery::FileChunk(0, 0).get_type_prefix()),
+        "Could not read file chunks"
+    );
+
+    Ok(!chunks.is_empty())
+}
+
+/// Migrate a store to the current layout version
+///
+/// The store is walked once for every layout version between its current
+/// version and `LAYOUT_VERSION`. Each step rewrites pairs into staged pairs
+/// kept under `STAGED_PREFIX`, and only moves them into place once every pair
+/// has been rewritten, so that keys in the new layout can't clobber keys in the
+/// old layout that haven't been migrated yet. Changes are written in batches of
+/// bounded size and only the keys of the store are held in memory. If
+/// `dry_run` is true the store will not be modified, but the report will
+/// contain the changes that would have been made. Because a dry run can't apply
+/// earlier steps, every step of a dry run examines the unmodified store and
+/// skips pairs it can't rewrite.
+///
+/// Every batch records how far its step got under `CURSOR_KEY`, and the new
+/// layout version is only written with the last batch of a step, so a
+/// migration that fails can be run again and resumes where it stopped. This
+/// relies on the store applying batches atomically, which not every backend
+/// does, so the store should still be backed up before migrating.
+pub fn migrate<S, F>(kv_store: &S, dry_run: bool, progress: F) -> PolyfsResult<MigrationReport>
+where
+    S: KeyValueStore,
+    F: FnMut(&MigrationProgress),
+{
+    migrate_in_batches(kv_store, dry_run, BATCH_OPS, progress)
+}
+
+fn migrate_in_batches<S, F>(
+    kv_store: &S,
+    dry_run: bool,
+    batch_ops: usize,
+    mut progress: F,
+) -> PolyfsResult<MigrationReport>
+where
+    S: KeyValueStore,
+    F: FnMut(&MigrationProgress),
+{
+    let from = match layout_version(kv_store)? {
+        Some(version) => version,
+        None => LAYOUT_VERSION,
+    };
+
+    if from > LAYOUT_VERSION {
+        return Err(PolyfsError {
+            message: format!(
+                "Cannot migrate store with layout version {} that is newer than this version \
+                 of PolyFS supports",
+                from
+            ),
+            cause: None,
+        });
+    }
+
+    let mut report = MigrationReport {
+        from,
+        to: LAYOUT_VERSION,
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut cursor = match dry_run {
+        true => None,
+        false => read_cursor(kv_store)?,
+    };
+
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= from) {
+        log::info!(
+            "Migrating layout version {} to {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        );
+
+        let mut step = Step {
+            kv_store,
+            from: migration.from,
+            dry_run,
+            batch_ops,
+            ops: vec![],
+            bytes: 0,
+        };
+
+        // Resume a step that was interrupted where it stopped
+        let (resume_after, rewritten) = match cursor.take() {
+            Some(Cursor::Rewrite { from, last }) if from == migration.from => {
+                log::info!("Resuming interrupted migration");
+                (Some(last), false)
+            }
+            Some(Cursor::Move { from }) if from == migration.from => {
+                log::info!("Resuming interrupted migration");
+                (None, true)
+            }
+            _ => (None, false),
+        };
+
+        if !rewritten {
+            let mut keys: Vec<Vec<u8>> = try_to!(kv_store.list(), "Could not list keys")
+                .into_iter()
+                .filter(|key| !is_migration_key(key))
+                .collect();
+            keys.sort();
+            if let Some(last) = &resume_after {
+                keys.retain(|key| key > last);
+            }
+
+            let mut state = MigrationProgress {
+                from: migration.from,
+                keys_processed: 0,
+                keys_total: keys.len(),
+                keys_changed: 0,
+            };
+
+            let mut last = resume_after.unwrap_or_default();
+            for key in keys {
+                state.keys_processed += 1;
+
+                let value = match try_to!(kv_store.get(key.clone()), "Could not read value") {
+                    Some(value) => value,
+                    // Deleted since the keys were listed
+                    None => {
+                        progress(&state);
+                        continue;
+                    }
+                };
+
+                let rewrite = match (migration.rewrite)(&key, &value) {
+                    Ok(rewrite) => rewrite,
+                    // Pairs in a layout older than the one this step expects
+                    // can only be seen by a dry run
+                    Err(e) if dry_run && migration.from > from => {
+                        log::debug!("Skipping pair that can't be rewritten in a dry run: {}", e);
+                        Rewrite::Keep
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let changed = !matches!(rewrite, Rewrite::Keep);
+                match rewrite {
+                    Rewrite::Keep => (),
+                    Rewrite::Replace(new_key, new_value) => {
+                        step.stage(new_key, new_value);
+                        report.keys_rewritten += 1;
+                    }
+                    Rewrite::Expand(pairs) => {
+                        for (new_key, new_value) in pairs {
+                            step.stage(new_key, new_value);
+                        }
+                        report.keys_rewritten += 1;
+                    }
+                    Rewrite::Delete => report.keys_deleted += 1,
+                }
+                if changed {
+                    step.ops.push(BatchOp::Delete(key.clone()));
+                    state.keys_changed += 1;
+                }
+
+                progress(&state);
+
+                if step.is_full() {
+                    step.write(Some(&Cursor::Rewrite {
+                        from: migration.from,
+                        last: key.clone(),
+                    }))?;
+                }
+                last = key;
+            }
+
+            if dry_run {
+                continue;
+            }
+            if !step.ops.is_empty() {
+                step.write(Some(&Cursor::Rewrite {
+                    from: migration.from,
+                    last,
+                }))?;
+            }
+
+            if let Some(finish) = migration.finish {
+                for (key, value) in finish(&mut step.staged_pairs()?)? {
+                    step.stage(key, value);
+                    if step.is_full() {
+                        step.write(None)?;
+                    }
+                }
+            }
+            step.write(Some(&Cursor::Move {
+                from: migration.from,
+            }))?;
+        }
+
+        // Move the staged pairs into place, and write the new layout version
+        // with the last of them
+        for staged_key in step.staged_keys()? {
+            let value = match try_to!(kv_store.get(staged_key.clone()), "Could not read value") {
+                Some(value) => value,
+                None => continue,
+            };
+
+            step.bytes += value.len();
+            step.ops.push(BatchOp::Set(staged_key[1..].to_vec(), value));
+            step.ops.push(BatchOp::Delete(staged_key));
+            if step.is_full() {
+                step.write(None)?;
+            }
+        }
+        step.ops.push(BatchOp::Set(
+            KvQuery::LayoutVersion.get_key(),
+            (migration.from + 1).to_be_bytes().to_vec(),
+        ));
+        step.ops.push(BatchOp::Delete(CURSOR_KEY.to_vec()));
+        try_to!(
+            kv_store.batch(step.ops),
+            format!("Could not write layout version {}", migration.from + 1)
+        );
+    }
+
+    Ok(report)
+}
+
+/// How far an interrupted migration step got
+#[derive(Serialize, Deserialize, Debug)]
+enum Cursor {
+    /// Every pair up to and including the key `last` has been rewritten into
+    /// staged pairs
+    Rewrite { from: u32, last: Vec<u8> },
+    /// Every pair has been rewritten, and the staged pairs are being moved
+    /// into place
+    Move { from: u32 },
+}
+
+fn read_cursor<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Option<Cursor>> {
+    match try_to!(kv_store.get(CURSOR_KEY.to_vec()), "Could not read migration cursor") {
+        Some(data) => Ok(Some(try_to!(
+            bincode::deserialize(&data),
+            "Could not deserialize migration cursor"
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Whether a key is used by PolyFS to keep track of the store instead of
+/// holding part of the filesystem, so that no migration must rewrite it
+fn is_migration_key(key: &[u8]) -> bool {
+    key == KvQuery::LayoutVersion.get_key().as_slice()
+        || key == LEASE_KEY
+        || key == CURSOR_KEY
+        || key.first() == Some(&STAGED_PREFIX)
+}
+
+/// The changes of a migration step that haven't been written yet
+struct Step<'a, S> {
+    kv_store: &'a S,
+    from: u32,
+    dry_run: bool,
+    batch_ops: usize,
+    ops: Vec<BatchOp>,
+    bytes: usize,
+}
+
+impl<'a, S: KeyValueStore> Step<'a, S> {
+    /// Add a rewritten pair to be staged
+    fn stage(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let mut staged_key = vec![STAGED_PREFIX];
+        staged_key.extend_from_slice(&key);
+
+        self.bytes += value.len();
+        self.ops.push(BatchOp::Set(staged_key, value));
+    }
+
+    /// Whether the changes should be written before adding more
+    fn is_full(&self) -> bool {
+        self.ops.len() >= self.batch_ops || self.bytes >= BATCH_BYTES
+    }
+
+    /// Write the changes, together with how far the step got if `cursor` is
+    /// given. The changes of a dry run are discarded instead.
+    fn write(&mut self, cursor: Option<&Cursor>) -> PolyfsResult<()> {
+        let mut ops = std::mem::take(&mut self.ops);
+        self.bytes = 0;
+        if self.dry_run {
+            return Ok(());
+        }
+
+        if let Some(cursor) = cursor {
+            ops.push(BatchOp::Set(
+                CURSOR_KEY.to_vec(),
+                try_to!(bincode::serialize(cursor), "Could not serialize migration cursor"),
+            ));
+        }
+
+        try_to!(
+            self.kv_store.batch(ops),
+            format!("Could not migrate layout version {}", self.from)
+        );
+
+        Ok(())
+    }
+
+    /// Get the keys of the staged pairs in order
+    fn staged_keys(&self) -> PolyfsResult<Vec<Vec<u8>>> {
+        let mut keys: Vec<Vec<u8>> = try_to!(self.kv_store.list(), "Could not list keys")
+            .into_iter()
+            .filter(|key| key.first() == Some(&STAGED_PREFIX))
+            .collect();
+        keys.sort();
+
+        Ok(keys)
+    }
+
+    /// Read the staged pairs one at a time, with the keys they will be moved to
+    fn staged_pairs(&self) -> PolyfsResult<impl Iterator<Item = PolyfsResult<Pair>> + 'a> {
+        let kv_store = self.kv_store;
+
+        Ok(self
+            .staged_keys()?
+            .into_iter()
+            .filter_map(move |staged_key| match kv_store.get(staged_key.clone()) {
+                Ok(Some(value)) => Some(Ok((staged_key[1..].to_vec(), value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(PolyfsError {
+                    message: String::from("Could not read staged pair"),
+                    cause: Some(Box::new(e)),
+                })),
+            }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+    use crate::app::keyvalue::{KeyValueError, KeyValueResult};
+    use std::ffi::OsStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn le_key(prefix: u8, ino: u64, filename: &[u8]) -> Vec<u8> {
+        let mut key = vec![prefix];
+        key.extend_from_slice(&ino.to_le_bytes());
+        key.extend_from_slice(filename);
+        key
+    }
+
+    fn legacy_attrs(ino: u64) -> Vec<u8> {
+        let time = (1i64, 2i32);
+        bincode::serialize(&(
+            ino,
+            0u64,
+            0u64,
+            time,
+            time,
+            time,
+            time,
+            FileKind::RegularFile,
+            0o644u16,
+            1u32,
+            1000u32,
+            1000u32,
+            0u32,
+            0u32,
+        ))
+        .unwrap()
+    }
+
+    /// A store whose writes start failing after a number of them
+    struct FailingStore {
+        inner: MemoryKvStore,
+        writes_left: AtomicUsize,
+    }
+
+    impl FailingStore {
+        fn write(&self) -> KeyValueResult<()> {
+            match self.writes_left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(KeyValueError::IoError(std::io::Error::other("Injected failure"))),
+            }
+        }
+    }
+
+    impl KeyValueStore for FailingStore {
+        fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+            self.inner.get(key)
+        }
+
+        fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+            self.write()?;
+            self.inner.set(key, value)
+        }
+
+        fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+            self.write()?;
+            self.inner.delete(key)
+        }
+
+        fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+            self.inner.list()
+        }
+
+        fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+            self.write()?;
+            self.inner.batch(ops)
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: Vec<u8>,
+            expected: Option<Vec<u8>>,
+            new: Option<Vec<u8>>,
+        ) -> KeyValueResult<bool> {
+            self.write()?;
+            self.inner.compare_and_swap(key, expected, new)
+        }
+    }
+
+    #[test]
+    fn interrupted_migration_resumes() -> TestResult {
+        // Fail after every possible number of writes, with batches small enough
+        // that every step takes several of them
+        for writes in 0.. {
+            let kv_store = FailingStore {
+                inner: MemoryKvStore::new(),
+                writes_left: AtomicUsize::new(usize::MAX),
+            };
+            let (a, b) = (1u64, 1u64.swap_bytes());
+            for ino in &[a, b, 3] {
+                kv_store.set(le_key(0, *ino, b""), legacy_attrs(*ino))?;
+            }
+            kv_store.set(le_key(1, 1, b"file"), a.to_le_bytes().to_vec())?;
+            for ino in &[a, 3] {
+                let mut chunk_key = vec![3];
+                chunk_key.extend_from_slice(&ino.to_be_bytes());
+                chunk_key.extend_from_slice(&0u64.to_be_bytes());
+                kv_store.set(chunk_key, b"content".to_vec())?;
+            }
+
+            kv_store.writes_left.store(writes, Ordering::SeqCst);
+            let interrupted = migrate_in_batches(&kv_store, false, 2, |_| ()).is_err();
+            if interrupted {
+                assert!(layout_version(&kv_store)? < Some(LAYOUT_VERSION));
+                kv_store.writes_left.store(usize::MAX, Ordering::SeqCst);
+                migrate_in_batches(&kv_store, false, 2, |_| ())?;
+            }
+
+            check_layout(&kv_store)?;
+            for ino in &[a, b, 3] {
+                let record = kv_store
+                    .get(KvQuery::FileAttributes(*ino).get_key())?
+                    .expect("Attributes were lost");
+                assert_eq!(Inode::decode(&record)?.ino, *ino);
+            }
+            assert_eq!(
+                kv_store.get(KvQuery::Files(1, OsStr::new("file")).get_key())?,
+                Some(a.to_be_bytes().to_vec())
+            );
+            let hash = hash_chunk(b"content");
+            assert_eq!(
+                kv_store.get(KvQuery::ChunkRefs(&hash).get_key())?,
+                Some(ChunkRefs { count: 2, size: 8 }.encode()?)
+            );
+            assert!(kv_store.list()?.iter().all(|key| !is_migration_key(key)
+                || key == &KvQuery::LayoutVersion.get_key()));
+
+            if !interrupted {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fresh_store_is_initialized() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        assert_eq!(layout_version(&kv_store)?, None);
+        check_layout(&kv_store)?;
+        assert_eq!(layout_version(&kv_store)?, Some(LAYOUT_VERSION));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stores_of_one_filesystem_are_matched() -> TestResult {
+        let (kv_store, data_store) = (MemoryKvStore::new(), MemoryKvStore::new());
+        check_stores(&kv_store, Some(&data_store))?;
+        assert!(filesystem_id(&kv_store)?.is_some());
+        assert_eq!(filesystem_id(&kv_store)?, filesystem_id(&data_store)?);
+        check_stores(&kv_store, Some(&data_store))?;
+
+        // A data store of another filesystem
+        let (other, other_data) = (MemoryKvStore::new(), MemoryKvStore::new());
+        check_stores(&other, Some(&other_data))?;
+        assert!(check_stores(&kv_store, Some(&other_data)).is_err());
+        assert!(check_stores(&MemoryKvStore::new(), Some(&other_data)).is_err());
+
+        // A new data store for a filesystem that already has contents
+        let kv_store = MemoryKvStore::new();
+        check_stores(&kv_store, None)?;
+        kv_store.set(KvQuery::FileChunk(2, 0).get_key(), vec![0; 32])?;
+        assert!(check_stores(&kv_store, Some(&MemoryKvStore::new())).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unversioned_store_is_rejected() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+        kv_store.set(le_key(0, 1, b""), b"attrs".to_vec())?;
+
+        assert_eq!(layout_version(&kv_store)?, Some(0));
+        assert!(check_layout(&kv_store).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_unversioned_store() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        // Two inos whose little-endian keys are each other's big-endian keys
+        let (a, b) = (1u64, 1u64.swap_bytes());
+        kv_store.set(le_key(0, a, b""), legacy_attrs(a))?;
+        kv_store.set(le_key(0, b, b""), legacy_attrs(b))?;
+        kv_store.set(le_key(1, 1, b"file"), a.to_le_bytes().to_vec())?;
+        kv_store.set(le_key(2, 1, b""), b"children".to_vec())?;
+        let mut chunk_key = vec![3];
+        chunk_key.extend_from_slice(&a.to_be_bytes());
+        chunk_key.extend_from_slice(&0u64.to_be_bytes());
+        kv_store.set(chunk_key.clone(), b"content".to_vec())?;
+
+        // A dry run doesn't change anything
+        let report = migrate(&kv_store, true, |_| ())?;
+        assert_eq!(report.from, 0);
+        assert_eq!(report.to, LAYOUT_VERSION);
+        assert_eq!(layout_version(&kv_store)?, Some(0));
+        assert_eq!(kv_store.get(le_key(1, 1, b"file"))?, Some(a.to_le_bytes().to_vec()));
+
+        let mut last_progress = None;
+        migrate(&kv_store, false, |progress| last_progress = Some(progress.clone()))?;
+        let last_progress = last_progress.expect("Progress was not reported");
+        assert_eq!(last_progress.keys_processed, last_progress.keys_total);
+
+        check_layout(&kv_store)?;
+        for ino in &[a, b] {
+            let record = kv_store
+                .get(KvQuery::FileAttributes(*ino).get_key())?
+                .expect("Attributes were not migrated");
+            let inode = Inode::decode(&record)?;
+            assert_eq!(inode.ino, *ino);
+            assert_eq!(inode.mtime, Timestamp { sec: 1, nsec: 2 });
+            assert_eq!(inode.kind, FileKind::RegularFile);
+        }
+        assert_eq!(
+            kv_store.get(KvQuery::Files(1, OsStr::new("file")).get_key())?,
+            Some(a.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            kv_store.get(KvQuery::InodeChildren(1).get_key())?,
+            Some(b"children".to_vec())
+        );
+        let hash = hash_chunk(b"content");
+        assert_eq!(kv_store.get(chunk_key)?, Some(hash.to_vec()));
+        assert_eq!(
+            kv_store.get(KvQuery::Chunk(&hash).get_key())?,
+            Some(b"\0content".to_vec())
+        );
+        assert_eq!(
+            kv_store.get(KvQuery::ChunkRefs(&hash).get_key())?,
+            Some(ChunkRefs { count: 1, size: 8 }.encode()?)
+        );
+        assert_eq!(kv_store.list()?.len(), 8);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2027 @@
+//! The filesystem operations, independent of how FUSE requests are dispatched
+//!
+//! Every operation returns the `errno` to reply with when it fails. Operations
+//! may be called concurrently from any number of threads: operations that
+//! modify an inode hold that inode's lock while doing so.
+//!
+//! File contents are split into chunks of `CHUNK_SIZE` bytes that are kept in
+//! the data store, which may be a different store than the one holding the
+//! metadata. The two stores can't be written atomically, so chunks are written
+//! before the inode that refers to them and deleted after it. Chunks are
+//! compressed with the configured codec and stored once for every distinct
+//! content ( see the `chunks` module ).
+//!
+//! Files with a versions policy keep their previous content as a version the
+//! first time they are changed after being closed ( see the `versions` module ).
+//! Versions are browsed through virtual nodes that only exist while the
+//! filesystem is mounted.
+
+use super::chunks::{adjust_refs, decode_hash, hash_chunk, ChunkHash, ChunkRefs};
+use super::compression::{decode_chunk, encode_chunk};
+use super::inode::{FileKind, Inode, Timestamp};
+use super::locks::InodeLocks;
+use super::quota::{
+    charge, transfer, usage_op, QuotaChange, QuotaId, QuotaKind, QuotaLimits, QuotaUsage, Quotas,
+};
+use super::trash::{list_trash, TrashEntry};
+use super::types::KvQuery;
+use super::usage::Usage;
+use super::versions::{
+    list_versions, Version, VersionPolicy, VirtualNode, VirtualNodes, VERSIONS_DIR, VERSIONS_XATTR,
+};
+use super::FilesystemConfig;
+use crate::app::hex;
+use crate::app::keyvalue::async_store::{AsyncKeyValueStore, BlockingAdapter};
+use crate::app::keyvalue::{BatchOp, KeyValueStore};
+
+use bincode::{deserialize, serialize};
+use futures::executor::block_on;
+use libc::{
+    c_int, EDQUOT, EEXIST, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, EROFS, XATTR_CREATE,
+    XATTR_REPLACE,
+};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Result of a filesystem operation
+pub type OpResult<T> = Result<T, c_int>;
+
+/// The size of the chunks that file contents are split into
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Log an unexpected error and convert it to `EIO`
+fn eio<E: Display>(error: E) -> c_int {
+    log::error!("{}", error);
+    EIO
+}
+
+/// Changes to make to the attributes of an inode in `setattr`
+#[derive(Default)]
+pub struct AttrChanges {
+    /// New permissions
+    pub mode: Option<u32>,
+    /// New owner
+    pub uid: Option<u32>,
+    /// New group
+    pub gid: Option<u32>,
+    /// New size
+    pub size: Option<u64>,
+    /// New access time
+    pub atime: Option<Timestamp>,
+    /// New modification time
+    pub mtime: Option<Timestamp>,
+    /// New creation time
+    pub crtime: Option<Timestamp>,
+    /// New change time
+    pub chgtime: Option<Timestamp>,
+    /// New flags
+    pub flags: Option<u32>,
+}
+
+/// A change to the chunk at one index of a file
+struct ChunkChange {
+    index: u64,
+    /// The hash of the chunk currently at the index
+    old: Option<ChunkHash>,
+    /// The hash and encoded content of the chunk to put at the index
+    new: Option<(ChunkHash, Vec<u8>)>,
+}
+
+/// The quota changes of an operation, with the space they add reserved from
+/// when they are checked until they are committed
+///
+/// The reservation is released if the operation fails before it commits.
+struct QuotaReservation<'a> {
+    quotas: &'a Mutex<Quotas>,
+    changes: Vec<QuotaChange>,
+    reserved: bool,
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        if self.reserved {
+            self.quotas
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .release(&self.changes);
+        }
+    }
+}
+
+/// The filesystem state shared by all worker threads
+pub struct FilesystemCore<KvStore: KeyValueStore + 'static> {
+    kv_store: Arc<KvStore>,
+    data_store: Option<Arc<KvStore>>,
+    /// The store that file contents are kept in, for reading several chunks at
+    /// once
+    chunk_reader: BlockingAdapter<Arc<KvStore>>,
+    config: FilesystemConfig,
+    read_only: bool,
+    locks: InodeLocks,
+    usage: Mutex<Usage>,
+    quotas: Mutex<Quotas>,
+    chunk_refs: Mutex<()>,
+    /// The files whose content has been kept as a version since they were
+    /// last closed
+    versioned: Mutex<HashSet<u64>>,
+    virtual_nodes: Mutex<VirtualNodes>,
+}
+
+impl<KvStore: KeyValueStore + 'static> FilesystemCore<KvStore> {
+    /// Create the filesystem state
+    ///
+    /// File contents are kept in `data_store`, or in `kv_store` with the
+    /// metadata if it is `None`. A `read_only` filesystem never writes to the
+    /// stores: operations that would modify it fail with `EROFS` and access
+    /// times aren't updated.
+    pub fn new(
+        kv_store: KvStore,
+        data_store: Option<KvStore>,
+        config: FilesystemConfig,
+        read_only: bool,
+    ) -> FilesystemCore<KvStore> {
+        let kv_store = Arc::new(kv_store);
+        let data_store = data_store.map(Arc::new);
+        let chunk_store = data_store.clone().unwrap_or_else(|| kv_store.clone());
+        let threads = config.threads.unwrap_or_else(num_cpus::get);
+
+        FilesystemCore {
+            kv_store,
+            data_store,
+            chunk_reader: BlockingAdapter::new(chunk_store, threads),
+            config,
+            read_only,
+            locks: InodeLocks::default(),
+            usage: Mutex::new(Usage::default()),
+            quotas: Mutex::new(Quotas::default()),
+            chunk_refs: Mutex::new(()),
+            versioned: Mutex::new(HashSet::new()),
+            virtual_nodes: Mutex::new(VirtualNodes::default()),
+        }
+    }
+
+    /// Get the store that file contents are kept in
+    fn data_store(&self) -> &KvStore {
+        self.data_store.as_deref().unwrap_or(&self.kv_store)
+    }
+
+    fn get_inode(&self, ino: u64) -> OpResult<Option<Inode>> {
+        let key = KvQuery::FileAttributes(ino).get_key();
+
+        match self.kv_store.get(key).map_err(eio)? {
+            Some(data) => Ok(Some(Inode::decode(&data).map_err(eio)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_inode_op(inode: &Inode) -> OpResult<BatchOp> {
+        Ok(BatchOp::Set(
+            KvQuery::FileAttributes(inode.ino).get_key(),
+            inode.encode().map_err(eio)?,
+        ))
+    }
+
+    fn set_inode(&self, inode: &Inode) -> OpResult<()> {
+        let key = KvQuery::FileAttributes(inode.ino).get_key();
+        self.kv_store
+            .set(key, inode.encode().map_err(eio)?)
+            .map_err(eio)
+    }
+
+    fn get_children(&self, ino: u64) -> OpResult<Vec<(u64, FileKind, String)>> {
+        let key = KvQuery::InodeChildren(ino).get_key();
+
+        match self.kv_store.get(key).map_err(eio)? {
+            Some(data) => deserialize(&data).map_err(eio),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn set_children_op(ino: u64, children: &[(u64, FileKind, String)]) -> OpResult<BatchOp> {
+        Ok(BatchOp::Set(
+            KvQuery::InodeChildren(ino).get_key(),
+            serialize(children).map_err(eio)?,
+        ))
+    }
+
+    fn get_file(&self, parent: u64, name: &OsStr) -> OpResult<Option<u64>> {
+        let key = KvQuery::Files(parent, name).get_key();
+
+        match self.kv_store.get(key).map_err(eio)? {
+            Some(data) => Ok(Some(u64::from_be_bytes(
+                data.as_slice().try_into().map_err(eio)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn check_writable(&self) -> OpResult<()> {
+        if self.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn lock_usage(&self) -> MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_versioned(&self) -> MutexGuard<'_, HashSet<u64>> {
+        self.versioned.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_virtual_nodes(&self) -> MutexGuard<'_, VirtualNodes> {
+        self.virtual_nodes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_quotas(&self) -> MutexGuard<'_, Quotas> {
+        self.quotas.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Fail with `EDQUOT` if the quota changes would take anyone over their
+    /// limits, or reserve the space they add until they are committed
+    ///
+    /// This is checked before anything is written, so that an operation that
+    /// is over quota doesn't change the filesystem at all.
+    fn check_quota(&self, quota: Vec<QuotaChange>) -> OpResult<QuotaReservation<'_>> {
+        if quota.is_empty() {
+            return Ok(self.unchecked_quota(quota));
+        }
+
+        let mut quotas = self.lock_quotas();
+        if quotas.limits_stale() {
+            // Keep the old limits if they can't be read
+            if let Err(e) = quotas.load_limits(&*self.kv_store) {
+                log::error!("Could not read quota limits: {}", e);
+            }
+        }
+        if !quotas.reserve(&quota) {
+            log::debug!("    Over quota: EDQUOT");
+            return Err(EDQUOT);
+        }
+
+        Ok(QuotaReservation {
+            quotas: &self.quotas,
+            changes: quota,
+            reserved: true,
+        })
+    }
+
+    /// Quota changes that aren't checked against the limits, because they only
+    /// free space or aren't limited
+    fn unchecked_quota(&self, changes: Vec<QuotaChange>) -> QuotaReservation<'_> {
+        QuotaReservation {
+            quotas: &self.quotas,
+            changes,
+            reserved: false,
+        }
+    }
+
+    /// Write a batch of metadata changes together with the changes they make
+    /// to the usage totals and quota usage
+    fn commit(
+        &self,
+        mut ops: Vec<BatchOp>,
+        logical: i64,
+        physical: i64,
+        files: i64,
+        mut quota: QuotaReservation<'_>,
+    ) -> OpResult<()> {
+        if logical == 0 && physical == 0 && files == 0 && quota.changes.is_empty() {
+            return self.kv_store.batch(ops).map_err(eio);
+        }
+
+        // Hold the locks until the batch is written so that the totals are
+        // written in the same order that they are changed
+        let mut usage = self.lock_usage();
+        let mut quotas = self.lock_quotas();
+        let mut updated = *usage;
+        updated.apply(logical, physical, files);
+
+        ops.push(BatchOp::Set(KvQuery::Usage.get_key(), updated.encode().map_err(eio)?));
+        let updated_quotas = quotas.updated(&quota.changes);
+        for (id, quota_usage) in &updated_quotas {
+            ops.push(usage_op(*id, quota_usage).map_err(eio)?);
+        }
+
+        self.kv_store.batch(ops).map_err(eio)?;
+        *usage = updated;
+        quotas.update(updated_quotas);
+        if quota.reserved {
+            quotas.release(&quota.changes);
+            quota.reserved = false;
+        }
+
+        Ok(())
+    }
+
+    /// Get the hash of the chunk at `index` in a file
+    fn get_chunk_hash(&self, ino: u64, index: u64) -> OpResult<Option<ChunkHash>> {
+        let key = KvQuery::FileChunk(ino, index).get_key();
+
+        match self.data_store().get(key).map_err(eio)? {
+            Some(value) => Ok(Some(decode_hash(&value).map_err(eio)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the uncompressed content of a chunk
+    fn get_chunk(&self, hash: &ChunkHash) -> OpResult<Vec<u8>> {
+        match self.data_store().get(KvQuery::Chunk(hash).get_key()).map_err(eio)? {
+            Some(stored) => decode_chunk(&stored).map_err(eio),
+            None => {
+                log::error!("Chunk {} is missing", hex::encode(hash));
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Get the uncompressed content of several chunks, reading them from the
+    /// store concurrently, or an empty chunk for each missing hash
+    fn get_chunks(&self, hashes: &[Option<ChunkHash>]) -> OpResult<Vec<Vec<u8>>> {
+        if let [hash] = hashes {
+            return match hash {
+                Some(hash) => Ok(vec![self.get_chunk(hash)?]),
+                None => Ok(vec![vec![]]),
+            };
+        }
+
+        let keys = hashes.iter().flatten().map(|hash| KvQuery::Chunk(hash).get_key()).collect();
+        let mut stored = block_on(self.chunk_reader.get_many(keys)).map_err(eio)?.into_iter();
+
+        let mut chunks = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let hash = match hash {
+                Some(hash) => hash,
+                None => {
+                    chunks.push(vec![]);
+                    continue;
+                }
+            };
+            match stored.next().flatten() {
+                Some(chunk) => chunks.push(decode_chunk(&chunk).map_err(eio)?),
+                None => {
+                    log::error!("Chunk {} is missing", hex::encode(hash));
+                    return Err(EIO);
+                }
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    /// Hash and encode the content of a chunk to be stored
+    fn new_chunk(&self, data: &[u8]) -> (ChunkHash, Vec<u8>) {
+        (hash_chunk(data), encode_chunk(&self.config.compression, data))
+    }
+
+    /// Get the reference count of a chunk, reading it into `refs` if it hasn't
+    /// been read yet
+    fn chunk_refs<'a>(
+        &self,
+        refs: &'a mut BTreeMap<ChunkHash, Option<ChunkRefs>>,
+        hash: ChunkHash,
+    ) -> OpResult<&'a mut Option<ChunkRefs>> {
+        Ok(match refs.entry(hash) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let key = KvQuery::ChunkRefs(&hash).get_key();
+                let stored = match self.data_store().get(key).map_err(eio)? {
+                    Some(record) => Some(ChunkRefs::decode(&record).map_err(eio)?),
+                    None => None,
+                };
+                entry.insert(stored)
+            }
+        })
+    }
+
+    /// Apply changes to the chunks of a file, returning the change in stored
+    /// bytes
+    ///
+    /// A new chunk is only stored if no file refers to an identical chunk yet.
+    /// Chunks that are no longer referred to are left for garbage collection.
+    fn set_chunks(&self, ino: u64, changes: Vec<ChunkChange>) -> OpResult<i64> {
+        if changes.is_empty() {
+            return Ok(0);
+        }
+
+        // Hold the lock until the batch is written so that concurrent changes
+        // to the same reference counts don't overwrite each other
+        let _refs_lock = self.chunk_refs.lock().unwrap_or_else(|e| e.into_inner());
+        let mut refs = BTreeMap::new();
+        let mut ops = vec![];
+        let mut physical = 0;
+
+        for change in changes {
+            let key = KvQuery::FileChunk(ino, change.index).get_key();
+            match change.new {
+                Some((hash, value)) => {
+                    let entry = self.chunk_refs(&mut refs, hash)?;
+                    if let Some(entry) = entry.as_mut() {
+                        entry.count += 1;
+                    } else {
+                        physical += value.len() as i64;
+                        *entry = Some(ChunkRefs { count: 1, size: value.len() as u64 });
+                        ops.push(BatchOp::Set(KvQuery::Chunk(&hash).get_key(), value));
+                    }
+                    ops.push(BatchOp::Set(key, hash.to_vec()));
+                }
+                None => ops.push(BatchOp::Delete(key)),
+            }
+
+            if let Some(hash) = change.old {
+                let entry = self.chunk_refs(&mut refs, hash)?;
+                match entry.as_mut() {
+                    Some(old) if old.count > 1 => old.count -= 1,
+                    Some(old) => {
+                        physical -= old.size as i64;
+                        *entry = None;
+                    }
+                    None => log::warn!("Chunk {} had no references left to remove", hex::encode(&hash)),
+                }
+            }
+        }
+
+        for (hash, entry) in refs {
+            let key = KvQuery::ChunkRefs(&hash).get_key();
+            ops.push(match entry {
+                Some(entry) => BatchOp::Set(key, entry.encode().map_err(eio)?),
+                None => BatchOp::Delete(key),
+            });
+        }
+
+        self.data_store().batch(ops).map_err(eio)?;
+        Ok(physical)
+    }
+
+    /// Remove the content of a file past `new_size` when it shrinks from
+    /// `size`, returning the change in stored bytes
+    fn truncate_chunks(&self, ino: u64, size: u64, new_size: u64) -> OpResult<i64> {
+        let mut changes = vec![];
+
+        let from = new_size.div_ceil(CHUNK_SIZE);
+        for index in from..size.div_ceil(CHUNK_SIZE) {
+            if let Some(hash) = self.get_chunk_hash(ino, index)? {
+                changes.push(ChunkChange { index, old: Some(hash), new: None });
+            }
+        }
+
+        // Bytes past the new end of the last chunk must read as zeros if the
+        // file grows again
+        let end = (new_size % CHUNK_SIZE) as usize;
+        if end != 0 && new_size < size {
+            if let Some(hash) = self.get_chunk_hash(ino, from - 1)? {
+                let mut chunk = self.get_chunk(&hash)?;
+                if chunk.len() > end {
+                    chunk.truncate(end);
+                    changes.push(ChunkChange {
+                        index: from - 1,
+                        old: Some(hash),
+                        new: Some(self.new_chunk(&chunk)),
+                    });
+                }
+            }
+        }
+
+        self.set_chunks(ino, changes)
+    }
+
+    /// Get the hash of every chunk of a file, by index
+    fn get_chunk_hashes(&self, ino: u64) -> OpResult<Vec<(u64, ChunkHash)>> {
+        let file_chunks = self
+            .data_store()
+            .scan_prefix(KvQuery::FileChunk(ino, 0).get_prefix())
+            .map_err(eio)?;
+
+        file_chunks
+            .iter()
+            .map(|(key, hash)| {
+                let index = u64::from_be_bytes(key[9..].try_into().map_err(eio)?);
+                Ok((index, decode_hash(hash).map_err(eio)?))
+            })
+            .collect()
+    }
+
+    /// Write a batch of data store changes together with the changes they make
+    /// to the reference counts of chunks, returning the change in stored bytes
+    fn write_with_refs(
+        &self,
+        mut ops: Vec<BatchOp>,
+        mut counts: BTreeMap<ChunkHash, i64>,
+    ) -> OpResult<i64> {
+        counts.retain(|_, change| *change != 0);
+
+        let _refs_lock = self.chunk_refs.lock().unwrap_or_else(|e| e.into_inner());
+        let (refs_ops, physical) = adjust_refs(self.data_store(), &counts).map_err(eio)?;
+        ops.extend(refs_ops);
+        self.data_store().batch(ops).map_err(eio)?;
+
+        Ok(physical)
+    }
+
+    /// Keep the content of a file as a version before it is first changed
+    /// since it was last closed, if its versions policy asks for it
+    ///
+    /// Versions that the policy no longer keeps are deleted at the same time.
+    /// The caller must hold the lock of the file.
+    fn keep_version(&self, inode: &Inode) -> OpResult<()> {
+        let policy = match VersionPolicy::of(inode) {
+            Some(policy) if inode.kind == FileKind::RegularFile => policy,
+            _ => return Ok(()),
+        };
+        if !self.lock_versioned().insert(inode.ino) || inode.size == 0 {
+            return Ok(());
+        }
+
+        let mut versions = list_versions(self.data_store(), inode.ino).map_err(eio)?;
+        let now = Timestamp::now();
+        let version = Version {
+            number: versions.last().map_or(1, |version| version.number + 1),
+            size: inode.size,
+            mtime: inode.mtime,
+            kept: now,
+            chunks: self.get_chunk_hashes(inode.ino)?,
+        };
+        log::debug!("    Keeping version {} of ino({})", version.number, inode.ino);
+
+        let mut counts = BTreeMap::new();
+        for (_, hash) in &version.chunks {
+            *counts.entry(*hash).or_insert(0) += 1;
+        }
+        let mut ops = vec![BatchOp::Set(
+            KvQuery::Version(inode.ino, version.number).get_key(),
+            version.encode().map_err(eio)?,
+        )];
+        versions.push(version);
+        for expired in policy.expired(&versions, now) {
+            for (_, hash) in &expired.chunks {
+                *counts.entry(*hash).or_insert(0) -= 1;
+            }
+            ops.push(BatchOp::Delete(KvQuery::Version(inode.ino, expired.number).get_key()));
+        }
+
+        let physical = self.write_with_refs(ops, counts)?;
+        self.commit(vec![], 0, physical, 0, self.unchecked_quota(vec![]))
+    }
+
+    /// Delete every version of a file
+    fn delete_versions(&self, ino: u64) -> OpResult<()> {
+        self.lock_versioned().remove(&ino);
+        let versions = list_versions(self.data_store(), ino).map_err(eio)?;
+        if versions.is_empty() {
+            return Ok(());
+        }
+
+        let mut counts = BTreeMap::new();
+        let mut ops = vec![];
+        for version in versions {
+            for (_, hash) in version.chunks {
+                *counts.entry(hash).or_insert(0) -= 1;
+            }
+            ops.push(BatchOp::Delete(KvQuery::Version(ino, version.number).get_key()));
+        }
+
+        let physical = self.write_with_refs(ops, counts)?;
+        self.commit(vec![], 0, physical, 0, self.unchecked_quota(vec![]))
+    }
+
+    /// Read up to `size` bytes starting at `offset` of content `file_size`
+    /// bytes long, whose chunks are found with `chunk_hash`
+    fn read_content<F>(&self, file_size: u64, offset: u64, size: u32, chunk_hash: F) -> OpResult<Vec<u8>>
+    where
+        F: Fn(u64) -> OpResult<Option<ChunkHash>>,
+    {
+        let end = file_size.min(offset.saturating_add(u64::from(size)));
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        let mut hashes = vec![];
+        for index in offset / CHUNK_SIZE..=(end - 1) / CHUNK_SIZE {
+            hashes.push(chunk_hash(index)?);
+        }
+        let mut chunks = self.get_chunks(&hashes)?.into_iter();
+
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        let mut position = offset;
+        while position < end {
+            let start = (position % CHUNK_SIZE) as usize;
+            let length = (CHUNK_SIZE as usize - start).min((end - position) as usize);
+
+            let chunk = chunks.next().unwrap_or_default();
+            let available = chunk.len().saturating_sub(start).min(length);
+            if available > 0 {
+                data.extend_from_slice(&chunk[start..start + available]);
+            }
+            data.resize(data.len() + length - available, 0);
+
+            position += length as u64;
+        }
+
+        Ok(data)
+    }
+
+    /// Get the version of a file with the given number
+    fn get_version(&self, ino: u64, number: u64) -> OpResult<Version> {
+        let key = KvQuery::Version(ino, number).get_key();
+
+        match self.data_store().get(key).map_err(eio)? {
+            Some(value) => Version::decode(&value).map_err(eio),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn virtual_node(&self, ino: u64) -> Option<VirtualNode> {
+        self.lock_virtual_nodes().get(ino)
+    }
+
+    /// Get the ino of a virtual node, giving it one if it doesn't have one yet
+    fn virtual_ino(&self, node: VirtualNode) -> OpResult<u64> {
+        if let Some(ino) = self.lock_virtual_nodes().ino(node) {
+            return Ok(ino);
+        }
+
+        let ino = self.get_available_ino()?;
+        let mut nodes = self.lock_virtual_nodes();
+        // Another request may have given the node an ino in the meantime
+        if let Some(ino) = nodes.ino(node) {
+            return Ok(ino);
+        }
+        nodes.insert(node, ino);
+
+        Ok(ino)
+    }
+
+    /// Get the attributes of a virtual node, which are read-only versions of
+    /// those of the directory or file it belongs to
+    fn virtual_attributes(&self, node: VirtualNode) -> OpResult<Inode> {
+        let (ino, kind, perm) = match node {
+            VirtualNode::VersionsDir(ino) | VirtualNode::FileVersions(ino) => {
+                (ino, FileKind::Directory, 0o555)
+            }
+            VirtualNode::Version(ino, _) => (ino, FileKind::RegularFile, 0o444),
+        };
+        let source = self.get_inode(ino)?.ok_or(ENOENT)?;
+
+        let mut inode = Inode::new(
+            self.virtual_ino(node)?,
+            kind,
+            perm & source.perm,
+            source.uid,
+            source.gid,
+            source.crtime,
+        );
+        inode.atime = source.atime;
+        inode.mtime = source.mtime;
+        inode.ctime = source.ctime;
+        if let VirtualNode::Version(ino, number) = node {
+            let version = self.get_version(ino, number)?;
+            inode.size = version.size;
+            inode.blocks = blocks(version.size);
+            inode.mtime = version.mtime;
+            inode.ctime = version.kept;
+            inode.crtime = version.kept;
+        }
+
+        Ok(inode)
+    }
+
+    fn lookup_virtual(&self, parent: VirtualNode, name: &OsStr) -> OpResult<Inode> {
+        let node = match parent {
+            VirtualNode::VersionsDir(directory) => {
+                let ino = self.get_file(directory, name)?.ok_or(ENOENT)?;
+                if self.get_inode(ino)?.ok_or(ENOENT)?.kind != FileKind::RegularFile {
+                    return Err(ENOENT);
+                }
+                VirtualNode::FileVersions(ino)
+            }
+            VirtualNode::FileVersions(ino) => {
+                let number = name.to_str().and_then(|name| name.parse().ok()).ok_or(ENOENT)?;
+                self.get_version(ino, number)?;
+                VirtualNode::Version(ino, number)
+            }
+            VirtualNode::Version(_, _) => return Err(ENOTDIR),
+        };
+
+        self.virtual_attributes(node)
+    }
+
+    fn readdir_virtual(&self, ino: u64, node: VirtualNode) -> OpResult<Vec<(u64, FileKind, String)>> {
+        let mut children = vec![
+            (ino, FileKind::Directory, String::from(".")),
+            (ino, FileKind::Directory, String::from("..")),
+        ];
+
+        match node {
+            VirtualNode::VersionsDir(directory) => {
+                for (child, kind, name) in self.get_children(directory)? {
+                    let versions = self
+                        .data_store()
+                        .scan_prefix(KvQuery::Version(child, 0).get_prefix())
+                        .map_err(eio)?;
+                    if kind == FileKind::RegularFile && !versions.is_empty() {
+                        let node = VirtualNode::FileVersions(child);
+                        children.push((self.virtual_ino(node)?, FileKind::Directory, name));
+                    }
+                }
+            }
+            VirtualNode::FileVersions(file) => {
+                for version in list_versions(self.data_store(), file).map_err(eio)? {
+                    let node = VirtualNode::Version(file, version.number);
+                    children.push((
+                        self.virtual_ino(node)?,
+                        FileKind::RegularFile,
+                        version.number.to_string(),
+                    ));
+                }
+            }
+            VirtualNode::Version(_, _) => return Err(ENOTDIR),
+        }
+
+        Ok(children)
+    }
+
+    /// Change every inode in the subtree rooted at `ino` with `change`, which
+    /// returns whether or not it changed the inode, returning the number of
+    /// inodes changed
+    fn change_subtree<F: Fn(&mut Inode) -> bool>(&self, ino: u64, change: F) -> OpResult<u64> {
+        let mut changed = 0;
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            let _locks = self.locks.lock(&[ino]);
+
+            let mut inode = match self.get_inode(ino)? {
+                Some(inode) => inode,
+                None => continue,
+            };
+            if inode.kind == FileKind::Directory {
+                pending.extend(self.get_children(ino)?.into_iter().map(|(child, _, _)| child));
+            }
+
+            let before = inode.clone();
+            if !change(&mut inode) {
+                continue;
+            }
+            inode.ctime = Timestamp::now();
+            self.commit(
+                vec![Self::set_inode_op(&inode)?],
+                0,
+                0,
+                0,
+                self.unchecked_quota(transfer(&before, &inode)),
+            )?;
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
+
+    /// Get an inode id that isn't used by any existing node
+    ///
+    /// The implementation involves generating a random ino and checking to see
+    /// whether or not it exists.
+    ///
+    /// TODO: This will be very unperformant as soon as the number of inodes
+    /// approaches the maxiumum number of inodes, but I'm not sure if that will
+    /// ever happen. Either way we should do this differently later.
+    fn get_available_ino(&self) -> OpResult<u64> {
+        loop {
+            let ino = rand::random::<u64>();
+
+            if self.get_inode(ino)?.is_none() && self.lock_virtual_nodes().get(ino).is_none() {
+                return Ok(ino);
+            }
+        }
+    }
+
+    /// Create the root directory if it doesn't exist yet
+    pub fn init(&self) -> OpResult<()> {
+        let _locks = self.locks.lock(&[1]);
+
+        if self.get_inode(1)?.is_none() && !self.read_only {
+            let mut root = Inode::new(
+                1,
+                FileKind::Directory,
+                0o777,
+                1001,
+                1001,
+                Timestamp::default(),
+            );
+            root.size = 13;
+            root.blocks = 1;
+
+            self.set_inode(&root)?;
+        }
+
+        let mut quotas = Quotas::load(&self.kv_store).map_err(eio)?;
+        let usage = match Usage::load(&self.kv_store).map_err(eio)? {
+            Some(usage) => usage,
+            None => {
+                // Quota usage is counted along with the totals, so that
+                // migrations can delete the totals to have both counted again
+                log::info!("Counting the space used by the filesystem");
+                let usage = Usage::count(&*self.kv_store, self.data_store()).map_err(eio)?;
+                let mut ops = quotas.count(&self.kv_store).map_err(eio)?;
+                if !self.read_only {
+                    ops.push(BatchOp::Set(KvQuery::Usage.get_key(), usage.encode().map_err(eio)?));
+                    self.kv_store.batch(ops).map_err(eio)?;
+                }
+                usage
+            }
+        };
+        *self.lock_usage() = usage;
+        *self.lock_quotas() = quotas;
+
+        Ok(())
+    }
+
+    /// Get the space used by the filesystem
+    pub fn usage(&self) -> Usage {
+        *self.lock_usage()
+    }
+
+    /// Get the usage and limits of the project that `ino` is in, if it is in a
+    /// project with limits
+    pub fn project_quota(&self, ino: u64) -> OpResult<Option<(QuotaUsage, QuotaLimits)>> {
+        let inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        if inode.project == 0 {
+            return Ok(None);
+        }
+
+        let id = QuotaId { kind: QuotaKind::Project, id: inode.project };
+        let quotas = self.lock_quotas();
+        let limits = quotas.limits(id);
+        if limits.is_unlimited() {
+            Ok(None)
+        } else {
+            Ok(Some((quotas.usage(id), limits)))
+        }
+    }
+
+    /// Put the directory `ino` and everything in it in a project, or in no
+    /// project if `project` is `0`, returning the number of inodes moved
+    ///
+    /// Moving files between projects isn't limited by the projects' quotas.
+    pub fn set_project(&self, ino: u64, project: u32) -> OpResult<u64> {
+        self.check_writable()?;
+
+        self.change_subtree(ino, |inode| {
+            let changed = inode.project != project;
+            inode.project = project;
+            changed
+        })
+    }
+
+    /// Get the attributes of the file named `name` in the `parent` directory
+    ///
+    /// Every directory has a `.versions` directory that isn't listed by
+    /// `readdir`, unless it has a file by that name.
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> OpResult<Inode> {
+        if let Some(node) = self.virtual_node(parent) {
+            return self.lookup_virtual(node, name);
+        }
+
+        let ino = match self.get_file(parent, name)? {
+            Some(ino) => ino,
+            None if name == VERSIONS_DIR
+                && self.get_inode(parent)?.map(|inode| inode.kind) == Some(FileKind::Directory) =>
+            {
+                return self.virtual_attributes(VirtualNode::VersionsDir(parent));
+            }
+            None => {
+                log::debug!("    Not found: ENOENT");
+                return Err(ENOENT);
+            }
+        };
+
+        log::debug!("    Found: ino({})", ino);
+
+        match self.get_inode(ino)? {
+            Some(inode) => Ok(inode),
+            None => {
+                log::debug!("    Attributes not found for ino!");
+                Err(ENOENT)
+            }
+        }
+    }
+
+    /// Get the attributes of an inode
+    pub fn getattr(&self, ino: u64) -> OpResult<Inode> {
+        match self.get_inode(ino)? {
+            Some(inode) => Ok(inode),
+            None => match self.virtual_node(ino) {
+                Some(node) => self.virtual_attributes(node),
+                None => Err(ENOENT),
+            },
+        }
+    }
+
+    /// Note that a file has been closed, so that its content is kept as a
+    /// version again the next time it changes
+    pub fn release(&self, ino: u64) {
+        self.lock_versioned().remove(&ino);
+    }
+
+    /// List the versions of a file, oldest first
+    pub fn versions(&self, ino: u64) -> OpResult<Vec<Version>> {
+        list_versions(self.data_store(), ino).map_err(eio)
+    }
+
+    /// Replace the content of a file with one of its versions
+    ///
+    /// The content being replaced is kept as a version first, if the file's
+    /// policy keeps versions, so that restoring a version can be undone.
+    pub fn restore_version(&self, ino: u64, number: u64) -> OpResult<Inode> {
+        self.check_writable()?;
+        let _locks = self.locks.lock(&[ino]);
+
+        let mut inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        let version = self.get_version(ino, number)?;
+        let logical = version.size as i64 - inode.size as i64;
+        let quota = self.check_quota(if logical > 0 { charge(&inode, logical, 0) } else { vec![] })?;
+
+        self.lock_versioned().remove(&ino);
+        self.keep_version(&inode)?;
+
+        // Point the file at the chunks of the version
+        let indexes: HashSet<u64> = version.chunks.iter().map(|(index, _)| *index).collect();
+        let mut counts = BTreeMap::new();
+        let mut ops = vec![];
+        for (index, hash) in self.get_chunk_hashes(ino)? {
+            *counts.entry(hash).or_insert(0) -= 1;
+            if !indexes.contains(&index) {
+                ops.push(BatchOp::Delete(KvQuery::FileChunk(ino, index).get_key()));
+            }
+        }
+        for (index, hash) in &version.chunks {
+            *counts.entry(*hash).or_insert(0) += 1;
+            ops.push(BatchOp::Set(KvQuery::FileChunk(ino, *index).get_key(), hash.to_vec()));
+        }
+        let physical = self.write_with_refs(ops, counts)?;
+
+        let now = Timestamp::now();
+        inode.size = version.size;
+        inode.blocks = blocks(version.size);
+        inode.mtime = now;
+        inode.ctime = now;
+        self.commit(vec![Self::set_inode_op(&inode)?], logical, physical, 0, quota)?;
+        self.lock_versioned().remove(&ino);
+
+        Ok(inode)
+    }
+
+    /// Get the value of an extended attribute
+    pub fn getxattr(&self, ino: u64, name: &OsStr) -> OpResult<Vec<u8>> {
+        let inode = self.getattr(ino)?;
+        inode.xattrs.get(name.as_bytes()).cloned().ok_or(ENODATA)
+    }
+
+    /// List the names of the extended attributes of an inode, each followed by
+    /// a NUL byte
+    pub fn listxattr(&self, ino: u64) -> OpResult<Vec<u8>> {
+        let inode = self.getattr(ino)?;
+
+        let mut names = vec![];
+        for name in inode.xattrs.keys() {
+            names.extend_from_slice(name);
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    /// Set an extended attribute
+    ///
+    /// `flags` may hold `XATTR_CREATE` to fail if the attribute exists or
+    /// `XATTR_REPLACE` to fail if it doesn't. Setting the versions policy of a
+    /// directory sets it for everything in the directory too.
+    pub fn setxattr(&self, ino: u64, name: &OsStr, value: &[u8], flags: u32) -> OpResult<()> {
+        self.check_writable()?;
+        let policy = name == VERSIONS_XATTR;
+        if policy && VersionPolicy::parse(value).is_err() {
+            return Err(EINVAL);
+        }
+
+        let _locks = self.locks.lock(&[ino]);
+        let mut inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        let exists = inode.xattrs.contains_key(name.as_bytes());
+        if exists && flags & XATTR_CREATE as u32 != 0 {
+            return Err(EEXIST);
+        }
+        if !exists && flags & XATTR_REPLACE as u32 != 0 {
+            return Err(ENODATA);
+        }
+
+        inode.xattrs.insert(name.as_bytes().to_vec(), value.to_vec());
+        inode.ctime = Timestamp::now();
+        self.set_inode(&inode)?;
+        drop(_locks);
+
+        if policy && inode.kind == FileKind::Directory {
+            self.change_subtree(ino, |inode| {
+                let old = inode.xattrs.insert(name.as_bytes().to_vec(), value.to_vec());
+                old.as_deref() != Some(value)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove an extended attribute
+    ///
+    /// Removing the versions policy of a directory removes it from everything
+    /// in the directory too. Versions that have already been kept are kept.
+    pub fn removexattr(&self, ino: u64, name: &OsStr) -> OpResult<()> {
+        self.check_writable()?;
+
+        let _locks = self.locks.lock(&[ino]);
+        let mut inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        if inode.xattrs.remove(name.as_bytes()).is_none() {
+            return Err(ENODATA);
+        }
+        inode.ctime = Timestamp::now();
+        self.set_inode(&inode)?;
+        drop(_locks);
+
+        if name == VERSIONS_XATTR && inode.kind == FileKind::Directory {
+            self.change_subtree(ino, |inode| inode.xattrs.remove(name.as_bytes()).is_some())?;
+        }
+
+        Ok(())
+    }
+
+    /// Change the attributes of an inode
+    ///
+    /// Any change to the attributes updates the change time and changing the
+    /// size also updates the modification time.
+    pub fn setattr(&self, ino: u64, changes: AttrChanges) -> OpResult<Inode> {
+        self.check_writable()?;
+        let _locks = self.locks.lock(&[ino]);
+
+        let mut attributes = self.get_inode(ino)?.ok_or(ENOENT)?;
+        let before = attributes.clone();
+
+        let now = Timestamp::now();
+        let (mut logical, mut physical) = (0, 0);
+        attributes.ctime = now;
+        if let Some(value) = changes.mode {
+            attributes.perm = value as u16;
+        }
+        if let Some(value) = changes.uid {
+            attributes.uid = value;
+        }
+        if let Some(value) = changes.gid {
+            attributes.gid = value;
+        }
+
+        // Changing the owner, group or size moves or changes the space charged
+        let quota = self.check_quota(transfer(
+            &before,
+            &Inode {
+                size: changes.size.unwrap_or(attributes.size),
+                ..attributes.clone()
+            },
+        ))?;
+
+        if let Some(value) = changes.size {
+            if attributes.kind == FileKind::Directory {
+                return Err(EISDIR);
+            }
+            self.keep_version(&before)?;
+            physical = self.truncate_chunks(ino, attributes.size, value)?;
+            logical = value as i64 - attributes.size as i64;
+            attributes.size = value;
+            attributes.blocks = blocks(value);
+            attributes.mtime = now;
+        }
+        if let Some(value) = changes.atime {
+            attributes.atime = value;
+        }
+        if let Some(value) = changes.mtime {
+            attributes.mtime = value;
+        }
+        if let Some(value) = changes.crtime {
+            attributes.crtime = value;
+        }
+        if let Some(value) = changes.chgtime {
+            attributes.ctime = value;
+        }
+        if let Some(value) = changes.flags {
+            attributes.flags = value;
+        }
+
+        self.commit(vec![Self::set_inode_op(&attributes)?], logical, physical, 0, quota)?;
+
+        Ok(attributes)
+    }
+
+    /// Create a file named `name` in the `parent` directory
+    pub fn create_file(
+        &self,
+        file_type: FileKind,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> OpResult<Inode> {
+        self.check_writable()?;
+        let filename = name.to_str().ok_or(EINVAL)?;
+
+        let _locks = self.locks.lock(&[parent]);
+
+        if self.get_file(parent, name)?.is_some() {
+            return Err(EEXIST);
+        }
+        let mut directory = self.get_inode(parent)?.ok_or(ENOENT)?;
+
+        let ino = self.get_available_ino()?;
+        let created_time = Timestamp::now();
+
+        let mut inode = Inode::new(ino, file_type, mode as u16, uid, gid, created_time);
+        inode.project = directory.project;
+        if let Some(policy) = directory.xattrs.get(VERSIONS_XATTR.as_bytes()) {
+            inode.xattrs.insert(VERSIONS_XATTR.as_bytes().to_vec(), policy.clone());
+        }
+
+        let quota = self.check_quota(charge(&inode, 0, 1))?;
+
+        let mut children = self.get_children(parent)?;
+        children.push((ino, inode.kind, filename.to_owned()));
+
+        directory.mtime = created_time;
+        directory.ctime = created_time;
+
+        // Write the file attributes, file record, and parent directory together
+        // so that the file never appears partially created
+        self.commit(
+            vec![
+                Self::set_inode_op(&inode)?,
+                BatchOp::Set(
+                    KvQuery::Files(parent, name).get_key(),
+                    ino.to_be_bytes().to_vec(),
+                ),
+                Self::set_children_op(parent, &children)?,
+                Self::set_inode_op(&directory)?,
+            ],
+            0,
+            0,
+            1,
+            quota,
+        )?;
+
+        Ok(inode)
+    }
+
+    /// Remove the file named `name` from the `parent` directory
+    ///
+    /// If the trash is enabled, the file is moved to the trash instead of
+    /// being deleted.
+    pub fn remove_file(&self, parent: u64, name: &OsStr) -> OpResult<()> {
+        self.check_writable()?;
+        let (ino, _locks) = loop {
+            let ino = self.get_file(parent, name)?.ok_or(ENOENT)?;
+            let locks = self.locks.lock(&[parent, ino]);
+
+            // Make sure the file wasn't replaced before we got the locks
+            if self.get_file(parent, name)? == Some(ino) {
+                break (ino, locks);
+            }
+        };
+
+        let mut children = self.get_children(parent)?;
+        if let Some(index) = children.iter().position(|(item_ino, _, _)| *item_ino == ino) {
+            children.remove(index);
+        }
+
+        let inode = self.get_inode(ino)?;
+
+        let mut ops = vec![
+            BatchOp::Delete(KvQuery::Files(parent, name).get_key()),
+            Self::set_children_op(parent, &children)?,
+        ];
+
+        let now = Timestamp::now();
+        if let Some(mut directory) = self.get_inode(parent)? {
+            directory.mtime = now;
+            directory.ctime = now;
+            ops.push(Self::set_inode_op(&directory)?);
+        }
+
+        // Trashed files keep their inode and contents, so nothing is freed
+        if let (true, Some(inode)) = (self.config.trash.enabled, &inode) {
+            let entry = TrashEntry {
+                ino,
+                parent,
+                name: name.to_string_lossy().into_owned(),
+                kind: inode.kind,
+                deleted: now,
+            };
+            ops.push(BatchOp::Set(KvQuery::Trash(ino).get_key(), entry.encode().map_err(eio)?));
+            return self.commit(ops, 0, 0, 0, self.unchecked_quota(vec![]));
+        }
+
+        ops.push(BatchOp::Delete(KvQuery::FileAttributes(ino).get_key()));
+        self.destroy(ino, inode.as_ref(), ops)
+    }
+
+    /// Delete a file that can't be reached anymore, together with the metadata
+    /// changes in `ops`
+    ///
+    /// The caller must hold the lock of `ino`.
+    fn destroy(&self, ino: u64, inode: Option<&Inode>, ops: Vec<BatchOp>) -> OpResult<()> {
+        let size = inode.map_or(0, |inode| inode.size);
+        let quota = match inode {
+            Some(inode) => charge(inode, -(size as i64), -1),
+            None => vec![],
+        };
+        // Removing a file only frees space, so there is nothing to check
+        self.commit(
+            ops,
+            -(size as i64),
+            0,
+            if inode.is_some() { -1 } else { 0 },
+            self.unchecked_quota(quota),
+        )?;
+
+        // The file can't be reached anymore, so a failure here only leaves
+        // unreachable chunks behind
+        if size > 0 {
+            let physical = self.truncate_chunks(ino, size, 0)?;
+            self.commit(vec![], 0, physical, 0, self.unchecked_quota(vec![]))?;
+        }
+        self.delete_versions(ino)
+    }
+
+    /// Put a file in the trash back where it was removed from, returning the
+    /// trash entries restored
+    ///
+    /// The trashed directories that the file was in are restored before it,
+    /// and the trashed contents of a restored directory are restored with it.
+    pub fn restore_trash(&self, ino: u64) -> OpResult<Vec<TrashEntry>> {
+        self.check_writable()?;
+        let entries = list_trash(&self.kv_store).map_err(eio)?;
+        let find = |ino: u64| entries.iter().find(|entry| entry.ino == ino);
+        let entry = find(ino).ok_or(ENOENT)?;
+
+        let mut restore = vec![entry];
+        while let Some(directory) = find(restore[restore.len() - 1].parent) {
+            if restore.len() > entries.len() {
+                break;
+            }
+            restore.push(directory);
+        }
+        restore.reverse();
+
+        let mut index = restore.len() - 1;
+        while index < restore.len() {
+            if restore[index].kind == FileKind::Directory {
+                let parent = restore[index].ino;
+                for child in &entries {
+                    if child.parent == parent && !restore.contains(&child) {
+                        restore.push(child);
+                    }
+                }
+            }
+            index += 1;
+        }
+
+        for entry in &restore {
+            self.untrash(entry)?;
+        }
+
+        Ok(restore.into_iter().cloned().collect())
+    }
+
+    /// Link a trashed file back into the directory it was removed from
+    fn untrash(&self, entry: &TrashEntry) -> OpResult<()> {
+        let _locks = self.locks.lock(&[entry.parent, entry.ino]);
+        let name = OsStr::new(&entry.name);
+
+        if self.get_file(entry.parent, name)?.is_some() {
+            return Err(EEXIST);
+        }
+        let mut directory = self.get_inode(entry.parent)?.ok_or(ENOENT)?;
+        if directory.kind != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+        let mut inode = self.get_inode(entry.ino)?.ok_or(ENOENT)?;
+
+        let mut children = self.get_children(entry.parent)?;
+        children.push((entry.ino, inode.kind, entry.name.clone()));
+
+        let now = Timestamp::now();
+        directory.mtime = now;
+        directory.ctime = now;
+        inode.ctime = now;
+
+        self.commit(
+            vec![
+                BatchOp::Set(
+                    KvQuery::Files(entry.parent, name).get_key(),
+                    entry.ino.to_be_bytes().to_vec(),
+                ),
+                Self::set_children_op(entry.parent, &children)?,
+                Self::set_inode_op(&directory)?,
+                Self::set_inode_op(&inode)?,
+                BatchOp::Delete(KvQuery::Trash(entry.ino).get_key()),
+            ],
+            0,
+            0,
+            0,
+            self.unchecked_quota(vec![]),
+        )
+    }
+
+    /// Delete the files in the trash that `purge` selects for good, returning
+    /// their trash entries
+    pub fn purge_trash<F: Fn(&TrashEntry) -> bool>(&self, purge: F) -> OpResult<Vec<TrashEntry>> {
+        self.check_writable()?;
+
+        let mut purged = vec![];
+        for entry in list_trash(&self.kv_store).map_err(eio)? {
+            if !purge(&entry) {
+                continue;
+            }
+            let _locks = self.locks.lock(&[entry.ino]);
+
+            // The file may have been restored since the trash was listed
+            let key = KvQuery::Trash(entry.ino).get_key();
+            if self.kv_store.get(key.clone()).map_err(eio)?.is_none() {
+                continue;
+            }
+
+            let inode = self.get_inode(entry.ino)?;
+            let ops = vec![
+                BatchOp::Delete(key),
+                BatchOp::Delete(KvQuery::FileAttributes(entry.ino).get_key()),
+                BatchOp::Delete(KvQuery::InodeChildren(entry.ino).get_key()),
+            ];
+            self.destroy(entry.ino, inode.as_ref(), ops)?;
+            purged.push(entry);
+        }
+
+        Ok(purged)
+    }
+
+    /// Purge the files that have been in the trash for longer than the
+    /// configured retention period, returning how many were purged
+    pub fn purge_expired_trash(&self) -> OpResult<usize> {
+        let days = match self.config.trash.retention_days {
+            Some(days) => days,
+            None => return Ok(0),
+        };
+
+        let now = Timestamp::now();
+        let retention = days.saturating_mul(24 * 60 * 60).min(i64::MAX as u64) as i64;
+        Ok(self.purge_trash(|entry| now.seconds_since(entry.deleted) >= retention)?.len())
+    }
+
+    /// Create a file named `name` in the `parent` directory that shares the
+    /// contents of the regular file `ino`
+    ///
+    /// No content is copied: the new file refers to the same chunks as the
+    /// original, and a chunk is only stored again once either file writes to
+    /// it. The new file keeps the original's owner, permissions and
+    /// modification time.
+    pub fn clone_file(&self, ino: u64, parent: u64, name: &OsStr) -> OpResult<Inode> {
+        self.check_writable()?;
+        let filename = name.to_str().ok_or(EINVAL)?;
+
+        let _locks = self.locks.lock(&[parent, ino]);
+
+        if self.get_file(parent, name)?.is_some() {
+            return Err(EEXIST);
+        }
+        let source = self.get_inode(ino)?.ok_or(ENOENT)?;
+        if source.kind != FileKind::RegularFile {
+            return Err(if source.kind == FileKind::Directory { EISDIR } else { EINVAL });
+        }
+        let mut directory = self.get_inode(parent)?.ok_or(ENOENT)?;
+        if directory.kind != FileKind::Directory {
+            return Err(ENOTDIR);
+        }
+
+        let now = Timestamp::now();
+        let mut inode = Inode::new(
+            self.get_available_ino()?,
+            FileKind::RegularFile,
+            source.perm,
+            source.uid,
+            source.gid,
+            now,
+        );
+        inode.size = source.size;
+        inode.blocks = source.blocks;
+        inode.mtime = source.mtime;
+        inode.xattrs = source.xattrs.clone();
+        inode.project = directory.project;
+
+        let quota = self.check_quota(charge(&inode, inode.size as i64, 1))?;
+
+        // Refer to the original's chunks before the new inode exists, like a
+        // write would
+        let mut counts = BTreeMap::new();
+        let mut pointers = vec![];
+        for (index, hash) in self.get_chunk_hashes(ino)? {
+            *counts.entry(hash).or_insert(0) += 1;
+            pointers.push(BatchOp::Set(KvQuery::FileChunk(inode.ino, index).get_key(), hash.to_vec()));
+        }
+        let physical = self.write_with_refs(pointers, counts)?;
+
+        let mut children = self.get_children(parent)?;
+        children.push((inode.ino, inode.kind, filename.to_owned()));
+        directory.mtime = now;
+        directory.ctime = now;
+
+        self.commit(
+            vec![
+                Self::set_inode_op(&inode)?,
+                BatchOp::Set(
+                    KvQuery::Files(parent, name).get_key(),
+                    inode.ino.to_be_bytes().to_vec(),
+                ),
+                Self::set_children_op(parent, &children)?,
+                Self::set_inode_op(&directory)?,
+            ],
+            inode.size as i64,
+            physical,
+            1,
+            quota,
+        )?;
+
+        Ok(inode)
+    }
+
+    /// Get the inode at `path`, relative to the root of the filesystem
+    ///
+    /// Paths can't contain `..` because directories don't record their parent.
+    pub fn resolve(&self, path: &Path) -> OpResult<Inode> {
+        let mut inode = self.getattr(1)?;
+
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => {
+                    if inode.kind != FileKind::Directory {
+                        return Err(ENOTDIR);
+                    }
+                    inode = self.lookup(inode.ino, name)?;
+                }
+                Component::ParentDir | Component::Prefix(_) => return Err(EINVAL),
+            }
+        }
+
+        Ok(inode)
+    }
+
+    /// Read up to `size` bytes of a file starting at `offset`
+    ///
+    /// Chunks that were never written read as zeros. Reading updates the access
+    /// time of the file according to the atime policy.
+    pub fn read(&self, ino: u64, offset: i64, size: u32) -> OpResult<Vec<u8>> {
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        let offset = offset as u64;
+
+        match self.virtual_node(ino) {
+            Some(VirtualNode::Version(file, number)) => {
+                let version = self.get_version(file, number)?;
+                let chunks: BTreeMap<u64, ChunkHash> = version.chunks.into_iter().collect();
+                return self.read_content(version.size, offset, size, |index| {
+                    Ok(chunks.get(&index).copied())
+                });
+            }
+            Some(_) => return Err(EISDIR),
+            None => (),
+        }
+
+        let inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        if inode.kind == FileKind::Directory {
+            return Err(EISDIR);
+        }
+
+        let data = self.read_content(inode.size, offset, size, |index| self.get_chunk_hash(ino, index))?;
+
+        let now = Timestamp::now();
+        if !self.read_only && self.config.atime.should_update(&inode, now) {
+            let _locks = self.locks.lock(&[ino]);
+
+            // Get the inode again now that it is locked
+            if let Some(mut inode) = self.get_inode(ino)? {
+                inode.atime = now;
+                self.set_inode(&inode)?;
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Write `data` to a file starting at `offset`, returning the number of
+    /// bytes written
+    pub fn write(&self, ino: u64, offset: i64, data: &[u8]) -> OpResult<u32> {
+        self.check_writable()?;
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        let offset = offset as u64;
+
+        let _locks = self.locks.lock(&[ino]);
+
+        let mut inode = self.get_inode(ino)?.ok_or(ENOENT)?;
+        if inode.kind == FileKind::Directory {
+            return Err(EISDIR);
+        }
+
+        let size = inode.size.max(offset + data.len() as u64);
+        let logical = (size - inode.size) as i64;
+        let quota = self.check_quota(if logical > 0 { charge(&inode, logical, 0) } else { vec![] })?;
+        self.keep_version(&inode)?;
+
+        let mut changes = vec![];
+        let mut written = 0;
+        while written < data.len() {
+            let position = offset + written as u64;
+            let index = position / CHUNK_SIZE;
+            let start = (position % CHUNK_SIZE) as usize;
+            let length = (CHUNK_SIZE as usize - start).min(data.len() - written);
+
+            // Only chunks that are partially overwritten need their old content
+            let old = self.get_chunk_hash(ino, index)?;
+            let mut chunk = match &old {
+                Some(hash) if length < CHUNK_SIZE as usize => self.get_chunk(hash)?,
+                _ => vec![],
+            };
+            if chunk.len() < start + length {
+                chunk.resize(start + length, 0);
+            }
+            chunk[start..start + length].copy_from_slice(&data[written..written + length]);
+
+            changes.push(ChunkChange { index, old, new: Some(self.new_chunk(&chunk)) });
+            written += length;
+        }
+        let physical = self.set_chunks(ino, changes)?;
+
+        let now = Timestamp::now();
+        inode.size = size;
+        inode.blocks = blocks(size);
+        inode.mtime = now;
+        inode.ctime = now;
+        self.commit(vec![Self::set_inode_op(&inode)?], logical, physical, 0, quota)?;
+
+        Ok(data.len() as u32)
+    }
+
+    /// List the entries of a directory, including `.` and `..`
+    ///
+    /// Reading a directory updates its access time according to the atime
+    /// policy.
+    pub fn readdir(&self, ino: u64) -> OpResult<Vec<(u64, FileKind, String)>> {
+        if let Some(node) = self.virtual_node(ino) {
+            return self.readdir_virtual(ino, node);
+        }
+        let directory = self.get_inode(ino)?.ok_or(ENOENT)?;
+
+        let now = Timestamp::now();
+        if !self.read_only && self.config.atime.should_update(&directory, now) {
+            let _locks = self.locks.lock(&[ino]);
+
+            // Get the inode again now that it is locked
+            if let Some(mut directory) = self.get_inode(ino)? {
+                directory.atime = now;
+                self.set_inode(&directory)?;
+            }
+        }
+
+        let mut children = vec![
+            (ino, FileKind::Directory, String::from(".")),
+            (ino, FileKind::Directory, String::from("..")),
+        ];
+        children.extend(self.get_children(ino)?);
+
+        Ok(children)
+    }
+}
+
+/// Get the number of 512 byte blocks needed to hold `size` bytes
+fn blocks(size: u64) -> u64 {
+    size.div_ceil(512)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+    use crate::app::filesystem::chunks::collect_garbage;
+    use crate::app::filesystem::compression::Codec;
+    use crate::app::filesystem::quota::set_limits;
+    use crate::app::filesystem::AtimePolicy;
+
+    fn filesystem() -> OpResult<FilesystemCore<MemoryKvStore>> {
+        let core = FilesystemCore::new(
+            MemoryKvStore::new(),
+            Some(MemoryKvStore::new()),
+            FilesystemConfig::default(),
+            false,
+        );
+        core.init()?;
+        Ok(core)
+    }
+
+    #[test]
+    fn create_lookup_and_remove() -> OpResult<()> {
+        let core = filesystem()?;
+        let name = OsStr::new("hello");
+
+        let inode = core.create_file(FileKind::RegularFile, 1000, 1000, 1, name, 0o644)?;
+        assert_eq!(core.lookup(1, name), Ok(inode.clone()));
+        assert_eq!(
+            core.create_file(FileKind::RegularFile, 1000, 1000, 1, name, 0o644),
+            Err(EEXIST)
+        );
+
+        let entries = core.readdir(1)?;
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], (inode.ino, FileKind::RegularFile, String::from("hello")));
+
+        core.remove_file(1, name)?;
+        assert_eq!(core.lookup(1, name), Err(ENOENT));
+        assert_eq!(core.getattr(inode.ino), Err(ENOENT));
+        assert_eq!(core.readdir(1)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn create_updates_parent_times() -> OpResult<()> {
+        let core = filesystem()?;
+
+        core.create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)?;
+
+        let root = core.getattr(1)?;
+        assert!(root.mtime > Timestamp::default());
+        assert_eq!(root.mtime, root.ctime);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_read_and_truncate() -> OpResult<()> {
+        let core = filesystem()?;
+        let name = OsStr::new("data");
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, 1, name, 0o644)?
+            .ino;
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        assert_eq!(core.write(ino, 10, &data)?, data.len() as u32);
+        assert_eq!(core.getattr(ino)?.size, data.len() as u64 + 10);
+
+        let read = core.read(ino, 0, CHUNK_SIZE as u32 * 4)?;
+        assert_eq!(&read[..10], &[0; 10]);
+        assert_eq!(&read[10..], &data[..]);
+        assert_eq!(core.read(ino, 15, 5)?, &data[5..10]);
+
+        // Chunks are kept in the data store, apart from the metadata
+        assert!(core.kv_store.get(KvQuery::FileChunk(ino, 0).get_key()).map_err(eio)?.is_none());
+        assert!(core.get_chunk_hash(ino, 2)?.is_some());
+
+        core.setattr(ino, AttrChanges { size: Some(15), ..AttrChanges::default() })?;
+        core.setattr(ino, AttrChanges { size: Some(20), ..AttrChanges::default() })?;
+        assert_eq!(core.read(ino, 10, 100)?, vec![0, 1, 2, 3, 4, 0, 0, 0, 0, 0]);
+        assert!(core.get_chunk_hash(ino, 1)?.is_none());
+
+        core.remove_file(1, name)?;
+        assert!(core.get_chunk_hash(ino, 0)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compression_and_usage() -> OpResult<()> {
+        let mut config = FilesystemConfig::default();
+        config.compression.codec = Codec::Zstd;
+        let core = FilesystemCore::new(MemoryKvStore::new(), None, config, false);
+        core.init()?;
+        let empty = core.usage();
+
+        let name = OsStr::new("log.json");
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, 1, name, 0o644)?
+            .ino;
+        let text = b"{\"level\":\"info\",\"message\":\"hello\"}\n".repeat(5000);
+        core.write(ino, 0, &text)?;
+        assert_eq!(core.read(ino, 0, text.len() as u32)?, text);
+
+        let usage = core.usage();
+        assert_eq!(usage.files, empty.files + 1);
+        assert_eq!(usage.logical_bytes, empty.logical_bytes + text.len() as u64);
+        assert!(usage.physical_bytes < text.len() as u64 / 10);
+        assert_eq!(
+            Usage::count(&*core.kv_store, core.data_store()).map_err(eio)?,
+            usage
+        );
+
+        core.remove_file(1, name)?;
+        assert_eq!(core.usage(), empty);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clone_shares_chunks() -> OpResult<()> {
+        let core = filesystem()?;
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| (i % 7) as u8).collect();
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("original"), 0o600)?
+            .ino;
+        core.write(ino, 0, &data)?;
+        let usage = core.usage();
+
+        let dir = core.create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)?;
+        let copy = core.clone_file(ino, dir.ino, OsStr::new("copy"))?;
+        assert_eq!(core.resolve(Path::new("/dir/copy"))?, copy);
+        assert_eq!((copy.size, copy.perm), (data.len() as u64, 0o600));
+        assert_eq!(core.read(copy.ino, 0, data.len() as u32)?, data);
+        assert_eq!(core.get_chunk_hash(copy.ino, 1)?, core.get_chunk_hash(ino, 1)?);
+        assert_eq!(core.usage().physical_bytes, usage.physical_bytes);
+        assert_eq!(core.clone_file(ino, dir.ino, OsStr::new("copy")), Err(EEXIST));
+        assert_eq!(core.clone_file(dir.ino, 1, OsStr::new("dir2")), Err(EISDIR));
+
+        // Writing to the copy leaves the original alone
+        core.write(copy.ino, 0, b"copy")?;
+        assert_eq!(core.read(ino, 0, 4)?, &data[..4]);
+        core.remove_file(1, OsStr::new("original"))?;
+        assert_eq!(core.read(copy.ino, 4, data.len() as u32)?, &data[4..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn quotas_limit_projects_and_users() -> OpResult<()> {
+        let project = QuotaId { kind: QuotaKind::Project, id: 7 };
+        let user = QuotaId { kind: QuotaKind::User, id: 1000 };
+        let kv_store = MemoryKvStore::new();
+        set_limits(&kv_store, project, QuotaLimits { bytes: Some(100), inodes: None }).map_err(eio)?;
+        set_limits(&kv_store, user, QuotaLimits { bytes: None, inodes: Some(3) }).map_err(eio)?;
+        let core = FilesystemCore::new(kv_store, None, FilesystemConfig::default(), false);
+        core.init()?;
+
+        let dir = core.create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)?;
+        assert_eq!(core.set_project(dir.ino, 7)?, 1);
+        let file = core.create_file(FileKind::RegularFile, 1000, 1000, dir.ino, OsStr::new("a"), 0o644)?;
+        assert_eq!(file.project, 7);
+
+        // Writes and truncates that grow the project past its limit fail
+        core.write(file.ino, 0, &[1; 100])?;
+        assert_eq!(core.write(file.ino, 100, &[1]), Err(EDQUOT));
+        let grow = AttrChanges { size: Some(101), ..AttrChanges::default() };
+        assert_eq!(core.setattr(file.ino, grow).map(|_| ()), Err(EDQUOT));
+        assert_eq!(core.read(file.ino, 0, 200)?.len(), 100);
+        assert_eq!(core.project_quota(dir.ino)?.map(|(usage, _)| usage.bytes), Some(100));
+
+        // The user's third inode is the last, until one is given away
+        core.create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("b"), 0o644)?;
+        assert_eq!(
+            core.create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("c"), 0o644),
+            Err(EDQUOT)
+        );
+        let chown = AttrChanges { uid: Some(1001), ..AttrChanges::default() };
+        core.setattr(file.ino, chown)?;
+        core.create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("c"), 0o644)?;
+
+        // Removing the file frees the project's space
+        core.remove_file(dir.ino, OsStr::new("a"))?;
+        assert_eq!(core.project_quota(dir.ino)?.map(|(usage, _)| usage.bytes), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn atime_policies() -> OpResult<()> {
+        let now = Timestamp::now();
+        let ago = |seconds: i64| Timestamp { sec: now.sec - seconds, nsec: now.nsec };
+        let mut inode = Inode::new(2, FileKind::RegularFile, 0o644, 0, 0, ago(48 * 60 * 60));
+
+        // Accessed since it was last modified or changed
+        inode.atime = ago(60);
+        assert!(!AtimePolicy::RelAtime.should_update(&inode, now));
+        assert!(AtimePolicy::StrictAtime.should_update(&inode, now));
+        assert!(!AtimePolicy::NoAtime.should_update(&inode, now));
+
+        // Modified or changed since it was last accessed
+        inode.mtime = ago(30);
+        assert!(AtimePolicy::RelAtime.should_update(&inode, now));
+        inode.mtime = ago(48 * 60 * 60);
+        inode.ctime = inode.atime;
+        assert!(AtimePolicy::RelAtime.should_update(&inode, now));
+        assert!(!AtimePolicy::NoAtime.should_update(&inode, now));
+
+        // Last accessed more than a day ago
+        inode.ctime = ago(48 * 60 * 60);
+        inode.atime = ago(25 * 60 * 60);
+        assert!(AtimePolicy::RelAtime.should_update(&inode, now));
+        inode.atime = ago(23 * 60 * 60);
+        assert!(!AtimePolicy::RelAtime.should_update(&inode, now));
+
+        // Reads follow the configured policy
+        let old = Timestamp { sec: 1, nsec: 0 };
+        for (atime, updated) in &[(AtimePolicy::NoAtime, false), (AtimePolicy::StrictAtime, true)] {
+            let mut config = FilesystemConfig::default();
+            config.atime = *atime;
+            let core = FilesystemCore::new(MemoryKvStore::new(), None, config, false);
+            core.init()?;
+            let file = core.create_file(FileKind::RegularFile, 0, 0, 1, OsStr::new("a"), 0o644)?;
+            core.setattr(file.ino, AttrChanges { atime: Some(old), ..AttrChanges::default() })?;
+            core.read(file.ino, 0, 10)?;
+            assert_eq!(core.getattr(file.ino)?.atime > old, *updated);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn changes_update_ctime_and_parent_times() -> OpResult<()> {
+        let core = filesystem()?;
+        let old = Timestamp { sec: 1, nsec: 0 };
+        let age = |ino: u64| {
+            core.setattr(
+                ino,
+                AttrChanges { mtime: Some(old), chgtime: Some(old), ..AttrChanges::default() },
+            )
+        };
+
+        let dir = core.create_file(FileKind::Directory, 0, 0, 1, OsStr::new("dir"), 0o755)?;
+        let file = core.create_file(FileKind::RegularFile, 0, 0, dir.ino, OsStr::new("a"), 0o644)?;
+
+        // Changing attributes changes ctime but not mtime
+        age(file.ino)?;
+        let changed = core.setattr(file.ino, AttrChanges { mode: Some(0o600), ..AttrChanges::default() })?;
+        assert!(changed.ctime > old);
+        assert_eq!(changed.mtime, old);
+
+        // Creating and removing files modifies their directory
+        age(dir.ino)?;
+        core.create_file(FileKind::RegularFile, 0, 0, dir.ino, OsStr::new("b"), 0o644)?;
+        let created = core.getattr(dir.ino)?;
+        assert!(created.mtime > old && created.ctime > old);
+
+        age(dir.ino)?;
+        core.remove_file(dir.ino, OsStr::new("b"))?;
+        let removed = core.getattr(dir.ino)?;
+        assert!(removed.mtime > old && removed.ctime > old);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trash_restore_and_purge() -> OpResult<()> {
+        let mut config = FilesystemConfig::default();
+        config.trash.enabled = true;
+        let core = FilesystemCore::new(MemoryKvStore::new(), None, config, false);
+        core.init()?;
+        let empty = core.usage();
+
+        // Remove a directory and its file like `rm -r` does
+        let dir = core.create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)?;
+        let file = core.create_file(FileKind::RegularFile, 1000, 1000, dir.ino, OsStr::new("a"), 0o644)?;
+        core.write(file.ino, 0, b"keep me")?;
+        core.remove_file(dir.ino, OsStr::new("a"))?;
+        core.remove_file(1, OsStr::new("dir"))?;
+        assert_eq!(core.lookup(1, OsStr::new("dir")), Err(ENOENT));
+        assert_eq!(list_trash(&core.kv_store).map_err(eio)?.len(), 2);
+
+        // Restoring the file brings back the directory it was in
+        assert_eq!(core.restore_trash(file.ino)?.len(), 2);
+        assert_eq!(core.resolve(Path::new("/dir/a"))?.ino, file.ino);
+        assert_eq!(core.read(file.ino, 0, 100)?, b"keep me");
+        assert_eq!(core.restore_trash(file.ino), Err(ENOENT));
+
+        core.remove_file(dir.ino, OsStr::new("a"))?;
+        core.remove_file(1, OsStr::new("dir"))?;
+        assert_eq!(core.purge_trash(|_| true)?.len(), 2);
+        assert_eq!(core.getattr(file.ino), Err(ENOENT));
+        assert_eq!(core.usage().files, empty.files);
+        assert_eq!(core.usage().logical_bytes, empty.logical_bytes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn versions_are_kept_browsed_and_restored() -> OpResult<()> {
+        let core = filesystem()?;
+
+        let dir = core.create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)?;
+        core.setxattr(dir.ino, OsStr::new(VERSIONS_XATTR), b"keep=2", 0)?;
+        assert_eq!(core.setxattr(dir.ino, OsStr::new(VERSIONS_XATTR), b"all", 0), Err(EINVAL));
+        let file = core.create_file(FileKind::RegularFile, 1000, 1000, dir.ino, OsStr::new("a"), 0o644)?;
+        assert_eq!(core.getxattr(file.ino, OsStr::new(VERSIONS_XATTR))?, b"keep=2");
+
+        // Each session of writes keeps the content from before it
+        for content in &[b"one", b"two", b"six", b"ten"] {
+            core.write(file.ino, 0, *content)?;
+            core.write(file.ino, 3, b"!")?;
+            core.release(file.ino);
+        }
+        let versions = core.versions(file.ino)?;
+        assert_eq!(versions.iter().map(|v| v.number).collect::<Vec<_>>(), vec![2, 3]);
+
+        // Versions survive garbage collection and can be read through `.versions`
+        collect_garbage(&*core.kv_store, core.data_store(), false).map_err(eio)?;
+        let versions_dir = core.lookup(dir.ino, OsStr::new(VERSIONS_DIR))?;
+        assert!(core.readdir(dir.ino)?.iter().all(|(_, _, name)| name != VERSIONS_DIR));
+        let file_versions = core.lookup(versions_dir.ino, OsStr::new("a"))?;
+        let names: Vec<String> = core.readdir(file_versions.ino)?.into_iter().map(|(_, _, name)| name).collect();
+        assert!(names.contains(&String::from("2")) && names.contains(&String::from("3")));
+        let version = core.lookup(file_versions.ino, OsStr::new("2"))?;
+        assert_eq!(core.read(version.ino, 0, 100)?, b"two!");
+        assert_eq!(core.lookup(file_versions.ino, OsStr::new("1")), Err(ENOENT));
+
+        // Restoring keeps the replaced content as a version too
+        core.restore_version(file.ino, 3)?;
+        assert_eq!(core.read(file.ino, 0, 100)?, b"six!");
+        assert_eq!(core.versions(file.ino)?.last().map(|v| v.size), Some(4));
+        assert_eq!(core.versions(file.ino)?.len(), 2);
+
+        // Removing the file deletes its versions
+        core.remove_file(dir.ino, OsStr::new("a"))?;
+        assert!(core.versions(file.ino)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn identical_chunks_are_stored_once() -> OpResult<()> {
+        let core = filesystem()?;
+        let empty = core.usage();
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let mut inos = vec![];
+        for name in &["a", "b"] {
+            let ino = core
+                .create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new(name), 0o644)?
+                .ino;
+            core.write(ino, 0, &data)?;
+            inos.push(ino);
+        }
+
+        let hash = core.get_chunk_hash(inos[0], 1)?.ok_or(ENOENT)?;
+        assert_eq!(core.get_chunk_hash(inos[1], 1)?, Some(hash));
+        let refs = core.data_store().get(KvQuery::ChunkRefs(&hash).get_key()).map_err(eio)?;
+        assert_eq!(ChunkRefs::decode(&refs.ok_or(ENOENT)?).map_err(eio)?.count, 2);
+
+        let usage = core.usage();
+        let stored = usage.physical_bytes - empty.physical_bytes;
+        assert_eq!(usage.logical_bytes - empty.logical_bytes, 2 * data.len() as u64);
+        // One byte per stored chunk for its codec tag
+        assert_eq!(stored, data.len() as u64 + 2);
+
+        // Changing one file leaves the other untouched
+        core.write(inos[0], 0, b"changed")?;
+        assert_eq!(core.read(inos[1], 0, data.len() as u32)?, data);
+
+        core.remove_file(1, OsStr::new("a"))?;
+        core.remove_file(1, OsStr::new("b"))?;
+        assert_eq!(core.usage(), empty);
+
+        // Unreferenced chunks stay until garbage is collected
+        let chunk_key = KvQuery::Chunk(&hash).get_key();
+        assert!(core.data_store().get(chunk_key.clone()).map_err(eio)?.is_some());
+        let report = collect_garbage(&*core.kv_store, core.data_store(), false).map_err(eio)?;
+        assert_eq!(report.chunks_deleted, 3);
+        assert_eq!(report.refcounts_fixed, 0);
+        assert!(core.data_store().get(chunk_key).map_err(eio)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_collection_keeps_chunks_of_corrupt_inodes() -> OpResult<()> {
+        let core = filesystem()?;
+
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("a"), 0o644)?
+            .ino;
+        core.write(ino, 0, b"keep me")?;
+        let hash = core.get_chunk_hash(ino, 0)?.ok_or(ENOENT)?;
+
+        core.kv_store
+            .set(KvQuery::FileAttributes(ino).get_key(), b"corrupt".to_vec())
+            .map_err(eio)?;
+
+        let report = collect_garbage(&*core.kv_store, core.data_store(), false).map_err(eio)?;
+        assert_eq!(report.orphaned_refs, 0);
+        assert_eq!(report.chunks_deleted, 0);
+        assert!(core.data_store().get(KvQuery::Chunk(&hash).get_key()).map_err(eio)?.is_some());
+        assert_eq!(core.get_chunk_hash(ino, 0)?, Some(hash));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+//! Quotas on the space used by users, groups and project directories
+//!
+//! Every inode is charged to its owner, its group and, if it is in one, its
+//! project. A directory is put in a project with `set_project`, and files
+//! created in it inherit the project. Usage is counted in logical bytes, the
+//! size of files as seen by users of the filesystem, and in inodes.
+//!
+//! | Key                  | Value                 |
+//! | -------------------- | --------------------- |
+//! | `QuotaUsage(id)`     | `QuotaUsage` record   |
+//! | `QuotaLimits(id)`    | `QuotaLimits` record  |
+//!
+//! Usage is kept in the metadata store and updated in the same batch as the
+//! change that caused it, like the usage totals. Operations reserve the space
+//! they add when they are checked, so that concurrent operations can't
+//! together go over a limit. Limits are read when the filesystem is mounted and
+//! read again every `LIMITS_REFRESH`, so that changed limits reach a mounted
+//! filesystem.
+
+use super::inode::Inode;
+use super::types::KvQuery;
+use super::usage::add;
+use crate::app::keyvalue::{BatchOp, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How often a mounted filesystem reads the limits again
+pub const LIMITS_REFRESH: Duration = Duration::from_secs(30);
+
+/// What a quota applies to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QuotaKind {
+    /// The files owned by a user
+    User,
+    /// The files owned by a group
+    Group,
+    /// The files in a project directory
+    Project,
+}
+
+/// The user, group or project that a quota applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QuotaId {
+    /// What `id` is the id of
+    pub kind: QuotaKind,
+    /// The uid, gid or project id
+    pub id: u32,
+}
+
+impl QuotaId {
+    /// Encode the id to be used in a key
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match self.kind {
+            QuotaKind::User => 0,
+            QuotaKind::Group => 1,
+            QuotaKind::Project => 2,
+        });
+        out.extend_from_slice(&self.id.to_be_bytes());
+    }
+
+    /// Decode an id encoded by `encode`
+    pub fn decode(bytes: &[u8]) -> PolyfsResult<QuotaId> {
+        let kind = match bytes.first() {
+            Some(0) => QuotaKind::User,
+            Some(1) => QuotaKind::Group,
+            Some(2) => QuotaKind::Project,
+            _ => {
+                return Err(PolyfsError {
+                    message: String::from("Could not decode quota kind"),
+                    cause: None,
+                })
+            }
+        };
+        let id = u32::from_be_bytes(try_to!(
+            bytes.get(1..).unwrap_or_default().try_into(),
+            "Could not decode quota id"
+        ));
+
+        Ok(QuotaId { kind, id })
+    }
+}
+
+impl fmt::Display for QuotaId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            QuotaKind::User => "user",
+            QuotaKind::Group => "group",
+            QuotaKind::Project => "project",
+        };
+        write!(f, "{} {}", kind, self.id)
+    }
+}
+
+/// The space charged to a user, group or project
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuotaUsage {
+    /// The total size of the files
+    pub bytes: u64,
+    /// The number of inodes
+    pub inodes: u64,
+}
+
+/// The most space a user, group or project may use
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QuotaLimits {
+    /// The most bytes, or `None` for no limit
+    pub bytes: Option<u64>,
+    /// The most inodes, or `None` for no limit
+    pub inodes: Option<u64>,
+}
+
+impl QuotaLimits {
+    /// Whether or not there are no limits
+    pub fn is_unlimited(&self) -> bool {
+        self.bytes.is_none() && self.inodes.is_none()
+    }
+}
+
+/// A signed change to the space charged to a user, group or project
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaChange {
+    /// Who the change is charged to
+    pub id: QuotaId,
+    /// The change in bytes
+    pub bytes: i64,
+    /// The change in inodes
+    pub inodes: i64,
+}
+
+/// Get the changes that charge `bytes` and `inodes` to everyone that `inode`
+/// is charged to
+pub fn charge(inode: &Inode, bytes: i64, inodes: i64) -> Vec<QuotaChange> {
+    let mut ids = vec![
+        QuotaId { kind: QuotaKind::User, id: inode.uid },
+        QuotaId { kind: QuotaKind::Group, id: inode.gid },
+    ];
+    if inode.project != 0 {
+        ids.push(QuotaId { kind: QuotaKind::Project, id: inode.project });
+    }
+
+    ids.into_iter()
+        .map(|id| QuotaChange { id, bytes, inodes })
+        .collect()
+}
+
+/// Get the changes that move the charges for `before` to `after`, when the
+/// owner, group, project or size of an inode changes
+pub fn transfer(before: &Inode, after: &Inode) -> Vec<QuotaChange> {
+    let mut changes = charge(before, -(before.size as i64), -1);
+    changes.extend(charge(after, after.size as i64, 1));
+    merge(&changes)
+        .into_iter()
+        .map(|(id, (bytes, inodes))| QuotaChange { id, bytes, inodes })
+        .collect()
+}
+
+/// Sum the changes for each id, leaving out ids that don't change
+fn merge(changes: &[QuotaChange]) -> BTreeMap<QuotaId, (i64, i64)> {
+    let mut merged = BTreeMap::new();
+    for change in changes {
+        let entry = merged.entry(change.id).or_insert((0, 0));
+        entry.0 += change.bytes;
+        entry.1 += change.inodes;
+    }
+    merged.retain(|_, change| *change != (0, 0));
+    merged
+}
+
+/// The usage and limits of every user, group and project
+#[derive(Debug, Default)]
+pub struct Quotas {
+    usage: HashMap<QuotaId, QuotaUsage>,
+    limits: HashMap<QuotaId, QuotaLimits>,
+    /// Space reserved by operations that have been checked but not committed
+    reserved: HashMap<QuotaId, QuotaUsage>,
+    /// When the limits were last read
+    limits_read: Option<Instant>,
+}
+
+impl Quotas {
+    /// Read the usage and limits from the metadata store
+    pub fn load<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Quotas> {
+        let mut quotas = Quotas::default();
+
+        let usage = try_to!(
+            kv_store.scan_prefix(KvQuery::QuotaUsage(QUOTA_ID_ANY).get_type_prefix()),
+            "Could not read quota usage"
+        );
+        for (key, value) in usage {
+            quotas.usage.insert(
+                QuotaId::decode(&key[1..])?,
+                try_to!(bincode::deserialize(&value), "Could not deserialize quota usage"),
+            );
+        }
+        quotas.load_limits(kv_store)?;
+
+        Ok(quotas)
+    }
+
+    /// Read the limits from the metadata store again
+    pub fn load_limits<S: KeyValueStore>(&mut self, kv_store: &S) -> PolyfsResult<()> {
+        let limits = try_to!(
+            kv_store.scan_prefix(KvQuery::QuotaLimits(QUOTA_ID_ANY).get_type_prefix()),
+            "Could not read quota limits"
+        );
+        self.limits.clear();
+        for (key, value) in limits {
+            self.limits.insert(
+                QuotaId::decode(&key[1..])?,
+                try_to!(bincode::deserialize(&value), "Could not deserialize quota limits"),
+            );
+        }
+        self.limits_read = Some(Instant::now());
+
+        Ok(())
+    }
+
+    /// Whether or not the limits were read more than `LIMITS_REFRESH` ago
+    pub fn limits_stale(&self) -> bool {
+        match self.limits_read {
+            Some(read) => read.elapsed() >= LIMITS_REFRESH,
+            None => true,
+        }
+    }
+
+    /// Count the usage by reading every inode in the metadata store, returning
+    /// the writes that replace the stored usage
+    ///
+    /// Like `Usage::count`, this should only be needed once for stores written
+    /// before usage was kept.
+    pub fn count<S: KeyValueStore>(&mut self, kv_store: &S) -> PolyfsResult<Vec<BatchOp>> {
+        let mut ops = vec![];
+        let prefix = KvQuery::QuotaUsage(QUOTA_ID_ANY).get_type_prefix();
+        for (key, _) in try_to!(kv_store.scan_prefix(prefix), "Could not read quota usage") {
+            ops.push(BatchOp::Delete(key));
+        }
+
+        self.usage.clear();
+        let inodes = try_to!(
+            kv_store.scan_prefix(KvQuery::FileAttributes(0).get_type_prefix()),
+            "Could not read inodes"
+        );
+        for (_, record) in inodes {
+            let inode = Inode::decode(&record)?;
+            for change in charge(&inode, inode.size as i64, 1) {
+                let usage = self.usage.entry(change.id).or_default();
+                usage.bytes += inode.size;
+                usage.inodes += 1;
+            }
+        }
+
+        for (id, usage) in &self.usage {
+            ops.push(usage_op(*id, usage)?);
+        }
+
+        Ok(ops)
+    }
+
+    /// Get the space charged to `id`
+    pub fn usage(&self, id: QuotaId) -> QuotaUsage {
+        self.usage.get(&id).copied().unwrap_or_default()
+    }
+
+    /// Get the limits of `id`
+    pub fn limits(&self, id: QuotaId) -> QuotaLimits {
+        self.limits.get(&id).copied().unwrap_or_default()
+    }
+
+    /// Get every id with usage or limits, in order
+    pub fn ids(&self) -> Vec<QuotaId> {
+        let mut ids: Vec<QuotaId> = self.usage.keys().chain(self.limits.keys()).copied().collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Check that the changes don't take anyone over their limits, counting
+    /// the space that is reserved
+    ///
+    /// Changes that reduce usage are always allowed, even for ids that are
+    /// already over their limits.
+    pub fn allows(&self, changes: &[QuotaChange]) -> bool {
+        merge(changes).into_iter().all(|(id, (bytes, inodes))| {
+            let usage = self.usage(id);
+            let reserved = self.reserved.get(&id).copied().unwrap_or_default();
+            let usage = QuotaUsage {
+                bytes: usage.bytes.saturating_add(reserved.bytes),
+                inodes: usage.inodes.saturating_add(reserved.inodes),
+            };
+            let limits = self.limits(id);
+            let within = |used: u64, change: i64, limit: Option<u64>| match limit {
+                Some(limit) if change > 0 => add(used, change) <= limit,
+                _ => true,
+            };
+
+            within(usage.bytes, bytes, limits.bytes) && within(usage.inodes, inodes, limits.inodes)
+        })
+    }
+
+    /// Get the usage of every id that the changes apply to, as it will be
+    /// once they have been applied
+    pub fn updated(&self, changes: &[QuotaChange]) -> Vec<(QuotaId, QuotaUsage)> {
+        merge(changes)
+            .into_iter()
+            .map(|(id, (bytes, inodes))| {
+                let usage = self.usage(id);
+                (id, QuotaUsage { bytes: add(usage.bytes, bytes), inodes: add(usage.inodes, inodes) })
+            })
+            .collect()
+    }
+
+    /// Replace the usage of ids with usage returned by `updated`
+    pub fn update(&mut self, updated: Vec<(QuotaId, QuotaUsage)>) {
+        self.usage.extend(updated);
+    }
+
+    /// Reserve the space that the changes add if they are allowed, returning
+    /// whether they are
+    ///
+    /// The reservation must be released with `release` once the changes have
+    /// been committed or abandoned.
+    pub fn reserve(&mut self, changes: &[QuotaChange]) -> bool {
+        if !self.allows(changes) {
+            return false;
+        }
+
+        for (id, (bytes, inodes)) in merge(changes) {
+            if bytes <= 0 && inodes <= 0 {
+                continue;
+            }
+            let reserved = self.reserved.entry(id).or_default();
+            reserved.bytes = add(reserved.bytes, bytes.max(0));
+            reserved.inodes = add(reserved.inodes, inodes.max(0));
+        }
+        true
+    }
+
+    /// Release the space reserved for the changes by `reserve`
+    pub fn release(&mut self, changes: &[QuotaChange]) {
+        for (id, (bytes, inodes)) in merge(changes) {
+            if let Some(reserved) = self.reserved.get_mut(&id) {
+                reserved.bytes = add(reserved.bytes, -bytes.max(0));
+                reserved.inodes = add(reserved.inodes, -inodes.max(0));
+                if *reserved == QuotaUsage::default() {
+                    self.reserved.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+/// An id used to get the key prefixes of quota queries
+const QUOTA_ID_ANY: QuotaId = QuotaId { kind: QuotaKind::User, id: 0 };
+
+/// Get the write that stores the usage of `id`
+pub fn usage_op(id: QuotaId, usage: &QuotaUsage) -> PolyfsResult<BatchOp> {
+    Ok(BatchOp::Set(
+        KvQuery::QuotaUsage(id).get_key(),
+        try_to!(bincode::serialize(usage), "Could not serialize quota usage"),
+    ))
+}
+
+/// Set the limits of `id`, removing them if they are unlimited
+///
+/// A mounted filesystem applies the new limits within `LIMITS_REFRESH`.
+pub fn set_limits<S: KeyValueStore>(
+    kv_store: &S,
+    id: QuotaId,
+    limits: QuotaLimits,
+) -> PolyfsResult<()> {
+    let key = KvQuery::QuotaLimits(id).get_key();
+    if limits.is_unlimited() {
+        try_to!(kv_store.delete(key), "Could not delete quota limits");
+    } else {
+        try_to!(
+            kv_store.set(
+                key,
+                try_to!(bincode::serialize(&limits), "Could not serialize quota limits")
+            ),
+            "Could not write quota limits"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::filesystem::inode::{FileKind, Timestamp};
+
+    #[test]
+    fn limits_and_transfers() {
+        let mut inode = Inode::new(2, FileKind::RegularFile, 0o644, 1000, 100, Timestamp::default());
+        inode.size = 100;
+        inode.project = 7;
+        let user = QuotaId { kind: QuotaKind::User, id: 1000 };
+        let project = QuotaId { kind: QuotaKind::Project, id: 7 };
+
+        let mut quotas = Quotas::default();
+        quotas.limits.insert(project, QuotaLimits { bytes: Some(150), inodes: None });
+        assert!(quotas.allows(&charge(&inode, 100, 1)));
+        quotas.update(quotas.updated(&charge(&inode, 100, 1)));
+        assert_eq!(quotas.usage(project), QuotaUsage { bytes: 100, inodes: 1 });
+        assert!(!quotas.allows(&charge(&inode, 51, 0)));
+        assert!(quotas.allows(&charge(&inode, -10, 0)));
+
+        // Changing the owner only moves the charge between users
+        let mut chowned = inode.clone();
+        chowned.uid = 1001;
+        let changes = transfer(&inode, &chowned);
+        assert_eq!(changes.len(), 2);
+        quotas.update(quotas.updated(&changes));
+        assert_eq!(quotas.usage(user), QuotaUsage::default());
+        assert_eq!(quotas.usage(project), QuotaUsage { bytes: 100, inodes: 1 });
+    }
+
+    #[test]
+    fn reservations_count_towards_limits() {
+        let inode = Inode::new(2, FileKind::RegularFile, 0o644, 1000, 100, Timestamp::default());
+        let user = QuotaId { kind: QuotaKind::User, id: 1000 };
+
+        let mut quotas = Quotas::default();
+        quotas.limits.insert(user, QuotaLimits { bytes: Some(100), inodes: None });
+
+        // Two operations checked before either commits can't both fit
+        let first = charge(&inode, 60, 0);
+        assert!(quotas.reserve(&first));
+        assert!(!quotas.reserve(&charge(&inode, 60, 0)));
+        assert!(quotas.reserve(&charge(&inode, -10, 0)));
+
+        quotas.update(quotas.updated(&first));
+        quotas.release(&first);
+        assert!(quotas.reserve(&charge(&inode, 40, 0)));
+        assert!(!quotas.reserve(&charge(&inode, 1, 0)));
+    }
+}
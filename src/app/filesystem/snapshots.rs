@@ -0,0 +1,500 @@
+//! Point-in-time snapshots of the whole filesystem
+//!
+//! A snapshot copies the filesystem's metadata, and the `FileChunk` and
+//! `Version` keys that refer to its file contents, under
+//! `KvQuery::SnapshotPair(id, key)` in the store each pair came from. Every
+//! chunk gains a reference for each chunk a key of the snapshot refers to, but
+//! the chunks themselves are shared with the live filesystem and never copied.
+//! A snapshot therefore only takes up the space of its metadata and of the
+//! chunks that have changed since it was taken.
+//!
+//! Snapshots are created, deleted and restored while the filesystem is not
+//! mounted. They can be mounted read-only through `SnapshotView`.
+
+use super::chunks::{adjust_refs, decode_hash, ChunkHash};
+use super::inode::Timestamp;
+use super::quota::{QuotaId, QuotaKind};
+use super::types::KvQuery;
+use super::usage::Usage;
+use super::versions::Version;
+use crate::app::keyvalue::{BatchOp, KeyValueError, KeyValueResult, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+
+/// A snapshot of the filesystem
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// The name the snapshot was created with
+    pub name: String,
+    /// The id that the snapshot's pairs are stored under
+    pub id: u64,
+    /// When the snapshot was created
+    pub created: Timestamp,
+}
+
+/// The key prefixes of the metadata captured by a snapshot
+fn metadata_prefixes() -> Vec<Vec<u8>> {
+    vec![
+        KvQuery::FileAttributes(0).get_type_prefix(),
+        KvQuery::Files(0, OsStr::new("")).get_type_prefix(),
+        KvQuery::InodeChildren(0).get_type_prefix(),
+        KvQuery::Usage.get_type_prefix(),
+        KvQuery::QuotaUsage(QuotaId { kind: QuotaKind::User, id: 0 }).get_type_prefix(),
+        KvQuery::Trash(0).get_type_prefix(),
+    ]
+}
+
+/// Get the snapshot id from a `SnapshotPair` key
+pub fn pair_snapshot_id(key: &[u8]) -> PolyfsResult<u64> {
+    Ok(u64::from_be_bytes(try_to!(
+        key.get(1..9).unwrap_or_default().try_into(),
+        "Could not decode snapshot id"
+    )))
+}
+
+/// Get the captured key from a `SnapshotPair` key
+fn pair_key(key: &[u8]) -> &[u8] {
+    key.get(9..).unwrap_or_default()
+}
+
+/// The key prefixes of the pairs in the data store that refer to chunks and
+/// are captured by a snapshot
+fn data_prefixes() -> Vec<Vec<u8>> {
+    vec![
+        KvQuery::FileChunk(0, 0).get_type_prefix(),
+        KvQuery::Version(0, 0).get_type_prefix(),
+    ]
+}
+
+/// Whether or not a captured key is a `FileChunk` or `Version` key
+fn is_data_key(key: &[u8]) -> bool {
+    data_prefixes().iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Get the chunks that a captured pair refers to: the chunk of a `FileChunk`
+/// key, every chunk of a `Version` key, and none for metadata
+pub fn captured_refs(key: &[u8], value: &[u8]) -> PolyfsResult<Vec<ChunkHash>> {
+    if key.starts_with(&KvQuery::FileChunk(0, 0).get_type_prefix()) {
+        Ok(vec![decode_hash(value)?])
+    } else if key.starts_with(&KvQuery::Version(0, 0).get_type_prefix()) {
+        Ok(Version::decode(value)?.chunks.into_iter().map(|(_, hash)| hash).collect())
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// Get the pairs in the data store that refer to chunks
+fn data_pairs<S: KeyValueStore>(data_store: &S) -> PolyfsResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut pairs = vec![];
+    for prefix in data_prefixes() {
+        pairs.extend(try_to!(data_store.scan_prefix(prefix), "Could not read file chunk keys"));
+    }
+    Ok(pairs)
+}
+
+/// Get every snapshot, oldest first
+pub fn list_snapshots<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Vec<Snapshot>> {
+    let records = try_to!(
+        kv_store.scan_prefix(KvQuery::Snapshot("").get_type_prefix()),
+        "Could not read snapshots"
+    );
+
+    let mut snapshots = Vec::with_capacity(records.len());
+    for (_, record) in records {
+        let snapshot: Snapshot = try_to!(bincode::deserialize(&record), "Could not deserialize snapshot");
+        snapshots.push(snapshot);
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.created);
+
+    Ok(snapshots)
+}
+
+/// Get a snapshot by name
+pub fn find_snapshot<S: KeyValueStore>(kv_store: &S, name: &str) -> PolyfsResult<Snapshot> {
+    match try_to!(kv_store.get(KvQuery::Snapshot(name).get_key()), "Could not read snapshot") {
+        Some(record) => Ok(try_to!(bincode::deserialize(&record), "Could not deserialize snapshot")),
+        None => Err(PolyfsError {
+            message: format!("There is no snapshot named \"{}\"", name),
+            cause: None,
+        }),
+    }
+}
+
+/// Get the pairs captured by a snapshot from one store, with the keys they were
+/// captured from
+fn snapshot_pairs<S: KeyValueStore>(store: &S, id: u64) -> PolyfsResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let pairs = try_to!(
+        store.scan_prefix(KvQuery::SnapshotPair(id, &[]).get_prefix()),
+        "Could not read snapshot"
+    );
+
+    Ok(pairs
+        .into_iter()
+        .map(|(key, value)| (pair_key(&key).to_vec(), value))
+        .collect())
+}
+
+/// Count the chunks referred to by `FileChunk` and `Version` pairs
+fn count_refs(
+    pairs: &[(Vec<u8>, Vec<u8>)],
+    change: i64,
+    counts: &mut BTreeMap<ChunkHash, i64>,
+) -> PolyfsResult<()> {
+    for (key, value) in pairs {
+        for hash in captured_refs(key, value)? {
+            *counts.entry(hash).or_insert(0) += change;
+        }
+    }
+    Ok(())
+}
+
+/// Change the physical size in the usage totals, if they have been written
+fn update_physical<S: KeyValueStore>(kv_store: &S, usage: Option<Usage>, physical: i64) -> PolyfsResult<()> {
+    if let Some(mut usage) = usage {
+        usage.apply(0, physical, 0);
+        try_to!(
+            kv_store.set(KvQuery::Usage.get_key(), usage.encode()?),
+            "Could not write usage"
+        );
+    }
+    Ok(())
+}
+
+/// Take a snapshot of the filesystem
+///
+/// The `FileChunk` and `Version` keys are captured before the metadata, and
+/// the snapshot is recorded in the same batch as its metadata, so an
+/// interrupted snapshot never shows up. `collect_garbage` deletes what it
+/// leaves behind.
+pub fn create_snapshot<S: KeyValueStore>(
+    kv_store: &S,
+    data_store: &S,
+    name: &str,
+) -> PolyfsResult<Snapshot> {
+    if name.is_empty() {
+        return Err(PolyfsError {
+            message: String::from("Snapshot names can't be empty"),
+            cause: None,
+        });
+    }
+    if try_to!(kv_store.get(KvQuery::Snapshot(name).get_key()), "Could not read snapshot").is_some() {
+        return Err(PolyfsError {
+            message: format!("A snapshot named \"{}\" already exists", name),
+            cause: None,
+        });
+    }
+
+    // Random ids can't collide with the pairs of an interrupted snapshot
+    let ids: HashSet<u64> = list_snapshots(kv_store)?.iter().map(|s| s.id).collect();
+    let id = loop {
+        let id = rand::random::<u64>();
+        if !ids.contains(&id) {
+            break id;
+        }
+    };
+
+    let file_chunks = data_pairs(data_store)?;
+    let mut counts = BTreeMap::new();
+    count_refs(&file_chunks, 1, &mut counts)?;
+    let (mut ops, physical) = adjust_refs(data_store, &counts)?;
+    for (key, value) in file_chunks {
+        ops.push(BatchOp::Set(KvQuery::SnapshotPair(id, &key).get_key(), value));
+    }
+    try_to!(data_store.batch(ops), "Could not write snapshot of file chunks");
+
+    let snapshot = Snapshot {
+        name: String::from(name),
+        id,
+        created: Timestamp::now(),
+    };
+    let mut ops = vec![BatchOp::Set(
+        KvQuery::Snapshot(name).get_key(),
+        try_to!(bincode::serialize(&snapshot), "Could not serialize snapshot"),
+    )];
+    for prefix in metadata_prefixes() {
+        for (key, value) in try_to!(kv_store.scan_prefix(prefix), "Could not read metadata") {
+            ops.push(BatchOp::Set(KvQuery::SnapshotPair(id, &key).get_key(), value));
+        }
+    }
+    try_to!(kv_store.batch(ops), "Could not write snapshot of metadata");
+
+    // Chunks only gain stored bytes here if their counts were missing
+    if physical != 0 {
+        update_physical(kv_store, Usage::load(kv_store)?, physical)?;
+    }
+
+    Ok(snapshot)
+}
+
+/// Delete a snapshot
+///
+/// Chunks that were only referred to by the snapshot are left for
+/// `collect_garbage`.
+pub fn delete_snapshot<S: KeyValueStore>(kv_store: &S, data_store: &S, name: &str) -> PolyfsResult<()> {
+    let snapshot = find_snapshot(kv_store, name)?;
+
+    // The file chunk and version keys go first so that reference counts are
+    // released even if the rest of the deletion is interrupted. Deleting again
+    // finishes it.
+    let file_chunks: Vec<_> = snapshot_pairs(data_store, snapshot.id)?
+        .into_iter()
+        .filter(|(key, _)| is_data_key(key))
+        .collect();
+    let mut counts = BTreeMap::new();
+    count_refs(&file_chunks, -1, &mut counts)?;
+    let (mut ops, physical) = adjust_refs(data_store, &counts)?;
+    for (key, _) in file_chunks {
+        ops.push(BatchOp::Delete(KvQuery::SnapshotPair(snapshot.id, &key).get_key()));
+    }
+    try_to!(data_store.batch(ops), "Could not delete snapshot of file chunks");
+
+    let mut ops = vec![];
+    for (key, _) in snapshot_pairs(kv_store, snapshot.id)? {
+        ops.push(BatchOp::Delete(KvQuery::SnapshotPair(snapshot.id, &key).get_key()));
+    }
+    ops.push(BatchOp::Delete(KvQuery::Snapshot(name).get_key()));
+    try_to!(kv_store.batch(ops), "Could not delete snapshot of metadata");
+
+    update_physical(kv_store, Usage::load(kv_store)?, physical)
+}
+
+/// Replace the filesystem with the contents of a snapshot
+///
+/// The snapshot is kept, so the filesystem can be restored to it again.
+pub fn restore_snapshot<S: KeyValueStore>(kv_store: &S, data_store: &S, name: &str) -> PolyfsResult<()> {
+    let snapshot = find_snapshot(kv_store, name)?;
+    let usage = Usage::load(kv_store)?;
+
+    let live_chunks = data_pairs(data_store)?;
+    let (snapshot_chunks, snapshot_metadata): (Vec<_>, Vec<_>) = snapshot_pairs(data_store, snapshot.id)?
+        .into_iter()
+        .partition(|(key, _)| is_data_key(key));
+
+    let mut counts = BTreeMap::new();
+    count_refs(&live_chunks, -1, &mut counts)?;
+    count_refs(&snapshot_chunks, 1, &mut counts)?;
+    let (mut ops, physical) = adjust_refs(data_store, &counts)?;
+    for (key, _) in live_chunks {
+        ops.push(BatchOp::Delete(key));
+    }
+    for (key, value) in snapshot_chunks {
+        ops.push(BatchOp::Set(key, value));
+    }
+    try_to!(data_store.batch(ops), "Could not restore file chunks");
+
+    // With a single store, the metadata pairs were read along with the chunks
+    let snapshot_metadata = if std::ptr::eq(kv_store, data_store) {
+        snapshot_metadata
+    } else {
+        snapshot_pairs(kv_store, snapshot.id)?
+    };
+    let mut ops = vec![];
+    for prefix in metadata_prefixes() {
+        for key in try_to!(kv_store.scan_prefix(prefix), "Could not read metadata")
+            .into_iter()
+            .map(|(key, _)| key)
+        {
+            ops.push(BatchOp::Delete(key));
+        }
+    }
+    for (key, value) in snapshot_metadata {
+        ops.push(BatchOp::Set(key, value));
+    }
+    try_to!(kv_store.batch(ops), "Could not restore metadata");
+
+    // The logical totals are restored with the metadata, but the stored bytes
+    // follow the reference counts
+    match (Usage::load(kv_store)?, usage) {
+        (Some(mut restored), Some(usage)) => {
+            restored.physical_bytes = usage.physical_bytes;
+            update_physical(kv_store, Some(restored), physical)
+        }
+        // Without live totals to go by, count them again on the next mount
+        (Some(_), None) => {
+            try_to!(kv_store.delete(KvQuery::Usage.get_key()), "Could not delete usage");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A read-only view of the pairs captured by a snapshot in one store
+///
+/// Keys are read as they were when the snapshot was taken, so the view can be
+/// used as the store of a read-only filesystem. Chunks are shared with the live
+/// filesystem and read from it directly.
+pub struct SnapshotView<S: KeyValueStore> {
+    inner: S,
+    id: u64,
+}
+
+impl<S: KeyValueStore> SnapshotView<S> {
+    /// View the pairs of the snapshot with the id `id` in `inner`
+    pub fn new(inner: S, id: u64) -> SnapshotView<S> {
+        SnapshotView { inner, id }
+    }
+}
+
+impl<S: KeyValueStore> KeyValueStore for SnapshotView<S> {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        if key.starts_with(&KvQuery::Chunk(&[]).get_type_prefix()) {
+            return self.inner.get(key);
+        }
+        self.inner.get(KvQuery::SnapshotPair(self.id, &key).get_key())
+    }
+
+    fn set(&self, _key: Vec<u8>, _value: Vec<u8>) -> KeyValueResult<()> {
+        Err(KeyValueError::ReadOnly)
+    }
+
+    fn delete(&self, _key: Vec<u8>) -> KeyValueResult<()> {
+        Err(KeyValueError::ReadOnly)
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        Ok(self
+            .scan_prefix(vec![])?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .inner
+            .scan_prefix(KvQuery::SnapshotPair(self.id, &prefix).get_key())?
+            .into_iter()
+            .map(|(key, value)| (pair_key(&key).to_vec(), value))
+            .collect())
+    }
+
+    fn batch(&self, _ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        Err(KeyValueError::ReadOnly)
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: Vec<u8>,
+        _expected: Option<Vec<u8>>,
+        _new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        Err(KeyValueError::ReadOnly)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+    use crate::app::filesystem::chunks::collect_garbage;
+    use crate::app::filesystem::inode::FileKind;
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::app::filesystem::versions::VERSIONS_XATTR;
+    use crate::app::filesystem::FilesystemConfig;
+    use std::sync::Arc;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn errno(errno: i32) -> String {
+        format!("Operation failed with errno {}", errno)
+    }
+
+    #[test]
+    fn snapshot_mount_and_restore() -> TestResult {
+        let (kv_store, data_store) = (Arc::new(MemoryKvStore::new()), Arc::new(MemoryKvStore::new()));
+        snapshot_mount_and_restore_in(&kv_store, Some(&data_store))?;
+
+        // Metadata and file contents may share a single store
+        snapshot_mount_and_restore_in(&Arc::new(MemoryKvStore::new()), None)
+    }
+
+    fn snapshot_mount_and_restore_in(
+        kv_store: &Arc<MemoryKvStore>,
+        data_store: Option<&Arc<MemoryKvStore>>,
+    ) -> TestResult {
+        let core = FilesystemCore::new(kv_store.clone(), data_store.cloned(), FilesystemConfig::default(), false);
+        core.init().map_err(errno)?;
+        let data_store = data_store.unwrap_or(kv_store);
+
+        let name = OsStr::new("file");
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, 1, name, 0o644)
+            .map_err(errno)?
+            .ino;
+        core.write(ino, 0, b"before").map_err(errno)?;
+        let snapshot = create_snapshot(kv_store, data_store, "first")?;
+        assert!(create_snapshot(kv_store, data_store, "first").is_err());
+
+        core.write(ino, 0, b"after!").map_err(errno)?;
+        core.create_file(FileKind::RegularFile, 1000, 1000, 1, OsStr::new("new"), 0o644)
+            .map_err(errno)?;
+
+        // The snapshot still has the old contents and can't be changed
+        let view = FilesystemCore::new(
+            SnapshotView::new(kv_store.clone(), snapshot.id),
+            Some(SnapshotView::new(data_store.clone(), snapshot.id)),
+            FilesystemConfig::default(),
+            true,
+        );
+        view.init().map_err(errno)?;
+        assert_eq!(view.read(ino, 0, 100).map_err(errno)?, b"before");
+        assert_eq!(view.lookup(1, OsStr::new("new")).map(|_| ()), Err(libc::ENOENT));
+        assert_eq!(view.write(ino, 0, b"x"), Err(libc::EROFS));
+        assert_eq!(core.read(ino, 0, 100).map_err(errno)?, b"after!");
+
+        restore_snapshot(kv_store, data_store, "first")?;
+        assert_eq!(core.read(ino, 0, 100).map_err(errno)?, b"before");
+        assert_eq!(core.lookup(1, OsStr::new("new")).map(|_| ()), Err(libc::ENOENT));
+        assert_eq!(list_snapshots(kv_store)?, vec![snapshot]);
+
+        delete_snapshot(kv_store, data_store, "first")?;
+        assert!(list_snapshots(kv_store)?.is_empty());
+        let report = collect_garbage(kv_store, data_store, false)?;
+        assert_eq!(report.chunks_deleted, 1);
+        assert_eq!(report.refcounts_fixed + report.orphaned_snapshot_pairs, 0);
+        assert_eq!(core.read(ino, 0, 100).map_err(errno)?, b"before");
+
+        Ok(())
+    }
+
+    #[test]
+    fn versions_are_captured_and_restored() -> TestResult {
+        let (kv_store, data_store) = (Arc::new(MemoryKvStore::new()), Arc::new(MemoryKvStore::new()));
+        let core = FilesystemCore::new(kv_store.clone(), Some(data_store.clone()), FilesystemConfig::default(), false);
+        core.init().map_err(errno)?;
+
+        let dir = core
+            .create_file(FileKind::Directory, 1000, 1000, 1, OsStr::new("dir"), 0o755)
+            .map_err(errno)?;
+        core.setxattr(dir.ino, OsStr::new(VERSIONS_XATTR), b"keep=5", 0).map_err(errno)?;
+        let ino = core
+            .create_file(FileKind::RegularFile, 1000, 1000, dir.ino, OsStr::new("a"), 0o644)
+            .map_err(errno)?
+            .ino;
+        for content in &[b"one", b"two"] {
+            core.write(ino, 0, *content).map_err(errno)?;
+            core.release(ino);
+        }
+        create_snapshot(&*kv_store, &*data_store, "first")?;
+
+        // Removing the file deletes its versions, but the snapshot keeps them
+        core.remove_file(dir.ino, OsStr::new("a")).map_err(errno)?;
+        let report = collect_garbage(&*kv_store, &*data_store, false)?;
+        assert_eq!(report.refcounts_fixed + report.chunks_missing, 0);
+
+        restore_snapshot(&*kv_store, &*data_store, "first")?;
+        assert_eq!(core.versions(ino).map_err(errno)?.len(), 1);
+        core.restore_version(ino, 1).map_err(errno)?;
+        assert_eq!(core.read(ino, 0, 100).map_err(errno)?, b"one");
+
+        delete_snapshot(&*kv_store, &*data_store, "first")?;
+        let report = collect_garbage(&*kv_store, &*data_store, false)?;
+        assert_eq!(report.refcounts_fixed + report.chunks_missing, 0);
+
+        Ok(())
+    }
+}
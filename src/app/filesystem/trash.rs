@@ -0,0 +1,131 @@
+//! Soft deletion of files into a trash that they can be restored from
+//!
+//! When the trash is enabled, removing a file only removes its directory
+//! entry: the inode and its contents stay in the store, and a `TrashEntry`
+//! recording where the file was is stored under `KvQuery::Trash(ino)`. Trashed
+//! files can't be reached through the filesystem, but still count towards the
+//! usage totals and quotas until they are purged.
+//!
+//! A directory removed with `rm -r` is trashed after its contents, which keep
+//! pointing to it as their parent. Restoring the directory therefore restores
+//! its contents too.
+
+use super::inode::{FileKind, Timestamp};
+use super::types::KvQuery;
+use crate::app::keyvalue::KeyValueStore;
+use crate::{try_to, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// Trash configuration
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TrashConfig {
+    /// Whether removed files are moved to the trash instead of being deleted
+    #[serde(default)]
+    pub enabled: bool,
+    /// The number of days that files are kept in the trash before they are
+    /// purged while the filesystem is mounted, or `None` to keep them until
+    /// they are purged with `polyfs trash purge`
+    #[serde(default)]
+    pub retention_days: Option<u64>,
+}
+
+/// A file in the trash
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrashEntry {
+    /// The ino of the trashed file
+    pub ino: u64,
+    /// The directory the file was removed from
+    pub parent: u64,
+    /// The name the file had in `parent`
+    pub name: String,
+    /// The kind of the file
+    pub kind: FileKind,
+    /// When the file was removed
+    pub deleted: Timestamp,
+}
+
+impl TrashEntry {
+    /// Encode the entry to be stored under `KvQuery::Trash`
+    pub fn encode(&self) -> PolyfsResult<Vec<u8>> {
+        Ok(try_to!(bincode::serialize(self), "Could not serialize trash entry"))
+    }
+
+    /// Decode an entry stored under `KvQuery::Trash`
+    pub fn decode(value: &[u8]) -> PolyfsResult<TrashEntry> {
+        Ok(try_to!(bincode::deserialize(value), "Could not deserialize trash entry"))
+    }
+}
+
+/// List the files in the trash, oldest first
+pub fn list_trash<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Vec<TrashEntry>> {
+    let entries = try_to!(
+        kv_store.scan_prefix(KvQuery::Trash(0).get_type_prefix()),
+        "Could not read trash"
+    );
+
+    let mut entries = entries
+        .iter()
+        .map(|(_, value)| TrashEntry::decode(value))
+        .collect::<PolyfsResult<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.deleted);
+
+    Ok(entries)
+}
+
+/// Get the paths that trashed files had before they were removed, by ino
+///
+/// Paths are found by walking every directory that can be reached from the
+/// root, because directories don't record their parents. The path of a file
+/// whose directory is gone starts with `?`.
+pub fn original_paths<S: KeyValueStore>(
+    kv_store: &S,
+    entries: &[TrashEntry],
+) -> PolyfsResult<HashMap<u64, String>> {
+    let mut directories: HashMap<u64, String> = HashMap::new();
+    directories.insert(1, String::new());
+
+    let mut pending = VecDeque::from(vec![1]);
+    while let Some(ino) = pending.pop_front() {
+        let children: Vec<(u64, FileKind, String)> =
+            match try_to!(kv_store.get(KvQuery::InodeChildren(ino).get_key()), "Could not read directory") {
+                Some(data) => try_to!(bincode::deserialize(&data), "Could not deserialize directory"),
+                None => continue,
+            };
+
+        let path = directories[&ino].clone();
+        for (child, kind, name) in children {
+            if kind == FileKind::Directory && !directories.contains_key(&child) {
+                directories.insert(child, format!("{}/{}", path, name));
+                pending.push_back(child);
+            }
+        }
+    }
+
+    let trashed: HashMap<u64, &TrashEntry> = entries.iter().map(|entry| (entry.ino, entry)).collect();
+    let mut paths = HashMap::new();
+    for entry in entries {
+        // Follow trashed parents up to a directory that still exists
+        let mut names = vec![entry.name.as_str()];
+        let mut parent = entry.parent;
+        let root = loop {
+            if let Some(path) = directories.get(&parent) {
+                break path.as_str();
+            }
+            match trashed.get(&parent) {
+                Some(trashed_parent) if names.len() <= entries.len() => {
+                    names.push(&trashed_parent.name);
+                    parent = trashed_parent.parent;
+                }
+                _ => break "?",
+            }
+        };
+
+        names.reverse();
+        paths.insert(entry.ino, format!("{}/{}", root, names.join("/")));
+    }
+
+    Ok(paths)
+}
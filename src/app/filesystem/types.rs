@@ -1,6 +1,10 @@
+//! Types used to represent the filesystem in the KV store
+
+use super::inode::{FileKind, Inode, Timestamp};
+use super::quota::QuotaId;
 use fuse::{FileAttr, FileType};
-use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use time::Timespec;
 
 /// Represents a query for a virtual table in the KV store
@@ -14,93 +18,232 @@ pub enum KvQuery<'a> {
     Files(u64, &'a OsStr),
     /// Query inode children by ino
     InodeChildren(u64),
+    /// Query the hash of a chunk of file content by ino and chunk index
+    FileChunk(u64, u64),
+    /// Query the totals of the space used by the filesystem
+    Usage,
+    /// Query the content of a chunk by its hash
+    Chunk(&'a [u8]),
+    /// Query the reference count of a chunk by its hash
+    ChunkRefs(&'a [u8]),
+    /// Query a snapshot by name
+    Snapshot(&'a str),
+    /// Query a key-value pair captured by a snapshot by snapshot id and the
+    /// key the pair had when it was captured
+    SnapshotPair(u64, &'a [u8]),
+    /// Query the space charged to a user, group or project
+    QuotaUsage(QuotaId),
+    /// Query the quota limits of a user, group or project
+    QuotaLimits(QuotaId),
+    /// Query a file in the trash by ino
+    Trash(u64),
+    /// Query a previous content of a file by ino and version number
+    Version(u64, u64),
+    /// Query the random id of the filesystem, which is written to both the
+    /// metadata store and the data store so that they can be matched up
+    FilesystemId,
+    /// Query the version of the on-disk layout used by the KV store
+    ///
+    /// The key for this query must never change between layout versions so
+    /// that any version of PolyFS can tell which layout a store was written in.
+    LayoutVersion,
 }
 
 impl<'a> KvQuery<'a> {
-    /// Generate a `Vec<u8>` key for representing the query as a key in the KV
-    /// store
-    pub fn get_key(self) -> Vec<u8> {
-        let prefix = match &self {
+    fn prefix(&self) -> u8 {
+        match self {
             KvQuery::FileAttributes(_) => 0u8,
             KvQuery::Files(_, _) => 1u8,
             KvQuery::InodeChildren(_) => 2u8,
-        };
+            KvQuery::FileChunk(_, _) => 3u8,
+            KvQuery::Usage => 4u8,
+            KvQuery::Chunk(_) => 5u8,
+            KvQuery::ChunkRefs(_) => 6u8,
+            KvQuery::Snapshot(_) => 7u8,
+            KvQuery::SnapshotPair(_, _) => 8u8,
+            KvQuery::QuotaUsage(_) => 9u8,
+            KvQuery::QuotaLimits(_) => 10u8,
+            KvQuery::Trash(_) => 11u8,
+            KvQuery::Version(_, _) => 12u8,
+            KvQuery::FilesystemId => 13u8,
+            // 253 and 254 are used by migrations ( see `migration::migrate` )
+            KvQuery::LayoutVersion => 255u8,
+        }
+    }
+
+    /// Generate a `Vec<u8>` key for representing the query as a key in the KV
+    /// store
+    ///
+    /// Keys are order preserving: integers are encoded big-endian and names are
+    /// escaped and terminated ( see `escape_bytes` ), so sorting keys by their
+    /// bytes sorts them by their fields. All keys that share a leading field
+    /// therefore form a contiguous range that can be scanned with the key
+    /// returned by `get_prefix`.
+    pub fn get_key(self) -> Vec<u8> {
+        let mut vec = vec![self.prefix()];
 
         match self {
             KvQuery::FileAttributes(ino) => {
-                let mut vec = vec![prefix];
-                vec.extend_from_slice(&u64::to_le_bytes(ino));
-
-                vec
+                vec.extend_from_slice(&u64::to_be_bytes(ino));
             }
             KvQuery::Files(ino, filename) => {
-                let mut vec = vec![prefix];
-                vec.extend_from_slice(&u64::to_le_bytes(ino));
-                vec.extend_from_slice(
-                    filename
-                        .to_str()
-                        .expect("Error parsing bytes in filename")
-                        .as_bytes(),
-                );
-
-                vec
+                vec.extend_from_slice(&u64::to_be_bytes(ino));
+                escape_bytes(filename.as_bytes(), &mut vec);
+            }
+            KvQuery::InodeChildren(ino) | KvQuery::Trash(ino) => {
+                vec.extend_from_slice(&u64::to_be_bytes(ino));
             }
-            KvQuery::InodeChildren(ino) => {
-                let mut vec = vec![prefix];
-                vec.extend_from_slice(&u64::to_le_bytes(ino));
+            KvQuery::FileChunk(ino, index) | KvQuery::Version(ino, index) => {
+                vec.extend_from_slice(&u64::to_be_bytes(ino));
+                vec.extend_from_slice(&u64::to_be_bytes(index));
+            }
+            KvQuery::Chunk(hash) | KvQuery::ChunkRefs(hash) => {
+                vec.extend_from_slice(hash);
+            }
+            KvQuery::Snapshot(name) => {
+                escape_bytes(name.as_bytes(), &mut vec);
+            }
+            KvQuery::SnapshotPair(id, key) => {
+                vec.extend_from_slice(&u64::to_be_bytes(id));
+                vec.extend_from_slice(key);
+            }
+            KvQuery::QuotaUsage(id) | KvQuery::QuotaLimits(id) => {
+                id.encode(&mut vec);
+            }
+            KvQuery::Usage | KvQuery::FilesystemId | KvQuery::LayoutVersion => (),
+        }
+
+        vec
+    }
+
+    /// Generate the key prefix shared by all keys of the same query type with
+    /// the same leading ino, or snapshot id for `SnapshotPair`
+    ///
+    /// For example, the prefix for `Files(parent, _)` can be used to scan all of
+    /// the files in the `parent` directory. Queries without an ino produce the
+    /// prefix shared by all keys of that type.
+    pub fn get_prefix(self) -> Vec<u8> {
+        let mut vec = vec![self.prefix()];
+
+        match self {
+            KvQuery::FileAttributes(ino)
+            | KvQuery::Files(ino, _)
+            | KvQuery::InodeChildren(ino)
+            | KvQuery::FileChunk(ino, _)
+            | KvQuery::SnapshotPair(ino, _)
+            | KvQuery::Trash(ino)
+            | KvQuery::Version(ino, _) => vec.extend_from_slice(&u64::to_be_bytes(ino)),
+            KvQuery::Usage
+            | KvQuery::Chunk(_)
+            | KvQuery::ChunkRefs(_)
+            | KvQuery::Snapshot(_)
+            | KvQuery::QuotaUsage(_)
+            | KvQuery::QuotaLimits(_)
+            | KvQuery::FilesystemId
+            | KvQuery::LayoutVersion => (),
+        }
 
-                vec
+        vec
+    }
+
+    /// Generate the key prefix shared by all keys of the same query type,
+    /// regardless of their fields
+    pub fn get_type_prefix(self) -> Vec<u8> {
+        vec![self.prefix()]
+    }
+}
+
+/// Append `bytes` to `out` in an order preserving, self-terminating encoding
+///
+/// Every `0x00` byte is escaped as `0x00 0xFF` and the encoded bytes are
+/// terminated with `0x00 0x00`. This keeps the byte order of encoded values the
+/// same as the original values while making it possible to place other fields
+/// after them in a key.
+pub fn escape_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for byte in bytes {
+        out.push(*byte);
+        if *byte == 0x00 {
+            out.push(0xFF);
+        }
+    }
+    out.extend_from_slice(&[0x00, 0x00]);
+}
+
+/// Decode bytes encoded by `escape_bytes` from the start of `encoded`,
+/// returning them together with the rest of `encoded` after the terminator
+///
+/// Returns `None` if `encoded` doesn't start with a valid encoding.
+pub fn unescape_bytes(encoded: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let mut bytes = vec![];
+    let mut index = 0;
+
+    while index < encoded.len() {
+        match (encoded[index], encoded.get(index + 1)) {
+            (0x00, Some(0x00)) => return Some((bytes, &encoded[index + 2..])),
+            (0x00, Some(0xFF)) => {
+                bytes.push(0x00);
+                index += 2;
             }
+            (0x00, _) => return None,
+            (byte, _) => {
+                bytes.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    None
+}
+
+impl From<FileKind> for FileType {
+    fn from(kind: FileKind) -> FileType {
+        match kind {
+            FileKind::NamedPipe => FileType::NamedPipe,
+            FileKind::CharDevice => FileType::CharDevice,
+            FileKind::BlockDevice => FileType::BlockDevice,
+            FileKind::Directory => FileType::Directory,
+            FileKind::RegularFile => FileType::RegularFile,
+            FileKind::Symlink => FileType::Symlink,
+            FileKind::Socket => FileType::Socket,
         }
     }
 }
 
-/// A serializable wrapper for `fuse::FileAttr`
-#[derive(Serialize, Deserialize)]
-pub struct SerdeFileAttr(#[serde(with = "FileAttrDef")] pub FileAttr);
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "FileAttr")]
-struct FileAttrDef {
-    pub ino: u64,
-    pub size: u64,
-    pub blocks: u64,
-    #[serde(with = "TimespecDef")]
-    pub atime: Timespec,
-    #[serde(with = "TimespecDef")]
-    pub mtime: Timespec,
-    #[serde(with = "TimespecDef")]
-    pub ctime: Timespec,
-    #[serde(with = "TimespecDef")]
-    pub crtime: Timespec,
-    #[serde(with = "FileTypeDef")]
-    pub kind: FileType,
-    pub perm: u16,
-    pub nlink: u32,
-    pub uid: u32,
-    pub gid: u32,
-    pub rdev: u32,
-    pub flags: u32,
+impl From<Timestamp> for Timespec {
+    fn from(time: Timestamp) -> Timespec {
+        Timespec {
+            sec: time.sec,
+            nsec: time.nsec as i32,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "Timespec")]
-struct TimespecDef {
-    pub sec: i64,
-    pub nsec: i32,
+impl From<Timespec> for Timestamp {
+    fn from(time: Timespec) -> Timestamp {
+        Timestamp {
+            sec: time.sec,
+            nsec: time.nsec as u32,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct SerdeFileType(#[serde(with = "FileTypeDef")] pub FileType);
-
-#[derive(Serialize, Deserialize)]
-#[serde(remote = "FileType")]
-enum FileTypeDef {
-    NamedPipe,
-    CharDevice,
-    BlockDevice,
-    Directory,
-    RegularFile,
-    Symlink,
-    Socket,
+impl From<&Inode> for FileAttr {
+    fn from(inode: &Inode) -> FileAttr {
+        FileAttr {
+            ino: inode.ino,
+            size: inode.size,
+            blocks: inode.blocks,
+            atime: inode.atime.into(),
+            mtime: inode.mtime.into(),
+            ctime: inode.ctime.into(),
+            crtime: inode.crtime.into(),
+            kind: inode.kind.into(),
+            perm: inode.perm,
+            nlink: inode.nlink,
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev: inode.rdev,
+            flags: inode.flags,
+        }
+    }
 }
@@ -0,0 +1,87 @@
+//! Totals of the space used by the filesystem
+//!
+//! The totals are kept in the metadata store and updated in the same batch as
+//! the metadata of the change that caused them, so that they stay in step with
+//! the files in the store.
+
+use super::chunks::ChunkRefs;
+use super::inode::Inode;
+use super::types::KvQuery;
+use crate::app::keyvalue::KeyValueStore;
+use crate::{try_to, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+
+/// The space used by the filesystem
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    /// The total size of every file, as seen by users of the filesystem
+    pub logical_bytes: u64,
+    /// The total size of the stored file chunks, after compression and
+    /// deduplication
+    pub physical_bytes: u64,
+    /// The number of inodes
+    pub files: u64,
+}
+
+/// Add a signed change to a total
+pub fn add(total: u64, change: i64) -> u64 {
+    if change < 0 {
+        total.saturating_sub(change.unsigned_abs())
+    } else {
+        total.saturating_add(change as u64)
+    }
+}
+
+impl Usage {
+    /// Add the signed changes to the totals
+    pub fn apply(&mut self, logical: i64, physical: i64, files: i64) {
+        self.logical_bytes = add(self.logical_bytes, logical);
+        self.physical_bytes = add(self.physical_bytes, physical);
+        self.files = add(self.files, files);
+    }
+
+    /// Encode the totals to be stored under `KvQuery::Usage`
+    pub fn encode(&self) -> PolyfsResult<Vec<u8>> {
+        Ok(try_to!(bincode::serialize(self), "Could not serialize usage"))
+    }
+
+    /// Read the totals from the metadata store, if they have been written
+    pub fn load<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Option<Usage>> {
+        match try_to!(kv_store.get(KvQuery::Usage.get_key()), "Could not read usage") {
+            Some(data) => Ok(Some(try_to!(
+                bincode::deserialize(&data),
+                "Could not deserialize usage"
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Count the totals by reading every inode and chunk reference count in
+    /// the stores
+    ///
+    /// This reads every inode, so it should only be needed once for stores
+    /// written before the totals were kept.
+    pub fn count<S: KeyValueStore>(kv_store: &S, data_store: &S) -> PolyfsResult<Usage> {
+        let mut usage = Usage::default();
+
+        let inodes = try_to!(
+            kv_store.scan_prefix(KvQuery::FileAttributes(0).get_type_prefix()),
+            "Could not read inodes"
+        );
+        for (_, record) in inodes {
+            usage.logical_bytes += Inode::decode(&record)?.size;
+            usage.files += 1;
+        }
+
+        let refs = try_to!(
+            data_store.scan_prefix(KvQuery::ChunkRefs(&[]).get_type_prefix()),
+            "Could not read chunk reference counts"
+        );
+        for (_, record) in refs {
+            usage.physical_bytes += ChunkRefs::decode(&record)?.size;
+        }
+
+        Ok(usage)
+    }
+}
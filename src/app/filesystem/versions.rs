@@ -0,0 +1,206 @@
+//! Version history of files
+//!
+//! Files whose `user.polyfs.versions` extended attribute holds a
+//! `VersionPolicy` keep their previous content as a version each time they are
+//! changed after being closed. A version records the chunks the file had,
+//! which gain a reference for it like they do for a snapshot, so keeping a
+//! version doesn't copy any content.
+//!
+//! | Key                     | Value               |
+//! | ----------------------- | ------------------- |
+//! | `Version(ino, number)`  | `Version` record    |
+//!
+//! Versions are kept in the data store with the chunks they refer to, so that
+//! the version and the reference counts are written in the same batch.
+//!
+//! The attribute is inherited by files and directories created in a directory
+//! that has it, and setting or removing it on a directory does the same for
+//! everything in the directory. The versions of the files in a directory can be
+//! browsed in its virtual `.versions` directory, as `.versions/<file>/<number>`.
+
+use super::chunks::ChunkHash;
+use super::inode::{Inode, Timestamp};
+use super::types::KvQuery;
+use crate::app::keyvalue::KeyValueStore;
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The extended attribute holding the versions policy of a file
+pub const VERSIONS_XATTR: &str = "user.polyfs.versions";
+
+/// The name of the virtual directory holding the versions of the files in a
+/// directory
+pub const VERSIONS_DIR: &str = ".versions";
+
+/// How many versions of a file to keep, and for how long
+///
+/// Written as comma-separated `keep=<count>` and `days=<days>` settings, e.g.
+/// `keep=10,days=30`. Either may be left out for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VersionPolicy {
+    /// The most versions to keep
+    pub keep: Option<usize>,
+    /// The most days to keep a version for
+    pub days: Option<u64>,
+}
+
+impl VersionPolicy {
+    /// Parse a policy from the value of `VERSIONS_XATTR`
+    pub fn parse(value: &[u8]) -> PolyfsResult<VersionPolicy> {
+        let invalid = || PolyfsError {
+            message: format!("Invalid versions policy: {}", String::from_utf8_lossy(value)),
+            cause: None,
+        };
+
+        let mut policy = VersionPolicy::default();
+        let value = std::str::from_utf8(value).map_err(|_| invalid())?;
+        for setting in value.trim().split(',').filter(|setting| !setting.is_empty()) {
+            let mut parts = setting.splitn(2, '=');
+            match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+                (Some("keep"), Some(count)) => policy.keep = Some(count.parse().map_err(|_| invalid())?),
+                (Some("days"), Some(days)) => policy.days = Some(days.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Get the policy of a file, if it has one that keeps any versions
+    pub fn of(inode: &Inode) -> Option<VersionPolicy> {
+        let value = inode.xattrs.get(VERSIONS_XATTR.as_bytes())?;
+        match VersionPolicy::parse(value) {
+            Ok(policy) if policy.keep != Some(0) => Some(policy),
+            Ok(_) => None,
+            Err(error) => {
+                log::warn!("Ignoring the versions policy of ino({}): {}", inode.ino, error);
+                None
+            }
+        }
+    }
+
+    /// Get the versions that the policy no longer keeps at `now`, out of
+    /// `versions` ordered oldest first
+    pub fn expired<'a>(&self, versions: &'a [Version], now: Timestamp) -> &'a [Version] {
+        let mut expired = match self.keep {
+            Some(keep) => versions.len().saturating_sub(keep),
+            None => 0,
+        };
+        if let Some(days) = self.days {
+            let age = days.saturating_mul(24 * 60 * 60).min(i64::MAX as u64) as i64;
+            while expired < versions.len() && now.seconds_since(versions[expired].kept) > age {
+                expired += 1;
+            }
+        }
+
+        &versions[..expired]
+    }
+}
+
+/// A previous content of a file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    /// The number of the version, counting up from 1 for each file
+    pub number: u64,
+    /// The size the file had
+    pub size: u64,
+    /// The modification time the file had
+    pub mtime: Timestamp,
+    /// When the version was kept
+    pub kept: Timestamp,
+    /// The hash of the chunk at each index of the file
+    pub chunks: Vec<(u64, ChunkHash)>,
+}
+
+impl Version {
+    /// Encode the version to be stored under `KvQuery::Version`
+    pub fn encode(&self) -> PolyfsResult<Vec<u8>> {
+        Ok(try_to!(bincode::serialize(self), "Could not serialize version"))
+    }
+
+    /// Decode a version stored under `KvQuery::Version`
+    pub fn decode(value: &[u8]) -> PolyfsResult<Version> {
+        Ok(try_to!(bincode::deserialize(value), "Could not deserialize version"))
+    }
+}
+
+/// List the versions of a file, oldest first
+pub fn list_versions<S: KeyValueStore>(data_store: &S, ino: u64) -> PolyfsResult<Vec<Version>> {
+    let versions = try_to!(
+        data_store.scan_prefix(KvQuery::Version(ino, 0).get_prefix()),
+        "Could not read versions"
+    );
+
+    versions.iter().map(|(_, value)| Version::decode(value)).collect()
+}
+
+/// A directory or file that only exists in the mounted filesystem, to browse
+/// versions through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VirtualNode {
+    /// The `.versions` directory of a directory
+    VersionsDir(u64),
+    /// The directory holding the versions of a file
+    FileVersions(u64),
+    /// A version of a file, by the file's ino and the version number
+    Version(u64, u64),
+}
+
+/// The inos given to virtual nodes while the filesystem is mounted
+#[derive(Debug, Default)]
+pub struct VirtualNodes {
+    by_ino: HashMap<u64, VirtualNode>,
+    by_node: HashMap<VirtualNode, u64>,
+}
+
+impl VirtualNodes {
+    /// Get the node with an ino
+    pub fn get(&self, ino: u64) -> Option<VirtualNode> {
+        self.by_ino.get(&ino).copied()
+    }
+
+    /// Get the ino of a node, if it has been given one
+    pub fn ino(&self, node: VirtualNode) -> Option<u64> {
+        self.by_node.get(&node).copied()
+    }
+
+    /// Give a node an ino that no other file has
+    pub fn insert(&mut self, node: VirtualNode, ino: u64) {
+        self.by_ino.insert(ino, node);
+        self.by_node.insert(node, ino);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policies() -> PolyfsResult<()> {
+        assert_eq!(
+            VersionPolicy::parse(b"keep=3, days=2")?,
+            VersionPolicy { keep: Some(3), days: Some(2) }
+        );
+        assert_eq!(VersionPolicy::parse(b"")?, VersionPolicy::default());
+        assert!(VersionPolicy::parse(b"keep=many").is_err());
+        assert!(VersionPolicy::parse(b"forever").is_err());
+
+        let now = Timestamp::now();
+        let version = |number: u64, age: i64| Version {
+            number,
+            size: 0,
+            mtime: now,
+            kept: Timestamp { sec: now.sec - age, nsec: now.nsec },
+            chunks: vec![],
+        };
+        let versions = vec![version(1, 5 * 86400), version(2, 86400), version(3, 0), version(4, 0)];
+        let policy = VersionPolicy { keep: Some(3), days: Some(2) };
+        assert_eq!(policy.expired(&versions, now), &versions[..1]);
+        let policy = VersionPolicy { keep: None, days: Some(0) };
+        assert_eq!(policy.expired(&versions, now), &versions[..2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+//! Lowercase hex encoding of bytes, used in key file names, object names and
+//! messages
+
+/// Encode bytes as lowercase hex
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode lowercase hex, or return `None` if it isn't valid
+///
+/// Uppercase digits are rejected so that every value has only one encoding.
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((digit_value(*high)? << 4) | digit_value(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn digit_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        assert_eq!(encode(&[0x00, 0x7f, 0xab, 0xff]), "007fabff");
+        assert_eq!(decode("007fabff"), Some(vec![0x00, 0x7f, 0xab, 0xff]));
+        assert_eq!(decode(""), Some(vec![]));
+
+        assert_eq!(decode("ABFF"), None);
+        assert_eq!(decode("abf"), None);
+        assert_eq!(decode("zz"), None);
+    }
+}
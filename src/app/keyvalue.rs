@@ -1,5 +1,9 @@
 //! Module containing key-value storage specific backends and types
 
+pub mod async_store;
+pub mod encrypted;
+pub mod lease;
+
 /// The result of a KeyValueStore operation
 pub type KeyValueResult<T> = Result<T, KeyValueError>;
 
@@ -7,14 +11,37 @@ pub type KeyValueResult<T> = Result<T, KeyValueError>;
 #[derive(Debug)]
 pub enum KeyValueError {
     /// A diesel error returned as a result of the operation.
-    DatabaseError(diesel::result::Error)
+    DatabaseError(diesel::result::Error),
+    /// A connection could not be taken from a connection pool
+    PoolError(diesel::r2d2::PoolError),
+    /// The operation was dropped before it completed
+    Canceled,
+    /// Reading or writing the store's files failed
+    IoError(std::io::Error),
+    /// An error returned by a Redis server or connection
+    RedisError(redis::RedisError),
+    /// A request to an S3-compatible service failed
+    S3Error(String),
+    /// Stored data could not be decrypted
+    EncryptionError(String),
+    /// The store can only be read
+    ReadOnly,
 }
 
 use std::fmt;
+use std::sync::Arc;
+
 impl fmt::Display for KeyValueError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
-            KeyValueError::DatabaseError(error) => write!(f, "DatabaseError: {}", error)
+            KeyValueError::DatabaseError(error) => write!(f, "DatabaseError: {}", error),
+            KeyValueError::PoolError(error) => write!(f, "PoolError: {}", error),
+            KeyValueError::Canceled => write!(f, "Operation was canceled"),
+            KeyValueError::IoError(error) => write!(f, "IoError: {}", error),
+            KeyValueError::RedisError(error) => write!(f, "RedisError: {}", error),
+            KeyValueError::S3Error(error) => write!(f, "S3Error: {}", error),
+            KeyValueError::EncryptionError(error) => write!(f, "EncryptionError: {}", error),
+            KeyValueError::ReadOnly => write!(f, "The store is read-only"),
         }
     }
 }
@@ -27,8 +54,38 @@ impl From<diesel::result::Error> for KeyValueError {
     }
 }
 
+impl From<diesel::r2d2::PoolError> for KeyValueError {
+    fn from(error: diesel::r2d2::PoolError) -> Self {
+        KeyValueError::PoolError(error)
+    }
+}
+
+impl From<std::io::Error> for KeyValueError {
+    fn from(error: std::io::Error) -> Self {
+        KeyValueError::IoError(error)
+    }
+}
+
+impl From<redis::RedisError> for KeyValueError {
+    fn from(error: redis::RedisError) -> Self {
+        KeyValueError::RedisError(error)
+    }
+}
+
+/// A single write in a batch of writes
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Set the value of a key
+    Set(Vec<u8>, Vec<u8>),
+    /// Delete a key and its value
+    Delete(Vec<u8>),
+}
+
 /// A key value store
-pub trait KeyValueStore {
+///
+/// Stores are shared between the filesystem's worker threads and must be safe
+/// to use concurrently.
+pub trait KeyValueStore: Send + Sync {
     /// Get the value of a key
     fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>>;
     /// Set the value of a key
@@ -37,4 +94,174 @@ pub trait KeyValueStore {
     fn delete(&self, key: Vec<u8>) -> KeyValueResult<()>;
     /// List all keys in the store
     fn list(&self) -> KeyValueResult<Vec<Vec<u8>>>;
+    /// Get all key-value pairs with keys that start with `prefix`, sorted by key
+    ///
+    /// The default implementation filters every key in the store. Stores that
+    /// keep their keys sorted should override it with a range scan.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut keys: Vec<Vec<u8>> = self
+            .list()?
+            .into_iter()
+            .filter(|key| key.starts_with(&prefix))
+            .collect();
+        keys.sort();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+    /// Apply a batch of writes in order
+    ///
+    /// Stores that support transactions should override this to apply the batch
+    /// atomically. The default implementation applies each write on its own.
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => self.set(key, value)?,
+                BatchOp::Delete(key) => self.delete(key)?,
+            }
+        }
+
+        Ok(())
+    }
+    /// Set the value of a key, or delete it if `new` is `None`, but only if its
+    /// current value is `expected`. Returns whether the write was made.
+    ///
+    /// The check and the write must be atomic, even with respect to other
+    /// processes using the same store, since the lease that keeps mounts and
+    /// commands out of each other's way is taken with it. There is no default
+    /// implementation since how to make them atomic depends on the store.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool>;
+}
+
+impl<S: KeyValueStore + ?Sized> KeyValueStore for Box<S> {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        (**self).delete(key)
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        (**self).list()
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        (**self).batch(ops)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        (**self).compare_and_swap(key, expected, new)
+    }
+}
+
+impl<S: KeyValueStore + ?Sized> KeyValueStore for Arc<S> {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        (**self).delete(key)
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        (**self).list()
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        (**self).batch(ops)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        (**self).compare_and_swap(key, expected, new)
+    }
+}
+
+impl<S: KeyValueStore + ?Sized> KeyValueStore for &S {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        (**self).set(key, value)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        (**self).delete(key)
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        (**self).list()
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KeyValueResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        (**self).batch(ops)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        (**self).compare_and_swap(key, expected, new)
+    }
+}
+
+/// Get the smallest key that is greater than every key starting with `prefix`
+///
+/// Returns `None` if there is no such key, i.e. when the prefix is empty or
+/// made up entirely of `0xFF` bytes.
+pub fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+
+    while let Some(last) = successor.pop() {
+        if last < 0xFF {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+
+    None
 }
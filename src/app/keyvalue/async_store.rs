@@ -0,0 +1,168 @@
+//! Asynchronous key-value stores
+//!
+//! `AsyncKeyValueStore` is the futures based counterpart of `KeyValueStore`
+//! for stores that spend most of their time waiting on the network. Many
+//! operations on an async store can be in flight at once without tying up a
+//! thread for each of them.
+//!
+//! Synchronous stores can be used wherever an async store is expected by
+//! wrapping them in a `BlockingAdapter`. The filesystem does this to fetch
+//! all the chunks of a read at once instead of one after another.
+
+use super::{BatchOp, KeyValueError, KeyValueResult, KeyValueStore};
+
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt};
+use std::sync::Arc;
+use threadpool::ThreadPool;
+
+/// The future returned by `AsyncKeyValueStore` operations
+pub type KvFuture<T> = BoxFuture<'static, KeyValueResult<T>>;
+
+/// An asynchronous key value store
+///
+/// The returned futures don't borrow the store so that they can be sent to
+/// and driven by any executor.
+pub trait AsyncKeyValueStore: Send + Sync {
+    /// Get the value of a key
+    fn get(&self, key: Vec<u8>) -> KvFuture<Option<Vec<u8>>>;
+    /// Set the value of a key
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KvFuture<()>;
+    /// Delete a key and its value
+    fn delete(&self, key: Vec<u8>) -> KvFuture<()>;
+    /// List all keys in the store
+    fn list(&self) -> KvFuture<Vec<Vec<u8>>>;
+    /// Get all key-value pairs with keys that start with `prefix`, sorted by key
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KvFuture<Vec<(Vec<u8>, Vec<u8>)>>;
+    /// Apply a batch of writes in order
+    fn batch(&self, ops: Vec<BatchOp>) -> KvFuture<()>;
+    /// Set or delete a key if its current value is `expected`, resolving to
+    /// whether the write was made
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvFuture<bool>;
+
+    /// Get the values of several keys concurrently
+    fn get_many(&self, keys: Vec<Vec<u8>>) -> KvFuture<Vec<Option<Vec<u8>>>> {
+        future::try_join_all(keys.into_iter().map(|key| self.get(key))).boxed()
+    }
+}
+
+/// Runs the operations of a synchronous `KeyValueStore` on a thread pool to
+/// make it usable as an `AsyncKeyValueStore`
+///
+/// Up to `threads` operations are run against the store at once. Any more wait
+/// in the pool's queue.
+pub struct BlockingAdapter<S: KeyValueStore + 'static> {
+    store: Arc<S>,
+    pool: ThreadPool,
+}
+
+impl<S: KeyValueStore + 'static> BlockingAdapter<S> {
+    /// Wrap a synchronous store, running its operations on `threads` threads
+    pub fn new(store: S, threads: usize) -> BlockingAdapter<S> {
+        BlockingAdapter {
+            store: Arc::new(store),
+            pool: ThreadPool::with_name(String::from("polyfs-kv"), threads.max(1)),
+        }
+    }
+
+    /// Get the wrapped store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    fn run<T, F>(&self, operation: F) -> KvFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> KeyValueResult<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let store = self.store.clone();
+
+        self.pool.execute(move || {
+            // The receiver may have been dropped if the result isn't needed
+            let _ = sender.send(operation(&store));
+        });
+
+        receiver
+            .map(|result| result.unwrap_or(Err(KeyValueError::Canceled)))
+            .boxed()
+    }
+}
+
+impl<S: KeyValueStore + 'static> AsyncKeyValueStore for BlockingAdapter<S> {
+    fn get(&self, key: Vec<u8>) -> KvFuture<Option<Vec<u8>>> {
+        self.run(move |store| store.get(key))
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KvFuture<()> {
+        self.run(move |store| store.set(key, value))
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KvFuture<()> {
+        self.run(move |store| store.delete(key))
+    }
+
+    fn list(&self) -> KvFuture<Vec<Vec<u8>>> {
+        self.run(|store| store.list())
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> KvFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |store| store.scan_prefix(prefix))
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KvFuture<()> {
+        self.run(move |store| store.batch(ops))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvFuture<bool> {
+        self.run(move |store| store.compare_and_swap(key, expected, new))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::sqlite::{SqliteConfig, SqliteDb, SqliteKvStore};
+    use futures::executor::block_on;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn db_config() -> SqliteConfig {
+        SqliteConfig {
+            db: SqliteDb::InMemory,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn adapter_set_and_get() -> TestResult {
+        let kv_store = BlockingAdapter::new(SqliteKvStore::new(db_config())?, 4);
+
+        block_on(future::try_join(
+            kv_store.set(b"hello".to_vec(), b"world".to_vec()),
+            kv_store.set(b"goodbye".to_vec(), b"later".to_vec()),
+        ))?;
+
+        assert_eq!(
+            block_on(kv_store.get_many(vec![
+                b"hello".to_vec(),
+                b"goodbye".to_vec(),
+                b"none".to_vec(),
+            ]))?,
+            vec![Some(b"world".to_vec()), Some(b"later".to_vec()), None]
+        );
+        assert_eq!(kv_store.store().get(b"hello".to_vec())?.unwrap(), b"world");
+
+        Ok(())
+    }
+}
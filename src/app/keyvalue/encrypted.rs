@@ -0,0 +1,596 @@
+//! Encryption of stored keys and values
+//!
+//! `EncryptedKvStore` wraps another store and encrypts every key and value
+//! written to it with ChaCha20-Poly1305. Values are encrypted with a random
+//! nonce and authenticated together with their encrypted key, so that they
+//! can't be moved to another key. Keys are encrypted deterministically with a
+//! nonce derived from the key itself, so that the same key is always stored the
+//! same way and can still be looked up. This reveals which stored keys are
+//! equal, but not how they are ordered, so prefix scans have to decrypt every
+//! key in the store.
+//!
+//! Keys and values are encrypted with subkeys of a random data key. The data
+//! key is kept in a header in the wrapped store, encrypted with a master key
+//! derived from a passphrase or key file. Changing the passphrase or key file
+//! only rewrites the header; replacing the data key rewrites every pair.
+
+use super::lease::LEASE_KEY;
+use super::{BatchOp, KeyValueError, KeyValueResult, KeyValueStore};
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+
+/// Where the master key of an encrypted store comes from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "snake_case")]
+pub enum EncryptionConfig {
+    /// Derive the key from a passphrase, read from `POLYFS_PASSPHRASE` or
+    /// asked for when the store is opened
+    Passphrase,
+    /// Derive the key from the contents of a key file
+    KeyFile(String),
+}
+
+/// The secret that the master key of a store is derived from
+pub enum Secret {
+    /// A passphrase, stretched with Argon2id
+    Passphrase(String),
+    /// The contents of a key file, which should be random
+    KeyFile(Vec<u8>),
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Passphrase(_) => write!(f, "Passphrase(<hidden>)"),
+            Secret::KeyFile(_) => write!(f, "KeyFile(<hidden>)"),
+        }
+    }
+}
+
+/// The key the header is stored under. It is shorter than any encrypted key,
+/// so it can't collide with one.
+const HEADER_KEY: &[u8] = b"polyfs:encryption";
+/// The version of the header written by this build of PolyFS
+const HEADER_VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+
+/// How the master key is derived from the secret
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum Kdf {
+    /// Argon2id with the given cost parameters
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// HMAC-SHA256 of the key file contents
+    KeyFile,
+}
+
+impl Kdf {
+    fn for_secret(secret: &Secret) -> Kdf {
+        match secret {
+            Secret::Passphrase(_) => Kdf::Argon2id {
+                memory_kib: argon2::Params::DEFAULT_M_COST,
+                iterations: argon2::Params::DEFAULT_T_COST,
+                parallelism: argon2::Params::DEFAULT_P_COST,
+            },
+            Secret::KeyFile(_) => Kdf::KeyFile,
+        }
+    }
+}
+
+/// The header stored in the wrapped store, bincode serialized
+#[derive(Serialize, Deserialize, Debug)]
+struct Header {
+    version: u8,
+    kdf: Kdf,
+    salt: Vec<u8>,
+    /// The data key, encrypted with the master key
+    data_key: Vec<u8>,
+    /// The data key being replaced while a key rotation is in progress,
+    /// encrypted with the master key
+    previous_data_key: Option<Vec<u8>>,
+}
+
+fn error(message: &str) -> KeyValueError {
+    KeyValueError::EncryptionError(message.to_owned())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn cipher(key: &[u8; KEY_LEN]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// Encrypt `message` and prepend the nonce
+fn seal(cipher: &ChaCha20Poly1305, nonce: [u8; NONCE_LEN], message: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: message, aad })
+            .expect("Encrypting in memory can't fail"),
+    );
+    sealed
+}
+
+/// Decrypt a message sealed by `seal`, returning `None` if it was tampered with
+/// or encrypted with another key
+fn open(cipher: &ChaCha20Poly1305, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .ok()
+}
+
+fn key_error(message: String) -> PolyfsError {
+    PolyfsError {
+        message,
+        cause: None,
+    }
+}
+
+fn derive_master_key(secret: &Secret, kdf: Kdf, salt: &[u8]) -> PolyfsResult<[u8; KEY_LEN]> {
+    match (secret, kdf) {
+        (
+            Secret::Passphrase(passphrase),
+            Kdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            },
+        ) => {
+            let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(KEY_LEN))
+                .map_err(|e| key_error(format!("Invalid key derivation parameters: {}", e)))?;
+            let mut key = [0; KEY_LEN];
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| key_error(format!("Could not derive key from passphrase: {}", e)))?;
+            Ok(key)
+        }
+        (Secret::KeyFile(contents), Kdf::KeyFile) => Ok(hmac(salt, contents)),
+        (Secret::Passphrase(_), Kdf::KeyFile) => {
+            Err(key_error(String::from("The store is encrypted with a key file, not a passphrase")))
+        }
+        (Secret::KeyFile(_), Kdf::Argon2id { .. }) => {
+            Err(key_error(String::from("The store is encrypted with a passphrase, not a key file")))
+        }
+    }
+}
+
+/// The subkeys derived from a data key
+struct DataKeys {
+    key_cipher: ChaCha20Poly1305,
+    key_nonce_key: [u8; KEY_LEN],
+    value_cipher: ChaCha20Poly1305,
+}
+
+impl DataKeys {
+    fn derive(data_key: &[u8]) -> DataKeys {
+        DataKeys {
+            key_cipher: cipher(&hmac(data_key, b"polyfs key")),
+            key_nonce_key: hmac(data_key, b"polyfs key nonce"),
+            value_cipher: cipher(&hmac(data_key, b"polyfs value")),
+        }
+    }
+
+    fn encrypt_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&hmac(&self.key_nonce_key, key)[..NONCE_LEN]);
+        seal(&self.key_cipher, nonce, key, b"key")
+    }
+
+    /// Decrypt a stored key, returning `None` if it wasn't encrypted with these
+    /// keys
+    fn decrypt_key(&self, stored: &[u8]) -> Option<Vec<u8>> {
+        let key = open(&self.key_cipher, stored, b"key")?;
+
+        // The nonce must be the one derived from the key, or the same key could
+        // be stored under several encrypted keys
+        if hmac(&self.key_nonce_key, &key)[..NONCE_LEN] != stored[..NONCE_LEN] {
+            return None;
+        }
+        Some(key)
+    }
+
+    fn encrypt_value(&self, stored_key: &[u8], value: &[u8]) -> Vec<u8> {
+        seal(&self.value_cipher, rand::random(), value, stored_key)
+    }
+
+    fn decrypt_value(&self, stored_key: &[u8], stored: &[u8]) -> KeyValueResult<Vec<u8>> {
+        open(&self.value_cipher, stored, stored_key)
+            .ok_or_else(|| error("Could not decrypt value: it is corrupt or has been tampered with"))
+    }
+}
+
+/// Whether a key of the wrapped store is one of the keys that are stored
+/// without encryption
+fn is_plain_key(stored_key: &[u8]) -> bool {
+    stored_key == HEADER_KEY || stored_key == LEASE_KEY
+}
+
+fn read_header<S: KeyValueStore>(inner: &S) -> PolyfsResult<Option<Header>> {
+    match try_to!(inner.get(HEADER_KEY.to_vec()), "Could not read encryption header") {
+        Some(data) => {
+            let header: Header = try_to!(
+                bincode::deserialize(&data),
+                "Could not deserialize encryption header"
+            );
+            if header.version != HEADER_VERSION {
+                return Err(PolyfsError {
+                    message: format!("Unsupported encryption header version {}", header.version),
+                    cause: None,
+                });
+            }
+            Ok(Some(header))
+        }
+        None => Ok(None),
+    }
+}
+
+fn write_header<S: KeyValueStore>(inner: &S, header: &Header) -> PolyfsResult<()> {
+    let data = try_to!(bincode::serialize(header), "Could not serialize encryption header");
+    try_to!(inner.set(HEADER_KEY.to_vec(), data), "Could not write encryption header");
+    Ok(())
+}
+
+fn seal_data_key(master_key: &[u8; KEY_LEN], data_key: &[u8; KEY_LEN]) -> Vec<u8> {
+    seal(&cipher(master_key), rand::random(), data_key, b"data key")
+}
+
+fn open_data_key(master_key: &[u8; KEY_LEN], sealed: &[u8]) -> PolyfsResult<[u8; KEY_LEN]> {
+    match open(&cipher(master_key), sealed, b"data key") {
+        Some(data_key) if data_key.len() == KEY_LEN => {
+            let mut key = [0; KEY_LEN];
+            key.copy_from_slice(&data_key);
+            Ok(key)
+        }
+        _ => Err(key_error(String::from(
+            "Could not decrypt the store: wrong passphrase or key file",
+        ))),
+    }
+}
+
+/// A `KeyValueStore` that encrypts every key and value before passing them on
+/// to the store it wraps
+pub struct EncryptedKvStore<S: KeyValueStore> {
+    inner: S,
+    keys: DataKeys,
+}
+
+impl<S: KeyValueStore> fmt::Debug for EncryptedKvStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedKvStore").finish()
+    }
+}
+
+impl<S: KeyValueStore> EncryptedKvStore<S> {
+    /// Open an encrypted store with the key derived from `secret`
+    ///
+    /// An empty store is set up for encryption with a new data key. A store
+    /// that has been written to without encryption can't be opened.
+    pub fn open(inner: S, secret: &Secret) -> PolyfsResult<EncryptedKvStore<S>> {
+        let data_key = match read_header(&inner)? {
+            Some(header) => {
+                if header.previous_data_key.is_some() {
+                    return Err(PolyfsError {
+                        message: String::from(
+                            "A key rotation was interrupted. Run `polyfs rotate-key` with the \
+                             same arguments again to finish it",
+                        ),
+                        cause: None,
+                    });
+                }
+                let master_key = derive_master_key(secret, header.kdf, &header.salt)?;
+                open_data_key(&master_key, &header.data_key)?
+            }
+            None => {
+                let keys = try_to!(inner.list(), "Could not list keys");
+                if keys.iter().any(|key| key != LEASE_KEY) {
+                    return Err(PolyfsError {
+                        message: String::from(
+                            "The store has been written to without encryption, so it can't be \
+                             opened as an encrypted store",
+                        ),
+                        cause: None,
+                    });
+                }
+
+                log::info!("Setting up encryption for a new store");
+                let kdf = Kdf::for_secret(secret);
+                let salt = rand::random::<[u8; SALT_LEN]>().to_vec();
+                let master_key = derive_master_key(secret, kdf, &salt)?;
+                let data_key = rand::random::<[u8; KEY_LEN]>();
+
+                write_header(
+                    &inner,
+                    &Header {
+                        version: HEADER_VERSION,
+                        kdf,
+                        salt,
+                        data_key: seal_data_key(&master_key, &data_key),
+                        previous_data_key: None,
+                    },
+                )?;
+                data_key
+            }
+        };
+
+        Ok(EncryptedKvStore {
+            inner,
+            keys: DataKeys::derive(&data_key),
+        })
+    }
+}
+
+/// Check whether a secret opens an encrypted store, without changing it
+pub fn opens_with<S: KeyValueStore>(inner: &S, secret: &Secret) -> PolyfsResult<bool> {
+    let header = read_header(inner)?.ok_or_else(|| PolyfsError {
+        message: String::from("The store is not encrypted"),
+        cause: None,
+    })?;
+
+    // A secret of the wrong kind doesn't open the store either
+    let matching_kind = matches!(
+        (secret, header.kdf),
+        (Secret::Passphrase(_), Kdf::Argon2id { .. }) | (Secret::KeyFile(_), Kdf::KeyFile)
+    );
+    if !matching_kind {
+        return Ok(false);
+    }
+    let master_key = derive_master_key(secret, header.kdf, &header.salt)?;
+
+    Ok(open_data_key(&master_key, &header.data_key).is_ok())
+}
+
+/// Change the secret that an encrypted store is opened with
+///
+/// If `reencrypt` is true, every pair in the store is also re-encrypted with a
+/// new data key, for when the data key itself may have been exposed. The store
+/// must not be in use while its pairs are re-encrypted. If re-encryption is
+/// interrupted it is resumed by running the rotation again with the same
+/// arguments. Returns the number of pairs that were re-encrypted.
+pub fn rotate_key<S: KeyValueStore>(
+    inner: &S,
+    old_secret: &Secret,
+    new_secret: &Secret,
+    reencrypt: bool,
+) -> PolyfsResult<usize> {
+    let header = read_header(inner)?.ok_or_else(|| PolyfsError {
+        message: String::from("The store is not encrypted"),
+        cause: None,
+    })?;
+    let old_master_key = derive_master_key(old_secret, header.kdf, &header.salt)?;
+    let current = open_data_key(&old_master_key, &header.data_key)?;
+
+    // Re-encrypt pairs from one data key to another, recording both in the
+    // header first so that an interrupted rotation can be resumed
+    let (from, to) = match &header.previous_data_key {
+        Some(previous) => (Some(open_data_key(&old_master_key, previous)?), current),
+        None if reencrypt => {
+            let new = rand::random::<[u8; KEY_LEN]>();
+            write_header(
+                inner,
+                &Header {
+                    data_key: seal_data_key(&old_master_key, &new),
+                    previous_data_key: Some(seal_data_key(&old_master_key, &current)),
+                    ..header
+                },
+            )?;
+            (Some(current), new)
+        }
+        None => (None, current),
+    };
+
+    let mut reencrypted = 0;
+    if let Some(from) = from {
+        let (from, to_keys) = (DataKeys::derive(&from), DataKeys::derive(&to));
+
+        for stored_key in try_to!(inner.list(), "Could not list keys") {
+            if is_plain_key(&stored_key) || to_keys.decrypt_key(&stored_key).is_some() {
+                continue;
+            }
+
+            let key = from.decrypt_key(&stored_key).ok_or_else(|| PolyfsError {
+                message: String::from("Found a key encrypted with an unknown data key"),
+                cause: None,
+            })?;
+            let value = match try_to!(inner.get(stored_key.clone()), "Could not read value") {
+                Some(value) => try_to!(from.decrypt_value(&stored_key, &value), "Could not decrypt value"),
+                None => continue,
+            };
+
+            let new_key = to_keys.encrypt_key(&key);
+            let new_value = to_keys.encrypt_value(&new_key, &value);
+            try_to!(
+                inner.batch(vec![
+                    BatchOp::Set(new_key, new_value),
+                    BatchOp::Delete(stored_key),
+                ]),
+                "Could not write re-encrypted pair"
+            );
+            reencrypted += 1;
+        }
+    }
+
+    let kdf = Kdf::for_secret(new_secret);
+    let salt = rand::random::<[u8; SALT_LEN]>().to_vec();
+    let new_master_key = derive_master_key(new_secret, kdf, &salt)?;
+    write_header(
+        inner,
+        &Header {
+            version: HEADER_VERSION,
+            kdf,
+            salt,
+            data_key: seal_data_key(&new_master_key, &to),
+            previous_data_key: None,
+        },
+    )?;
+
+    Ok(reencrypted)
+}
+
+impl<S: KeyValueStore> KeyValueStore for EncryptedKvStore<S> {
+    fn get(&self, key: Vec<u8>) -> KeyValueResult<Option<Vec<u8>>> {
+        let stored_key = self.keys.encrypt_key(&key);
+
+        match self.inner.get(stored_key.clone())? {
+            Some(value) => Ok(Some(self.keys.decrypt_value(&stored_key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> KeyValueResult<()> {
+        let stored_key = self.keys.encrypt_key(&key);
+        let value = self.keys.encrypt_value(&stored_key, &value);
+        self.inner.set(stored_key, value)
+    }
+
+    fn delete(&self, key: Vec<u8>) -> KeyValueResult<()> {
+        self.inner.delete(self.keys.encrypt_key(&key))
+    }
+
+    fn list(&self) -> KeyValueResult<Vec<Vec<u8>>> {
+        self.inner
+            .list()?
+            .into_iter()
+            .filter(|stored_key| !is_plain_key(stored_key))
+            .map(|stored_key| {
+                self.keys
+                    .decrypt_key(&stored_key)
+                    .ok_or_else(|| error("Could not decrypt key: it is corrupt or has been tampered with"))
+            })
+            .collect()
+    }
+
+    fn batch(&self, ops: Vec<BatchOp>) -> KeyValueResult<()> {
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => {
+                    let stored_key = self.keys.encrypt_key(&key);
+                    let value = self.keys.encrypt_value(&stored_key, &value);
+                    BatchOp::Set(stored_key, value)
+                }
+                BatchOp::Delete(key) => BatchOp::Delete(self.keys.encrypt_key(&key)),
+            })
+            .collect();
+
+        self.inner.batch(ops)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KeyValueResult<bool> {
+        let stored_key = self.keys.encrypt_key(&key);
+
+        // Values are encrypted with random nonces, so compare the decrypted
+        // value and swap against the exact stored value
+        let current = self.inner.get(stored_key.clone())?;
+        let decrypted = match &current {
+            Some(value) => Some(self.keys.decrypt_value(&stored_key, value)?),
+            None => None,
+        };
+        if decrypted != expected {
+            return Ok(false);
+        }
+
+        let new = new.map(|value| self.keys.encrypt_value(&stored_key, &value));
+        self.inner.compare_and_swap(stored_key, current, new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn key_file(byte: u8) -> Secret {
+        Secret::KeyFile(vec![byte; 32])
+    }
+
+    #[test]
+    fn keys_and_values_are_encrypted() -> TestResult {
+        let kv_store = EncryptedKvStore::open(MemoryKvStore::new(), &key_file(1))?;
+
+        kv_store.set(b"secret name".to_vec(), b"secret content".to_vec())?;
+        kv_store.batch(vec![
+            BatchOp::Set(b"a".to_vec(), b"1".to_vec()),
+            BatchOp::Set(b"b".to_vec(), b"2".to_vec()),
+            BatchOp::Delete(b"b".to_vec()),
+        ])?;
+        assert!(kv_store.compare_and_swap(b"a".to_vec(), Some(b"1".to_vec()), Some(b"3".to_vec()))?);
+        assert!(!kv_store.compare_and_swap(b"a".to_vec(), Some(b"1".to_vec()), None)?);
+
+        assert_eq!(kv_store.get(b"secret name".to_vec())?.unwrap(), b"secret content");
+        assert_eq!(kv_store.get(b"a".to_vec())?.unwrap(), b"3");
+        assert_eq!(kv_store.get(b"b".to_vec())?, None);
+        assert_eq!(
+            kv_store.scan_prefix(b"secret".to_vec())?,
+            vec![(b"secret name".to_vec(), b"secret content".to_vec())]
+        );
+
+        for stored_key in kv_store.inner.list()? {
+            assert!(!stored_key.windows(6).any(|window| window == b"secret"));
+            let value = kv_store.inner.get(stored_key)?.unwrap();
+            assert!(!value.windows(6).any(|window| window == b"secret"));
+        }
+
+        // A value moved to another key doesn't decrypt
+        let a = kv_store.keys.encrypt_key(b"a");
+        let value = kv_store.inner.get(kv_store.keys.encrypt_key(b"secret name"))?.unwrap();
+        kv_store.inner.set(a, value)?;
+        assert!(kv_store.get(b"a".to_vec()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rotate_and_reencrypt() -> TestResult {
+        let kv_store = EncryptedKvStore::open(MemoryKvStore::new(), &key_file(1))?;
+        kv_store.set(b"key".to_vec(), b"value".to_vec())?;
+        let inner = kv_store.inner;
+        let stored_key = inner.list()?.into_iter().find(|key| key != HEADER_KEY).unwrap();
+
+        assert_eq!(rotate_key(&inner, &key_file(1), &key_file(2), false)?, 0);
+        assert!(rotate_key(&inner, &key_file(1), &key_file(1), false).is_err());
+        assert!(inner.get(stored_key.clone())?.is_some());
+
+        let passphrase = Secret::Passphrase(String::from("correct horse battery staple"));
+        assert_eq!(rotate_key(&inner, &key_file(2), &passphrase, true)?, 1);
+        assert!(inner.get(stored_key)?.is_none());
+        assert!(rotate_key(&inner, &key_file(2), &key_file(2), false).is_err());
+
+        assert!(opens_with(&inner, &passphrase)?);
+        assert!(!opens_with(&inner, &key_file(2))?);
+
+        // The data key can be replaced without changing the secret
+        assert_eq!(rotate_key(&inner, &passphrase, &passphrase, true)?, 1);
+
+        let kv_store = EncryptedKvStore::open(inner, &passphrase)?;
+        assert_eq!(kv_store.get(b"key".to_vec())?.unwrap(), b"value");
+
+        Ok(())
+    }
+}
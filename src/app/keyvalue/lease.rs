@@ -0,0 +1,201 @@
+//! An exclusive lease on a store
+//!
+//! A mounted filesystem and every command that changes a store offline hold
+//! the store's lease for as long as they run, so that they can't change the
+//! store underneath each other. The lease is a single pair written with
+//! `compare_and_swap`, so only one holder can take it even when several hosts
+//! share the store. It is kept in the wrapped store of an encrypted store, so
+//! that it can be checked without the secret.
+//!
+//! A holder that is killed leaves its lease behind. Such a lease can only be
+//! cleared by taking it over with `force`, since a lease that looks stale may
+//! belong to a holder on another host that is still running.
+
+use super::KeyValueStore;
+use crate::{try_to, PolyfsError, PolyfsResult};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The key the lease is stored under. Like the encryption header, it is
+/// shorter than any encrypted key, so it can't collide with one.
+pub const LEASE_KEY: &[u8] = b"polyfs:lease";
+
+/// Who holds a lease
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LeaseHolder {
+    /// The host the holder runs on
+    pub host: String,
+    /// The process id of the holder
+    pub pid: u32,
+    /// What the lease was taken for, e.g. `mount` or the name of a command
+    pub purpose: String,
+    /// When the lease was taken, in RFC 3339 format
+    pub acquired: String,
+    /// Distinguishes leases taken by the same process
+    token: u64,
+}
+
+impl fmt::Display for LeaseHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` on {} ( pid {} ) since {}",
+            self.purpose, self.host, self.pid, self.acquired
+        )
+    }
+}
+
+/// A lease held on a store, released when it is dropped
+pub struct Lease<S: KeyValueStore> {
+    kv_store: S,
+    holder: LeaseHolder,
+    value: Vec<u8>,
+}
+
+impl<S: KeyValueStore> Lease<S> {
+    /// Take the lease of `kv_store` for `purpose`
+    ///
+    /// Fails if the lease is held by someone else, unless `force` is true, in
+    /// which case the lease is taken over.
+    pub fn acquire(kv_store: S, purpose: &str, force: bool) -> PolyfsResult<Lease<S>> {
+        let holder = LeaseHolder {
+            host: hostname(),
+            pid: std::process::id(),
+            purpose: purpose.to_string(),
+            acquired: chrono::Utc::now().to_rfc3339(),
+            token: rand::random(),
+        };
+        let value = try_to!(bincode::serialize(&holder), "Could not serialize lease");
+
+        loop {
+            let current = try_to!(kv_store.get(LEASE_KEY.to_vec()), "Could not read lease");
+
+            if let Some(current) = &current {
+                if !force {
+                    return Err(PolyfsError {
+                        message: match decode_holder(current) {
+                            Some(held_by) => format!(
+                                "The filesystem is in use by {}. If that isn't running \
+                                 anymore, run again with --force to clear its lease",
+                                held_by
+                            ),
+                            None => String::from(
+                                "The filesystem is in use. If nothing is using it anymore, \
+                                 run again with --force to clear its lease",
+                            ),
+                        },
+                        cause: None,
+                    });
+                }
+
+                if let Some(held_by) = decode_holder(current) {
+                    log::warn!("Clearing the lease held by {}", held_by);
+                }
+            }
+
+            // Try again if the lease changed since it was read
+            if try_to!(
+                kv_store.compare_and_swap(LEASE_KEY.to_vec(), current, Some(value.clone())),
+                "Could not write lease"
+            ) {
+                break;
+            }
+        }
+
+        Ok(Lease {
+            kv_store,
+            holder,
+            value,
+        })
+    }
+
+    /// Who holds the lease
+    pub fn holder(&self) -> &LeaseHolder {
+        &self.holder
+    }
+}
+
+impl<S: KeyValueStore> fmt::Debug for Lease<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lease").field("holder", &self.holder).finish()
+    }
+}
+
+impl<S: KeyValueStore> Drop for Lease<S> {
+    fn drop(&mut self) {
+        // Leave the lease alone if it has been taken over in the meantime
+        match self
+            .kv_store
+            .compare_and_swap(LEASE_KEY.to_vec(), Some(self.value.clone()), None)
+        {
+            Ok(true) => (),
+            Ok(false) => log::warn!("The lease was taken over while it was held"),
+            Err(e) => log::error!("Could not release lease: {}", e),
+        }
+    }
+}
+
+/// Get who holds the lease of `kv_store`, if anyone
+pub fn lease_holder<S: KeyValueStore>(kv_store: &S) -> PolyfsResult<Option<LeaseHolder>> {
+    match try_to!(kv_store.get(LEASE_KEY.to_vec()), "Could not read lease") {
+        Some(value) => Ok(decode_holder(&value)),
+        None => Ok(None),
+    }
+}
+
+fn decode_holder(value: &[u8]) -> Option<LeaseHolder> {
+    bincode::deserialize(value).ok()
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+        return String::from("unknown host");
+    }
+
+    let len = buffer.iter().position(|&byte| byte == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::backends::memory::MemoryKvStore;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn lease_is_exclusive_until_released() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        let lease = Lease::acquire(&kv_store, "mount", false)?;
+        assert_eq!(lease_holder(&kv_store)?.as_ref(), Some(lease.holder()));
+        assert!(Lease::acquire(&kv_store, "gc", false).is_err());
+
+        drop(lease);
+        assert_eq!(lease_holder(&kv_store)?, None);
+        let lease = Lease::acquire(&kv_store, "gc", false)?;
+        assert_eq!(lease.holder().purpose, "gc");
+
+        Ok(())
+    }
+
+    #[test]
+    fn force_takes_over_lease() -> TestResult {
+        let kv_store = MemoryKvStore::new();
+
+        let stale = Lease::acquire(&kv_store, "mount", false)?;
+        let lease = Lease::acquire(&kv_store, "gc", true)?;
+        assert_eq!(lease_holder(&kv_store)?.as_ref(), Some(lease.holder()));
+
+        // Dropping the stale lease leaves the new one in place
+        drop(stale);
+        assert_eq!(lease_holder(&kv_store)?.as_ref(), Some(lease.holder()));
+
+        drop(lease);
+        assert_eq!(lease_holder(&kv_store)?, None);
+
+        Ok(())
+    }
+}
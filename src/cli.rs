@@ -8,8 +8,19 @@ use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
 use crate::log::{LoggingConfig, setup_logging};
 
 // Subcommands
+pub mod clone;
 pub mod config;
+pub mod fsck;
+pub mod gc;
+pub mod migrate;
 pub mod mount;
+pub mod quota;
+pub mod rotate_key;
+pub mod snapshot;
+pub mod store;
+pub mod trash;
+pub mod usage;
+pub mod versions;
 
 /// This is a convenient way to pass the arguments that a subcommand are going
 /// to need.
@@ -110,6 +121,76 @@ pub fn run() {
             });
         }
 
+        ("migrate", Some(sub)) => {
+            migrate::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("clone", Some(sub)) => {
+            clone::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("gc", Some(sub)) => {
+            gc::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("fsck", Some(sub)) => {
+            fsck::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("snapshot", Some(sub)) => {
+            snapshot::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("quota", Some(sub)) => {
+            quota::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("trash", Some(sub)) => {
+            trash::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("versions", Some(sub)) => {
+            versions::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("rotate-key", Some(sub)) => {
+            rotate_key::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
+        ("usage", Some(sub)) => {
+            usage::run(ArgSet { global: &args, sub }).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(1);
+            });
+        }
+
         _ => panic!(
             "Unimplemented command or failure to show help message when lacking a subcommand."
         ),
@@ -126,12 +207,13 @@ pub fn get_cli() -> App<'static, 'static> {
         .long_about(
 "A FUSE filesystem for many backends.
 
-PolyFS allows you to mount a filesystem built on a key-value store and \
-a metadata store. Multiple key-value and metadata stores are supported.
-            
-Usually you will run `polyfs config kv` and `polyfs config meta` to create the \
-config file with your connection information, followed  by `polyfs mount` to \
-mount the filesystem."
+PolyFS allows you to mount a filesystem built on a metadata store and, \
+optionally, a separate data store for file contents. Many different key-value \
+stores are supported for both.
+
+Usually you will run `polyfs config backend` and optionally \
+`polyfs config backend --data` to create the config file with your connection \
+information, followed by `polyfs mount` to mount the filesystem."
         )
         .global_setting(AppSettings::ColoredHelp)
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -177,6 +259,26 @@ Can be conveniently created and modified with the `config` subcommand."
 
         .subcommand(mount::get_cli())
 
+        .subcommand(migrate::get_cli())
+
+        .subcommand(usage::get_cli())
+
+        .subcommand(rotate_key::get_cli())
+
+        .subcommand(gc::get_cli())
+
+        .subcommand(fsck::get_cli())
+
+        .subcommand(snapshot::get_cli())
+
+        .subcommand(clone::get_cli())
+
+        .subcommand(quota::get_cli())
+
+        .subcommand(trash::get_cli())
+
+        .subcommand(versions::get_cli())
+
         .subcommand(SubCommand::with_name("completion")
             .about("Output shell completion scripts")
             .arg(Arg::with_name("shell")
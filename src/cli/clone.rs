@@ -0,0 +1,92 @@
+//! PolyFS `clone` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{try_to, PolyfsError, PolyfsResult};
+use clap::{App, Arg, SubCommand};
+
+/// Get CLI for the `clone` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("clone")
+        .about("Copy a file instantly by sharing its contents")
+        .long_about(
+"Copy a file instantly by sharing its contents. The copy refers to the same \
+stored contents as the original until either of them is written to, so no \
+file contents are copied. Paths are relative to the root of the filesystem. \
+This works on the stores directly, so it can't be done while the filesystem \
+is mounted. The FUSE version PolyFS is built on can't receive the FICLONE \
+ioctl, so `cp --reflink` on a mounted filesystem makes a regular copy."
+        )
+        .arg(Arg::with_name("source")
+            .help("The file to copy, e.g. `/images/base.img`")
+            .required(true))
+        .arg(Arg::with_name("destination")
+            .help("The path of the new file. Its parent directory must exist.")
+            .required(true))
+        .arg(force_arg())
+}
+
+/// Run `clone` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `clone` subcommand");
+
+    use crate::app::filesystem::migration::check_stores;
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::cli::store::{open_stores, Access};
+    use std::io::Error;
+    use std::path::Path;
+
+    let source = Path::new(args.sub.value_of("source").expect("Could not load source arg"));
+    let destination = Path::new(
+        args.sub
+            .value_of("destination")
+            .expect("Could not load destination arg"),
+    );
+    let (parent, name) = match (destination.parent(), destination.file_name()) {
+        (Some(parent), Some(name)) => (parent, name),
+        _ => {
+            return Err(PolyfsError {
+                message: format!("Invalid destination: {}", destination.display()),
+                cause: None,
+            })
+        }
+    };
+
+    let config = load_config(args.global)?;
+
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        Access::Exclusive {
+            purpose: "clone",
+            force: args.sub.is_present("force"),
+        },
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+
+    let core = FilesystemCore::new(kv_store, data_store, config.filesystem, false);
+    try_to!(
+        core.init().map_err(Error::from_raw_os_error),
+        "Could not open filesystem"
+    );
+
+    let source = try_to!(
+        core.resolve(source).map_err(Error::from_raw_os_error),
+        format!("Could not find {}", source.display())
+    );
+    let parent = try_to!(
+        core.resolve(parent).map_err(Error::from_raw_os_error),
+        format!("Could not find {}", parent.display())
+    );
+    try_to!(
+        core.clone_file(source.ino, parent.ino, name)
+            .map_err(Error::from_raw_os_error),
+        format!("Could not create {}", destination.display())
+    );
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
 //! `config` subcommand. Also contains utilities for accessing commandline
 //! config.
 
-use crate::app::config::{AppConfig};
+use crate::app::backends::sqlite::SqliteDb;
+use crate::app::config::{AppConfig, Backend};
 use crate::PolyfsResult;
 use crate::cli::{ArgSet, ConfigFormat};
 use crate::try_to;
@@ -14,6 +15,8 @@ use std::path::Path;
 
 pub mod backend;
 pub mod default;
+pub mod encryption;
+pub mod filesystem;
 
 /// Run `config` subcommand
 pub fn run(args: ArgSet) -> PolyfsResult<()> {
@@ -37,6 +40,16 @@ pub fn run(args: ArgSet) -> PolyfsResult<()> {
             sub,
         })?,
 
+        ("encryption", Some(sub)) => encryption::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+
+        ("filesystem", Some(sub)) => filesystem::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+
         _ => panic!(
             "Unimplemented command or failure to show help message when lacking a subcommand."
         ),
@@ -50,7 +63,9 @@ pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
     let mut command = SubCommand::with_name("config")
         .about("Create or update PolyFS config file")
         .subcommand(backend::get_cli())
-        .subcommand(default::get_cli());
+        .subcommand(default::get_cli())
+        .subcommand(encryption::get_cli())
+        .subcommand(filesystem::get_cli());
 
     if std::env::var("POLYFS_DEBUG").is_ok() {
         command = command.subcommand(SubCommand::with_name("dump")
@@ -89,7 +104,7 @@ pub fn load_config<'a>(args: &ArgMatches<'a>) -> PolyfsResult<AppConfig> {
             "Could not create config file."
         );
 
-        config = AppConfig::default();
+        config = default_config(config_path)?;
         let serialized = serialize_config(&config, config_format)?;
 
         try_to!(
@@ -103,6 +118,31 @@ pub fn load_config<'a>(args: &ArgMatches<'a>) -> PolyfsResult<AppConfig> {
     Ok(config)
 }
 
+/// Get the default configuration for the config file at `config_path`
+///
+/// The default Sqlite database is put next to the config file. Its path is
+/// written as an absolute path, so that commands run from another directory
+/// open the same database.
+pub fn default_config(config_path: &str) -> PolyfsResult<AppConfig> {
+    let mut config = AppConfig::default();
+
+    if let Backend::Sqlite(sqlite_config) = &mut config.backend {
+        if let SqliteDb::File(file) = &mut sqlite_config.db {
+            let config_dir = match Path::new(config_path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let config_dir = try_to!(
+                fs::canonicalize(config_dir),
+                "Could not find config file directory."
+            );
+            *file = config_dir.join(&file).to_string_lossy().into_owned();
+        }
+    }
+
+    Ok(config)
+}
+
 /// Save config file with the provide config
 pub fn save_config<'a>(args: &ArgMatches<'a>, config: &AppConfig) -> PolyfsResult<()> {
     let config_format = value_t!(args, "config_format", ConfigFormat)
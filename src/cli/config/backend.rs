@@ -1,26 +1,65 @@
 //! Key-value store configuration subcommand
 
 use crate::PolyfsResult;
-use crate::cli::config::{ArgSet};
-use clap::{App, SubCommand};
+use crate::cli::config::{ArgSet, load_config, save_config};
+use clap::{App, Arg, SubCommand};
 
 // Backends
+mod directory;
+mod log;
+mod memory;
+mod postgres;
+mod redis;
+mod s3;
 mod sqlite;
 
 /// Run `kv` subcommand
 pub fn run(args: ArgSet) -> PolyfsResult<()> {
-    log::debug!("Running `backend` subcommand");
+    ::log::debug!("Running `backend` subcommand");
+    let mut config = load_config(args.global)?;
 
-    match args.sub.subcommand() {
+    let backend = match args.sub.subcommand() {
         ("sqlite", Some(sub)) => sqlite::run(ArgSet {
             global: args.global,
             sub,
         })?,
+        ("memory", Some(sub)) => memory::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+        ("log", Some(sub)) => log::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+        ("postgres", Some(sub)) => postgres::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+        ("redis", Some(sub)) => redis::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+        ("directory", Some(sub)) => directory::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
+        ("s3", Some(sub)) => s3::run(ArgSet {
+            global: args.global,
+            sub,
+        })?,
         _ => panic!(
             "Unimplemented command or failure to show help message when lacking a subcommand."
         ),
+    };
+
+    if args.sub.is_present("data") {
+        config.data_backend = Some(backend);
+    } else {
+        config.backend = backend;
     }
 
+    save_config(args.global, &config)?;
+
     Ok(())
 }
 
@@ -31,8 +70,24 @@ pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
         .about("Configure backend key-value store")
         .long_about(
 "Configure the key-value store. Each subcommand allows you to configure a \
-different supported key-value backend. Only one backend can be used at a time. \
-If a backend is configured it will replace any previous backend configuration."
+different supported key-value backend. If a backend is configured it will \
+replace any previous backend configuration.
+
+The backend holds the filesystem metadata, and file contents as well unless a \
+separate data backend is configured with --data. This allows keeping metadata \
+in a fast local store while file contents go to a larger, slower one. The data \
+backend has to be configured before any file has contents: PolyFS refuses to \
+use an empty data store for a filesystem that already has contents, or a data \
+store that belongs to another filesystem."
         )
+        .arg(Arg::with_name("data")
+            .long("data")
+            .help("Configure the backend for file contents instead of the metadata backend"))
         .subcommand(sqlite::get_cli())
+        .subcommand(memory::get_cli())
+        .subcommand(log::get_cli())
+        .subcommand(postgres::get_cli())
+        .subcommand(redis::get_cli())
+        .subcommand(directory::get_cli())
+        .subcommand(s3::get_cli())
 }
@@ -0,0 +1,57 @@
+//! The `config backend directory` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::cli::ArgSet;
+use crate::app::config::Backend;
+use crate::app::backends::directory::DirectoryConfig;
+
+use clap::{App, Arg, SubCommand};
+
+/// Run `directory` subcommand, returning the configured backend
+pub fn run(args: ArgSet) -> PolyfsResult<Backend> {
+    log::debug!("Running `directory` subcommand");
+
+    let mut directory_config = DirectoryConfig::new(
+        args.sub
+            .value_of("root")
+            .expect("Required root argument doesn't exist")
+            .into(),
+    );
+    directory_config.sync = !args.sub.is_present("no_sync");
+
+    if let Some(fanout) = args.sub.value_of("fanout") {
+        directory_config.fanout = try_to!(fanout.parse(), "Could not parse fan-out levels");
+    }
+
+    Ok(Backend::Directory(directory_config))
+}
+
+/// Get CLI for the `directory` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("directory")
+        .about("Configure directory of files backend")
+        .long_about(
+"Configure a backend that stores each key as a file in a directory, named after \
+the key in hex. Useful for debugging, or for keeping the store on a network share."
+        )
+        .arg(Arg::with_name("root")
+            .long("root")
+            .short("r")
+            .value_name("dir")
+            .required(true)
+            .help("Directory to store the files in"))
+        .arg(Arg::with_name("fanout")
+            .long("fanout")
+            .value_name("levels")
+            .help(
+"Number of levels of subdirectories to spread the files over. Can't be changed \
+once the store has been written to. Defaults to 2."
+            ))
+        .arg(Arg::with_name("no_sync")
+            .long("no-sync")
+            .help(
+"Don't wait for each write to reach the disk. Faster, but writes made shortly \
+before a crash may be lost."
+            ))
+}
@@ -0,0 +1,61 @@
+//! The `config backend log` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::cli::ArgSet;
+use crate::app::config::Backend;
+use crate::app::backends::log::{LogConfig, DEFAULT_COMPACTION_THRESHOLD};
+
+use clap::{App, Arg, SubCommand};
+
+/// Run `log` subcommand, returning the configured backend
+pub fn run(args: ArgSet) -> PolyfsResult<Backend> {
+    ::log::debug!("Running `log` subcommand");
+
+    let mut log_config = LogConfig {
+        dir: args.sub
+            .value_of("dir")
+            .expect("Required dir argument doesn't exist")
+            .into(),
+        sync: !args.sub.is_present("no_sync"),
+        compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+    };
+
+    if let Some(threshold) = args.sub.value_of("compaction_threshold") {
+        log_config.compaction_threshold = try_to!(
+            threshold.parse(),
+            "Could not parse compaction threshold"
+        );
+    }
+
+    Ok(Backend::Log(log_config))
+}
+
+/// Get CLI for the `log` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("log")
+        .about("Configure log-structured backend")
+        .long_about(
+"Configure a log-structured backend. Every write is appended to a log file and \
+the position of each value is kept in memory. Suited to write-heavy workloads."
+        )
+        .arg(Arg::with_name("dir")
+            .long("dir")
+            .short("d")
+            .value_name("dir")
+            .required(true)
+            .help("Directory to keep the log in"))
+        .arg(Arg::with_name("no_sync")
+            .long("no-sync")
+            .help(
+"Don't wait for each write to reach the disk. Faster, but writes made shortly \
+before a crash may be lost."
+            ))
+        .arg(Arg::with_name("compaction_threshold")
+            .long("compaction-threshold")
+            .value_name("bytes")
+            .help(
+"Bytes of overwritten and deleted data to allow in the log before compacting it. \
+Defaults to 64 MiB."
+            ))
+}
@@ -0,0 +1,26 @@
+//! The `config backend memory` subcommand
+
+use crate::PolyfsResult;
+use crate::cli::ArgSet;
+use crate::app::config::Backend;
+
+use clap::{App, SubCommand};
+
+/// Run `memory` subcommand, returning the configured backend
+pub fn run(_args: ArgSet) -> PolyfsResult<Backend> {
+    log::debug!("Running `memory` subcommand");
+    log::warn!("The in-memory store will lose all data when the filesystem is unmounted!");
+
+    Ok(Backend::Memory)
+}
+
+/// Get CLI for the `memory` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("memory")
+        .about("Configure in-memory backend")
+        .long_about(
+"Configure an in-memory backend. Nothing is persisted, so this is only useful \
+for testing."
+        )
+}
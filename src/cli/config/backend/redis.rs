@@ -0,0 +1,82 @@
+//! The `config backend redis` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::cli::ArgSet;
+use crate::app::config::Backend;
+use crate::app::backends::redis::RedisConfig;
+
+use clap::{App, Arg, SubCommand};
+
+/// Run `redis` subcommand, returning the configured backend
+pub fn run(args: ArgSet) -> PolyfsResult<Backend> {
+    log::debug!("Running `redis` subcommand");
+
+    let mut redis_config = RedisConfig::default();
+
+    if let Some(host) = args.sub.value_of("host") {
+        redis_config.host = host.into();
+    }
+
+    if let Some(port) = args.sub.value_of("port") {
+        redis_config.port = try_to!(port.parse(), "Could not parse port");
+    }
+
+    if let Some(db) = args.sub.value_of("db") {
+        redis_config.db = try_to!(db.parse(), "Could not parse database number");
+    }
+
+    if let Some(password) = args.sub.value_of("password") {
+        redis_config.password = Some(password.into());
+    }
+
+    if let Some(prefix) = args.sub.value_of("key_prefix") {
+        redis_config.key_prefix = prefix.into();
+    }
+
+    if let Some(connections) = args.sub.value_of("connections") {
+        redis_config.connections = try_to!(
+            connections.parse(),
+            "Could not parse number of connections"
+        );
+    }
+
+    Ok(Backend::Redis(redis_config))
+}
+
+/// Get CLI for the `redis` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("redis")
+        .about("Configure Redis backend")
+        .long_about(
+"Configure a Redis backend. Any server that speaks the Redis protocol can be \
+used. Note that the password is saved in the config file."
+        )
+        .arg(Arg::with_name("host")
+            .long("host")
+            .short("H")
+            .value_name("host")
+            .help("Host name of the server. Defaults to 127.0.0.1."))
+        .arg(Arg::with_name("port")
+            .long("port")
+            .short("p")
+            .value_name("port")
+            .help("Port of the server. Defaults to 6379."))
+        .arg(Arg::with_name("db")
+            .long("db")
+            .short("d")
+            .value_name("number")
+            .help("Database number to use. Defaults to 0."))
+        .arg(Arg::with_name("password")
+            .long("password")
+            .value_name("password")
+            .help("Password to authenticate with"))
+        .arg(Arg::with_name("key_prefix")
+            .long("key-prefix")
+            .value_name("prefix")
+            .help("Prefix added to every key. Defaults to `polyfs:`."))
+        .arg(Arg::with_name("connections")
+            .long("connections")
+            .value_name("count")
+            .help("Maximum number of idle connections to keep open. Defaults to 4."))
+}
@@ -0,0 +1,120 @@
+//! The `config backend s3` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::cli::ArgSet;
+use crate::app::config::Backend;
+use crate::app::backends::s3::S3Config;
+
+use clap::{App, Arg, SubCommand};
+
+/// Run `s3` subcommand, returning the configured backend
+pub fn run(args: ArgSet) -> PolyfsResult<Backend> {
+    log::debug!("Running `s3` subcommand");
+
+    let mut s3_config = S3Config::new(
+        args.sub
+            .value_of("bucket")
+            .expect("Required bucket argument doesn't exist")
+            .into(),
+    );
+    s3_config.path_style = args.sub.is_present("path_style");
+
+    if let Some(prefix) = args.sub.value_of("prefix") {
+        s3_config.prefix = prefix.into();
+    }
+
+    if let Some(endpoint) = args.sub.value_of("endpoint") {
+        s3_config.endpoint = Some(endpoint.into());
+    }
+
+    if let Some(region) = args.sub.value_of("region") {
+        s3_config.region = region.into();
+    }
+
+    if let Some(access_key) = args.sub.value_of("access_key") {
+        s3_config.access_key = Some(access_key.into());
+    }
+
+    if let Some(secret_key) = args.sub.value_of("secret_key") {
+        s3_config.secret_key = Some(secret_key.into());
+    }
+
+    if let Some(threshold) = args.sub.value_of("multipart_threshold") {
+        s3_config.multipart_threshold = try_to!(
+            threshold.parse(),
+            "Could not parse multipart threshold"
+        );
+    }
+
+    if let Some(part_size) = args.sub.value_of("part_size") {
+        s3_config.part_size = try_to!(part_size.parse(), "Could not parse part size");
+    }
+
+    if let Some(timeout) = args.sub.value_of("timeout") {
+        s3_config.timeout = try_to!(timeout.parse(), "Could not parse timeout");
+    }
+
+    Ok(Backend::S3(s3_config))
+}
+
+/// Get CLI for the `s3` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("s3")
+        .about("Configure S3-compatible object storage backend")
+        .long_about(
+"Configure a backend that stores each key as an object in an S3 bucket. Any \
+service that accepts AWS Signature Version 4, like MinIO, can be used. The \
+credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY unless \
+they are given here, in which case they are saved in the config file."
+        )
+        .arg(Arg::with_name("bucket")
+            .long("bucket")
+            .short("b")
+            .value_name("name")
+            .required(true)
+            .help("Bucket to store objects in"))
+        .arg(Arg::with_name("prefix")
+            .long("prefix")
+            .value_name("prefix")
+            .help("Prefix added to every object name, like 'polyfs/'"))
+        .arg(Arg::with_name("endpoint")
+            .long("endpoint")
+            .short("e")
+            .value_name("url")
+            .help(
+"URL of the service, like 'http://localhost:9000'. Defaults to the AWS endpoint \
+for the region."
+            ))
+        .arg(Arg::with_name("region")
+            .long("region")
+            .short("r")
+            .value_name("region")
+            .help("Region of the bucket. Defaults to us-east-1."))
+        .arg(Arg::with_name("path_style")
+            .long("path-style")
+            .help(
+"Put the bucket in the path of requests instead of the host name. Most \
+stand-ins for S3 need this."
+            ))
+        .arg(Arg::with_name("access_key")
+            .long("access-key")
+            .value_name("key")
+            .help("Access key ID"))
+        .arg(Arg::with_name("secret_key")
+            .long("secret-key")
+            .value_name("key")
+            .help("Secret access key"))
+        .arg(Arg::with_name("multipart_threshold")
+            .long("multipart-threshold")
+            .value_name("bytes")
+            .help("Size above which values are uploaded in parts. Defaults to 16 MiB."))
+        .arg(Arg::with_name("part_size")
+            .long("part-size")
+            .value_name("bytes")
+            .help("Size of each part of a multipart upload. At least 5 MiB, defaults to 8 MiB."))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("seconds")
+            .help("Seconds to wait for a request before giving up. Defaults to 30."))
+}
@@ -1,36 +1,83 @@
 //! The `config backend sqlite` subcommand
 
-use crate::PolyfsResult;
+use crate::{PolyfsResult, try_to};
 use crate::cli::ArgSet;
-use crate::cli::config::{load_config, save_config};
 use crate::app::config::Backend;
-use crate::app::backends::sqlite::{SqliteConfig, SqliteDb};
+use crate::app::backends::sqlite::{SqliteConfig, SqliteDb, SqliteJournalMode, SqliteSynchronous};
 
 use clap::{App, Arg, ArgGroup, SubCommand};
 
-/// Run `sqlite` subcommand
-pub fn run(args: ArgSet) -> PolyfsResult<()> {
+/// Run `sqlite` subcommand, returning the configured backend
+pub fn run(args: ArgSet) -> PolyfsResult<Backend> {
     log::debug!("Running `sqlite` subcommand");
-    let mut config = load_config(args.global)?;
 
-    let sqlite_config = SqliteConfig {
+    let mut sqlite_config = SqliteConfig {
         db: match args.sub.value_of("db_file") {
             Some(file) => SqliteDb::File(file.into()),
             None => {
-                if !args.sub.is_present("in_memory") {
-                    panic!("db_file not specified, but in_memory not present");
-                } else {
+                if args.sub.is_present("in_memory") {
                     SqliteDb::InMemory
+                } else if args.sub.is_present("temporary") {
+                    SqliteDb::Temporary
+                } else {
+                    panic!("db_file not specified, but in_memory or temporary not present");
                 }
             }
-        }
+        },
+        temp_dir: args.sub.value_of("temp_dir").map(String::from),
+        ..Default::default()
     };
 
-    config.backend = Backend::Sqlite(sqlite_config);
+    match sqlite_config.db {
+        SqliteDb::File(_) => (),
+        _ => log::warn!(
+            "The Sqlite database will not be persisted. All data will be lost when the \
+             filesystem is unmounted!"
+        ),
+    }
+
+    if let Some(mode) = args.sub.value_of("journal_mode") {
+        sqlite_config.journal_mode = match mode {
+            "delete" => SqliteJournalMode::Delete,
+            "truncate" => SqliteJournalMode::Truncate,
+            "persist" => SqliteJournalMode::Persist,
+            "memory" => SqliteJournalMode::Memory,
+            "wal" => SqliteJournalMode::Wal,
+            "off" => SqliteJournalMode::Off,
+            _ => panic!("Unrecognized journal mode"),
+        };
+    }
+
+    if let Some(synchronous) = args.sub.value_of("synchronous") {
+        sqlite_config.synchronous = match synchronous {
+            "off" => SqliteSynchronous::Off,
+            "normal" => SqliteSynchronous::Normal,
+            "full" => SqliteSynchronous::Full,
+            "extra" => SqliteSynchronous::Extra,
+            _ => panic!("Unrecognized synchronous setting"),
+        };
+    }
+
+    if let Some(timeout) = args.sub.value_of("busy_timeout") {
+        sqlite_config.busy_timeout = try_to!(timeout.parse(), "Could not parse busy timeout");
+    }
+
+    if let Some(cache_size) = args.sub.value_of("cache_size") {
+        sqlite_config.cache_size = Some(try_to!(
+            cache_size.parse(),
+            "Could not parse cache size"
+        ));
+    }
+
+    if let Some(mmap_size) = args.sub.value_of("mmap_size") {
+        sqlite_config.mmap_size = Some(try_to!(mmap_size.parse(), "Could not parse mmap size"));
+    }
 
-    save_config(args.global, &config)?;
+    if let Some(readers) = args.sub.value_of("readers") {
+        sqlite_config.readers = try_to!(readers.parse(), "Could not parse number of readers");
+    }
 
-    Ok(())
+    Ok(Backend::Sqlite(sqlite_config))
 }
 
 /// Get CLI for the `sqlite` subcommand
@@ -47,7 +94,50 @@ pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
             .long("in-memory")
             .short("m")
             .help("Use an in-memory Sqlite database for testing"))
+        .arg(Arg::with_name("temporary")
+            .long("temporary")
+            .short("t")
+            .help("Use a database file that is deleted when the filesystem is unmounted"))
         .group(ArgGroup::with_name("db")
-            .args(&["db_file", "in_memory"])
+            .args(&["db_file", "in_memory", "temporary"])
             .required(true))
+        .arg(Arg::with_name("temp_dir")
+            .long("temp-dir")
+            .value_name("dir")
+            .requires("temporary")
+            .help("Directory to create the temporary database in. Defaults to the system \
+                   temporary directory."))
+        .arg(Arg::with_name("journal_mode")
+            .long("journal-mode")
+            .value_name("mode")
+            .possible_values(&["delete", "truncate", "persist", "memory", "wal", "off"])
+            .help("Journal mode of the database. Defaults to `wal`."))
+        .arg(Arg::with_name("synchronous")
+            .long("synchronous")
+            .value_name("level")
+            .possible_values(&["off", "normal", "full", "extra"])
+            .help("How often to wait for data to reach the disk. Defaults to `full`."))
+        .arg(Arg::with_name("busy_timeout")
+            .long("busy-timeout")
+            .value_name("milliseconds")
+            .help("How long to wait for a locked database. Defaults to 5000."))
+        .arg(Arg::with_name("cache_size")
+            .long("cache-size")
+            .value_name("size")
+            .allow_hyphen_values(true)
+            .help(
+"Page cache size of each connection. Positive values are a number of pages and \
+negative values a number of KiB."
+            ))
+        .arg(Arg::with_name("mmap_size")
+            .long("mmap-size")
+            .value_name("bytes")
+            .help("Maximum number of bytes of the database to memory map"))
+        .arg(Arg::with_name("readers")
+            .long("readers")
+            .value_name("count")
+            .help(
+"Number of read-only connections to keep open alongside the write connection. \
+Defaults to 4."
+            ))
 }
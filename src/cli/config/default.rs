@@ -2,9 +2,8 @@
 use std::path::Path;
 
 use crate::{PolyfsResult, try_to};
-use crate::app::config::AppConfig;
 use crate::cli::ArgSet;
-use crate::cli::config::save_config;
+use crate::cli::config::{default_config, save_config};
 
 use clap::{App, Arg, SubCommand};
 
@@ -29,7 +28,7 @@ pub fn run(args: ArgSet) -> PolyfsResult<()> {
         .expect("Required config file argument doesn't exist");
 
     let write_config = || {
-        save_config(args.global, &AppConfig::default())?;
+        save_config(args.global, &default_config(config_path)?)?;
 
         Ok(())
     };
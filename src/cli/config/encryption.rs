@@ -0,0 +1,92 @@
+//! The `config encryption` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::app::keyvalue::encrypted::EncryptionConfig;
+use crate::cli::ArgSet;
+use crate::cli::config::{load_config, save_config};
+
+use clap::{App, Arg, ArgGroup, SubCommand};
+
+use std::path::Path;
+
+/// Run `encryption` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `encryption` subcommand");
+    let mut config = load_config(args.global)?;
+
+    let encryption = if args.sub.is_present("passphrase") {
+        Some(EncryptionConfig::Passphrase)
+    } else if let Some(key_file) = args.sub.value_of("key_file") {
+        if !Path::new(key_file).exists() {
+            create_key_file(key_file)?;
+            log::info!("Created new key file: {}", key_file);
+        }
+        Some(EncryptionConfig::KeyFile(String::from(key_file)))
+    } else {
+        None
+    };
+
+    if encryption != config.encryption {
+        log::warn!(
+            "Encryption only takes effect for stores that haven't been written to yet. Use \
+             `polyfs rotate-key` to change the key of an encrypted store."
+        );
+    }
+
+    config.encryption = encryption;
+    save_config(args.global, &config)?;
+
+    Ok(())
+}
+
+/// Write a new random key to a key file that only its owner can read
+pub fn create_key_file(path: &str) -> PolyfsResult<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = try_to!(
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path),
+        "Could not create key file"
+    );
+    try_to!(
+        file.write_all(&rand::random::<[u8; 32]>()),
+        "Could not write key file"
+    );
+
+    Ok(())
+}
+
+/// Get CLI for the `encryption` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("encryption")
+        .about("Configure encryption of the stored keys and values")
+        .long_about(
+"Configure encryption of the stored keys and values. Encryption must be set up \
+before the filesystem is first mounted: stores that have already been written \
+to without encryption can't be opened once it is enabled."
+        )
+        .arg(Arg::with_name("passphrase")
+            .long("passphrase")
+            .help(
+"Derive the key from a passphrase, read from the POLYFS_PASSPHRASE environment \
+variable or asked for when the stores are opened."
+            ))
+        .arg(Arg::with_name("key_file")
+            .long("key-file")
+            .value_name("file")
+            .help(
+"Derive the key from the contents of a file. A new random key is written to \
+the file if it doesn't exist."
+            ))
+        .arg(Arg::with_name("none")
+            .long("none")
+            .help("Don't encrypt the stores"))
+        .group(ArgGroup::with_name("source")
+            .args(&["passphrase", "key_file", "none"])
+            .required(true))
+}
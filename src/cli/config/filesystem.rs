@@ -0,0 +1,120 @@
+//! The `config filesystem` subcommand
+
+use crate::{PolyfsResult, try_to};
+use crate::app::filesystem::AtimePolicy;
+use crate::app::filesystem::compression::Codec;
+use crate::cli::ArgSet;
+use crate::cli::config::{load_config, save_config};
+
+use clap::{App, Arg, SubCommand};
+
+/// Run `filesystem` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `filesystem` subcommand");
+    let mut config = load_config(args.global)?;
+
+    if let Some(policy) = args.sub.value_of("atime") {
+        config.filesystem.atime = match policy {
+            "strictatime" => AtimePolicy::StrictAtime,
+            "relatime" => AtimePolicy::RelAtime,
+            "noatime" => AtimePolicy::NoAtime,
+            _ => panic!("Unrecognized atime policy"),
+        };
+    }
+
+    if let Some(threads) = args.sub.value_of("threads") {
+        config.filesystem.threads = match threads {
+            "auto" => None,
+            threads => Some(try_to!(
+                threads.parse::<usize>(),
+                "Could not parse number of threads"
+            )),
+        };
+    }
+
+    if let Some(codec) = args.sub.value_of("compression") {
+        config.filesystem.compression.codec = match codec {
+            "none" => Codec::None,
+            "lz4" => Codec::Lz4,
+            "zstd" => Codec::Zstd,
+            _ => panic!("Unrecognized compression codec"),
+        };
+    }
+
+    if let Some(level) = args.sub.value_of("compression_level") {
+        config.filesystem.compression.level = try_to!(
+            level.parse(),
+            "Could not parse compression level"
+        );
+    }
+
+    if let Some(trash) = args.sub.value_of("trash") {
+        config.filesystem.trash.enabled = trash == "on";
+    }
+
+    if let Some(days) = args.sub.value_of("trash_retention") {
+        config.filesystem.trash.retention_days = match days {
+            "forever" => None,
+            days => Some(try_to!(
+                days.parse::<u64>(),
+                "Could not parse trash retention period"
+            )),
+        };
+    }
+
+    save_config(args.global, &config)?;
+
+    Ok(())
+}
+
+/// Get CLI for the `filesystem` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("filesystem")
+        .about("Configure filesystem behavior")
+        .arg(Arg::with_name("atime")
+            .long("atime")
+            .value_name("policy")
+            .possible_values(&["strictatime", "relatime", "noatime"])
+            .help(
+"When to update file access times. `strictatime` updates them on every access, \
+`relatime` only when they are older than the modification or change time or \
+more than a day old, and `noatime` never updates them."
+            ))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .value_name("count")
+            .help(
+"The number of worker threads handling filesystem requests, or `auto` to use \
+one thread per CPU."
+            ))
+        .arg(Arg::with_name("compression")
+            .long("compression")
+            .value_name("codec")
+            .possible_values(&["none", "lz4", "zstd"])
+            .help(
+"How to compress file contents. `lz4` is very fast, `zstd` compresses better. \
+Contents that don't compress are stored as is. Changing this only affects \
+contents written afterwards."
+            ))
+        .arg(Arg::with_name("compression_level")
+            .long("compression-level")
+            .value_name("level")
+            .help("The zstd compression level, or 0 for zstd's default level."))
+        .arg(Arg::with_name("trash")
+            .long("trash")
+            .value_name("on|off")
+            .possible_values(&["on", "off"])
+            .help(
+"Whether removed files are moved to the trash, where `polyfs trash` can \
+restore them from, instead of being deleted."
+            ))
+        .arg(Arg::with_name("trash_retention")
+            .long("trash-retention")
+            .value_name("days")
+            .help(
+"The number of days that files are kept in the trash before the mounted \
+filesystem purges them, or `forever` to keep them until they are purged with \
+`polyfs trash purge`."
+            ))
+}
@@ -0,0 +1,88 @@
+//! PolyFS `fsck` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{PolyfsError, PolyfsResult};
+use clap::{App, Arg, SubCommand};
+
+/// Get CLI for the `fsck` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("fsck")
+        .about("Check the filesystem metadata for consistency")
+        .long_about(
+"Check the filesystem metadata for consistency. Directory entries must name \
+files that exist, the children of every directory must match its entries, \
+link counts must match the number of entries naming each file and every file \
+must be reachable from the root directory or the trash. With `--repair`, \
+broken entries are deleted, children lists and link counts are corrected and \
+unreachable files are linked into `/lost+found`. The filesystem can't be \
+checked or repaired while it is mounted, since changes made by the mount would \
+show up as problems."
+        )
+        .arg(Arg::with_name("repair")
+            .long("repair")
+            .short("r")
+            .help("Repair the problems found"))
+        .arg(force_arg())
+}
+
+/// Run `fsck` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `fsck` subcommand");
+
+    use crate::app::filesystem::fsck::check_filesystem;
+    use crate::app::filesystem::migration::check_layout;
+    use crate::cli::store::{open_stores, Access};
+
+    let config = load_config(args.global)?;
+
+    let (kv_store, _, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        Access::Exclusive {
+            purpose: "fsck",
+            force: args.sub.is_present("force"),
+        },
+    )?;
+    check_layout(&kv_store)?;
+
+    let report = check_filesystem(&kv_store, args.sub.is_present("repair"))?;
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    if report.relinked > 0 {
+        println!(
+            "{} {} unreachable files into /lost+found",
+            if report.repaired { "Linked" } else { "Would link" },
+            report.relinked
+        );
+    }
+
+    let repairable = report.problems.iter().filter(|problem| problem.is_repairable()).count();
+    let left = report.problems.len() - repairable;
+    if report.repaired {
+        println!("Repaired {} problems", repairable);
+    }
+
+    if left > 0 {
+        Err(PolyfsError {
+            message: format!("Found {} problems that can't be repaired", left),
+            cause: None,
+        })
+    } else if !report.repaired {
+        Err(PolyfsError {
+            message: format!("Found {} problems, run with `--repair` to repair them", repairable),
+            cause: None,
+        })
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! PolyFS `gc` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::PolyfsResult;
+use clap::{App, Arg, SubCommand};
+
+/// Get CLI for the `gc` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("gc")
+        .about("Delete file chunks that no file refers to")
+        .long_about(
+"Delete file chunks that no file refers to. Identical chunks are stored once \
+and shared by every file containing them, so chunks are not deleted as soon as \
+the last file referring to them changes. Reference counts are checked and \
+corrected along the way. Garbage can't be collected while the filesystem is \
+mounted."
+        )
+        .arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .short("n")
+            .help("Report what would be deleted without modifying the store"))
+        .arg(force_arg())
+}
+
+/// Run `gc` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `gc` subcommand");
+
+    use crate::app::filesystem::chunks::collect_garbage;
+    use crate::app::filesystem::migration::check_stores;
+    use crate::cli::store::{open_stores, Access};
+
+    let config = load_config(args.global)?;
+
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        Access::Exclusive {
+            purpose: "gc",
+            force: args.sub.is_present("force"),
+        },
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+
+    let report = collect_garbage(
+        &kv_store,
+        data_store.as_ref().unwrap_or(&kv_store),
+        args.sub.is_present("dry_run"),
+    )?;
+
+    println!(
+        "{} {} unreferenced chunks ( {} bytes )",
+        if report.dry_run { "Would delete" } else { "Deleted" },
+        report.chunks_deleted,
+        report.bytes_freed
+    );
+    if report.orphaned_refs > 0 || report.refcounts_fixed > 0 {
+        println!(
+            "{} {} references from removed files and {} reference counts",
+            if report.dry_run { "Would fix" } else { "Fixed" },
+            report.orphaned_refs,
+            report.refcounts_fixed
+        );
+    }
+    if report.orphaned_snapshot_pairs > 0 {
+        println!(
+            "{} {} pairs left behind by interrupted snapshot operations",
+            if report.dry_run { "Would delete" } else { "Deleted" },
+            report.orphaned_snapshot_pairs
+        );
+    }
+    if report.chunks_missing > 0 {
+        println!(
+            "{} chunks referred to by files are missing from the data store",
+            report.chunks_missing
+        );
+    }
+
+    Ok(())
+}
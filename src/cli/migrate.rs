@@ -0,0 +1,96 @@
+//! PolyFS `migrate` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::PolyfsResult;
+use clap::{App, Arg, SubCommand};
+
+/// Get CLI for the `migrate` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("migrate")
+        .about("Upgrade the backend stores to the current on-disk layout")
+        .long_about(
+"Upgrade the backend stores to the current on-disk layout. Every key in the \
+metadata store, and in the data store if one is configured, will be read and \
+any keys or values using an older layout will be rewritten. Changes are \
+written in batches that record how far the migration got, so a migration that \
+fails can be run again and resumes where it stopped. Only backends that write \
+batches atomically can be relied on to resume correctly: back up the stores \
+before running them. The stores can't be migrated while the filesystem is \
+mounted."
+        )
+        .arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .short("n")
+            .help("Report the changes that would be made without modifying the store"))
+        .arg(Arg::with_name("allow_migrations")
+            .long("allow-migrations")
+            .help("Migrate the backend database without asking for confirmation"))
+        .arg(force_arg())
+}
+
+/// Run `migrate` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `migrate` subcommand");
+
+    use crate::app::filesystem::migration::migrate;
+    use crate::cli::store::{open_stores, Access};
+
+    let dry_run = args.sub.is_present("dry_run");
+    let config = load_config(args.global)?;
+
+    let access = match dry_run {
+        true => Access::Read,
+        false => Access::Exclusive {
+            purpose: "migrate",
+            force: args.sub.is_present("force"),
+        },
+    };
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        args.sub.is_present("allow_migrations"),
+        access,
+    )?;
+
+    let mut stores = vec![("Metadata", kv_store)];
+    if let Some(data_store) = data_store {
+        stores.push(("Data", data_store));
+    }
+
+    for (role, kv_store) in stores {
+        let report = migrate(&kv_store, dry_run, |progress| {
+            if progress.keys_processed % 1000 == 0
+                || progress.keys_processed == progress.keys_total
+            {
+                log::info!(
+                    "{} store layout version {}: {}/{} keys processed, {} changed",
+                    role,
+                    progress.from,
+                    progress.keys_processed,
+                    progress.keys_total,
+                    progress.keys_changed
+                );
+            }
+        })?;
+
+        if report.from == report.to {
+            println!("{} store is already at layout version {}", role, report.to);
+        } else {
+            println!(
+                "{} store: {} layout version {} to {}: {} keys rewritten, {} keys deleted",
+                role,
+                if report.dry_run { "Would migrate" } else { "Migrated" },
+                report.from,
+                report.to,
+                report.keys_rewritten,
+                report.keys_deleted
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,6 +1,7 @@
 //! PolyFS `mount` subcommand
 
 use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
 use crate::cli::ArgSet;
 use crate::PolyfsResult;
 use clap::{App, Arg, SubCommand};
@@ -15,6 +16,18 @@ pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
                 .short("r")
                 .help("Mount the filesystem as read-only"),
         )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("name")
+                .help("Mount a snapshot of the filesystem. Snapshots are always read-only."),
+        )
+        .arg(
+            Arg::with_name("allow_migrations")
+                .long("allow-migrations")
+                .help("Migrate the backend database without asking for confirmation"),
+        )
+        .arg(force_arg())
         .arg(
             Arg::with_name("mountpoint")
                 .help("location to mount the filesystem")
@@ -26,9 +39,11 @@ pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
 pub fn run(args: ArgSet) -> PolyfsResult<()> {
     log::debug!("Running `mount` subcommand");
 
-    use crate::app::backends::sqlite::SqliteKvStore;
-    use crate::app::config::Backend;
+    use crate::app::filesystem::migration::check_stores;
+    use crate::app::filesystem::snapshots::{find_snapshot, SnapshotView};
     use crate::app::filesystem::PolyfsFilesystem;
+    use crate::app::keyvalue::KeyValueStore;
+    use crate::cli::store::{open_stores, Access};
 
     let mountpoint = args
         .sub
@@ -36,16 +51,32 @@ pub fn run(args: ArgSet) -> PolyfsResult<()> {
         .expect("Could not load mountpoint arg");
     let config = load_config(args.global)?;
 
-    let kv_store;
-    match config.backend {
-        Backend::Sqlite(sqlite_config) => {
-            kv_store = SqliteKvStore::new(sqlite_config)?;
+    // The lease is held until the filesystem is unmounted
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        args.sub.is_present("allow_migrations"),
+        Access::Exclusive {
+            purpose: "mount",
+            force: args.sub.is_present("force"),
+        },
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+
+    let (kv_store, data_store, read_only) = match args.sub.value_of("snapshot") {
+        Some(name) => {
+            let id = find_snapshot(&kv_store, name)?.id;
+            let view = |store| Box::new(SnapshotView::new(store, id)) as Box<dyn KeyValueStore>;
+            (view(kv_store), data_store.map(view), true)
         }
-    }
+        None => (kv_store, data_store, args.sub.is_present("read_only")),
+    };
 
     use std::ffi::OsStr;
-    let fuse_args: &[&OsStr] = &[&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-    let filesystem = PolyfsFilesystem::new(kv_store);
+    let options = if read_only { "auto_unmount,ro" } else { "auto_unmount" };
+    let fuse_args: &[&OsStr] = &[&OsStr::new("-o"), &OsStr::new(options)];
+    let filesystem = PolyfsFilesystem::new(kv_store, data_store, config.filesystem, read_only);
 
     crate::try_to!(
         fuse::mount(filesystem, &mountpoint, fuse_args),
@@ -0,0 +1,250 @@
+//! PolyFS `quota` subcommand
+
+use crate::app::filesystem::quota::{QuotaId, QuotaKind, QuotaLimits, QuotaUsage};
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{try_to, PolyfsError, PolyfsResult};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+
+/// Get CLI for the `quota` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    let id_args = [
+        Arg::with_name("user")
+            .long("user")
+            .short("u")
+            .value_name("uid")
+            .help("A user, by uid"),
+        Arg::with_name("group")
+            .long("group")
+            .short("g")
+            .value_name("gid")
+            .help("A group, by gid"),
+        Arg::with_name("project")
+            .long("project")
+            .short("p")
+            .value_name("id")
+            .help("A project, by project id"),
+    ];
+    let id_group = ArgGroup::with_name("id")
+        .args(&["user", "group", "project"])
+        .required(true);
+
+    SubCommand::with_name("quota")
+        .about("Limit the space used by users, groups and project directories")
+        .long_about(
+"Limit the space used by users, groups and project directories. Every file \
+counts towards the quotas of its owner, its group and its project, if it is in \
+a project directory. Space is counted as the size of the files as seen \
+through the filesystem, before compression. Creating files or growing them \
+past a limit fails with `EDQUOT`. A mounted filesystem applies new limits \
+within 30 seconds. Projects can't be assigned while the filesystem is \
+mounted."
+        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("set")
+            .about("Set the limits of a user, group or project")
+            .args(&id_args)
+            .group(id_group.clone())
+            .arg(Arg::with_name("bytes")
+                .long("bytes")
+                .short("b")
+                .value_name("size")
+                .help(
+"The most space the files may use, in bytes or with a K, M, G or T suffix, or \
+`none` for no limit"
+                ))
+            .arg(Arg::with_name("inodes")
+                .long("inodes")
+                .short("i")
+                .value_name("count")
+                .help("The most files there may be, or `none` for no limit")))
+        .subcommand(SubCommand::with_name("get")
+            .about("Show the usage and limits of a user, group or project")
+            .args(&id_args)
+            .group(id_group))
+        .subcommand(SubCommand::with_name("report")
+            .about("Show the usage and limits of every user, group and project"))
+        .subcommand(SubCommand::with_name("project")
+            .about(
+"Put a directory and everything in it in a project. Files created in the \
+directory later inherit the project."
+            )
+            .arg(Arg::with_name("directory")
+                .help("The directory, relative to the root of the filesystem")
+                .required(true))
+            .arg(Arg::with_name("id")
+                .help("The project id, or `0` to take the directory out of its project")
+                .required(true))
+            .arg(force_arg()))
+}
+
+/// Run `quota` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `quota` subcommand");
+
+    use crate::app::filesystem::migration::{check_layout, check_stores};
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::app::filesystem::quota::{set_limits, Quotas};
+    use crate::app::filesystem::usage::Usage;
+    use crate::cli::store::{open_stores, Access};
+    use std::io::Error;
+    use std::path::Path;
+
+    let config = load_config(args.global)?;
+
+    // Assigning a project rewrites inodes and usage, which needs the lease.
+    // Limits are only read by a mount, so they can be set while it runs.
+    let access = match args.sub.subcommand() {
+        ("project", Some(sub)) => Access::Exclusive {
+            purpose: "quota project",
+            force: sub.is_present("force"),
+        },
+        _ => Access::Read,
+    };
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        access,
+    )?;
+    check_layout(&kv_store)?;
+
+    // Stores that were just migrated haven't had their usage counted yet
+    let load_quotas = |kv_store| -> PolyfsResult<Quotas> {
+        let mut quotas = Quotas::load(kv_store)?;
+        if Usage::load(kv_store)?.is_none() {
+            quotas.count(kv_store)?;
+        }
+        Ok(quotas)
+    };
+
+    match args.sub.subcommand() {
+        ("set", Some(sub)) => {
+            let id = parse_id(sub)?;
+            let mut limits = Quotas::load(&kv_store)?.limits(id);
+            if let Some(bytes) = sub.value_of("bytes") {
+                limits.bytes = parse_limit(bytes, true)?;
+            }
+            if let Some(inodes) = sub.value_of("inodes") {
+                limits.inodes = parse_limit(inodes, false)?;
+            }
+
+            set_limits(&kv_store, id, limits)?;
+            println!(
+                "Limits of {}: bytes {}, files {}",
+                id,
+                format_limit(limits.bytes),
+                format_limit(limits.inodes)
+            );
+        }
+
+        ("get", Some(sub)) => {
+            let id = parse_id(sub)?;
+            let quotas = load_quotas(&kv_store)?;
+            print_quota(id, quotas.usage(id), quotas.limits(id));
+        }
+
+        ("report", Some(_)) => {
+            let quotas = load_quotas(&kv_store)?;
+            for id in quotas.ids() {
+                print_quota(id, quotas.usage(id), quotas.limits(id));
+            }
+        }
+
+        ("project", Some(sub)) => {
+            let directory = Path::new(sub.value_of("directory").expect("Could not load directory arg"));
+            let project = try_to!(
+                sub.value_of("id").expect("Could not load id arg").parse::<u32>(),
+                "Invalid project id"
+            );
+            check_stores(&kv_store, data_store.as_ref())?;
+
+            let core = FilesystemCore::new(kv_store, data_store, config.filesystem, false);
+            try_to!(
+                core.init().map_err(Error::from_raw_os_error),
+                "Could not open filesystem"
+            );
+            let inode = try_to!(
+                core.resolve(directory).map_err(Error::from_raw_os_error),
+                format!("Could not find {}", directory.display())
+            );
+            let moved = try_to!(
+                core.set_project(inode.ino, project).map_err(Error::from_raw_os_error),
+                format!("Could not set the project of {}", directory.display())
+            );
+            println!("Moved {} files to project {}", moved, project);
+        }
+
+        _ => panic!(
+            "Unimplemented command or failure to show help message when lacking a subcommand."
+        ),
+    }
+
+    Ok(())
+}
+
+/// Get the user, group or project given on the command line
+fn parse_id(sub: &ArgMatches) -> PolyfsResult<QuotaId> {
+    let (kind, value) = if let Some(uid) = sub.value_of("user") {
+        (QuotaKind::User, uid)
+    } else if let Some(gid) = sub.value_of("group") {
+        (QuotaKind::Group, gid)
+    } else {
+        (
+            QuotaKind::Project,
+            sub.value_of("project").expect("Could not load id arg"),
+        )
+    };
+
+    Ok(QuotaId {
+        kind,
+        id: try_to!(value.parse::<u32>(), format!("Invalid id: {}", value)),
+    })
+}
+
+/// Parse a limit, which may have a binary size suffix if it is in bytes
+fn parse_limit(value: &str, size: bool) -> PolyfsResult<Option<u64>> {
+    if value == "none" {
+        return Ok(None);
+    }
+
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') if size => (&value[..value.len() - 1], 1 << 10),
+        Some('M') if size => (&value[..value.len() - 1], 1 << 20),
+        Some('G') if size => (&value[..value.len() - 1], 1 << 30),
+        Some('T') if size => (&value[..value.len() - 1], 1 << 40),
+        _ => (value, 1),
+    };
+    let number = try_to!(number.parse::<u64>(), format!("Invalid limit: {}", value));
+
+    match number.checked_mul(multiplier) {
+        Some(limit) => Ok(Some(limit)),
+        None => Err(PolyfsError {
+            message: format!("Limit is too large: {}", value),
+            cause: None,
+        }),
+    }
+}
+
+/// Format a limit for printing
+fn format_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(limit) => limit.to_string(),
+        None => String::from("none"),
+    }
+}
+
+/// Print the usage and limits of a user, group or project
+fn print_quota(id: QuotaId, usage: QuotaUsage, limits: QuotaLimits) {
+    println!(
+        "{:<16} bytes {:>14} / {:<14} files {:>10} / {}",
+        id.to_string(),
+        usage.bytes,
+        format_limit(limits.bytes),
+        usage.inodes,
+        format_limit(limits.inodes),
+    );
+}
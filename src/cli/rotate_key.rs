@@ -0,0 +1,165 @@
+//! PolyFS `rotate-key` subcommand
+
+use crate::app::keyvalue::encrypted::{opens_with, rotate_key, EncryptionConfig, Secret};
+use crate::app::keyvalue::KeyValueStore;
+use crate::cli::config::{load_config, save_config};
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{PolyfsError, PolyfsResult};
+use clap::{App, Arg, ArgGroup, SubCommand};
+
+/// Get CLI for the `rotate-key` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("rotate-key")
+        .about("Change the passphrase or key file of the encrypted stores")
+        .long_about(
+"Change the passphrase or key file that the encrypted stores are opened with. \
+The current secret is read the same way as when mounting, and a new passphrase \
+from the POLYFS_NEW_PASSPHRASE environment variable or asked for. Only the \
+stored data key is re-encrypted unless --reencrypt is given, which can also be \
+used with the current passphrase or key file to only replace the data key. \
+Pairs can't be re-encrypted while the filesystem is mounted. Every store is \
+checked before any of them is changed. If the rotation fails partway, run the \
+same command again: stores that already use the new secret are skipped."
+        )
+        .arg(Arg::with_name("passphrase")
+            .long("passphrase")
+            .help("Switch to a new passphrase"))
+        .arg(Arg::with_name("key_file")
+            .long("key-file")
+            .value_name("file")
+            .help(
+"Switch to a new key file. A new random key is written to the file if it \
+doesn't exist."
+            ))
+        .group(ArgGroup::with_name("source")
+            .args(&["passphrase", "key_file"]))
+        .arg(Arg::with_name("reencrypt")
+            .long("reencrypt")
+            .help(
+"Also re-encrypt every key and value with a new data key, for when the data \
+key itself may have been exposed"
+            ))
+        .arg(force_arg())
+}
+
+/// Run `rotate-key` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `rotate-key` subcommand");
+
+    use crate::cli::config::encryption::create_key_file;
+    use crate::cli::store::{open_kv_store, prompt_passphrase, read_secret, take_lease, Access};
+    use std::sync::Arc;
+
+    let config = load_config(args.global)?;
+    let encryption = match &config.encryption {
+        Some(encryption) => encryption.clone(),
+        None => {
+            return Err(PolyfsError {
+                message: String::from("Encryption is not configured"),
+                cause: None,
+            })
+        }
+    };
+
+    let new_encryption = if let Some(key_file) = args.sub.value_of("key_file") {
+        EncryptionConfig::KeyFile(String::from(key_file))
+    } else if args.sub.is_present("passphrase") {
+        EncryptionConfig::Passphrase
+    } else {
+        encryption.clone()
+    };
+
+    let reencrypt = args.sub.is_present("reencrypt");
+    let old_secret = read_secret(&encryption, "Current passphrase: ", "POLYFS_PASSPHRASE")?;
+    let new_secret = match &new_encryption {
+        EncryptionConfig::Passphrase => match std::env::var("POLYFS_NEW_PASSPHRASE") {
+            Ok(passphrase) => Secret::Passphrase(passphrase),
+            Err(_) => {
+                let passphrase = prompt_passphrase("New passphrase: ")?;
+                if prompt_passphrase("Repeat new passphrase: ")? != passphrase {
+                    return Err(PolyfsError {
+                        message: String::from("The passphrases don't match"),
+                        cause: None,
+                    });
+                }
+                Secret::Passphrase(passphrase)
+            }
+        },
+        EncryptionConfig::KeyFile(path) => {
+            if new_encryption == encryption && !reencrypt {
+                return Err(PolyfsError {
+                    message: String::from(
+                        "The stores already use this key file. Use --key-file with a new file, \
+                         or --reencrypt to only replace the data key",
+                    ),
+                    cause: None,
+                });
+            }
+            if !std::path::Path::new(path).exists() {
+                create_key_file(path)?;
+                log::info!("Created new key file: {}", path);
+            }
+            read_secret(&new_encryption, "", "")?
+        }
+    };
+
+    let mut backends = vec![("Metadata", config.backend)];
+    if let Some(data_backend) = config.data_backend {
+        backends.push(("Data", data_backend));
+    }
+
+    // Open and check every store before changing any of them. A store that
+    // only opens with the new secret was rotated by an earlier run that failed
+    // on a later store.
+    let mut stores = vec![];
+    let mut _lease = None;
+    for (role, backend) in backends {
+        // Re-encrypting rewrites every pair, which a mount must not see
+        let exclusive = reencrypt && role == "Metadata";
+        let kv_store: Arc<dyn KeyValueStore> = Arc::from(open_kv_store(backend, false, exclusive)?);
+
+        if exclusive {
+            _lease = take_lease(
+                &kv_store,
+                Access::Exclusive {
+                    purpose: "rotate-key",
+                    force: args.sub.is_present("force"),
+                },
+            )?;
+        }
+
+        if opens_with(&kv_store, &old_secret)? {
+            stores.push((role, kv_store));
+        } else if opens_with(&kv_store, &new_secret)? {
+            println!("{} store: already rotated", role);
+        } else {
+            return Err(PolyfsError {
+                message: format!(
+                    "Could not decrypt the {} store: wrong passphrase or key file",
+                    role.to_lowercase()
+                ),
+                cause: None,
+            });
+        }
+    }
+
+    for (role, kv_store) in stores {
+        let reencrypted = rotate_key(&kv_store, &old_secret, &new_secret, reencrypt)?;
+
+        if reencrypt {
+            println!("{} store: rotated key, {} pairs re-encrypted", role, reencrypted);
+        } else {
+            println!("{} store: rotated key", role);
+        }
+    }
+
+    if new_encryption != encryption {
+        let mut config = load_config(args.global)?;
+        config.encryption = Some(new_encryption);
+        save_config(args.global, &config)?;
+    }
+
+    Ok(())
+}
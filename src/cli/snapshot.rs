@@ -0,0 +1,110 @@
+//! PolyFS `snapshot` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::PolyfsResult;
+use clap::{App, AppSettings, Arg, SubCommand};
+
+/// Get CLI for the `snapshot` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    let name = Arg::with_name("name")
+        .help("The name of the snapshot")
+        .required(true);
+
+    SubCommand::with_name("snapshot")
+        .about("Create, list, delete and restore snapshots of the filesystem")
+        .long_about(
+"Create, list, delete and restore point-in-time snapshots of the filesystem. \
+Snapshots copy the filesystem's metadata but share file contents with it, so \
+they only take up space for what has changed since. Snapshots can be mounted \
+read-only with `polyfs mount --snapshot <name>`. Snapshots can't be created, \
+deleted or restored while the filesystem or a snapshot is mounted."
+        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("create")
+            .about("Take a snapshot of the filesystem")
+            .arg(name.clone())
+            .arg(force_arg()))
+        .subcommand(SubCommand::with_name("list")
+            .about("List snapshots, oldest first"))
+        .subcommand(SubCommand::with_name("delete")
+            .about(
+"Delete a snapshot. Run `polyfs gc` afterwards to free the space of contents \
+that only the snapshot referred to."
+            )
+            .arg(name.clone())
+            .arg(force_arg()))
+        .subcommand(SubCommand::with_name("restore")
+            .about("Replace the contents of the filesystem with a snapshot")
+            .arg(name)
+            .arg(force_arg()))
+}
+
+/// Run `snapshot` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `snapshot` subcommand");
+
+    use crate::app::filesystem::migration::check_stores;
+    use crate::app::filesystem::snapshots::{
+        create_snapshot, delete_snapshot, list_snapshots, restore_snapshot,
+    };
+    use crate::cli::store::{open_stores, Access};
+
+    let config = load_config(args.global)?;
+
+    let access = match args.sub.subcommand() {
+        ("list", _) => Access::Read,
+        (_, sub) => Access::Exclusive {
+            purpose: "snapshot",
+            force: sub.is_some_and(|sub| sub.is_present("force")),
+        },
+    };
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        access,
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+    let data_store = data_store.as_ref().unwrap_or(&kv_store);
+
+    let name = |sub: &clap::ArgMatches| {
+        String::from(sub.value_of("name").expect("Could not load name arg"))
+    };
+
+    match args.sub.subcommand() {
+        ("create", Some(sub)) => {
+            let snapshot = create_snapshot(&kv_store, data_store, &name(sub))?;
+            println!("Created snapshot {}", snapshot.name);
+        }
+
+        ("list", Some(_)) => {
+            for snapshot in list_snapshots(&kv_store)? {
+                let created = chrono::NaiveDateTime::from_timestamp(
+                    snapshot.created.sec,
+                    snapshot.created.nsec,
+                );
+                println!("{}  {}", created.format("%Y-%m-%d %H:%M:%S UTC"), snapshot.name);
+            }
+        }
+
+        ("delete", Some(sub)) => {
+            delete_snapshot(&kv_store, data_store, &name(sub))?;
+            println!("Deleted snapshot {}", name(sub));
+        }
+
+        ("restore", Some(sub)) => {
+            restore_snapshot(&kv_store, data_store, &name(sub))?;
+            println!("Restored snapshot {}", name(sub));
+        }
+
+        _ => panic!(
+            "Unimplemented command or failure to show help message when lacking a subcommand."
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Opening the configured backend store from the CLI
+
+use crate::app::backends::directory::DirectoryKvStore;
+use crate::app::backends::log::LogKvStore;
+use crate::app::backends::memory::MemoryKvStore;
+use crate::app::backends::postgres::{PostgresConfig, PostgresKvStore};
+use crate::app::backends::redis::RedisKvStore;
+use crate::app::backends::s3::S3KvStore;
+use crate::app::backends::sqlite::{SqliteConfig, SqliteKvStore};
+use crate::app::config::Backend;
+use crate::app::keyvalue::encrypted::{EncryptedKvStore, EncryptionConfig, Secret};
+use crate::app::keyvalue::lease::Lease;
+use crate::app::keyvalue::KeyValueStore;
+use crate::{PolyfsError, PolyfsResult, try_to};
+use clap::Arg;
+use std::sync::Arc;
+
+/// Open the store for the configured backend
+///
+/// Migrating an existing database must be confirmed by the user unless
+/// `allow_migrations` is true. With `exclusive`, a backend that can lock its
+/// store against other processes holds that lock until the store is dropped.
+pub fn open_kv_store(
+    backend: Backend,
+    allow_migrations: bool,
+    exclusive: bool,
+) -> PolyfsResult<Box<dyn KeyValueStore>> {
+    Ok(match backend {
+        Backend::Sqlite(sqlite_config) => {
+            Box::new(open_sqlite_store(sqlite_config, allow_migrations)?)
+        }
+        Backend::Memory => {
+            log::warn!("Using an in-memory store. All data will be lost on exit!");
+            Box::new(MemoryKvStore::new())
+        }
+        Backend::Log(log_config) => Box::new(LogKvStore::open(log_config)?),
+        Backend::Postgres(postgres_config) => {
+            let mut kv_store = open_postgres_store(postgres_config, allow_migrations)?;
+            if exclusive {
+                kv_store.lock_exclusive()?;
+            }
+            Box::new(kv_store)
+        }
+        Backend::Redis(redis_config) => Box::new(RedisKvStore::new(redis_config)?),
+        Backend::Directory(directory_config) => {
+            Box::new(DirectoryKvStore::new(directory_config)?)
+        }
+        Backend::S3(s3_config) => Box::new(S3KvStore::new(s3_config)?),
+    })
+}
+
+/// How a command uses the stores it opens
+pub enum Access<'a> {
+    /// Only read the stores, which can be done while the filesystem is mounted
+    Read,
+    /// Change the stores, which needs the lease of the metadata store. A lease
+    /// held by someone else is taken over if `force` is true.
+    Exclusive {
+        /// What the lease is taken for, shown to anyone else trying to take it
+        purpose: &'a str,
+        /// Take over a lease left behind by a mount or command that was killed
+        force: bool,
+    },
+}
+
+/// The lease held on the metadata store while it is opened exclusively
+pub type StoreLease = Lease<Arc<dyn KeyValueStore>>;
+
+/// The metadata store, the separate data store if one is configured, and the
+/// lease on the metadata store if it was opened exclusively
+///
+/// The lease is released when it is dropped, so it must be kept for as long as
+/// the stores are changed.
+pub type Stores = (
+    Box<dyn KeyValueStore>,
+    Option<Box<dyn KeyValueStore>>,
+    Option<StoreLease>,
+);
+
+/// Get the argument for taking over a stale lease, for commands that open the
+/// stores exclusively
+pub fn force_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("force")
+        .long("force")
+        .help("Clear a lease left behind by a mount or command that was killed")
+}
+
+/// Open the configured metadata store, and the data store if one is configured,
+/// wrapping both in an encrypted store if encryption is configured
+///
+/// With `Access::Exclusive` the lease of the metadata store is taken before the
+/// stores are returned, and opening fails if it is held by a mount or another
+/// command. A PostgreSQL metadata store is also locked for as long as it is
+/// open.
+pub fn open_stores(
+    backend: Backend,
+    data_backend: Option<Backend>,
+    encryption: Option<&EncryptionConfig>,
+    allow_migrations: bool,
+    access: Access,
+) -> PolyfsResult<Stores> {
+    let exclusive = matches!(access, Access::Exclusive { .. });
+    let kv_store: Arc<dyn KeyValueStore> =
+        Arc::from(open_kv_store(backend, allow_migrations, exclusive)?);
+    let lease = take_lease(&kv_store, access)?;
+    let data_store = match data_backend {
+        Some(backend) => Some(open_kv_store(backend, allow_migrations, false)?),
+        None => None,
+    };
+
+    let encryption = match encryption {
+        Some(encryption) => encryption,
+        None => return Ok((Box::new(kv_store), data_store, lease)),
+    };
+
+    let secret = read_secret(encryption, "Passphrase: ", "POLYFS_PASSPHRASE")?;
+    let kv_store: Box<dyn KeyValueStore> = Box::new(EncryptedKvStore::open(kv_store, &secret)?);
+    let data_store = match data_store {
+        Some(data_store) => Some(
+            Box::new(EncryptedKvStore::open(data_store, &secret)?) as Box<dyn KeyValueStore>
+        ),
+        None => None,
+    };
+
+    Ok((kv_store, data_store, lease))
+}
+
+/// Take the lease of an opened metadata store if `access` needs it
+///
+/// The lease is kept in the wrapped store of an encrypted store, so `kv_store`
+/// must not be wrapped in an `EncryptedKvStore` yet.
+pub fn take_lease(
+    kv_store: &Arc<dyn KeyValueStore>,
+    access: Access,
+) -> PolyfsResult<Option<StoreLease>> {
+    match access {
+        Access::Read => Ok(None),
+        Access::Exclusive { purpose, force } => {
+            Ok(Some(Lease::acquire(kv_store.clone(), purpose, force)?))
+        }
+    }
+}
+
+/// Read the secret for an encrypted store
+///
+/// A passphrase is taken from the environment variable `env_var` if it is set,
+/// and asked for with `prompt` otherwise.
+pub fn read_secret(
+    encryption: &EncryptionConfig,
+    prompt: &str,
+    env_var: &str,
+) -> PolyfsResult<Secret> {
+    match encryption {
+        EncryptionConfig::Passphrase => match std::env::var(env_var) {
+            Ok(passphrase) => Ok(Secret::Passphrase(passphrase)),
+            Err(_) => Ok(Secret::Passphrase(prompt_passphrase(prompt)?)),
+        },
+        EncryptionConfig::KeyFile(path) => Ok(Secret::KeyFile(try_to!(
+            std::fs::read(path),
+            "Could not read key file"
+        ))),
+    }
+}
+
+/// Ask the user for a passphrase without echoing it to the terminal
+pub fn prompt_passphrase(prompt: &str) -> PolyfsResult<String> {
+    use std::os::unix::io::AsRawFd;
+
+    eprint!("{}", prompt);
+
+    let fd = std::io::stdin().as_raw_fd();
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    let is_terminal = unsafe { libc::tcgetattr(fd, &mut original) } == 0;
+    if is_terminal {
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        silent.c_lflag |= libc::ECHONL;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+
+    let mut passphrase = String::new();
+    let result = std::io::stdin().read_line(&mut passphrase);
+
+    if is_terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    }
+    try_to!(result, "Could not read passphrase");
+
+    let passphrase = passphrase.trim_end_matches(&['\r', '\n'][..]);
+    if passphrase.is_empty() {
+        return Err(PolyfsError {
+            message: String::from("The passphrase can't be empty"),
+            cause: None,
+        });
+    }
+    Ok(passphrase.to_string())
+}
+
+/// Ask the user to confirm running `pending` database migrations unless there
+/// are none or `allow_migrations` is true
+fn confirm_migrations(
+    pending: &[&str],
+    note: &str,
+    allow_migrations: bool,
+) -> PolyfsResult<()> {
+    if pending.is_empty() || allow_migrations {
+        return Ok(());
+    }
+
+    eprintln!(
+        "The database needs to be migrated ({}). {} Migrate it? Type \"yes\" to confirm:",
+        pending.join(", "),
+        note
+    );
+
+    let mut prompt_result = String::new();
+    try_to!(
+        std::io::stdin().read_line(&mut prompt_result),
+        "Could not readline for prompt"
+    );
+
+    if prompt_result.trim() == "yes" {
+        Ok(())
+    } else {
+        Err(PolyfsError {
+            message: String::from("Not migrating database"),
+            cause: None,
+        })
+    }
+}
+
+/// Open a Sqlite KV store, asking for confirmation before migrating an
+/// existing database unless `allow_migrations` is true
+pub fn open_sqlite_store(
+    config: SqliteConfig,
+    allow_migrations: bool,
+) -> PolyfsResult<SqliteKvStore> {
+    confirm_migrations(
+        &SqliteKvStore::pending_migrations(&config)?,
+        "It will be backed up first.",
+        allow_migrations,
+    )?;
+
+    SqliteKvStore::open(config, true)
+}
+
+/// Open a PostgreSQL KV store, asking for confirmation before migrating an
+/// existing database unless `allow_migrations` is true
+pub fn open_postgres_store(
+    config: PostgresConfig,
+    allow_migrations: bool,
+) -> PolyfsResult<PostgresKvStore> {
+    confirm_migrations(
+        &PostgresKvStore::pending_migrations(&config)?,
+        "Back it up with `pg_dump` first, and stop any other hosts using it.",
+        allow_migrations,
+    )?;
+
+    PostgresKvStore::open(config, true)
+}
@@ -0,0 +1,149 @@
+//! PolyFS `trash` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{try_to, PolyfsResult};
+use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
+
+/// Get CLI for the `trash` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    let ids = Arg::with_name("id")
+        .help("The ids of the files, as shown by `polyfs trash list`")
+        .multiple(true);
+
+    SubCommand::with_name("trash")
+        .about("List, restore and purge removed files")
+        .long_about(
+"List, restore and purge removed files. When the trash is enabled with \
+`polyfs config filesystem --trash on`, removed files are kept in the trash \
+until they are purged, and still count towards usage and quotas until then. \
+Files can't be restored or purged while the filesystem is mounted."
+        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("list")
+            .about("List the files in the trash, oldest first"))
+        .subcommand(SubCommand::with_name("restore")
+            .about(
+"Put files back where they were removed from. Removed directories that a file \
+was in are restored with it, and so are the removed contents of a directory."
+            )
+            .arg(ids.clone().required(true))
+            .arg(force_arg()))
+        .subcommand(SubCommand::with_name("purge")
+            .about("Delete files in the trash for good")
+            .arg(ids)
+            .arg(force_arg())
+            .arg(Arg::with_name("older_than")
+                .long("older-than")
+                .value_name("days")
+                .help("Purge the files removed more than this many days ago"))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .help("Purge every file in the trash"))
+            .group(ArgGroup::with_name("which")
+                .args(&["id", "older_than", "all"])
+                .required(true)))
+}
+
+/// Run `trash` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `trash` subcommand");
+
+    use crate::app::filesystem::inode::Timestamp;
+    use crate::app::filesystem::migration::check_stores;
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::app::filesystem::trash::{list_trash, original_paths};
+    use crate::cli::store::{open_stores, Access};
+    use std::io::Error;
+
+    let config = load_config(args.global)?;
+
+    // Restoring and purging files needs the lease, so that it can't race a
+    // mount or its purge thread
+    let access = match args.sub.subcommand() {
+        ("list", _) => Access::Read,
+        (_, sub) => Access::Exclusive {
+            purpose: "trash",
+            force: sub.is_some_and(|sub| sub.is_present("force")),
+        },
+    };
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        access,
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+
+    let parse_ids = |sub: &clap::ArgMatches| -> PolyfsResult<Vec<u64>> {
+        let mut ids = vec![];
+        for id in sub.values_of("id").into_iter().flatten() {
+            ids.push(try_to!(id.parse::<u64>(), format!("Invalid id: {}", id)));
+        }
+        Ok(ids)
+    };
+
+    if let ("list", Some(_)) = args.sub.subcommand() {
+        let entries = list_trash(&kv_store)?;
+        let paths = original_paths(&kv_store, &entries)?;
+        for entry in entries {
+            let deleted = chrono::NaiveDateTime::from_timestamp(entry.deleted.sec, entry.deleted.nsec);
+            println!(
+                "{}  {:>20}  {}",
+                deleted.format("%Y-%m-%d %H:%M:%S UTC"),
+                entry.ino,
+                paths[&entry.ino]
+            );
+        }
+        return Ok(());
+    }
+
+    let core = FilesystemCore::new(kv_store, data_store, config.filesystem, false);
+    try_to!(
+        core.init().map_err(Error::from_raw_os_error),
+        "Could not open filesystem"
+    );
+
+    match args.sub.subcommand() {
+        ("restore", Some(sub)) => {
+            for id in parse_ids(sub)? {
+                let restored = try_to!(
+                    core.restore_trash(id).map_err(Error::from_raw_os_error),
+                    format!("Could not restore {}", id)
+                );
+                println!("Restored {} files", restored.len());
+            }
+        }
+
+        ("purge", Some(sub)) => {
+            let ids = parse_ids(sub)?;
+            let older_than = match sub.value_of("older_than") {
+                Some(days) => Some(try_to!(days.parse::<i64>(), "Could not parse number of days")),
+                None => None,
+            };
+
+            let now = Timestamp::now();
+            let purged = try_to!(
+                core.purge_trash(|entry| {
+                    sub.is_present("all")
+                        || ids.contains(&entry.ino)
+                        || older_than.is_some_and(|days| {
+                            now.seconds_since(entry.deleted) >= days.saturating_mul(24 * 60 * 60)
+                        })
+                })
+                .map_err(Error::from_raw_os_error),
+                "Could not purge the trash"
+            );
+            println!("Purged {} files", purged.len());
+        }
+
+        _ => panic!(
+            "Unimplemented command or failure to show help message when lacking a subcommand."
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! PolyFS `usage` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::ArgSet;
+use crate::PolyfsResult;
+use clap::{App, SubCommand};
+
+/// Get CLI for the `usage` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("usage")
+        .about("Show the space used by the filesystem")
+        .long_about(
+"Show the space used by the filesystem: the logical size of the files as seen \
+through the filesystem and the physical size of their stored contents after \
+compression. Backends that can only be opened by one process at a time can't \
+be read while the filesystem is mounted."
+        )
+}
+
+/// Run `usage` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `usage` subcommand");
+
+    use crate::app::filesystem::migration::check_layout;
+    use crate::app::filesystem::usage::Usage;
+    use crate::cli::store::{open_stores, Access};
+
+    let config = load_config(args.global)?;
+
+    let (kv_store, data_store, _) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        Access::Read,
+    )?;
+    check_layout(&kv_store)?;
+
+    let usage = match Usage::load(&kv_store)? {
+        Some(usage) => usage,
+        None => Usage::count(&kv_store, data_store.as_ref().unwrap_or(&kv_store))?,
+    };
+
+    println!("Files:         {}", usage.files);
+    println!("Logical size:  {} bytes", usage.logical_bytes);
+    print!("Physical size: {} bytes", usage.physical_bytes);
+    if usage.physical_bytes > 0 {
+        print!(
+            " ( compression ratio {:.2} )",
+            usage.logical_bytes as f64 / usage.physical_bytes as f64
+        );
+    }
+    println!();
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! PolyFS `versions` subcommand
+
+use crate::cli::config::load_config;
+use crate::cli::store::force_arg;
+use crate::cli::ArgSet;
+use crate::{try_to, PolyfsResult};
+use clap::{App, Arg, SubCommand};
+
+/// Get CLI for the `versions` subcommand
+#[rustfmt::skip]
+pub fn get_cli<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("versions")
+        .about("List and restore the previous contents of a file")
+        .long_about(
+"List and restore the previous contents of a file. Files keep versions when \
+their `user.polyfs.versions` extended attribute is set to a policy such as \
+`keep=10,days=30`, which keeps up to 10 versions for up to 30 days. Setting it \
+on a directory sets it for everything in the directory. Versions can also be \
+read from the `.versions` directory of any directory while the filesystem is \
+mounted. A version can't be restored while the filesystem is mounted."
+        )
+        .arg(Arg::with_name("path")
+            .help("The file, relative to the root of the filesystem")
+            .required(true))
+        .arg(Arg::with_name("restore")
+            .long("restore")
+            .short("r")
+            .value_name("number")
+            .help(
+"Replace the content of the file with this version, keeping the current \
+content as a new version"
+            ))
+        .arg(force_arg())
+}
+
+/// Run `versions` subcommand
+pub fn run(args: ArgSet) -> PolyfsResult<()> {
+    log::debug!("Running `versions` subcommand");
+
+    use crate::app::filesystem::migration::check_stores;
+    use crate::app::filesystem::operations::FilesystemCore;
+    use crate::cli::store::{open_stores, Access};
+    use std::io::Error;
+    use std::path::Path;
+
+    let config = load_config(args.global)?;
+    let path = Path::new(args.sub.value_of("path").expect("Could not load path arg"));
+    let restore = match args.sub.value_of("restore") {
+        Some(number) => Some(try_to!(number.parse::<u64>(), format!("Invalid version: {}", number))),
+        None => None,
+    };
+
+    let access = match restore {
+        Some(_) => Access::Exclusive {
+            purpose: "versions",
+            force: args.sub.is_present("force"),
+        },
+        None => Access::Read,
+    };
+    let (kv_store, data_store, _lease) = open_stores(
+        config.backend,
+        config.data_backend,
+        config.encryption.as_ref(),
+        false,
+        access,
+    )?;
+    check_stores(&kv_store, data_store.as_ref())?;
+
+    let core = FilesystemCore::new(kv_store, data_store, config.filesystem, false);
+    try_to!(
+        core.init().map_err(Error::from_raw_os_error),
+        "Could not open filesystem"
+    );
+    let inode = try_to!(
+        core.resolve(path).map_err(Error::from_raw_os_error),
+        format!("Could not find {}", path.display())
+    );
+
+    if let Some(number) = restore {
+        try_to!(
+            core.restore_version(inode.ino, number).map_err(Error::from_raw_os_error),
+            format!("Could not restore version {} of {}", number, path.display())
+        );
+        println!("Restored version {} of {}", number, path.display());
+        return Ok(());
+    }
+
+    let versions = try_to!(
+        core.versions(inode.ino).map_err(Error::from_raw_os_error),
+        format!("Could not list the versions of {}", path.display())
+    );
+    for version in versions {
+        let kept = chrono::NaiveDateTime::from_timestamp(version.kept.sec, version.kept.nsec);
+        let mtime = chrono::NaiveDateTime::from_timestamp(version.mtime.sec, version.mtime.nsec);
+        println!(
+            "{:>6}  {}  {:>14}  modified {}",
+            version.number,
+            kept.format("%Y-%m-%d %H:%M:%S UTC"),
+            version.size,
+            mtime.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+
+    Ok(())
+}